@@ -1,14 +1,206 @@
+use crate::daemon::DEFAULT_ADDR;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 pub use clap::Parser;
+use clap::Subcommand;
 
 #[derive(Parser)]
 #[command(author, version, about)]
 pub struct Args {
-    /// Path to one or more torrent files
-    #[arg(short, long, value_name = "FILE(s)")]
-    pub torrents: Option<Vec<PathBuf>>,
-    /// Path to out directory
-    #[arg(short, long, value_name = "DIR")]
-    pub out: PathBuf,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Download torrents
+    Download {
+        /// Path to one or more torrent files
+        #[arg(short, long, value_name = "FILE(s)", required = true)]
+        torrents: Vec<PathBuf>,
+        /// Path to out directory, instead of the configured `download_dir`
+        #[arg(short, long, value_name = "DIR")]
+        out: Option<PathBuf>,
+        /// Only download files with these indices (as printed by `info`) or matching these
+        /// globs over their path, like `0,docs/*.pdf`
+        #[arg(short, long, value_name = "INDEX|GLOB", value_delimiter = ',')]
+        files: Vec<String>,
+        /// Download pieces in order, so files can be played while they're downloaded
+        #[arg(long)]
+        sequential: bool,
+        /// Only print errors
+        #[arg(short, long, conflicts_with = "json")]
+        quiet: bool,
+        /// Print events and stats as JSON lines on stdout
+        #[arg(long)]
+        json: bool,
+        #[command(flatten)]
+        agent: AgentArgs,
+    },
+    /// Print the contents of a torrent file
+    Info {
+        /// Path to torrent file
+        file: PathBuf,
+    },
+    /// Hash-check data of a torrent that was already downloaded
+    Verify {
+        /// Path to torrent file
+        file: PathBuf,
+        /// Directory the torrent was downloaded into
+        dir: PathBuf,
+    },
+    /// Create a torrent file from a file or directory
+    Create {
+        /// File or directory to share
+        path: PathBuf,
+        /// URL of the tracker to announce to
+        #[arg(short, long, value_name = "URL")]
+        announce: String,
+        /// Path to write the torrent file to, instead of `<name>.torrent`
+        #[arg(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+        /// Length of pieces in bytes, a power of two of at least 16 KiB
+        #[arg(short, long, value_name = "BYTES", default_value_t = 256 * 1024)]
+        piece_length: usize,
+        /// Only find peers through the tracker
+        #[arg(long)]
+        private: bool,
+        /// Comment to add
+        #[arg(short, long)]
+        comment: Option<String>,
+    },
+    /// Print a magnet URI for a torrent file
+    Magnet {
+        /// Path to torrent file
+        file: PathBuf,
+    },
+    /// Ask the tracker of a torrent for statistics of its swarm
+    Scrape {
+        /// Path to torrent file
+        file: PathBuf,
+    },
+    /// Print a Bencoded file in a readable form
+    Bdecode {
+        /// Path to Bencoded file
+        file: PathBuf,
+        /// Print as JSON instead
+        #[arg(short, long)]
+        json: bool,
+    },
+    /// Convert a JSON file (as printed by `bdecode --json`) to Bencode
+    Bencode {
+        /// Path to JSON file
+        file: PathBuf,
+        /// Path to write Bencode to, instead of stdout
+        #[arg(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+    /// Run in the background, controlled through a local HTTP API
+    Daemon {
+        /// Address to serve the control API on, which must be a loopback address with
+        /// `--no-auth`
+        #[arg(short, long, value_name = "ADDR", default_value = DEFAULT_ADDR)]
+        listen: SocketAddr,
+        /// Path to write the access token of the control API to, instead of
+        /// `$XDG_CONFIG_HOME/rip/daemon.token`
+        #[arg(long, value_name = "FILE", conflicts_with = "no_auth")]
+        token_file: Option<PathBuf>,
+        /// Serve the control API without requiring the access token
+        #[arg(long)]
+        no_auth: bool,
+        /// Default directory to download into, instead of the configured `download_dir`
+        #[arg(short, long, value_name = "DIR")]
+        out: Option<PathBuf>,
+        #[command(flatten)]
+        agent: AgentArgs,
+    },
+    /// Add a torrent file or magnet URI to a running daemon
+    Add {
+        /// Path to torrent file, or magnet URI
+        source: String,
+        /// Directory to download into, instead of the daemon's default
+        #[arg(short, long, value_name = "DIR")]
+        out: Option<PathBuf>,
+        /// Add without starting the download
+        #[arg(long)]
+        paused: bool,
+        #[command(flatten)]
+        remote: Remote,
+    },
+    /// List torrents of a running daemon
+    Ls {
+        #[command(flatten)]
+        remote: Remote,
+    },
+    /// Pause a torrent on a running daemon
+    Pause {
+        /// Info hash of the torrent, as printed by `ls`
+        hash: String,
+        #[command(flatten)]
+        remote: Remote,
+    },
+    /// Resume a torrent on a running daemon
+    Resume {
+        /// Info hash of the torrent, as printed by `ls`
+        hash: String,
+        #[command(flatten)]
+        remote: Remote,
+    },
+    /// Remove a torrent from a running daemon
+    Rm {
+        /// Info hash of the torrent, as printed by `ls`
+        hash: String,
+        /// Delete downloaded data as well
+        #[arg(long)]
+        delete_data: bool,
+        #[command(flatten)]
+        remote: Remote,
+    },
+}
+
+/// Options for talking to a running daemon.
+#[derive(clap::Args)]
+pub struct Remote {
+    /// Address of the daemon's control API
+    #[arg(long, value_name = "ADDR", default_value = DEFAULT_ADDR)]
+    pub daemon: SocketAddr,
+    /// Path to the daemon's access token, instead of `$XDG_CONFIG_HOME/rip/daemon.token`
+    #[arg(long, value_name = "FILE")]
+    pub token_file: Option<PathBuf>,
+}
+
+/// Settings of the torrent agent, overriding those of the configuration file.
+#[derive(clap::Args, Default)]
+pub struct AgentArgs {
+    /// Path to configuration file, instead of `$XDG_CONFIG_HOME/rip/config.toml`
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+    /// Port or port range to listen on, like `6881-6889`
+    #[arg(long, value_name = "PORT(S)")]
+    pub port: Option<String>,
+    /// Maximum number of peer connections
+    #[arg(long, value_name = "N")]
+    pub max_connections: Option<usize>,
+    /// Maximum number of peer connections per torrent
+    #[arg(long, value_name = "N")]
+    pub max_connections_per_torrent: Option<usize>,
+    /// Maximum number of peers uploaded to at once, per torrent
+    #[arg(long, value_name = "N")]
+    pub upload_slots: Option<usize>,
+    /// Maximum download rate in bytes per second
+    #[arg(long, value_name = "BYTES")]
+    pub download_limit: Option<u64>,
+    /// Maximum upload rate in bytes per second
+    #[arg(long, value_name = "BYTES")]
+    pub upload_limit: Option<u64>,
+    /// URL of a proxy for tracker requests
+    #[arg(long, value_name = "URL")]
+    pub proxy: Option<String>,
+    /// Encryption of peer connections: disabled, enabled or forced
+    #[arg(long, value_name = "POLICY")]
+    pub encryption: Option<String>,
+    /// Transport tried first when connecting to peers: tcp or utp
+    #[arg(long, value_name = "TRANSPORT")]
+    pub transport: Option<String>,
 }
@@ -0,0 +1,39 @@
+use rip_lib::prelude::*;
+use std::io::Write;
+use std::path::Path;
+
+/// Print the Bencoded file at `path`, either pretty-printed or as JSON.
+pub fn bdecode(path: &Path, json: bool) -> anyhow::Result<()> {
+    let contents = std::fs::read(path)?;
+    let value = decode(&contents)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&value.to_json())?);
+    } else {
+        println!("{}", value.pretty());
+    }
+
+    Ok(())
+}
+
+/// Convert the JSON file at `path` to Bencode, writing it to `out` or stdout.
+pub fn bencode(path: &Path, out: Option<&Path>) -> anyhow::Result<()> {
+    let contents = std::fs::read(path)?;
+    let json = serde_json::from_slice::<serde_json::Value>(&contents)?;
+    let value = Value::from_json(&json)?;
+
+    match out {
+        Some(out) => {
+            let mut file = std::io::BufWriter::new(std::fs::File::create(out)?);
+            encode_to(&value, &mut file)?;
+            file.flush()?;
+        }
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            encode_to(&value, &mut stdout)?;
+            stdout.flush()?;
+        }
+    }
+
+    Ok(())
+}
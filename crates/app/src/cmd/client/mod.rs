@@ -0,0 +1,201 @@
+use crate::cli::Remote;
+use crate::config;
+use crate::daemon::api::*;
+use anyhow::bail;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Client of a running daemon's control API.
+pub struct Client {
+    base: String,
+    /// Access token, unless the daemon doesn't require one.
+    token: Option<String>,
+    http: reqwest::Client,
+}
+
+impl Client {
+    /// Create a [`Client`] for the daemon at `addr`, authenticating with `token`.
+    pub fn new(addr: SocketAddr, token: Option<String>) -> Self {
+        Self {
+            base: format!("http://{addr}"),
+            token,
+            http: reqwest::Client::new(),
+        }
+    }
+
+    /// Create a [`Client`] for the daemon of `remote`, reading its token file if there is one.
+    pub fn connect(remote: &Remote) -> anyhow::Result<Self> {
+        let token = match &remote.token_file {
+            Some(path) => Some(std::fs::read_to_string(path)?),
+            None => match config::token_path().map(std::fs::read_to_string) {
+                Some(Ok(token)) => Some(token),
+                Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => None,
+            },
+        };
+
+        Ok(Self::new(
+            remote.daemon,
+            token.map(|t| t.trim().to_string()),
+        ))
+    }
+
+    /// Send a request and parse the JSON response.
+    async fn send<T: serde::de::DeserializeOwned>(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<Vec<u8>>,
+    ) -> anyhow::Result<T> {
+        let mut request = self
+            .http
+            .request(method, format!("{}{path}", self.base))
+            .header(reqwest::header::CONTENT_TYPE, "application/json");
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(body) = body {
+            request = request.body(body);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let bytes = response.bytes().await?;
+
+        if !status.is_success() {
+            let error = serde_json::from_slice::<ErrorResponse>(&bytes)
+                .map(|e| e.error)
+                .unwrap_or_else(|_| status.to_string());
+            bail!("{error}");
+        }
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Add a torrent file or magnet URI.
+    pub async fn add(&self, request: &AddRequest) -> anyhow::Result<TorrentStatus> {
+        let body = serde_json::to_vec(request)?;
+        self.send(reqwest::Method::POST, "/torrents", Some(body))
+            .await
+    }
+
+    /// List all torrents.
+    pub async fn list(&self) -> anyhow::Result<Vec<TorrentStatus>> {
+        self.send(reqwest::Method::GET, "/torrents", None).await
+    }
+
+    /// Pause torrent with `hash`.
+    pub async fn pause(&self, hash: &str) -> anyhow::Result<TorrentStatus> {
+        self.send(
+            reqwest::Method::POST,
+            &format!("/torrents/{hash}/pause"),
+            None,
+        )
+        .await
+    }
+
+    /// Resume torrent with `hash`.
+    pub async fn resume(&self, hash: &str) -> anyhow::Result<TorrentStatus> {
+        self.send(
+            reqwest::Method::POST,
+            &format!("/torrents/{hash}/resume"),
+            None,
+        )
+        .await
+    }
+
+    /// Remove torrent with `hash`.
+    pub async fn remove(
+        &self,
+        hash: &str,
+        request: &RemoveRequest,
+    ) -> anyhow::Result<TorrentStatus> {
+        let body = serde_json::to_vec(request)?;
+        self.send(
+            reqwest::Method::DELETE,
+            &format!("/torrents/{hash}"),
+            Some(body),
+        )
+        .await
+    }
+}
+
+/// Add a torrent file or magnet URI to the daemon of `remote`.
+pub async fn add(
+    remote: &Remote,
+    source: String,
+    out: Option<PathBuf>,
+    paused: bool,
+) -> anyhow::Result<()> {
+    // The daemon may run in another directory, so send absolute paths.
+    let source = match source.starts_with("magnet:") {
+        true => source,
+        false => std::fs::canonicalize(&source)?
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let out = out.map(std::fs::canonicalize).transpose()?;
+    let status = Client::connect(remote)?
+        .add(&AddRequest {
+            source,
+            out,
+            paused,
+        })
+        .await?;
+    print_status(&status);
+
+    Ok(())
+}
+
+/// List torrents of the daemon of `remote`.
+pub async fn ls(remote: &Remote) -> anyhow::Result<()> {
+    for status in Client::connect(remote)?.list().await? {
+        print_status(&status);
+    }
+
+    Ok(())
+}
+
+/// Pause torrent with `hash` on the daemon of `remote`.
+pub async fn pause(remote: &Remote, hash: &str) -> anyhow::Result<()> {
+    print_status(&Client::connect(remote)?.pause(hash).await?);
+
+    Ok(())
+}
+
+/// Resume torrent with `hash` on the daemon of `remote`.
+pub async fn resume(remote: &Remote, hash: &str) -> anyhow::Result<()> {
+    print_status(&Client::connect(remote)?.resume(hash).await?);
+
+    Ok(())
+}
+
+/// Remove torrent with `hash` from the daemon of `remote`.
+pub async fn rm(remote: &Remote, hash: &str, delete_data: bool) -> anyhow::Result<()> {
+    let status = Client::connect(remote)?
+        .remove(hash, &RemoveRequest { delete_data })
+        .await?;
+    print_status(&status);
+
+    Ok(())
+}
+
+/// Print a single line describing a torrent.
+fn print_status(status: &TorrentStatus) {
+    let state = match &status.state {
+        TorrentState::FetchingMetadata => "fetching metadata".to_string(),
+        TorrentState::Queued => "queued".to_string(),
+        TorrentState::Checking => "checking".to_string(),
+        TorrentState::Downloading => "downloading".to_string(),
+        TorrentState::Seeding => "seeding".to_string(),
+        TorrentState::Paused => "paused".to_string(),
+        TorrentState::Error(reason) => format!("error ({reason})"),
+    };
+
+    println!(
+        "{}  {:>5.1}%  {}  {}",
+        status.hash,
+        status.progress * 100.0,
+        status.name,
+        state
+    );
+}
@@ -0,0 +1,131 @@
+mod progress;
+
+pub use progress::Output;
+
+use super::display_path;
+use anyhow::{anyhow, bail};
+use glob::{MatchOptions, Pattern};
+use rip_lib::prelude::*;
+use std::path::PathBuf;
+
+/// Download `torrents` into `out`, or the configured directory, reporting progress through `output`.
+///
+/// If `files` is not empty, only files matching it are downloaded, see [`select`].
+pub async fn download(
+    torrents: Vec<PathBuf>,
+    out: Option<PathBuf>,
+    files: &[String],
+    sequential: bool,
+    config: AgentConfig,
+    output: Output,
+) -> anyhow::Result<()> {
+    let out = out.unwrap_or(config.download_dir.clone());
+    let agent = Agent::with_config(config).await?;
+    let hashes = match files.is_empty() {
+        true => agent.add_torrents(torrents, &out).await?,
+        false => {
+            let mut hashes = Vec::new();
+            for path in torrents {
+                let torrent = Torrent::from_bytes(&tokio::fs::read(&path).await?)?;
+                let priorities = select(&torrent.info, files)?;
+                hashes.push(
+                    agent
+                        .add_torrent_with_priorities(torrent, &out, priorities)
+                        .await?,
+                );
+            }
+            hashes
+        }
+    };
+    if sequential {
+        for hash in &hashes {
+            agent.set_sequential(hash, true).await?;
+        }
+    }
+
+    let failed = progress::run(&agent, &hashes, output).await?;
+    agent.shutdown().await?;
+
+    if failed > 0 {
+        bail!("{failed} of {} torrents failed", hashes.len());
+    }
+
+    Ok(())
+}
+
+/// Get priorities that skip all files of `info` except those whose index is in `specs`,
+/// or whose path matches a glob in `specs`.
+///
+/// `*` doesn't match `/`, but `**` does. Fails if no file is selected.
+pub fn select(info: &TorrentInfo, specs: &[String]) -> anyhow::Result<Vec<FilePriority>> {
+    let patterns = specs
+        .iter()
+        .map(|spec| Pattern::new(spec).map_err(|e| anyhow!("invalid glob {spec:?}: {e}")))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..Default::default()
+    };
+
+    let priorities = info
+        .files
+        .iter()
+        .enumerate()
+        .map(|(index, file)| {
+            let path = display_path(info, file);
+            let selected = specs.iter().zip(&patterns).any(|(spec, pattern)| {
+                spec.parse::<usize>() == Ok(index) || pattern.matches_with(&path, options)
+            });
+
+            match selected {
+                true => FilePriority::Normal,
+                false => FilePriority::Skip,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if !priorities.contains(&FilePriority::Normal) {
+        bail!(
+            "no files of {} match {}",
+            String::from_utf8_lossy(&info.name),
+            specs.join(",")
+        );
+    }
+
+    Ok(priorities)
+}
+
+#[test]
+fn test_select() {
+    let file = |path: &[&str]| File {
+        length: 1,
+        path: path.iter().map(|c| c.as_bytes().to_vec()).collect(),
+        md5sum: None,
+    };
+    let info = TorrentInfo {
+        files: vec![
+            file(&["README.md"]),
+            file(&["docs", "guide.pdf"]),
+            file(&["docs", "old", "manual.pdf"]),
+            file(&["data.bin"]),
+        ],
+        name: b"dataset".to_vec(),
+        piece_length: 1,
+        pieces: vec![0; 80],
+        private: None,
+        is_single_file: false,
+    };
+    let specs = |specs: &[&str]| specs.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let (skip, normal) = (FilePriority::Skip, FilePriority::Normal);
+
+    assert_eq!(
+        select(&info, &specs(&["3", "docs/*.pdf"])).unwrap(),
+        [skip, normal, skip, normal]
+    );
+    assert_eq!(
+        select(&info, &specs(&["**/*.pdf"])).unwrap(),
+        [skip, normal, normal, skip]
+    );
+    assert!(select(&info, &specs(&["*.iso"])).is_err());
+    assert!(select(&info, &specs(&["[z-a"])).is_err());
+}
@@ -0,0 +1,316 @@
+use crate::cmd::format_bytes;
+use rip_lib::prelude::*;
+use serde_json::json;
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+/// Width of progress bars, in characters.
+const BAR_WIDTH: usize = 24;
+/// Interval between redraws of progress bars.
+const REDRAW: Duration = Duration::from_millis(500);
+/// Interval between status lines, when stderr isn't a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+/// Interval between `stats` lines in JSON mode.
+const JSON_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How download progress is reported.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Output {
+    /// Progress bars on a terminal, or periodic status lines otherwise, on stderr.
+    Human,
+    /// Only errors.
+    Quiet,
+    /// One JSON object per line on stdout, for events and regular stats.
+    Json,
+}
+
+/// Report progress of torrents with `hashes` until each is seeding or failed,
+/// returning the number of failed torrents.
+pub async fn run(agent: &Agent, hashes: &[Vec<u8>], output: Output) -> anyhow::Result<usize> {
+    let mut events = agent.subscribe();
+    let interactive = std::io::stderr().is_terminal();
+    let interval = match output {
+        Output::Human if interactive => REDRAW,
+        Output::Human | Output::Quiet => LOG_INTERVAL,
+        Output::Json => JSON_INTERVAL,
+    };
+    let mut tick = tokio::time::interval(interval);
+    let mut drawn = 0;
+    let started = Instant::now();
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) => {
+                    match output {
+                        Output::Json => writeln!(std::io::stdout(), "{}", event_to_json(&event))?,
+                        Output::Quiet | Output::Human => {
+                            if let AgentEvent::Error { message, .. } = &event {
+                                eprintln!("error: {message}");
+                            }
+                        }
+                    }
+                    // Only state changes can end the download; everything else waits for a tick.
+                    if !matches!(event, AgentEvent::StateChanged { .. }) {
+                        continue;
+                    }
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            },
+            _ = tick.tick() => {}
+        }
+
+        let stats = agent.stats().await?;
+        let torrents = stats
+            .torrents
+            .iter()
+            .filter(|torrent| hashes.contains(&torrent.status.hash))
+            .collect::<Vec<_>>();
+
+        match output {
+            Output::Human if interactive => {
+                let mut stderr = std::io::stderr().lock();
+                if drawn > 0 {
+                    write!(stderr, "\x1b[{drawn}A")?;
+                }
+                for torrent in &torrents {
+                    writeln!(stderr, "\x1b[2K{}", bar_line(torrent))?;
+                }
+                writeln!(
+                    stderr,
+                    "\x1b[2K{}",
+                    summary_line(&stats, &torrents, started)
+                )?;
+                drawn = torrents.len() + 1;
+            }
+            Output::Human => {
+                for torrent in &torrents {
+                    eprintln!("{}", log_line(torrent));
+                }
+            }
+            Output::Quiet => {}
+            Output::Json => writeln!(std::io::stdout(), "{}", stats_to_json(&torrents))?,
+        }
+
+        let done = torrents.iter().all(|torrent| {
+            matches!(
+                torrent.status.state,
+                TorrentState::Seeding | TorrentState::Error(_)
+            )
+        });
+        if done {
+            if output == Output::Human && !interactive {
+                eprintln!("{}", summary_line(&stats, &torrents, started));
+            }
+
+            let failed = torrents
+                .iter()
+                .filter(|torrent| matches!(torrent.status.state, TorrentState::Error(_)))
+                .count();
+            return Ok(failed);
+        }
+    }
+
+    Ok(0)
+}
+
+/// Format a line with a progress bar for `torrent`.
+fn bar_line(torrent: &TorrentStats) -> String {
+    let progress = torrent.progress();
+    let filled = (progress * BAR_WIDTH as f64) as usize;
+
+    format!(
+        "{:<24.24} [{}{}] {:>5.1}%  {}",
+        torrent.status.name,
+        "#".repeat(filled),
+        "-".repeat(BAR_WIDTH - filled),
+        progress * 100.0,
+        details(torrent),
+    )
+}
+
+/// Format a plain status line for `torrent`, for logs.
+fn log_line(torrent: &TorrentStats) -> String {
+    format!(
+        "{}: {:.1}%  {}",
+        torrent.status.name,
+        torrent.progress() * 100.0,
+        details(torrent),
+    )
+}
+
+/// Format rates, ETA, peers, ratio and state of `torrent`.
+fn details(torrent: &TorrentStats) -> String {
+    let status = &torrent.status;
+    let eta = match torrent.eta {
+        Some(eta) => format_duration(eta),
+        None => "-".to_string(),
+    };
+
+    format!(
+        "down {}/s  up {}/s  eta {}  peers {} ({} seeds)  ratio {:.2}  {}",
+        format_bytes(torrent.download_rate),
+        format_bytes(torrent.upload_rate),
+        eta,
+        status.peers,
+        status.seeds,
+        ratio(status),
+        state_name(&status.state),
+    )
+}
+
+/// Format a line summarizing all `torrents`.
+fn summary_line(stats: &AgentStats, torrents: &[&TorrentStats], started: Instant) -> String {
+    let finished = torrents
+        .iter()
+        .filter(|torrent| torrent.status.state == TorrentState::Seeding)
+        .count();
+    let verified = torrents.iter().map(|t| t.status.verified).sum::<u64>();
+    let total = torrents.iter().map(|t| t.status.total_length).sum::<u64>();
+
+    format!(
+        "{finished}/{} done  {} of {}  down {}/s  up {}/s  elapsed {}",
+        torrents.len(),
+        format_bytes(verified),
+        format_bytes(total),
+        format_bytes(stats.download_rate),
+        format_bytes(stats.upload_rate),
+        format_duration(started.elapsed()),
+    )
+}
+
+/// Get upload/download ratio.
+fn ratio(status: &TorrentStatus) -> f64 {
+    match status.downloaded {
+        0 => 0.0,
+        downloaded => status.uploaded as f64 / downloaded as f64,
+    }
+}
+
+fn state_name(state: &TorrentState) -> String {
+    match state {
+        TorrentState::Queued => "queued".to_string(),
+        TorrentState::Checking => "checking".to_string(),
+        TorrentState::Downloading => "downloading".to_string(),
+        TorrentState::Seeding => "done".to_string(),
+        TorrentState::Paused => "paused".to_string(),
+        TorrentState::Error(reason) => format!("error: {reason}"),
+    }
+}
+
+/// Format a duration as hours, minutes and seconds.
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{s}s"),
+        (0, m, s) => format!("{m}m{s:02}s"),
+        (h, m, s) => format!("{h}h{m:02}m{s:02}s"),
+    }
+}
+
+/// Convert `event` to a JSON object, tagged by its `event` field.
+fn event_to_json(event: &AgentEvent) -> serde_json::Value {
+    let hex = |hash: &[u8]| hash.iter().map(|b| format!("{b:02x}")).collect::<String>();
+
+    match event {
+        AgentEvent::TorrentAdded { hash, name } => {
+            json!({"event": "torrent_added", "hash": hex(hash), "name": name})
+        }
+        AgentEvent::TorrentRemoved { hash } => {
+            json!({"event": "torrent_removed", "hash": hex(hash)})
+        }
+        AgentEvent::StateChanged { hash, state } => {
+            json!({"event": "state_changed", "hash": hex(hash), "state": state_name(state)})
+        }
+        AgentEvent::Announced { hash, peers } => {
+            json!({"event": "announced", "hash": hex(hash), "peers": peers})
+        }
+        AgentEvent::AnnounceFailed { hash, reason } => {
+            json!({"event": "announce_failed", "hash": hex(hash), "reason": reason})
+        }
+        AgentEvent::PeerConnected {
+            hash,
+            addr,
+            client,
+            encrypted,
+            transport,
+        } => json!({
+            "event": "peer_connected",
+            "hash": hex(hash),
+            "addr": addr.to_string(),
+            "client": client.as_ref().map(ToString::to_string),
+            "encrypted": encrypted,
+            "transport": transport.to_string(),
+        }),
+        AgentEvent::PeerDisconnected { hash, addr, reason } => json!({
+            "event": "peer_disconnected",
+            "hash": hex(hash),
+            "addr": addr.to_string(),
+            "reason": reason,
+        }),
+        AgentEvent::PeerBanned { hash, ip } => {
+            json!({"event": "peer_banned", "hash": hex(hash), "ip": ip.to_string()})
+        }
+        AgentEvent::PieceVerified { hash, index } => {
+            json!({"event": "piece_verified", "hash": hex(hash), "index": index})
+        }
+        AgentEvent::PieceFailed { hash, index } => {
+            json!({"event": "piece_failed", "hash": hex(hash), "index": index})
+        }
+        AgentEvent::FileCompleted { hash, index, path } => json!({
+            "event": "file_completed",
+            "hash": hex(hash),
+            "index": index,
+            "path": path,
+        }),
+        AgentEvent::TorrentFinished { hash } => {
+            json!({"event": "torrent_finished", "hash": hex(hash)})
+        }
+        AgentEvent::Error { hash, message } => {
+            json!({"event": "error", "hash": hex(hash), "message": message})
+        }
+        AgentEvent::AcceptFailed { reason } => {
+            json!({"event": "accept_failed", "reason": reason})
+        }
+    }
+}
+
+/// Convert stats of `torrents` to a JSON object.
+fn stats_to_json(torrents: &[&TorrentStats]) -> serde_json::Value {
+    let torrents = torrents
+        .iter()
+        .map(|torrent| {
+            let status = &torrent.status;
+            json!({
+                "hash": status.hash.iter().map(|b| format!("{b:02x}")).collect::<String>(),
+                "name": status.name,
+                "state": state_name(&status.state),
+                "progress": torrent.progress(),
+                "verified": status.verified,
+                "total": status.total_length,
+                "downloaded": status.downloaded,
+                "uploaded": status.uploaded,
+                "download_rate": torrent.download_rate,
+                "upload_rate": torrent.upload_rate,
+                "eta": torrent.eta.map(|eta| eta.as_secs()),
+                "peers": status.peers,
+                "seeds": status.seeds,
+                "ratio": ratio(status),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({"event": "stats", "torrents": torrents})
+}
+
+#[test]
+fn test_format_duration() {
+    assert_eq!(format_duration(Duration::from_secs(59)), "59s");
+    assert_eq!(format_duration(Duration::from_secs(61)), "1m01s");
+    assert_eq!(
+        format_duration(Duration::from_secs(3 * 3600 + 5)),
+        "3h00m05s"
+    );
+}
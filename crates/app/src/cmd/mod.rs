@@ -0,0 +1,70 @@
+mod bcode;
+pub mod client;
+mod download;
+mod torrent;
+
+pub use bcode::*;
+pub use download::*;
+pub use torrent::*;
+
+use rip_lib::prelude::{File, TorrentInfo};
+
+/// Format a number of bytes with a binary unit.
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{bytes} B"),
+        _ => format!("{value:.1} {}", UNITS[unit]),
+    }
+}
+
+/// Get path of `file` of torrent `info` as shown to users, with `/` between components.
+///
+/// Files of single-file torrents are named after the torrent.
+pub fn display_path(info: &TorrentInfo, file: &File) -> String {
+    match info.is_single_file {
+        true => String::from_utf8_lossy(&info.name).into_owned(),
+        false => file
+            .path
+            .iter()
+            .map(|component| String::from_utf8_lossy(component))
+            .collect::<Vec<_>>()
+            .join("/"),
+    }
+}
+
+#[test]
+fn test_format_bytes() {
+    assert_eq!(format_bytes(1000), "1000 B");
+    assert_eq!(format_bytes(1536), "1.5 KiB");
+    assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+}
+
+#[test]
+fn test_display_path() {
+    let file = File {
+        length: 1,
+        path: vec![b"dir".to_vec(), b"a.txt".to_vec()],
+        md5sum: None,
+    };
+    let mut info = TorrentInfo {
+        files: vec![file.clone()],
+        name: b"name".to_vec(),
+        piece_length: 16 * 1024,
+        pieces: vec![0; 20],
+        private: None,
+        is_single_file: false,
+    };
+    assert_eq!(display_path(&info, &file), "dir/a.txt");
+
+    info.is_single_file = true;
+    assert_eq!(display_path(&info, &file), "name");
+}
@@ -0,0 +1,395 @@
+use super::{display_path, format_bytes};
+use anyhow::{anyhow, bail};
+use rip_lib::prelude::*;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Smallest piece length of created torrents, that of a block.
+const MIN_PIECE_LENGTH: usize = 16 * 1024;
+
+/// Read and parse the torrent file at `path`.
+fn read_torrent(path: &Path) -> anyhow::Result<Torrent> {
+    Ok(Torrent::from_bytes(&std::fs::read(path)?)?)
+}
+
+/// Print the contents of the torrent file at `path`.
+pub fn info(path: &Path) -> anyhow::Result<()> {
+    let torrent = read_torrent(path)?;
+    let info = &torrent.info;
+
+    println!("name:          {}", String::from_utf8_lossy(&info.name));
+    println!("info hash:     {}", torrent.get_hash_hex());
+    println!("  (base32):    {}", torrent.get_hash_base32());
+    println!("announce:      {}", torrent.announce);
+    for (i, tier) in torrent.announce_list.iter().flatten().enumerate() {
+        println!("  tier {}:      {}", i + 1, tier.join(", "));
+    }
+    if let Some(date) = torrent.creation_date {
+        println!("created:       {}", format_date(date));
+    }
+    if let Some(created_by) = &torrent.created_by {
+        println!("created by:    {created_by}");
+    }
+    if let Some(comment) = &torrent.comment {
+        println!("comment:       {comment}");
+    }
+    if let Some(encoding) = &torrent.encoding {
+        println!("encoding:      {encoding}");
+    }
+    println!("private:       {}", info.private.unwrap_or(false));
+    println!(
+        "pieces:        {} x {}",
+        info.piece_count(),
+        format_bytes(info.piece_length as u64)
+    );
+    println!(
+        "size:          {} ({} bytes)",
+        format_bytes(info.total_length()),
+        info.total_length()
+    );
+
+    println!("files:");
+    if info.is_single_file {
+        println!(
+            "  {}  {}",
+            String::from_utf8_lossy(&info.name),
+            format_bytes(info.total_length())
+        );
+    } else {
+        let mut tree = Tree::default();
+        for (index, file) in info.files.iter().enumerate() {
+            tree.insert(&file.path, index, file.length);
+        }
+        println!("  {}/", String::from_utf8_lossy(&info.name));
+        tree.print(2);
+    }
+
+    Ok(())
+}
+
+/// Hash-check data of the torrent file at `path` in directory `dir`.
+pub async fn verify(path: &Path, dir: &Path) -> anyhow::Result<()> {
+    let torrent = read_torrent(path)?;
+    let info = &torrent.info;
+    let have = torrent.verify(dir).await?;
+
+    let mut offset = 0;
+    for file in &info.files {
+        let complete = match file.length {
+            0 => true,
+            length => {
+                let first = offset / info.piece_length as u64;
+                let last = (offset + length - 1) / info.piece_length as u64;
+                (first..=last).all(|piece| have.get(piece as usize))
+            }
+        };
+        let name = display_path(info, file);
+        println!("{}  {name}", if complete { "ok  " } else { "FAIL" });
+        offset += file.length;
+    }
+
+    println!(
+        "{}/{} pieces ok ({:.1}%)",
+        have.count(),
+        have.len(),
+        match have.len() {
+            0 => 100.0,
+            len => have.count() as f64 * 100.0 / len as f64,
+        }
+    );
+    if !have.is_full() {
+        bail!(
+            "{} pieces are missing or corrupt",
+            have.len() - have.count()
+        );
+    }
+
+    Ok(())
+}
+
+/// Print a magnet URI for the torrent file at `path`.
+pub fn magnet(path: &Path) -> anyhow::Result<()> {
+    println!("{}", read_torrent(path)?.magnet_uri());
+
+    Ok(())
+}
+
+/// Print swarm statistics of the torrent file at `path`, as reported by its tracker.
+pub async fn scrape(path: &Path) -> anyhow::Result<()> {
+    let torrent = read_torrent(path)?;
+    let response = Tracker::create_scrape(&torrent)?.send().await?;
+
+    println!("seeders:    {}", response.complete);
+    println!("leechers:   {}", response.incomplete);
+    println!("completed:  {}", response.downloaded);
+
+    Ok(())
+}
+
+/// Create a torrent file of the file or directory at `path` with pieces of `piece_length`,
+/// announcing to `announce`, and write it to `out`, or `<name>.torrent`.
+pub fn create(
+    path: &Path,
+    announce: &str,
+    out: Option<&Path>,
+    piece_length: usize,
+    private: bool,
+    comment: Option<String>,
+) -> anyhow::Result<()> {
+    let metainfo = make_metainfo(path, announce, piece_length, private, comment)?;
+    let torrent = Torrent::from_bytes(&metainfo)?;
+    let out = match out {
+        Some(out) => out.to_path_buf(),
+        None => PathBuf::from(format!(
+            "{}.torrent",
+            String::from_utf8_lossy(&torrent.info.name)
+        )),
+    };
+    std::fs::write(&out, metainfo)?;
+
+    println!("{}  {}", torrent.get_hash_hex(), out.display());
+
+    Ok(())
+}
+
+/// Build Bencoded metainfo of the file or directory at `path`.
+fn make_metainfo(
+    path: &Path,
+    announce: &str,
+    piece_length: usize,
+    private: bool,
+    comment: Option<String>,
+) -> anyhow::Result<Vec<u8>> {
+    if piece_length < MIN_PIECE_LENGTH || !piece_length.is_power_of_two() {
+        bail!("piece length must be a power of two of at least {MIN_PIECE_LENGTH}");
+    }
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("{} has no UTF-8 name", path.display()))?;
+    let files = match path.is_dir() {
+        true => list_files(path)?,
+        false => vec![(Vec::new(), path.to_path_buf())],
+    };
+
+    let (lengths, pieces) = hash_files(files.iter().map(|(_, path)| path.as_path()), piece_length)?;
+    if lengths.iter().sum::<u64>() == 0 {
+        bail!("{} has no data to share", path.display());
+    }
+
+    let mut info = Dictionary::try_from(bdict! {
+        "name" => name,
+        "piece length" => i64::try_from(piece_length)?,
+        "pieces" => pieces,
+    })?;
+    match path.is_dir() {
+        true => {
+            let files = files
+                .iter()
+                .zip(&lengths)
+                .map(|((components, _), length)| -> anyhow::Result<Value> {
+                    let components = components.iter().map(|c| Value::from(c.as_str()));
+                    Ok(bdict! {
+                        "length" => i64::try_from(*length)?,
+                        "path" => components.collect::<Vec<_>>(),
+                    })
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?;
+            info.0.insert("files".into(), files.into());
+        }
+        false => {
+            info.0
+                .insert("length".into(), i64::try_from(lengths[0])?.into());
+        }
+    }
+    if private {
+        info.0.insert("private".into(), 1.into());
+    }
+
+    let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut metainfo = Dictionary::try_from(bdict! {
+        "announce" => announce,
+        "created by" => format!("rip {}", env!("CARGO_PKG_VERSION")),
+        "creation date" => i64::try_from(created)?,
+        "info" => info,
+    })?;
+    if let Some(comment) = comment {
+        metainfo.0.insert("comment".into(), comment.into());
+    }
+
+    Ok(encode(&metainfo.into()))
+}
+
+/// Get files under directory `dir`, in order, with the components of their paths within it.
+fn list_files(dir: &Path) -> anyhow::Result<Vec<(Vec<String>, PathBuf)>> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry.path();
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|_| anyhow!("{} has no UTF-8 name", path.display()))?;
+        match path.is_dir() {
+            true => {
+                for (mut components, path) in list_files(&path)? {
+                    components.insert(0, name.clone());
+                    files.push((components, path));
+                }
+            }
+            false => files.push((vec![name], path)),
+        }
+    }
+
+    Ok(files)
+}
+
+/// Hash the data of the files at `paths` as one stream of pieces of `piece_length`, getting
+/// the length of each file and the concatenated piece hashes.
+fn hash_files<'a>(
+    paths: impl Iterator<Item = &'a Path>,
+    piece_length: usize,
+) -> anyhow::Result<(Vec<u64>, Vec<u8>)> {
+    let mut lengths = Vec::new();
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(piece_length);
+
+    for path in paths {
+        let mut file = std::fs::File::open(path)?;
+        let mut length = 0;
+        loop {
+            let remaining = (piece_length - piece.len()) as u64;
+            length += (&mut file).take(remaining).read_to_end(&mut piece)? as u64;
+            if piece.len() < piece_length {
+                break;
+            }
+            pieces.extend(sha1_smol::Sha1::from(&piece).digest().bytes());
+            piece.clear();
+        }
+        lengths.push(length);
+    }
+    if !piece.is_empty() {
+        pieces.extend(sha1_smol::Sha1::from(&piece).digest().bytes());
+    }
+
+    Ok((lengths, pieces))
+}
+
+/// Directory tree of a multi-file torrent.
+#[derive(Default)]
+struct Tree {
+    dirs: BTreeMap<String, Tree>,
+    /// Index and length of each file.
+    files: BTreeMap<String, (usize, u64)>,
+}
+
+impl Tree {
+    /// Insert file `index` at `path` with `length`.
+    fn insert(&mut self, path: &[Vec<u8>], index: usize, length: u64) {
+        match path {
+            [] => {}
+            [name] => {
+                self.files
+                    .insert(String::from_utf8_lossy(name).into_owned(), (index, length));
+            }
+            [dir, rest @ ..] => self
+                .dirs
+                .entry(String::from_utf8_lossy(dir).into_owned())
+                .or_default()
+                .insert(rest, index, length),
+        }
+    }
+
+    /// Print tree, indented by `indent` spaces.
+    fn print(&self, indent: usize) {
+        for (name, dir) in &self.dirs {
+            println!("{:indent$}  {name}/", "");
+            dir.print(indent + 2);
+        }
+        for (name, (index, length)) in &self.files {
+            println!(
+                "{:indent$}  {name}  {}  [{index}]",
+                "",
+                format_bytes(*length)
+            );
+        }
+    }
+}
+
+/// Format seconds since the Unix epoch as a UTC date and time.
+fn format_date(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let secs = timestamp % 86400;
+
+    // Convert days since 1970-01-01 to a civil date (proleptic Gregorian calendar).
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02} UTC",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+#[test]
+fn test_format_date() {
+    assert_eq!(format_date(0), "1970-01-01 00:00:00 UTC");
+    assert_eq!(format_date(1681992794), "2023-04-20 12:13:14 UTC");
+    assert_eq!(format_date(951782400), "2000-02-29 00:00:00 UTC");
+}
+
+#[tokio::test]
+async fn test_create() {
+    let root = std::env::temp_dir().join(format!("rip-create-{}", std::process::id()));
+    let dir = root.join("content");
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("b.bin"), vec![1; 20_000]).unwrap();
+    std::fs::write(dir.join("sub").join("a.bin"), vec![2; 30_000]).unwrap();
+    std::fs::write(dir.join("empty"), b"").unwrap();
+
+    let metainfo = make_metainfo(&dir, "http://tracker/announce", 16 * 1024, true, None).unwrap();
+    let torrent = Torrent::from_bytes(&metainfo).unwrap();
+    let paths = torrent.info.files.iter().map(|file| file.path.concat());
+    assert_eq!(
+        paths.collect::<Vec<_>>(),
+        [&b"b.bin"[..], b"empty", b"suba.bin"]
+    );
+    assert_eq!(torrent.info.total_length(), 50_000);
+    assert_eq!(torrent.info.private, Some(true));
+    assert!(torrent.verify(&root).await.unwrap().is_full());
+
+    let single = make_metainfo(
+        &dir.join("b.bin"),
+        "http://tracker/announce",
+        16 * 1024,
+        false,
+        None,
+    );
+    let torrent = Torrent::from_bytes(&single.unwrap()).unwrap();
+    assert!(torrent.info.is_single_file);
+    assert!(torrent.verify(&dir).await.unwrap().is_full());
+
+    assert!(make_metainfo(&dir, "http://tracker/announce", 20_000, false, None).is_err());
+    assert!(make_metainfo(
+        &dir.join("empty"),
+        "http://tracker/announce",
+        16 * 1024,
+        false,
+        None
+    )
+    .is_err());
+    std::fs::remove_dir_all(root).unwrap();
+}
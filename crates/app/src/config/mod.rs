@@ -0,0 +1,249 @@
+use crate::cli::AgentArgs;
+use anyhow::Context;
+use rip_lib::prelude::*;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+
+/// Contents of a configuration file, every setting is optional.
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub listen_ports: Option<Ports>,
+    pub max_connections: Option<usize>,
+    pub max_connections_per_torrent: Option<usize>,
+    pub upload_slots: Option<usize>,
+    pub download_rate_limit: Option<u64>,
+    pub upload_rate_limit: Option<u64>,
+    pub peer_download_rate_limit: Option<u64>,
+    pub peer_upload_rate_limit: Option<u64>,
+    pub exempt_local_peers: Option<bool>,
+    pub download_dir: Option<PathBuf>,
+    pub dht: Option<bool>,
+    pub dht_routers: Option<Vec<String>>,
+    pub pex: Option<bool>,
+    pub lsd: Option<bool>,
+    pub proxy: Option<String>,
+    pub encryption: Option<String>,
+    pub preferred_transport: Option<String>,
+    pub allocation: Option<String>,
+    pub disk_threads: Option<usize>,
+    pub disk_cache_size: Option<usize>,
+}
+
+/// Port setting, either a single port like `6881` or a range like `"6881-6889"`.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Ports {
+    Single(u16),
+    Range(String),
+}
+
+impl ConfigFile {
+    /// Parse a configuration file.
+    pub fn parse(text: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(text)?)
+    }
+
+    /// Overwrite settings of `config` that are set in this file.
+    pub fn apply(self, config: &mut AgentConfig) -> Result<(), ConfigError> {
+        if let Some(ports) = self.listen_ports {
+            config.listen_ports = match ports {
+                Ports::Single(port) => port..=port,
+                Ports::Range(range) => parse_ports(&range)?,
+            };
+        }
+        set(&mut config.max_connections, self.max_connections);
+        set(
+            &mut config.max_connections_per_torrent,
+            self.max_connections_per_torrent,
+        );
+        set(&mut config.upload_slots, self.upload_slots);
+        if self.download_rate_limit.is_some() {
+            config.download_rate_limit = self.download_rate_limit;
+        }
+        if self.upload_rate_limit.is_some() {
+            config.upload_rate_limit = self.upload_rate_limit;
+        }
+        if self.peer_download_rate_limit.is_some() {
+            config.peer_download_rate_limit = self.peer_download_rate_limit;
+        }
+        if self.peer_upload_rate_limit.is_some() {
+            config.peer_upload_rate_limit = self.peer_upload_rate_limit;
+        }
+        set(&mut config.exempt_local_peers, self.exempt_local_peers);
+        set(&mut config.download_dir, self.download_dir);
+        set(&mut config.dht, self.dht);
+        set(&mut config.dht_routers, self.dht_routers);
+        set(&mut config.pex, self.pex);
+        set(&mut config.lsd, self.lsd);
+        if self.proxy.is_some() {
+            config.proxy = self.proxy;
+        }
+        if let Some(encryption) = self.encryption {
+            config.encryption = encryption.parse()?;
+        }
+        if let Some(transport) = self.preferred_transport {
+            config.preferred_transport = transport.parse()?;
+        }
+        if let Some(allocation) = self.allocation {
+            config.allocation = allocation.parse()?;
+        }
+        set(&mut config.disk_threads, self.disk_threads);
+        set(&mut config.disk_cache_size, self.disk_cache_size);
+
+        Ok(())
+    }
+}
+
+impl AgentArgs {
+    /// Overwrite settings of `config` that are given on the command line.
+    pub fn apply(&self, config: &mut AgentConfig) -> Result<(), ConfigError> {
+        ConfigFile {
+            listen_ports: self.port.clone().map(Ports::Range),
+            max_connections: self.max_connections,
+            max_connections_per_torrent: self.max_connections_per_torrent,
+            upload_slots: self.upload_slots,
+            download_rate_limit: self.download_limit,
+            upload_rate_limit: self.upload_limit,
+            proxy: self.proxy.clone(),
+            encryption: self.encryption.clone(),
+            preferred_transport: self.transport.clone(),
+            ..Default::default()
+        }
+        .apply(config)
+    }
+}
+
+/// Set `setting` to `value`, if there is one.
+fn set<T>(setting: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *setting = value;
+    }
+}
+
+/// Path of the default configuration file, `$XDG_CONFIG_HOME/rip/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    Some(dir()?.join("config.toml"))
+}
+
+/// Path of the daemon's default token file, `$XDG_CONFIG_HOME/rip/daemon.token`.
+pub fn token_path() -> Option<PathBuf> {
+    Some(dir()?.join("daemon.token"))
+}
+
+/// Directory of the configuration, `$XDG_CONFIG_HOME/rip`.
+fn dir() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(dir.join("rip"))
+}
+
+/// Read the configuration file at `path`, or an empty one if it's the default and missing.
+fn read(path: &Path, required: bool) -> anyhow::Result<ConfigFile> {
+    match std::fs::read_to_string(path) {
+        Ok(text) => ConfigFile::parse(&text),
+        Err(e) if !required && e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(ConfigFile::default())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Load settings from the configuration file, then from the command line `args`.
+pub fn load(args: &AgentArgs) -> anyhow::Result<AgentConfig> {
+    let mut config = AgentConfig::default();
+
+    let path = args.config.clone().or_else(default_path);
+    if let Some(path) = path {
+        read(&path, args.config.is_some())
+            .and_then(|file| Ok(file.apply(&mut config)?))
+            .with_context(|| format!("failed to load {}", path.display()))?;
+    }
+
+    args.apply(&mut config)?;
+    config.validate()?;
+
+    Ok(config)
+}
+
+#[test]
+fn test_config_file() {
+    let file = ConfigFile::parse(
+        r#"
+        listen_ports = "7000-7010"
+        max_connections = 100
+        upload_rate_limit = 65536
+        exempt_local_peers = false
+        download_dir = "/srv/torrents"
+        encryption = "disabled"
+        preferred_transport = "utp"
+        allocation = "full"
+        disk_cache_size = 16777216
+        dht = true
+        dht_routers = ["[::1]:6881"]
+        "#,
+    )
+    .unwrap();
+    let mut config = AgentConfig::default();
+    file.apply(&mut config).unwrap();
+    assert_eq!(config.listen_ports, 7000..=7010);
+    assert_eq!(config.max_connections, 100);
+    assert_eq!(config.max_connections_per_torrent, 50);
+    assert_eq!(config.upload_rate_limit, Some(65536));
+    assert!(!config.exempt_local_peers);
+    assert_eq!(config.download_dir, Path::new("/srv/torrents"));
+    assert_eq!(config.preferred_transport, Transport::Utp);
+    assert_eq!(config.allocation, AllocationMode::Full);
+    assert_eq!(config.disk_cache_size, 16 * 1024 * 1024);
+    assert!(config.dht);
+    assert_eq!(config.dht_routers, ["[::1]:6881"]);
+
+    let file = ConfigFile::parse("listen_ports = 7000").unwrap();
+    assert_eq!(file.listen_ports, Some(Ports::Single(7000)));
+
+    let error = ConfigFile::parse("max_conections = 10").unwrap_err();
+    assert!(error.to_string().contains("max_conections"));
+
+    let file = ConfigFile::parse(r#"encryption = "always""#).unwrap();
+    let error = file.apply(&mut AgentConfig::default()).unwrap_err();
+    assert_eq!(error.key, "encryption");
+}
+
+#[test]
+fn test_config_overrides() {
+    let dir = std::env::temp_dir().join("rip_test_config_overrides");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("config.toml");
+    std::fs::write(&path, "max_connections = 100\nupload_slots = 8\n").unwrap();
+
+    let args = AgentArgs {
+        config: Some(path),
+        max_connections: Some(20),
+        ..Default::default()
+    };
+    let config = load(&args).unwrap();
+    assert_eq!(config.max_connections, 20);
+    assert_eq!(config.upload_slots, 8);
+
+    let args = AgentArgs {
+        config: Some(dir.join("missing.toml")),
+        ..Default::default()
+    };
+    assert!(load(&args).is_err());
+
+    let args = AgentArgs {
+        config: Some(dir.join("config.toml")),
+        max_connections_per_torrent: Some(0),
+        ..Default::default()
+    };
+    let error = load(&args).unwrap_err();
+    assert_eq!(
+        error.downcast_ref::<ConfigError>().unwrap().key,
+        "max_connections_per_torrent"
+    );
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,98 @@
+//! Types exchanged as JSON over the daemon's control API.
+//!
+//! | Method   | Path                      | Body             | Response              |
+//! |----------|---------------------------|------------------|-----------------------|
+//! | `GET`    | `/torrents`               |                  | `Vec<TorrentStatus>`  |
+//! | `POST`   | `/torrents`               | [`AddRequest`]   | [`TorrentStatus`]     |
+//! | `POST`   | `/torrents/<hash>/pause`  |                  | [`TorrentStatus`]     |
+//! | `POST`   | `/torrents/<hash>/resume` |                  | [`TorrentStatus`]     |
+//! | `DELETE` | `/torrents/<hash>`        | [`RemoveRequest`]| [`TorrentStatus`]     |
+//! | `GET`    | `/settings`               |                  | [`Settings`]          |
+//! | `PUT`    | `/settings`               | [`Settings`]     | [`Settings`]          |
+//!
+//! Requests must carry `Authorization: Bearer <token>`, with the token the daemon writes to
+//! its token file, unless it runs with `--no-auth`. Requests other than `GET` must have
+//! `Content-Type: application/json`, and on a loopback address, `Host` must be loopback too.
+//!
+//! Failed requests respond with an [`ErrorResponse`].
+
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// Request to add a torrent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AddRequest {
+    /// Path to a torrent file (on the daemon's machine) or a magnet URI.
+    pub source: String,
+    /// Directory to download into, instead of the default.
+    pub out: Option<PathBuf>,
+    /// Whether to add the torrent paused.
+    #[serde(default)]
+    pub paused: bool,
+}
+
+/// Request to remove a torrent.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RemoveRequest {
+    /// Whether to delete downloaded data as well.
+    #[serde(default)]
+    pub delete_data: bool,
+}
+
+/// Daemon settings that can be changed at runtime.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Settings {
+    /// Default directory to download into.
+    pub download_dir: PathBuf,
+    /// Maximum download rate in bytes per second, over all torrents.
+    pub download_rate_limit: Option<u64>,
+    /// Maximum upload rate in bytes per second, over all torrents.
+    pub upload_rate_limit: Option<u64>,
+}
+
+/// State of a torrent.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "state", content = "reason")]
+pub enum TorrentState {
+    /// Added from a magnet URI, getting the torrent's metadata from peers.
+    FetchingMetadata,
+    Queued,
+    Checking,
+    Downloading,
+    Seeding,
+    Paused,
+    Error(String),
+}
+
+/// Status of a torrent.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TorrentStatus {
+    /// Info hash as hexadecimal.
+    pub hash: String,
+    /// Name of the torrent.
+    pub name: String,
+    /// Path of the downloaded file or directory.
+    pub path: PathBuf,
+    /// Current state.
+    #[serde(flatten)]
+    pub state: TorrentState,
+    /// Total size in bytes.
+    pub size: u64,
+    /// Bytes downloaded.
+    pub downloaded: u64,
+    /// Bytes uploaded.
+    pub uploaded: u64,
+    /// Fraction of the torrent that is done, from 0 to 1.
+    pub progress: f64,
+    /// Download rate in bytes per second.
+    pub download_rate: u64,
+    /// Upload rate in bytes per second.
+    pub upload_rate: u64,
+}
+
+/// Response to a failed request.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorResponse {
+    /// Description of the error.
+    pub error: String,
+}
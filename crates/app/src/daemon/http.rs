@@ -0,0 +1,154 @@
+//! Just enough HTTP/1.1 to serve the control API: one request per connection,
+//! bodies sized by `Content-Length`.
+
+use anyhow::{anyhow, bail};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Maximum size of the request line and headers.
+const MAX_HEAD: usize = 16 * 1024;
+/// Maximum size of a request body.
+const MAX_BODY: usize = 1024 * 1024;
+
+/// HTTP request.
+#[derive(Debug)]
+pub struct Request {
+    /// Method, such as `GET`.
+    pub method: String,
+    /// Path, without any query string.
+    pub path: String,
+    /// Headers, with lowercase names.
+    pub headers: Vec<(String, String)>,
+    /// Body, possibly empty.
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Get value of header `name`, which must be lowercase.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// HTTP response with a JSON body.
+#[derive(Debug)]
+pub struct Response {
+    /// Status code.
+    pub status: u16,
+    /// JSON body.
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    /// Create a [`Response`] with `status`, serializing `body` as JSON.
+    pub fn json<T: serde::Serialize>(status: u16, body: &T) -> Self {
+        Self {
+            status,
+            body: serde_json::to_vec(body).expect("API types serialize"),
+        }
+    }
+}
+
+/// Read a request from `reader`.
+pub async fn read_request<R: AsyncBufRead + Unpin>(reader: &mut R) -> anyhow::Result<Request> {
+    let mut head_len = 0;
+
+    let request_line = read_head_line(reader, &mut head_len).await?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().ok_or_else(|| anyhow!("missing method"))?;
+    let target = parts.next().ok_or_else(|| anyhow!("missing path"))?;
+    let path = target.split('?').next().unwrap_or_default().to_string();
+    let method = method.to_string();
+
+    let mut headers = Vec::new();
+    let mut content_length = 0;
+    loop {
+        let header = read_head_line(reader, &mut head_len).await?;
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let name = name.trim().to_ascii_lowercase();
+            if name == "content-length" {
+                content_length = value.trim().parse::<usize>()?;
+            }
+            headers.push((name, value.trim().to_string()));
+        }
+    }
+
+    if content_length > MAX_BODY {
+        bail!("request body too large");
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+
+    Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    })
+}
+
+/// Read a line of the request head, keeping count of its total length.
+async fn read_head_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    head_len: &mut usize,
+) -> anyhow::Result<String> {
+    let mut line = String::new();
+    let remaining = (MAX_HEAD + 1).saturating_sub(*head_len) as u64;
+    let n = (&mut *reader).take(remaining).read_line(&mut line).await?;
+    *head_len += n;
+    if n == 0 || *head_len > MAX_HEAD {
+        bail!("malformed request head");
+    }
+
+    Ok(line.trim_end().to_string())
+}
+
+/// Write `response` to `writer`.
+pub async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: &Response,
+) -> anyhow::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        415 => "Unsupported Media Type",
+        _ => "Internal Server Error",
+    };
+    let head = format!(
+        "HTTP/1.1 {} {reason}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.body.len()
+    );
+
+    writer.write_all(head.as_bytes()).await?;
+    writer.write_all(&response.body).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_read_request() {
+    let raw = b"POST /torrents?x=1 HTTP/1.1\r\nHost: localhost\r\nContent-Length: 2\r\n\r\n{}";
+    let request = read_request(&mut &raw[..]).await.unwrap();
+
+    assert_eq!(request.method, "POST");
+    assert_eq!(request.path, "/torrents");
+    assert_eq!(request.get_header("host"), Some("localhost"));
+    assert_eq!(request.get_header("content-type"), None);
+    assert_eq!(request.body, b"{}");
+
+    let truncated = b"GET /torrents HTTP/1.1\r\n";
+    assert!(read_request(&mut &truncated[..]).await.is_err());
+}
@@ -0,0 +1,427 @@
+pub mod api;
+mod http;
+mod session;
+
+use anyhow::bail;
+use api::*;
+use http::{Request, Response};
+use rip_lib::prelude::{Agent, AgentConfig, AgentEvent};
+use session::{Session, Shared};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::BufReader;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast;
+
+/// Default address of the control API.
+pub const DEFAULT_ADDR: &str = "127.0.0.1:6880";
+/// Time to wait before accepting control connections again after that failed.
+const ACCEPT_RETRY: Duration = Duration::from_millis(500);
+
+/// Who may use the control API.
+#[derive(Debug)]
+struct Guard {
+    /// Token that requests must carry as `Authorization: Bearer <token>`, if any.
+    token: Option<String>,
+    /// Whether requests must be addressed to a loopback host, against DNS rebinding.
+    local: bool,
+}
+
+impl Guard {
+    /// Check that `request` may be served, or get the response refusing it.
+    ///
+    /// Requiring JSON for requests that change anything makes browsers send a CORS
+    /// preflight, which the API never answers, so web pages can't forge them.
+    fn check(&self, request: &Request) -> Result<(), Response> {
+        if self.local && !request.get_header("host").is_some_and(is_loopback_host) {
+            return Err(error(403, "host not allowed"));
+        }
+        if let Some(token) = &self.token {
+            let authorization = request.get_header("authorization").unwrap_or_default();
+            let given = authorization.strip_prefix("Bearer ").unwrap_or_default();
+            if !equal_tokens(given, token) {
+                return Err(error(401, "missing or wrong token"));
+            }
+        }
+        let content_type = request.get_header("content-type").unwrap_or_default();
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        if request.method != "GET" && !mime.eq_ignore_ascii_case("application/json") {
+            return Err(error(415, "content type must be application/json"));
+        }
+
+        Ok(())
+    }
+}
+
+/// Run the daemon with `config`, serving the control API on `listen` until the process is stopped.
+///
+/// Requests must carry a token that is written to `token_file`, unless it's `None`, in which
+/// case `listen` must be a loopback address.
+pub async fn run(
+    listen: SocketAddr,
+    token_file: Option<&Path>,
+    config: AgentConfig,
+    settings: Settings,
+) -> anyhow::Result<()> {
+    if token_file.is_none() && !listen.ip().is_loopback() {
+        bail!("refusing to serve the control API on {listen} without authentication");
+    }
+
+    let listener = TcpListener::bind(listen).await?;
+    let guard = Arc::new(Guard {
+        token: token_file.map(write_token).transpose()?,
+        local: listen.ip().is_loopback(),
+    });
+    let agent = Agent::with_config(config).await?;
+    tokio::spawn(log_events(agent.subscribe()));
+    let session = Session::new(agent, settings);
+    eprintln!("listening on {}", listener.local_addr()?);
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            // Such as when out of file descriptors, which passes.
+            Err(e) => {
+                eprintln!("accepting control connection failed: {e}");
+                tokio::time::sleep(ACCEPT_RETRY).await;
+                continue;
+            }
+        };
+        let session = session.clone();
+        let guard = Arc::clone(&guard);
+
+        tokio::spawn(async move {
+            if let Err(e) = serve(stream, &session, &guard).await {
+                eprintln!("control connection failed: {e}");
+            }
+        });
+    }
+}
+
+/// Print events of the agent that whoever runs the daemon should know about.
+async fn log_events(mut events: broadcast::Receiver<AgentEvent>) {
+    loop {
+        match events.recv().await {
+            Ok(AgentEvent::AcceptFailed { reason }) => {
+                eprintln!("accepting peer connection failed: {reason}");
+            }
+            Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+            Err(broadcast::error::RecvError::Closed) => return,
+        }
+    }
+}
+
+/// Serve a single request on `stream`.
+async fn serve(stream: TcpStream, session: &Shared, guard: &Guard) -> anyhow::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let response = match http::read_request(&mut stream).await {
+        Ok(request) => match guard.check(&request) {
+            Ok(()) => route(session, request).await,
+            Err(response) => response,
+        },
+        Err(e) => error(400, e),
+    };
+
+    http::write_response(stream.get_mut(), &response).await
+}
+
+/// Dispatch `request` to its handler.
+async fn route(session: &Shared, request: Request) -> Response {
+    let segments = request
+        .path
+        .trim_matches('/')
+        .split('/')
+        .collect::<Vec<_>>();
+
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["torrents"]) => session.list().await.map(|list| Response::json(200, &list)),
+        ("POST", ["torrents"]) => match parse::<AddRequest>(&request.body) {
+            Ok(add) => session::add(session, add)
+                .await
+                .map(|status| Response::json(201, &status)),
+            Err(e) => return error(400, e),
+        },
+        ("GET", ["torrents", hash]) => session::status(session, hash)
+            .await
+            .map(|status| Response::json(200, &status)),
+        ("POST", ["torrents", hash, "pause"]) => session::pause(session, hash)
+            .await
+            .map(|status| Response::json(200, &status)),
+        ("POST", ["torrents", hash, "resume"]) => session::resume(session, hash)
+            .await
+            .map(|status| Response::json(200, &status)),
+        ("DELETE", ["torrents", hash]) => {
+            let remove = match request.body.is_empty() {
+                true => Ok(RemoveRequest::default()),
+                false => parse::<RemoveRequest>(&request.body),
+            };
+            match remove {
+                Ok(remove) => session::remove(session, hash, remove)
+                    .await
+                    .map(|status| Response::json(200, &status)),
+                Err(e) => return error(400, e),
+            }
+        }
+        ("GET", ["settings"]) => Ok(Response::json(200, &session.settings())),
+        ("PUT", ["settings"]) => match parse::<Settings>(&request.body) {
+            Ok(settings) => match session.set_settings(settings).await {
+                Ok(()) => Ok(Response::json(200, &session.settings())),
+                Err(e) => return error(400, e),
+            },
+            Err(e) => return error(400, e),
+        },
+        (_, ["torrents", ..] | ["settings"]) => return error(405, "method not allowed"),
+        _ => return error(404, "not found"),
+    };
+
+    result.unwrap_or_else(|e| error(409, e))
+}
+
+/// Write a new random token to `path`, readable only by the current user, and get it.
+fn write_token(path: &Path) -> anyhow::Result<String> {
+    let token = (0..32)
+        .map(|_| format!("{:02x}", rand::random::<u8>()))
+        .collect::<String>();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // The mode only applies to new files, so restrict an existing one before writing.
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(token.as_bytes())?;
+
+    Ok(token)
+}
+
+/// Compare tokens in time that doesn't depend on where they differ.
+fn equal_tokens(a: &str, b: &str) -> bool {
+    let diff = a
+        .bytes()
+        .zip(b.bytes())
+        .fold(0, |acc, (x, y)| acc | (x ^ y));
+    a.len() == b.len() && diff == 0
+}
+
+/// Check whether `host`, the value of a `Host` header, names a loopback address.
+fn is_loopback_host(host: &str) -> bool {
+    let name = match host.strip_prefix('[') {
+        Some(rest) => rest.split(']').next().unwrap_or_default(),
+        None => host.split(':').next().unwrap_or_default(),
+    };
+
+    name.eq_ignore_ascii_case("localhost")
+        || name.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+/// Parse JSON request body.
+fn parse<T: serde::de::DeserializeOwned>(body: &[u8]) -> anyhow::Result<T> {
+    Ok(serde_json::from_slice(body)?)
+}
+
+/// Create an error response.
+fn error(status: u16, error: impl ToString) -> Response {
+    Response::json(
+        status,
+        &ErrorResponse {
+            error: error.to_string(),
+        },
+    )
+}
+
+#[tokio::test]
+async fn test_route() {
+    let agent = Agent::with_port(0).await.unwrap();
+    let session = Session::new(
+        agent,
+        Settings {
+            download_dir: "/tmp".into(),
+            download_rate_limit: None,
+            upload_rate_limit: None,
+        },
+    );
+    let request = |method: &str, path: &str, body: &[u8]| Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        headers: Vec::new(),
+        body: body.to_vec(),
+    };
+
+    let list = route(&session, request("GET", "/torrents", b"")).await;
+    assert_eq!((list.status, list.body.as_slice()), (200, &b"[]"[..]));
+
+    let settings = route(
+        &session,
+        request("PUT", "/settings", br#"{"download_dir":"/srv"}"#),
+    )
+    .await;
+    assert_eq!(settings.status, 200);
+    assert_eq!(
+        session.settings().download_dir,
+        std::path::Path::new("/srv")
+    );
+
+    let settings = route(
+        &session,
+        request(
+            "PUT",
+            "/settings",
+            br#"{"download_dir":"/srv","upload_rate_limit":1024}"#,
+        ),
+    )
+    .await;
+    assert_eq!(settings.status, 200);
+    assert_eq!(session.settings().upload_rate_limit, Some(1024));
+
+    let settings = route(
+        &session,
+        request(
+            "PUT",
+            "/settings",
+            br#"{"download_dir":"/srv","upload_rate_limit":0}"#,
+        ),
+    )
+    .await;
+    assert_eq!(settings.status, 400);
+
+    // Magnets wait for their metadata, which isn't fetched while they're paused.
+    let hash = "c9e15763f722f23e98a29decdfae341b98d53056";
+    let body = format!(r#"{{"source":"magnet:?xt=urn:btih:{hash}&dn=a","paused":true}}"#);
+    let magnet = route(&session, request("POST", "/torrents", body.as_bytes())).await;
+    assert_eq!(magnet.status, 201);
+    let status = serde_json::from_slice::<TorrentStatus>(&magnet.body).unwrap();
+    assert_eq!(
+        (status.name.as_str(), status.state),
+        ("a", TorrentState::Paused)
+    );
+    let again = route(&session, request("POST", "/torrents", body.as_bytes())).await;
+    assert_eq!(again.status, 409);
+    let resume = route(
+        &session,
+        request("POST", &format!("/torrents/{hash}/resume"), b""),
+    )
+    .await;
+    let status = serde_json::from_slice::<TorrentStatus>(&resume.body).unwrap();
+    assert_eq!(status.state, TorrentState::FetchingMetadata);
+    let list = route(&session, request("GET", "/torrents", b"")).await;
+    assert_eq!(
+        serde_json::from_slice::<Vec<TorrentStatus>>(&list.body)
+            .unwrap()
+            .len(),
+        1
+    );
+    let remove = route(
+        &session,
+        request("DELETE", &format!("/torrents/{hash}"), b""),
+    )
+    .await;
+    assert_eq!(remove.status, 200);
+    let list = route(&session, request("GET", "/torrents", b"")).await;
+    assert_eq!(list.body, b"[]");
+
+    // Invalid hashes are refused.
+    let magnet = route(
+        &session,
+        request(
+            "POST",
+            "/torrents",
+            br#"{"source":"magnet:?xt=urn:btih:00"}"#,
+        ),
+    )
+    .await;
+    assert_eq!(magnet.status, 409);
+
+    assert_eq!(
+        route(&session, request("GET", "/nope", b"")).await.status,
+        404
+    );
+    assert_eq!(
+        route(&session, request("PATCH", "/settings", b""))
+            .await
+            .status,
+        405
+    );
+    assert_eq!(
+        route(
+            &session,
+            request("POST", &format!("/torrents/{}/pause", "0".repeat(40)), b"")
+        )
+        .await
+        .status,
+        409
+    );
+}
+
+#[test]
+fn test_guard() {
+    let request = |method: &str, headers: &[(&str, &str)]| Request {
+        method: method.to_string(),
+        path: "/torrents".to_string(),
+        headers: headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        body: Vec::new(),
+    };
+    let status = |guard: &Guard, request: &Request| guard.check(request).err().map(|r| r.status);
+    let open = Guard {
+        token: None,
+        local: true,
+    };
+
+    assert_eq!(
+        status(&open, &request("GET", &[("host", "127.0.0.1:6880")])),
+        None
+    );
+    assert_eq!(
+        status(&open, &request("GET", &[("host", "[::1]:6880")])),
+        None
+    );
+    assert_eq!(
+        status(&open, &request("GET", &[("host", "localhost")])),
+        None
+    );
+    assert_eq!(
+        status(&open, &request("GET", &[("host", "evil.com:6880")])),
+        Some(403)
+    );
+    assert_eq!(status(&open, &request("GET", &[])), Some(403));
+
+    // A form or `fetch` without a preflight can only send text/plain and the like.
+    let plain = [("host", "localhost"), ("content-type", "text/plain")];
+    assert_eq!(status(&open, &request("POST", &plain)), Some(415));
+    let json = [
+        ("host", "localhost"),
+        ("content-type", "application/json; charset=utf-8"),
+    ];
+    assert_eq!(status(&open, &request("POST", &json)), None);
+
+    let locked = Guard {
+        token: Some("secret".to_string()),
+        local: false,
+    };
+    assert_eq!(status(&locked, &request("GET", &[])), Some(401));
+    let wrong = [("authorization", "Bearer secreT")];
+    assert_eq!(status(&locked, &request("GET", &wrong)), Some(401));
+    let right = [("authorization", "Bearer secret")];
+    assert_eq!(status(&locked, &request("GET", &right)), None);
+
+    let path = std::env::temp_dir().join(format!("rip-token-{}", std::process::id()));
+    let token = write_token(&path).unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), token);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    std::fs::remove_file(path).unwrap();
+}
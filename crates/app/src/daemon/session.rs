@@ -0,0 +1,300 @@
+use super::api::*;
+use anyhow::{anyhow, bail};
+use rip_lib::prelude::{Agent, AgentError, Magnet, RateLimits, Torrent, TorrentStats};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::task::AbortHandle;
+
+/// Time to wait before asking peers for the metadata of a magnet URI again.
+const METADATA_RETRY: Duration = Duration::from_secs(60);
+
+/// Session shared between connections.
+pub type Shared = Arc<Session>;
+
+/// Torrents managed by the daemon, and its settings.
+pub struct Session {
+    agent: Agent,
+    settings: Mutex<Settings>,
+    /// Torrents added from magnet URIs whose metadata isn't known yet, by hexadecimal hash.
+    pending: Mutex<HashMap<String, Pending>>,
+}
+
+/// Torrent added from a magnet URI, added to the agent once its metadata is fetched.
+struct Pending {
+    magnet: Magnet,
+    out: PathBuf,
+    /// Task fetching the metadata, unless the torrent is paused.
+    fetching: Option<AbortHandle>,
+}
+
+impl Pending {
+    /// Get status of the torrent, which is empty until its metadata is known.
+    fn status(&self, hash: &str) -> TorrentStatus {
+        let name = self.magnet.name.clone().unwrap_or_else(|| hash.to_string());
+        let state = match self.fetching {
+            Some(_) => TorrentState::FetchingMetadata,
+            None => TorrentState::Paused,
+        };
+
+        TorrentStatus {
+            hash: hash.to_string(),
+            path: self.out.join(&name),
+            name,
+            state,
+            size: 0,
+            downloaded: 0,
+            uploaded: 0,
+            progress: 0.0,
+            download_rate: 0,
+            upload_rate: 0,
+        }
+    }
+}
+
+impl Session {
+    /// Create a new [`Session`] running torrents on `agent`.
+    pub fn new(agent: Agent, settings: Settings) -> Shared {
+        Arc::new(Self {
+            agent,
+            settings: Mutex::new(settings),
+            pending: Mutex::default(),
+        })
+    }
+
+    /// Get settings.
+    pub fn settings(&self) -> Settings {
+        self.settings.lock().unwrap().clone()
+    }
+
+    /// Replace settings, applying rate limits to the agent.
+    pub async fn set_settings(&self, settings: Settings) -> anyhow::Result<()> {
+        if settings.download_rate_limit == Some(0) || settings.upload_rate_limit == Some(0) {
+            bail!("rate limits must be at least 1, or unset");
+        }
+        self.agent
+            .set_rate_limits(RateLimits {
+                download: settings.download_rate_limit,
+                upload: settings.upload_rate_limit,
+            })
+            .await?;
+        *self.settings.lock().unwrap() = settings;
+
+        Ok(())
+    }
+
+    /// Get status of all torrents.
+    pub async fn list(&self) -> anyhow::Result<Vec<TorrentStatus>> {
+        let mut list = self
+            .agent
+            .stats()
+            .await?
+            .torrents
+            .into_iter()
+            .map(convert)
+            .collect::<Vec<_>>();
+        let pending = self.pending.lock().unwrap();
+        list.extend(pending.iter().map(|(hash, pending)| pending.status(hash)));
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+
+        Ok(list)
+    }
+}
+
+/// Add a torrent and start downloading it, unless `request.paused` is set.
+///
+/// Torrents from magnet URIs are added once their metadata is fetched from peers, which
+/// waits until they're resumed if they're paused.
+pub async fn add(session: &Shared, request: AddRequest) -> anyhow::Result<TorrentStatus> {
+    let out = request
+        .out
+        .unwrap_or_else(|| session.settings().download_dir);
+    if request.source.starts_with("magnet:") {
+        let magnet = Magnet::parse(&request.source)?;
+        let hash = hex(&magnet.info_hash);
+        if session.agent.status(&magnet.info_hash).await.is_ok() {
+            return Err(AgentError::AlreadyAdded.into());
+        }
+
+        let mut pending = session.pending.lock().unwrap();
+        if pending.contains_key(&hash) {
+            return Err(AgentError::AlreadyAdded.into());
+        }
+        let fetching = (!request.paused).then(|| fetch(session, &hash, &magnet));
+        let torrent = Pending {
+            magnet,
+            out,
+            fetching,
+        };
+        let status = torrent.status(&hash);
+        pending.insert(hash, torrent);
+        return Ok(status);
+    }
+
+    let path = PathBuf::from(request.source);
+    let torrent = Torrent::from_bytes(&tokio::fs::read(&path).await?)?;
+    let hash = if request.paused {
+        session.agent.add_torrent_paused(torrent, &out).await?
+    } else {
+        session.agent.add_torrent(torrent, &out).await?
+    };
+
+    stats(session, &hash).await
+}
+
+/// Start fetching metadata of the torrent of `magnet` with `hash`, until peers send it,
+/// then add the torrent to the agent, unless it was removed or paused meanwhile.
+fn fetch(session: &Shared, hash: &str, magnet: &Magnet) -> AbortHandle {
+    let session = Arc::clone(session);
+    let hash = hash.to_string();
+    let magnet = magnet.clone();
+
+    let task = tokio::spawn(async move {
+        let torrent = loop {
+            match session.agent.fetch_metadata(&magnet).await {
+                Ok(torrent) => break torrent,
+                Err(_) => tokio::time::sleep(METADATA_RETRY).await,
+            }
+        };
+
+        let Some(pending) = session.pending.lock().unwrap().remove(&hash) else {
+            return;
+        };
+        if session
+            .agent
+            .add_torrent(torrent, &pending.out)
+            .await
+            .is_ok()
+        {
+            for addr in &magnet.peers {
+                let _ = session.agent.add_peer(&magnet.info_hash, *addr).await;
+            }
+        }
+    });
+
+    task.abort_handle()
+}
+
+/// Get status of torrent with `hash`.
+pub async fn status(session: &Shared, hash: &str) -> anyhow::Result<TorrentStatus> {
+    let hash = parse_hash(hash)?;
+    let key = hex(&hash);
+    if let Some(pending) = session.pending.lock().unwrap().get(&key) {
+        return Ok(pending.status(&key));
+    }
+
+    stats(session, &hash).await
+}
+
+/// Get status of torrent with `hash`, including its transfer rates.
+async fn stats(session: &Shared, hash: &[u8]) -> anyhow::Result<TorrentStatus> {
+    let stats = session.agent.stats().await?;
+    let torrent = stats
+        .torrents
+        .into_iter()
+        .find(|torrent| torrent.status.hash == hash)
+        .ok_or(AgentError::NotFound)?;
+
+    Ok(convert(torrent))
+}
+
+/// Pause torrent with `hash`.
+pub async fn pause(session: &Shared, hash: &str) -> anyhow::Result<TorrentStatus> {
+    let hash = parse_hash(hash)?;
+    let key = hex(&hash);
+    if let Some(pending) = session.pending.lock().unwrap().get_mut(&key) {
+        if let Some(fetching) = pending.fetching.take() {
+            fetching.abort();
+        }
+        return Ok(pending.status(&key));
+    }
+
+    session.agent.pause(&hash).await?;
+
+    stats(session, &hash).await
+}
+
+/// Resume (or start) torrent with `hash`.
+pub async fn resume(session: &Shared, hash: &str) -> anyhow::Result<TorrentStatus> {
+    let hash = parse_hash(hash)?;
+    let key = hex(&hash);
+    if let Some(pending) = session.pending.lock().unwrap().get_mut(&key) {
+        if pending.fetching.is_none() {
+            pending.fetching = Some(fetch(session, &key, &pending.magnet));
+        }
+        return Ok(pending.status(&key));
+    }
+
+    session.agent.resume(&hash).await?;
+
+    stats(session, &hash).await
+}
+
+/// Remove torrent with `hash`, optionally deleting its downloaded data.
+pub async fn remove(
+    session: &Shared,
+    hash: &str,
+    request: RemoveRequest,
+) -> anyhow::Result<TorrentStatus> {
+    let hash = parse_hash(hash)?;
+    let key = hex(&hash);
+    if let Some(pending) = session.pending.lock().unwrap().remove(&key) {
+        if let Some(fetching) = &pending.fetching {
+            fetching.abort();
+        }
+        return Ok(pending.status(&key));
+    }
+
+    let status = stats(session, &hash).await?;
+    session.agent.remove(&hash, request.delete_data).await?;
+
+    Ok(status)
+}
+
+/// Parse hexadecimal info hash.
+fn parse_hash(hash: &str) -> anyhow::Result<Vec<u8>> {
+    if hash.len() != 40 || !hash.is_ascii() {
+        return Err(anyhow!("invalid info hash {hash:?}"));
+    }
+
+    (0..hash.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hash[i..i + 2], 16)
+                .map_err(|_| anyhow!("invalid info hash {hash:?}"))
+        })
+        .collect()
+}
+
+/// Encode info hash as lowercase hexadecimal.
+fn hex(hash: &[u8]) -> String {
+    hash.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Convert torrent statistics from the library into their API form.
+fn convert(stats: TorrentStats) -> TorrentStatus {
+    use rip_lib::prelude::TorrentState as State;
+
+    let progress = stats.progress();
+    let status = stats.status;
+    TorrentStatus {
+        hash: hex(&status.hash),
+        name: status.name,
+        path: status.path,
+        state: match status.state {
+            State::Queued => TorrentState::Queued,
+            State::Checking => TorrentState::Checking,
+            State::Downloading => TorrentState::Downloading,
+            State::Seeding => TorrentState::Seeding,
+            State::Paused => TorrentState::Paused,
+            State::Error(reason) => TorrentState::Error(reason),
+        },
+        size: status.total_length,
+        downloaded: status.downloaded,
+        uploaded: status.uploaded,
+        progress,
+        download_rate: stats.download_rate,
+        upload_rate: stats.upload_rate,
+    }
+}
@@ -1,18 +1,88 @@
 mod cli;
+mod cmd;
+mod config;
+mod daemon;
 
 use cli::*;
-use rip_lib::prelude::*;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let args = Args::parse();
-    let mut agent = Agent::new()?;
 
-    if let Some(paths) = args.torrents {
-        agent.add_torrents(paths).await?;
+    match args.command {
+        Command::Download {
+            torrents,
+            out,
+            files,
+            sequential,
+            quiet,
+            json,
+            agent,
+        } => {
+            let output = match (quiet, json) {
+                (true, _) => cmd::Output::Quiet,
+                (_, true) => cmd::Output::Json,
+                _ => cmd::Output::Human,
+            };
+            let config = config::load(&agent)?;
+            cmd::download(torrents, out, &files, sequential, config, output).await
+        }
+        Command::Info { file } => cmd::info(&file),
+        Command::Verify { file, dir } => cmd::verify(&file, &dir).await,
+        Command::Create {
+            path,
+            announce,
+            out,
+            piece_length,
+            private,
+            comment,
+        } => cmd::create(
+            &path,
+            &announce,
+            out.as_deref(),
+            piece_length,
+            private,
+            comment,
+        ),
+        Command::Magnet { file } => cmd::magnet(&file),
+        Command::Scrape { file } => cmd::scrape(&file).await,
+        Command::Bdecode { file, json } => cmd::bdecode(&file, json),
+        Command::Bencode { file, out } => cmd::bencode(&file, out.as_deref()),
+        Command::Daemon {
+            listen,
+            token_file,
+            no_auth,
+            out,
+            agent,
+        } => {
+            let config = config::load(&agent)?;
+            let download_dir = std::fs::canonicalize(out.unwrap_or(config.download_dir.clone()))?;
+            let settings = daemon::api::Settings {
+                download_dir,
+                download_rate_limit: config.download_rate_limit,
+                upload_rate_limit: config.upload_rate_limit,
+            };
+            let token_file = match no_auth {
+                true => None,
+                false => Some(token_file.or_else(config::token_path).ok_or_else(|| {
+                    anyhow::anyhow!("no token file given, and no configuration directory")
+                })?),
+            };
+            daemon::run(listen, token_file.as_deref(), config, settings).await
+        }
+        Command::Add {
+            source,
+            out,
+            paused,
+            remote,
+        } => cmd::client::add(&remote, source, out, paused).await,
+        Command::Ls { remote } => cmd::client::ls(&remote).await,
+        Command::Pause { hash, remote } => cmd::client::pause(&remote, &hash).await,
+        Command::Resume { hash, remote } => cmd::client::resume(&remote, &hash).await,
+        Command::Rm {
+            hash,
+            delete_data,
+            remote,
+        } => cmd::client::rm(&remote, &hash, delete_data).await,
     }
-
-    agent.download(&args.out).await?;
-
-    Ok(())
 }
@@ -0,0 +1,712 @@
+use super::stats::RateMeter;
+use super::{
+    AgentEvent, AgentStats, EncryptionPolicy, FileStats, RateLimits, TorrentStats, TorrentStatus,
+};
+use crate::error::{AgentError, Error, PeerError};
+use crate::peer::wire::Handshake;
+use crate::peer::{metadata, mse, PeerStream, UtpStream};
+use crate::prelude::*;
+use crate::storage::{FileStorage, Storage};
+use crate::torrent::engine::{self, Context, EngineCommand, Shared};
+use crate::util;
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::{JoinHandle, JoinSet};
+
+/// Time allowed for an incoming peer to send its handshake.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time to wait before accepting connections again after that failed.
+const ACCEPT_RETRY: Duration = Duration::from_millis(500);
+
+/// Reply to a command.
+type Reply<T> = oneshot::Sender<T>;
+
+/// Where the data of an added torrent goes.
+pub(super) enum Destination {
+    /// Files in this directory.
+    Dir(PathBuf),
+    Storage(Arc<dyn Storage>),
+}
+
+/// Command sent from an [`Agent`] handle to its background task.
+pub(super) enum Command {
+    Add {
+        torrent: Box<Torrent>,
+        destination: Destination,
+        priorities: Option<Vec<FilePriority>>,
+        paused: bool,
+        reply: Reply<Result<Vec<u8>, Error>>,
+    },
+    Remove {
+        hash: Vec<u8>,
+        delete_data: bool,
+        reply: Reply<Result<(), Error>>,
+    },
+    Pause {
+        hash: Vec<u8>,
+        reply: Reply<Result<(), Error>>,
+    },
+    Resume {
+        hash: Vec<u8>,
+        reply: Reply<Result<(), Error>>,
+    },
+    SetPriority {
+        hash: Vec<u8>,
+        priority: i32,
+        reply: Reply<Result<(), Error>>,
+    },
+    SetFilePriorities {
+        hash: Vec<u8>,
+        priorities: Vec<FilePriority>,
+        reply: Reply<Result<(), Error>>,
+    },
+    SetSequential {
+        hash: Vec<u8>,
+        sequential: bool,
+        reply: Reply<Result<(), Error>>,
+    },
+    SetPlayhead {
+        hash: Vec<u8>,
+        playhead: Option<(usize, u64)>,
+        reply: Reply<Result<(), Error>>,
+    },
+    Open {
+        hash: Vec<u8>,
+        reply: Reply<Result<Arc<Shared>, Error>>,
+    },
+    SetMaxActive {
+        max: Option<usize>,
+        reply: Reply<()>,
+    },
+    SetRateLimits {
+        limits: RateLimits,
+        reply: Reply<()>,
+    },
+    SetTorrentRateLimits {
+        hash: Vec<u8>,
+        limits: RateLimits,
+        reply: Reply<Result<(), Error>>,
+    },
+    SetPeerRateLimits {
+        limits: RateLimits,
+        reply: Reply<()>,
+    },
+    Status {
+        hash: Vec<u8>,
+        reply: Reply<Result<TorrentStatus, Error>>,
+    },
+    List {
+        reply: Reply<Vec<TorrentStatus>>,
+    },
+    Stats {
+        reply: Reply<AgentStats>,
+    },
+    AddPeer {
+        hash: Vec<u8>,
+        addr: SocketAddr,
+        reply: Reply<Result<(), Error>>,
+    },
+    Subscribe {
+        hash: Vec<u8>,
+        reply: Reply<Result<watch::Receiver<TorrentState>, Error>>,
+    },
+    FetchMetadata {
+        magnet: Box<Magnet>,
+        reply: Reply<Result<Torrent, Error>>,
+    },
+    Shutdown {
+        reply: Reply<()>,
+    },
+}
+
+/// Incoming connection whose handshake was read.
+pub(super) type Incoming = (Box<PeerStream>, SocketAddr, Handshake);
+
+/// Background task of an [`Agent`], owning all torrents.
+pub(super) struct Actor {
+    context: Arc<Context>,
+    torrents: HashMap<Vec<u8>, Entry>,
+    /// Number of torrents ever added, to keep them in order.
+    added: u64,
+    max_active: Option<usize>,
+}
+
+/// A torrent owned by the [`Actor`].
+struct Entry {
+    shared: Arc<Shared>,
+    priority: i32,
+    order: u64,
+    paused: bool,
+    engine: Option<Engine>,
+    rates: RateMeter,
+}
+
+/// Handle to a running engine task, which is aborted when dropped.
+struct Engine {
+    commands: mpsc::UnboundedSender<EngineCommand>,
+    task: JoinHandle<()>,
+}
+
+impl Engine {
+    /// Abort the engine task, waiting until it's gone.
+    async fn stop(mut self) {
+        self.task.abort();
+        let _ = (&mut self.task).await;
+    }
+}
+
+impl Drop for Engine {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+impl Actor {
+    /// Create a new `Actor` for an agent with `context`.
+    pub fn new(context: Arc<Context>) -> Self {
+        Self {
+            context,
+            torrents: HashMap::new(),
+            added: 0,
+            max_active: None,
+        }
+    }
+
+    /// Handle commands until all [`Agent`] handles are dropped, or it's shut down.
+    pub async fn run(
+        mut self,
+        mut commands: mpsc::UnboundedReceiver<Command>,
+        mut incoming: mpsc::UnboundedReceiver<Incoming>,
+        _tasks: JoinSet<()>,
+    ) {
+        let mut tick = tokio::time::interval(Duration::from_secs(1));
+
+        loop {
+            tokio::select! {
+                command = commands.recv() => match command {
+                    Some(Command::Shutdown { reply }) => {
+                        let mut stopping = Vec::new();
+                        for entry in self.torrents.values_mut() {
+                            stopping.push(stop(entry).await);
+                        }
+                        self.torrents.clear();
+                        for task in stopping {
+                            let _ = task.await;
+                        }
+                        let _ = reply.send(());
+                        return;
+                    }
+                    Some(command) => self.handle(command).await,
+                    None => return,
+                },
+                Some((stream, addr, handshake)) = incoming.recv() => {
+                    let engine = self
+                        .torrents
+                        .get(handshake.info_hash.as_slice())
+                        .and_then(|entry| entry.engine.as_ref());
+                    if let Some(engine) = engine {
+                        let _ = engine.commands.send(EngineCommand::Incoming(stream, addr, handshake));
+                    }
+                }
+                _ = tick.tick() => self.sample(),
+            }
+
+            self.schedule();
+        }
+    }
+
+    /// Handle `command`, other than [`Command::Shutdown`].
+    async fn handle(&mut self, command: Command) {
+        match command {
+            Command::Add {
+                torrent,
+                destination,
+                priorities,
+                paused,
+                reply,
+            } => {
+                let result = self.add(*torrent, destination, priorities, paused).await;
+                let _ = reply.send(result);
+            }
+            Command::Remove {
+                hash,
+                delete_data,
+                reply,
+            } => {
+                let _ = reply.send(self.remove(&hash, delete_data).await);
+            }
+            Command::Pause { hash, reply } => {
+                let result = match self.get_mut(&hash) {
+                    Ok(entry) => {
+                        entry.paused = true;
+                        stop(entry).await;
+                        entry.shared.set_state(TorrentState::Paused);
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                };
+                let _ = reply.send(result);
+            }
+            Command::Resume { hash, reply } => {
+                let _ = reply.send(self.get_mut(&hash).map(|entry| {
+                    entry.paused = false;
+                    if entry.engine.is_none() {
+                        entry.shared.set_state(TorrentState::Queued);
+                    }
+                }));
+            }
+            Command::SetPriority {
+                hash,
+                priority,
+                reply,
+            } => {
+                let _ = reply.send(self.get_mut(&hash).map(|entry| entry.priority = priority));
+            }
+            Command::SetFilePriorities {
+                hash,
+                priorities,
+                reply,
+            } => {
+                let result = match self.get_mut(&hash) {
+                    Ok(entry) => set_file_priorities(&entry.shared, priorities).await,
+                    Err(e) => Err(e),
+                };
+                let _ = reply.send(result);
+            }
+            Command::SetSequential {
+                hash,
+                sequential,
+                reply,
+            } => {
+                let _ = reply.send(
+                    self.get_mut(&hash)
+                        .map(|entry| entry.shared.set_sequential(sequential)),
+                );
+            }
+            Command::SetPlayhead {
+                hash,
+                playhead,
+                reply,
+            } => {
+                let result = self.get_mut(&hash).and_then(|entry| {
+                    if let Some((index, _)) = playhead {
+                        if index >= entry.shared.storage.files().len() {
+                            return Err(AgentError::InvalidFile(index).into());
+                        }
+                    }
+                    entry.shared.set_playhead(playhead);
+                    Ok(())
+                });
+                let _ = reply.send(result);
+            }
+            Command::Open { hash, reply } => {
+                let _ = reply.send(self.get_mut(&hash).map(|entry| Arc::clone(&entry.shared)));
+            }
+            Command::SetMaxActive { max, reply } => {
+                self.max_active = max;
+                let _ = reply.send(());
+            }
+            Command::SetRateLimits { limits, reply } => {
+                self.context.limiters.set(limits);
+                let _ = reply.send(());
+            }
+            Command::SetTorrentRateLimits {
+                hash,
+                limits,
+                reply,
+            } => {
+                let _ = reply.send(
+                    self.get_mut(&hash)
+                        .map(|entry| entry.shared.limiters.set(limits)),
+                );
+            }
+            Command::SetPeerRateLimits { limits, reply } => {
+                self.context.peer_limits.send_replace(limits);
+                let _ = reply.send(());
+            }
+            Command::Status { hash, reply } => {
+                let _ = reply.send(self.get_mut(&hash).map(|entry| status(entry)));
+            }
+            Command::List { reply } => {
+                let mut entries = self.torrents.values().collect::<Vec<_>>();
+                entries.sort_by_key(|entry| entry.order);
+                let _ = reply.send(entries.into_iter().map(status).collect());
+            }
+            Command::Stats { reply } => {
+                let _ = reply.send(self.stats());
+            }
+            Command::AddPeer { hash, addr, reply } => {
+                let _ = reply.send(self.get_mut(&hash).map(|entry| {
+                    if let Some(engine) = &entry.engine {
+                        let _ = engine.commands.send(EngineCommand::Connect(addr));
+                    }
+                }));
+            }
+            Command::Subscribe { hash, reply } => {
+                let _ = reply.send(
+                    self.get_mut(&hash)
+                        .map(|entry| entry.shared.state.subscribe()),
+                );
+            }
+            Command::FetchMetadata { magnet, mut reply } => {
+                let context = Arc::clone(&self.context);
+                tokio::spawn(async move {
+                    let fetching = async {
+                        let metadata = metadata::fetch(context, &magnet).await?;
+                        magnet.to_torrent(&metadata)
+                    };
+                    tokio::select! {
+                        result = fetching => {
+                            let _ = reply.send(result);
+                        }
+                        // Nobody waits for the metadata anymore.
+                        _ = reply.closed() => {}
+                    }
+                });
+            }
+            Command::Shutdown { .. } => unreachable!("handled by run"),
+        }
+    }
+
+    /// Get entry of torrent with `hash`.
+    fn get_mut(&mut self, hash: &[u8]) -> Result<&mut Entry, Error> {
+        self.torrents
+            .get_mut(hash)
+            .ok_or_else(|| AgentError::NotFound.into())
+    }
+
+    /// Add `torrent`, stored in `destination`, queued to start unless `paused`.
+    async fn add(
+        &mut self,
+        torrent: Torrent,
+        destination: Destination,
+        priorities: Option<Vec<FilePriority>>,
+        paused: bool,
+    ) -> Result<Vec<u8>, Error> {
+        let hash = torrent.get_hash().to_vec();
+        if self.torrents.contains_key(&hash) {
+            return Err(AgentError::AlreadyAdded.into());
+        }
+
+        let storage: Arc<dyn Storage> = match destination {
+            Destination::Dir(out) => {
+                let disk = self.context.disk.clone();
+                Arc::new(FileStorage::new(&torrent.info, &out, disk)?)
+            }
+            Destination::Storage(storage) => storage,
+        };
+        let lengths = storage.files().iter().map(|file| file.length);
+        if !lengths.eq(torrent.info.files.iter().map(|file| file.length)) {
+            return Err(AgentError::StorageMismatch.into());
+        }
+        let name = String::from_utf8_lossy(&torrent.info.name).into_owned();
+        let shared = Shared::new(Arc::new(torrent), storage, Arc::clone(&self.context));
+        if let Some(priorities) = priorities {
+            set_file_priorities(&shared, priorities).await?;
+        }
+        if paused {
+            shared.set_state(TorrentState::Paused);
+        }
+        self.added += 1;
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(&hash);
+        self.context
+            .info_hashes
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(info_hash);
+        self.context
+            .metadata
+            .insert(info_hash, shared.torrent.get_metadata());
+        self.torrents.insert(
+            hash.clone(),
+            Entry {
+                shared: Arc::new(shared),
+                priority: 0,
+                order: self.added,
+                paused,
+                engine: None,
+                rates: RateMeter::default(),
+            },
+        );
+        let _ = self.context.events.send(AgentEvent::TorrentAdded {
+            hash: hash.clone(),
+            name,
+        });
+
+        Ok(hash)
+    }
+
+    /// Remove torrent with `hash`, deleting its data if `delete_data` is set.
+    async fn remove(&mut self, hash: &[u8], delete_data: bool) -> Result<(), Error> {
+        let mut entry = self.torrents.remove(hash).ok_or(AgentError::NotFound)?;
+        self.context
+            .info_hashes
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&entry.shared.info_hash);
+        self.context.metadata.remove(&entry.shared.info_hash);
+
+        stop(&mut entry).await;
+        if delete_data {
+            entry.shared.storage.delete().await?;
+        }
+        let _ = self.context.events.send(AgentEvent::TorrentRemoved {
+            hash: hash.to_vec(),
+        });
+
+        Ok(())
+    }
+
+    /// Sample transfer totals of all torrents, for their rates.
+    fn sample(&mut self) {
+        let now = Instant::now();
+        for entry in self.torrents.values_mut() {
+            let counters = &entry.shared.counters;
+            entry.rates.sample(
+                now,
+                counters.downloaded.load(Ordering::Relaxed),
+                counters.uploaded.load(Ordering::Relaxed),
+            );
+        }
+    }
+
+    /// Get statistics of the agent and all torrents.
+    fn stats(&self) -> AgentStats {
+        let mut entries = self.torrents.values().collect::<Vec<_>>();
+        entries.sort_by_key(|entry| entry.order);
+
+        let torrents = entries
+            .into_iter()
+            .map(|entry| {
+                let status = status(entry);
+                let (download_rate, upload_rate) = entry.rates.rates();
+
+                let (have, priorities) = {
+                    let inner = entry.shared.lock();
+                    (inner.picker.have().clone(), inner.priorities.clone())
+                };
+                let files = entry
+                    .shared
+                    .storage
+                    .files()
+                    .iter()
+                    .zip(entry.shared.file_progress(&have))
+                    .zip(priorities)
+                    .map(|((file, verified), priority)| FileStats {
+                        path: file.path.clone(),
+                        length: file.length,
+                        verified,
+                        priority,
+                    })
+                    .collect();
+
+                let mut stats = TorrentStats {
+                    status,
+                    download_rate,
+                    upload_rate,
+                    eta: None,
+                    files,
+                };
+                let (verified, total) = stats.wanted();
+                stats.eta = (stats.status.state == TorrentState::Downloading && download_rate > 0)
+                    .then(|| Duration::from_secs(total.saturating_sub(verified) / download_rate));
+
+                stats
+            })
+            .collect::<Vec<_>>();
+
+        AgentStats {
+            download_rate: torrents.iter().map(|t| t.download_rate).sum(),
+            upload_rate: torrents.iter().map(|t| t.upload_rate).sum(),
+            rate_limits: self.context.limiters.get(),
+            torrents,
+        }
+    }
+
+    /// Start queued torrents, as long as the number of active downloads allows.
+    fn schedule(&mut self) {
+        let mut active = 0;
+        for entry in self.torrents.values_mut() {
+            if entry.engine.as_ref().is_some_and(|e| e.task.is_finished()) {
+                entry.engine = None;
+            }
+            let state = entry.shared.state.borrow().clone();
+            if entry.engine.is_some()
+                && matches!(state, TorrentState::Checking | TorrentState::Downloading)
+            {
+                active += 1;
+            }
+        }
+
+        let mut queued = self
+            .torrents
+            .values_mut()
+            .filter(|entry| {
+                !entry.paused
+                    && entry.engine.is_none()
+                    && *entry.shared.state.borrow() == TorrentState::Queued
+            })
+            .collect::<Vec<_>>();
+        queued.sort_by_key(|entry| (-(entry.priority as i64), entry.order));
+
+        for entry in queued {
+            if self.max_active.is_some_and(|max| active >= max) {
+                break;
+            }
+            entry.engine = Some(start(Arc::clone(&entry.shared)));
+            active += 1;
+        }
+    }
+}
+
+/// Stop the engine of `entry` if it runs, getting the task that tells the tracker.
+async fn stop(entry: &mut Entry) -> JoinHandle<()> {
+    if let Some(engine) = entry.engine.take() {
+        engine.stop().await;
+    }
+    entry.shared.context.pex.stop(&entry.shared.info_hash);
+    entry.shared.lock().smart_ban.clear();
+
+    tokio::spawn(engine::stopped(Arc::clone(&entry.shared)))
+}
+
+/// Spawn an engine task for a torrent, exchanging its peers with peers if that's enabled
+/// and the torrent isn't private.
+fn start(shared: Arc<Shared>) -> Engine {
+    let (commands, receiver) = mpsc::unbounded_channel();
+    if shared.context.config.pex && shared.torrent.info.private != Some(true) {
+        let pex = &shared.context.pex;
+        pex.start(shared.info_hash, commands.clone());
+    }
+    let task = tokio::spawn(async move {
+        if let Err(error) = engine::run(Arc::clone(&shared), receiver).await {
+            shared.set_state(TorrentState::Error(error.to_string()));
+            shared.emit(AgentEvent::Error {
+                hash: shared.hash(),
+                message: error.to_string(),
+            });
+        }
+    });
+
+    Engine { commands, task }
+}
+
+/// Get status of torrent `entry`.
+fn status(entry: &Entry) -> TorrentStatus {
+    let shared = &entry.shared;
+    let counters = &shared.counters;
+
+    TorrentStatus {
+        hash: shared.info_hash.to_vec(),
+        name: String::from_utf8_lossy(&shared.torrent.info.name).into_owned(),
+        path: shared.storage.root().to_path_buf(),
+        state: shared.state.borrow().clone(),
+        priority: entry.priority,
+        total_length: shared.torrent.info.total_length(),
+        verified: counters.verified.load(Ordering::Relaxed),
+        downloaded: counters.downloaded.load(Ordering::Relaxed),
+        uploaded: counters.uploaded.load(Ordering::Relaxed),
+        peers: counters.peers.load(Ordering::Relaxed),
+        seeds: counters.seeds.load(Ordering::Relaxed),
+        rate_limits: shared.limiters.get(),
+    }
+}
+
+/// Set priority of each file of torrent `shared`, checking that there is one per file.
+async fn set_file_priorities(shared: &Shared, priorities: Vec<FilePriority>) -> Result<(), Error> {
+    let count = shared.torrent.info.files.len();
+    if priorities.len() != count {
+        return Err(AgentError::InvalidPriorities(count).into());
+    }
+
+    shared.set_file_priorities(priorities).await
+}
+
+/// Accept incoming connections over TCP from `listener` and over uTP from `utp`, and pass
+/// them on once their handshake was read.
+pub(super) async fn listen(
+    listener: TcpListener,
+    mut utp: mpsc::UnboundedReceiver<(UtpStream, SocketAddr)>,
+    context: Arc<Context>,
+    incoming: mpsc::UnboundedSender<Incoming>,
+) {
+    loop {
+        let (mut stream, addr) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, addr)) => (PeerStream::tcp(stream), util::canonical(addr)),
+                // The listener is unusable, or the agent stopped.
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput || incoming.is_closed() => {
+                    return;
+                }
+                Err(e) => {
+                    let reason = e.to_string();
+                    let _ = context.events.send(AgentEvent::AcceptFailed { reason });
+                    tokio::time::sleep(ACCEPT_RETRY).await;
+                    continue;
+                }
+            },
+            Some((stream, addr)) = utp.recv() => (PeerStream::utp(stream), addr),
+        };
+        if context.is_banned(addr.ip()) {
+            continue;
+        }
+        let context = Arc::clone(&context);
+        let incoming = incoming.clone();
+        tokio::spawn(async move {
+            let handshake =
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake(&mut stream, &context));
+            if let Ok(Ok(handshake)) = handshake.await {
+                let _ = incoming.send((Box::new(stream), addr, handshake));
+            }
+        });
+    }
+}
+
+/// Read the handshake of an incoming connection, after an encryption handshake if the
+/// connection starts with one and the encryption policy allows it.
+async fn handshake(stream: &mut PeerStream, context: &Context) -> Result<Handshake, Error> {
+    let mut start = [0; 20];
+    stream.read_exact(&mut start).await?;
+    stream.unread(&start);
+
+    let policy = context.config.encryption;
+    let info_hash = match Handshake::is_start(&start) {
+        true if policy == EncryptionPolicy::Forced => {
+            return Err(PeerError::EncryptionPolicy.into())
+        }
+        true => None,
+        false if policy == EncryptionPolicy::Disabled => {
+            return Err(PeerError::EncryptionPolicy.into())
+        }
+        false => {
+            let info_hashes = context
+                .info_hashes
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .iter()
+                .copied()
+                .collect::<Vec<_>>();
+            let accept = match policy {
+                EncryptionPolicy::Forced => mse::RC4,
+                _ => mse::RC4 | mse::PLAINTEXT,
+            };
+            Some(mse::respond(stream, &info_hashes, accept).await?)
+        }
+    };
+
+    let handshake = Handshake::read(stream).await?;
+    if info_hash.is_some_and(|info_hash| info_hash != handshake.info_hash) {
+        return Err(PeerError::UnknownTorrent.into());
+    }
+
+    Ok(handshake)
+}
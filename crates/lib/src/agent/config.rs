@@ -0,0 +1,347 @@
+use crate::error::ConfigError;
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Settings of an [`Agent`](super::Agent).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AgentConfig {
+    /// Ports to listen on, the first free one is used. `0..=0` means any free port.
+    pub listen_ports: RangeInclusive<u16>,
+    /// Maximum number of peer connections, over all torrents.
+    pub max_connections: usize,
+    /// Maximum number of peer connections per torrent.
+    pub max_connections_per_torrent: usize,
+    /// Maximum number of peers uploaded to at once, per torrent.
+    pub upload_slots: usize,
+    /// Maximum download rate in bytes per second, over all torrents.
+    pub download_rate_limit: Option<u64>,
+    /// Maximum upload rate in bytes per second, over all torrents.
+    pub upload_rate_limit: Option<u64>,
+    /// Maximum download rate in bytes per second, per peer.
+    pub peer_download_rate_limit: Option<u64>,
+    /// Maximum upload rate in bytes per second, per peer.
+    pub peer_upload_rate_limit: Option<u64>,
+    /// Whether peers on the local network are exempt from rate limits.
+    pub exempt_local_peers: bool,
+    /// Default directory to download into.
+    pub download_dir: PathBuf,
+    /// Whether to find peers through the DHT (BEP 5), over IPv4 and IPv6 (BEP 32).
+    pub dht: bool,
+    /// Nodes to join the DHT through, as `host:port`.
+    pub dht_routers: Vec<String>,
+    /// Whether to exchange peers with other peers (BEP 11).
+    pub pex: bool,
+    /// Whether to find peers on the local network (BEP 14).
+    pub lsd: bool,
+    /// URL of a proxy for tracker requests, such as `http://127.0.0.1:8080`.
+    pub proxy: Option<String>,
+    /// Whether to encrypt peer connections.
+    pub encryption: EncryptionPolicy,
+    /// Transport tried first when connecting to peers, the other one is tried if it fails.
+    pub preferred_transport: Transport,
+    /// How files are allocated on disk before downloading.
+    pub allocation: AllocationMode,
+    /// Number of threads reading, writing and hashing pieces.
+    pub disk_threads: usize,
+    /// Bytes of received blocks kept in memory until their piece is verified, over all
+    /// torrents. Beyond that, blocks are written to disk early and peers are slowed down.
+    pub disk_cache_size: usize,
+}
+
+/// Download and upload rate limits in bytes per second, `None` means no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RateLimits {
+    pub download: Option<u64>,
+    pub upload: Option<u64>,
+}
+
+/// Policy for encrypting peer connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EncryptionPolicy {
+    /// Only use plain connections.
+    #[default]
+    Disabled,
+    /// Try encrypted connections first and fall back to plain ones, accept both.
+    Enabled,
+    /// Only use connections encrypted with RC4.
+    Forced,
+}
+
+/// Transport of peer connections, both are accepted on the listen port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Transport {
+    #[default]
+    Tcp,
+    /// uTP over UDP (BEP 29), which yields to other traffic on congested links.
+    Utp,
+}
+
+/// How files are allocated on disk before downloading.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocationMode {
+    /// Create files at their full length without reserving space, which is quick.
+    #[default]
+    Sparse,
+    /// Reserve space for whole files up front, so they aren't fragmented.
+    Full,
+    /// Grow files as pieces arrive.
+    Compact,
+}
+
+impl Default for AgentConfig {
+    fn default() -> Self {
+        Self {
+            listen_ports: 6881..=6889,
+            max_connections: 200,
+            max_connections_per_torrent: 50,
+            upload_slots: 4,
+            download_rate_limit: None,
+            upload_rate_limit: None,
+            peer_download_rate_limit: None,
+            peer_upload_rate_limit: None,
+            exempt_local_peers: true,
+            download_dir: PathBuf::from("."),
+            dht: false,
+            dht_routers: vec![
+                "router.bittorrent.com:6881".to_string(),
+                "dht.transmissionbt.com:6881".to_string(),
+                "router.utorrent.com:6881".to_string(),
+            ],
+            pex: false,
+            lsd: false,
+            proxy: None,
+            encryption: EncryptionPolicy::Disabled,
+            preferred_transport: Transport::Tcp,
+            allocation: AllocationMode::Sparse,
+            disk_threads: 4,
+            disk_cache_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+impl AgentConfig {
+    /// Check that all settings are valid and supported.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let ports = &self.listen_ports;
+        if ports.is_empty() {
+            return Err(ConfigError::new("listen_ports", "range is empty"));
+        }
+        if ports.contains(&0) && *ports != (0..=0) {
+            return Err(ConfigError::new(
+                "listen_ports",
+                "0 (any port) can't be part of a range",
+            ));
+        }
+        if self.max_connections == 0 {
+            return Err(ConfigError::new("max_connections", "must be at least 1"));
+        }
+        if self.max_connections_per_torrent == 0 {
+            return Err(ConfigError::new(
+                "max_connections_per_torrent",
+                "must be at least 1",
+            ));
+        }
+        let limits = [
+            ("download_rate_limit", self.download_rate_limit),
+            ("upload_rate_limit", self.upload_rate_limit),
+            ("peer_download_rate_limit", self.peer_download_rate_limit),
+            ("peer_upload_rate_limit", self.peer_upload_rate_limit),
+        ];
+        for (key, limit) in limits {
+            if limit == Some(0) {
+                return Err(ConfigError::new(key, "must be at least 1, or unset"));
+            }
+        }
+        if self.download_dir.as_os_str().is_empty() {
+            return Err(ConfigError::new("download_dir", "must not be empty"));
+        }
+        if self.disk_threads == 0 {
+            return Err(ConfigError::new("disk_threads", "must be at least 1"));
+        }
+        for router in &self.dht_routers {
+            let port = router.rsplit_once(':').map(|(_, port)| port.parse::<u16>());
+            if !matches!(port, Some(Ok(port)) if port != 0) {
+                let reason = format!("{router:?} isn't a host and port");
+                return Err(ConfigError::new("dht_routers", reason));
+            }
+        }
+        if self.lsd {
+            return Err(ConfigError::new("lsd", "not supported yet"));
+        }
+        if let Some(proxy) = &self.proxy {
+            reqwest::Proxy::all(proxy).map_err(|e| ConfigError::new("proxy", e.to_string()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Get rate limits over all torrents.
+    pub fn rate_limits(&self) -> RateLimits {
+        RateLimits {
+            download: self.download_rate_limit,
+            upload: self.upload_rate_limit,
+        }
+    }
+
+    /// Get rate limits per peer.
+    pub fn peer_rate_limits(&self) -> RateLimits {
+        RateLimits {
+            download: self.peer_download_rate_limit,
+            upload: self.peer_upload_rate_limit,
+        }
+    }
+
+    /// Create an HTTP client for tracker requests, going through `proxy` if set.
+    pub(crate) fn http_client(&self) -> Result<reqwest::Client, ConfigError> {
+        let mut builder = reqwest::Client::builder();
+        if let Some(proxy) = &self.proxy {
+            let proxy =
+                reqwest::Proxy::all(proxy).map_err(|e| ConfigError::new("proxy", e.to_string()))?;
+            builder = builder.proxy(proxy);
+        }
+
+        builder
+            .build()
+            .map_err(|e| ConfigError::new("proxy", e.to_string()))
+    }
+}
+
+/// Parse a port range, either a single port like `6881` or a range like `6881-6889`.
+pub fn parse_ports(text: &str) -> Result<RangeInclusive<u16>, ConfigError> {
+    let invalid = || ConfigError::new("listen_ports", format!("{text:?} isn't a port or range"));
+    let (start, end) = text.split_once('-').unwrap_or((text, text));
+    let start = start.trim().parse::<u16>().map_err(|_| invalid())?;
+    let end = end.trim().parse::<u16>().map_err(|_| invalid())?;
+
+    Ok(start..=end)
+}
+
+impl FromStr for EncryptionPolicy {
+    type Err = ConfigError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "disabled" => Ok(Self::Disabled),
+            "enabled" => Ok(Self::Enabled),
+            "forced" => Ok(Self::Forced),
+            _ => Err(ConfigError::new(
+                "encryption",
+                format!("{text:?} isn't one of \"disabled\", \"enabled\" or \"forced\""),
+            )),
+        }
+    }
+}
+
+impl Transport {
+    /// Get the other transport.
+    pub fn other(self) -> Self {
+        match self {
+            Self::Tcp => Self::Utp,
+            Self::Utp => Self::Tcp,
+        }
+    }
+}
+
+impl FromStr for Transport {
+    type Err = ConfigError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "tcp" => Ok(Self::Tcp),
+            "utp" => Ok(Self::Utp),
+            _ => Err(ConfigError::new(
+                "preferred_transport",
+                format!("{text:?} isn't one of \"tcp\" or \"utp\""),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Transport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Tcp => "tcp",
+            Self::Utp => "utp",
+        })
+    }
+}
+
+impl FromStr for AllocationMode {
+    type Err = ConfigError;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        match text {
+            "sparse" => Ok(Self::Sparse),
+            "full" => Ok(Self::Full),
+            "compact" => Ok(Self::Compact),
+            _ => Err(ConfigError::new(
+                "allocation",
+                format!("{text:?} isn't one of \"sparse\", \"full\" or \"compact\""),
+            )),
+        }
+    }
+}
+
+#[test]
+fn test_agent_config_validate() {
+    assert_eq!(AgentConfig::default().validate(), Ok(()));
+    assert_eq!(parse_ports("6881-6889"), Ok(6881..=6889));
+    assert_eq!(parse_ports("0"), Ok(0..=0));
+    assert_eq!(parse_ports("68a").unwrap_err().key, "listen_ports");
+
+    let config = AgentConfig {
+        listen_ports: 0..=10,
+        ..Default::default()
+    };
+    assert_eq!(config.validate().unwrap_err().key, "listen_ports");
+
+    let config = AgentConfig {
+        max_connections: 0,
+        ..Default::default()
+    };
+    assert_eq!(config.validate().unwrap_err().key, "max_connections");
+
+    let config = AgentConfig {
+        peer_upload_rate_limit: Some(0),
+        ..Default::default()
+    };
+    assert_eq!(config.validate().unwrap_err().key, "peer_upload_rate_limit");
+
+    let config = AgentConfig {
+        proxy: Some("not a url".to_string()),
+        ..Default::default()
+    };
+    assert_eq!(config.validate().unwrap_err().key, "proxy");
+
+    let config = AgentConfig {
+        disk_threads: 0,
+        ..Default::default()
+    };
+    assert_eq!(config.validate().unwrap_err().key, "disk_threads");
+
+    let config = AgentConfig {
+        dht: true,
+        dht_routers: vec!["[::1]:6881".to_string(), "localhost".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(config.validate().unwrap_err().key, "dht_routers");
+
+    assert_eq!("forced".parse(), Ok(EncryptionPolicy::Forced));
+    assert_eq!(
+        "always".parse::<EncryptionPolicy>().unwrap_err().key,
+        "encryption"
+    );
+    assert_eq!("utp".parse(), Ok(Transport::Utp));
+    assert_eq!(Transport::Utp.to_string(), "utp");
+    assert_eq!("full".parse(), Ok(AllocationMode::Full));
+    assert_eq!(
+        "preallocate".parse::<AllocationMode>().unwrap_err().key,
+        "allocation"
+    );
+    assert_eq!(
+        "udp".parse::<Transport>().unwrap_err().key,
+        "preferred_transport"
+    );
+}
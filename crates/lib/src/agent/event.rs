@@ -0,0 +1,55 @@
+use crate::prelude::*;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+/// Something that happened in an [`Agent`], received through [`Agent::subscribe`].
+///
+/// Torrents are identified by the SHA1 hash of their info dictionary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgentEvent {
+    /// A torrent was added.
+    TorrentAdded { hash: Vec<u8>, name: String },
+    /// A torrent was removed.
+    TorrentRemoved { hash: Vec<u8> },
+    /// A torrent changed state.
+    StateChanged { hash: Vec<u8>, state: TorrentState },
+    /// The tracker responded to an announce with `peers` peers.
+    Announced { hash: Vec<u8>, peers: usize },
+    /// An announce to the tracker failed.
+    AnnounceFailed { hash: Vec<u8>, reason: String },
+    /// Handshakes with a peer were exchanged, its client is known if its peer ID has a known style.
+    PeerConnected {
+        hash: Vec<u8>,
+        addr: SocketAddr,
+        client: Option<ClientId>,
+        /// Whether the connection is encrypted with RC4.
+        encrypted: bool,
+        /// Transport the connection runs over.
+        transport: Transport,
+    },
+    /// A connected peer went away, with the error that caused it, if any.
+    PeerDisconnected {
+        hash: Vec<u8>,
+        addr: SocketAddr,
+        reason: Option<String>,
+    },
+    /// Peers at `ip` were banned from all torrents, for sending corrupt data to a torrent.
+    PeerBanned { hash: Vec<u8>, ip: IpAddr },
+    /// A piece was downloaded and matched its hash.
+    PieceVerified { hash: Vec<u8>, index: u32 },
+    /// A piece was downloaded but didn't match its hash, so it's downloaded again.
+    PieceFailed { hash: Vec<u8>, index: u32 },
+    /// All pieces of file `index` of a torrent are verified.
+    FileCompleted {
+        hash: Vec<u8>,
+        index: usize,
+        path: PathBuf,
+    },
+    /// All pieces of a torrent are verified.
+    TorrentFinished { hash: Vec<u8> },
+    /// A torrent stopped because of an error.
+    Error { hash: Vec<u8>, message: String },
+    /// Accepting an incoming connection failed, such as when out of file descriptors.
+    /// Connections are accepted again after a moment.
+    AcceptFailed { reason: String },
+}
@@ -0,0 +1,175 @@
+use crate::error::{AgentError, Error};
+use crate::torrent::engine::Shared;
+use crate::torrent::TorrentState;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::fmt;
+use std::io::{self, SeekFrom};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncSeek, ReadBuf};
+use tokio::sync::broadcast;
+
+/// Handle to a file of a torrent, for reading it while it's downloaded.
+///
+/// Reads wait until the pieces they need are verified, and move the playhead of the
+/// torrent, so the pieces following the read position are downloaded first.
+pub struct TorrentFile {
+    shared: Arc<Shared>,
+    index: usize,
+    /// Offset of the file within the torrent.
+    offset: u64,
+    length: u64,
+    position: u64,
+    /// Read in progress, giving its data.
+    pending: Option<BoxFuture<'static, Result<Vec<u8>, Error>>>,
+}
+
+impl TorrentFile {
+    /// Create [`TorrentFile`] for file `index` of the torrent with `shared` state.
+    pub(crate) fn new(shared: Arc<Shared>, index: usize) -> Result<Self, Error> {
+        let file = shared
+            .storage
+            .files()
+            .get(index)
+            .ok_or(AgentError::InvalidFile(index))?;
+        let (offset, length) = (file.offset, file.length);
+
+        Ok(Self {
+            shared,
+            index,
+            offset,
+            length,
+            position: 0,
+            pending: None,
+        })
+    }
+
+    /// Get length of the file.
+    pub fn get_length(&self) -> u64 {
+        self.length
+    }
+
+    /// Get current read position.
+    pub fn get_position(&self) -> u64 {
+        self.position
+    }
+
+    /// Start reading up to `length` bytes at the current position, without crossing a piece.
+    fn start_read(&self, length: usize) -> BoxFuture<'static, Result<Vec<u8>, Error>> {
+        let shared = Arc::clone(&self.shared);
+        let (index, position) = (self.index, self.position);
+        let offset = self.offset + position;
+        let piece_length = shared.torrent.info.piece_length as u64;
+        let piece = offset / piece_length;
+        let length = (length as u64)
+            .min((piece + 1) * piece_length - offset)
+            .min(self.length - position);
+
+        Box::pin(async move {
+            shared.set_playhead(Some((index, position)));
+            wait_piece(&shared, piece as usize, index, position).await?;
+            let begin = (offset % piece_length) as u32;
+            let storage = &shared.storage;
+            storage
+                .read_block(piece as u32, begin, length as usize)
+                .await
+        })
+    }
+}
+
+/// Wait until piece `index` is verified, setting the playhead to `position` of `file` again
+/// whenever the state of the torrent changes, since checking its data resets deadlines.
+async fn wait_piece(
+    shared: &Shared,
+    index: usize,
+    file: usize,
+    position: u64,
+) -> Result<(), Error> {
+    let mut haves = shared.haves.subscribe();
+    let mut state = shared.state.subscribe();
+
+    loop {
+        if shared.lock().picker.have().get(index) {
+            return Ok(());
+        }
+        if let TorrentState::Error(reason) = &*state.borrow_and_update() {
+            return Err(AgentError::Failed(reason.clone()).into());
+        }
+
+        tokio::select! {
+            result = haves.recv() => match result {
+                Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return Err(AgentError::NotFound.into()),
+            },
+            result = state.changed() => {
+                result.map_err(|_| AgentError::NotFound)?;
+                shared.set_playhead(Some((file, position)));
+            }
+        }
+    }
+}
+
+impl AsyncRead for TorrentFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.position >= self.length || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let mut pending = match self.pending.take() {
+            Some(pending) => pending,
+            None => self.start_read(buf.remaining()),
+        };
+        match pending.poll_unpin(cx) {
+            Poll::Ready(Ok(data)) => {
+                let data = &data[..data.len().min(buf.remaining())];
+                buf.put_slice(data);
+                self.position += data.len() as u64;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, e))),
+            Poll::Pending => {
+                self.pending = Some(pending);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncSeek for TorrentFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(delta) => self.length.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.position.checked_add_signed(delta),
+        };
+        let Some(position) = position else {
+            let message = "seek before the start of the file";
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        };
+
+        self.position = position;
+        self.pending = None;
+
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+impl fmt::Debug for TorrentFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TorrentFile")
+            .field("index", &self.index)
+            .field("length", &self.length)
+            .field("position", &self.position)
+            .finish_non_exhaustive()
+    }
+}
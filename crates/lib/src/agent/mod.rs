@@ -1,74 +1,557 @@
-pub mod traits;
-
-use self::traits::Download;
-use super::error::Error;
-use super::torrent::Torrent;
-use std::collections::HashMap;
-use std::future::Future;
-use futures::future::try_join_all;
-use futures::stream::FuturesUnordered;
+mod actor;
+mod config;
+mod event;
+mod file;
+mod stats;
+
+pub use config::{
+    parse_ports, AgentConfig, AllocationMode, EncryptionPolicy, RateLimits, Transport,
+};
+pub use event::AgentEvent;
+pub use file::TorrentFile;
+pub use stats::{AgentStats, FileStats, TorrentStats};
+
+use self::actor::{Command, Destination};
+use super::dht::Dht;
+use super::error::{AgentError, Error};
+use super::peer::{new_peer_id, Extension, Extensions, UtMetadata, UtPex, UtpSocket};
+use super::storage::{DiskPool, Storage};
+use super::torrent::engine::{Context, Limiters};
+use super::torrent::{FilePriority, Magnet, Torrent, TorrentState};
+use socket2::{Domain, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::ops::RangeInclusive;
 use std::path::{Path, PathBuf};
-use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use tokio::net::TcpListener;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Semaphore};
 use tokio::task::JoinSet;
 
+/// Number of events kept for subscribers that fall behind.
+const EVENT_CAPACITY: usize = 1024;
+/// Number of free TCP ports tried when listening on any port, in case UDP isn't free on it.
+const ANY_PORT_ATTEMPTS: usize = 8;
+
 /// Agent, which handles download process.
+///
+/// This is a cheap handle to a background task that runs all torrents, and can be cloned
+/// and sent between tasks. The background task stops once every handle is dropped,
+/// or [`Agent::shutdown`] is called.
+#[derive(Debug, Clone)]
 pub struct Agent {
-    files: HashMap<Vec<u8>, Box<dyn Download<Error = Error>>>,
-    futures: FuturesUnordered<Pin<Box<dyn Future<Output = Result<(), Error>>>>>,
+    commands: mpsc::UnboundedSender<Command>,
+    events: broadcast::Sender<AgentEvent>,
+    config: Arc<AgentConfig>,
+    port: u16,
+    peer_id: [u8; 20],
+    extensions: Arc<Extensions>,
+    dht: Option<Dht>,
+}
+
+/// Snapshot of a torrent's progress.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TorrentStatus {
+    /// SHA1 hash of info dictionary.
+    pub hash: Vec<u8>,
+    /// Torrent name.
+    pub name: String,
+    /// Path of the top-level file or directory.
+    pub path: PathBuf,
+    /// Current state.
+    pub state: TorrentState,
+    /// Queue priority, higher first.
+    pub priority: i32,
+    /// Total size of all files.
+    pub total_length: u64,
+    /// Bytes of verified pieces.
+    pub verified: u64,
+    /// Bytes of piece data received.
+    pub downloaded: u64,
+    /// Bytes of piece data sent.
+    pub uploaded: u64,
+    /// Number of connected peers.
+    pub peers: usize,
+    /// Number of connected peers that have all pieces.
+    pub seeds: usize,
+    /// Rate limits of the torrent, on top of those over all torrents.
+    pub rate_limits: RateLimits,
 }
 
 impl Agent {
-    /// Create a new `Agent`.
-    pub fn new() -> Result<Self, Error> {
+    /// Create a new `Agent` with default settings, listening on the first free port
+    /// of 6881-6889, or any free port.
+    ///
+    /// Must be called within a Tokio runtime.
+    pub async fn new() -> Result<Self, Error> {
+        match Self::with_config(AgentConfig::default()).await {
+            Err(Error::Io(_)) => Self::with_port(0).await,
+            result => result,
+        }
+    }
+
+    /// Create a new `Agent` with default settings, listening on `port`, or any free port if it's 0.
+    ///
+    /// Must be called within a Tokio runtime.
+    pub async fn with_port(port: u16) -> Result<Self, Error> {
+        Self::with_config(AgentConfig {
+            listen_ports: port..=port,
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Create a new `Agent` with `config`, listening on the first free port of its range.
+    ///
+    /// Must be called within a Tokio runtime.
+    pub async fn with_config(config: AgentConfig) -> Result<Self, Error> {
+        config.validate()?;
+
+        let (listener, utp) = bind(config.listen_ports.clone()).await?;
+        let port = listener.local_addr()?.port();
+
+        let peer_id = new_peer_id();
+        let config = Arc::new(config);
+        let (commands, receiver) = mpsc::unbounded_channel();
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let (events, _) = broadcast::channel(EVENT_CAPACITY);
+        let extensions = Arc::new(Extensions::default());
+        let metadata = Arc::new(UtMetadata::default());
+        extensions.add(Arc::clone(&metadata) as Arc<dyn Extension>)?;
+        let pex = Arc::new(UtPex::default());
+        if config.pex {
+            extensions.add(Arc::clone(&pex) as Arc<dyn Extension>)?;
+        }
+        let dht = config.dht.then(|| Dht::new(utp.clone()));
+        let context = Context {
+            peer_id,
+            port,
+            http: config.http_client()?,
+            connections: Arc::new(Semaphore::new(config.max_connections)),
+            limiters: Limiters::new(config.rate_limits()),
+            peer_limits: watch::channel(config.peer_rate_limits()).0,
+            info_hashes: RwLock::default(),
+            utp: utp.clone(),
+            extensions: Arc::clone(&extensions),
+            metadata,
+            pex,
+            dht: dht.clone(),
+            banned: RwLock::default(),
+            bans: broadcast::channel(64).0,
+            disk: DiskPool::new(config.disk_threads, config.disk_cache_size)?,
+            config: Arc::clone(&config),
+            events: events.clone(),
+        };
+
+        let context = Arc::new(context);
+        let mut tasks = JoinSet::new();
+        let (utp_tx, utp_incoming) = mpsc::unbounded_channel();
+        let (others, datagrams) = mpsc::unbounded_channel();
+        tasks.spawn(utp.run(utp_tx, others));
+        if let Some(dht) = &dht {
+            tasks.spawn(dht.clone().run(datagrams, config.dht_routers.clone()));
+        }
+        tasks.spawn(actor::listen(
+            listener,
+            utp_incoming,
+            Arc::clone(&context),
+            incoming_tx,
+        ));
+        tokio::spawn(actor::Actor::new(context).run(receiver, incoming, tasks));
+
         Ok(Self {
-            files: HashMap::new(),
-            futures: FuturesUnordered::new(),
+            commands,
+            events,
+            config,
+            port,
+            peer_id,
+            extensions,
+            dht,
         })
     }
 
-    /// Get file with `hash`.
-    pub fn get_file(&self, hash: &[u8]) -> Result<&Box<dyn Download<Error = Error>>, Error> {
-        self.files
-            .get(hash)
-            .ok_or_else(|| Error::Agent("file not found".to_string()))
+    /// Get settings.
+    pub fn get_config(&self) -> &AgentConfig {
+        &self.config
     }
 
     /// Get IP port.
     pub fn get_port(&self) -> u16 {
-        6881
-        //todo!()
+        self.port
+    }
+
+    /// Get peer ID sent to trackers and peers, the same for the whole session.
+    pub fn get_peer_id(&self) -> &[u8; 20] {
+        &self.peer_id
+    }
+
+    /// Add `extension` of the peer protocol, announced to peers connected from now on.
+    ///
+    /// Fails if an extension with the same name was already added.
+    pub fn add_extension(&self, extension: impl Extension) -> Result<(), Error> {
+        Ok(self.extensions.add(Arc::new(extension))?)
+    }
+
+    /// Subscribe to events of all torrents, from now on.
+    ///
+    /// Subscribers that fall more than 1024 events behind miss the oldest ones,
+    /// see [`broadcast::Receiver::recv`].
+    pub fn subscribe(&self) -> broadcast::Receiver<AgentEvent> {
+        self.events.subscribe()
+    }
+
+    /// Send a command built by `command` to the background task, and wait for its reply.
+    async fn call<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> Command,
+    ) -> Result<T, Error> {
+        let (reply, response) = oneshot::channel();
+        self.commands
+            .send(command(reply))
+            .map_err(|_| AgentError::ShutDown)?;
+
+        Ok(response.await.map_err(|_| AgentError::ShutDown)?)
+    }
+
+    /// Add `torrent`, saving its files into `out`, and return its hash.
+    pub async fn add_torrent(&self, torrent: Torrent, out: &Path) -> Result<Vec<u8>, Error> {
+        let destination = Destination::Dir(out.to_path_buf());
+        self.call(|reply| Command::Add {
+            torrent: Box::new(torrent),
+            destination,
+            priorities: None,
+            paused: false,
+            reply,
+        })
+        .await?
+    }
+
+    /// Add a torrent like [`Agent::add_torrent`], paused, so it isn't checked or started
+    /// until it's resumed.
+    pub async fn add_torrent_paused(&self, torrent: Torrent, out: &Path) -> Result<Vec<u8>, Error> {
+        let destination = Destination::Dir(out.to_path_buf());
+        self.call(|reply| Command::Add {
+            torrent: Box::new(torrent),
+            destination,
+            priorities: None,
+            paused: true,
+            reply,
+        })
+        .await?
+    }
+
+    /// Add a torrent like [`Agent::add_torrent`], keeping its data in `storage` rather than
+    /// files, such as a [`MemoryStorage`](crate::prelude::MemoryStorage).
+    pub async fn add_torrent_with_storage(
+        &self,
+        torrent: Torrent,
+        storage: Arc<dyn Storage>,
+    ) -> Result<Vec<u8>, Error> {
+        let destination = Destination::Storage(storage);
+        self.call(|reply| Command::Add {
+            torrent: Box::new(torrent),
+            destination,
+            priorities: None,
+            paused: false,
+            reply,
+        })
+        .await?
+    }
+
+    /// Add a torrent like [`Agent::add_torrent`], with a priority for each of its files.
+    ///
+    /// Skipped files aren't created, parts of them that share a piece with other files are
+    /// kept in a partfile next to the torrent's data.
+    pub async fn add_torrent_with_priorities(
+        &self,
+        torrent: Torrent,
+        out: &Path,
+        priorities: Vec<FilePriority>,
+    ) -> Result<Vec<u8>, Error> {
+        let destination = Destination::Dir(out.to_path_buf());
+        self.call(|reply| Command::Add {
+            torrent: Box::new(torrent),
+            destination,
+            priorities: Some(priorities),
+            paused: false,
+            reply,
+        })
+        .await?
     }
 
-    /// Read and parse torrents from a list of file paths.
-    pub async fn add_torrents(&mut self, paths: Vec<PathBuf>) -> Result<(), Error> {
+    /// Read and parse torrents from a list of file paths, and add them all.
+    pub async fn add_torrents(
+        &self,
+        paths: Vec<PathBuf>,
+        out: &Path,
+    ) -> Result<Vec<Vec<u8>>, Error> {
         let mut set = JoinSet::new();
 
-        for path in paths {
-            set.spawn(tokio::spawn(async move {
+        for (i, path) in paths.into_iter().enumerate() {
+            set.spawn(async move {
                 let contents = tokio::fs::read(path).await?;
                 let torrent = Torrent::from_bytes(&contents)?;
 
-                Ok::<Torrent, Error>(torrent)
-            }));
+                Ok::<_, Error>((i, torrent))
+            });
         }
 
+        let mut torrents = Vec::new();
         while let Some(res) = set.join_next().await {
-            let torrent = res???;
-            let hash = torrent.get_hash().to_owned();
-            self.files.insert(hash, Box::new(torrent));
+            torrents.push(res??);
+        }
+        torrents.sort_by_key(|(i, _)| *i);
+
+        let mut hashes = Vec::new();
+        for (_, torrent) in torrents {
+            hashes.push(self.add_torrent(torrent, out).await?);
+        }
+
+        Ok(hashes)
+    }
+
+    /// Remove torrent with `hash`, deleting its files if `delete_data` is set.
+    pub async fn remove(&self, hash: &[u8], delete_data: bool) -> Result<(), Error> {
+        let hash = hash.to_vec();
+        self.call(|reply| Command::Remove {
+            hash,
+            delete_data,
+            reply,
+        })
+        .await?
+    }
+
+    /// Pause torrent with `hash`, disconnecting from its peers.
+    pub async fn pause(&self, hash: &[u8]) -> Result<(), Error> {
+        let hash = hash.to_vec();
+        self.call(|reply| Command::Pause { hash, reply }).await?
+    }
+
+    /// Resume torrent with `hash` after it was paused, or failed.
+    pub async fn resume(&self, hash: &[u8]) -> Result<(), Error> {
+        let hash = hash.to_vec();
+        self.call(|reply| Command::Resume { hash, reply }).await?
+    }
+
+    /// Set queue priority of torrent with `hash`. Queued torrents with higher priority start first.
+    pub async fn set_priority(&self, hash: &[u8], priority: i32) -> Result<(), Error> {
+        let hash = hash.to_vec();
+        self.call(|reply| Command::SetPriority {
+            hash,
+            priority,
+            reply,
+        })
+        .await?
+    }
+
+    /// Set priority of each file of torrent with `hash`.
+    ///
+    /// A torrent that was done starts downloading again if files it skipped no longer are.
+    pub async fn set_file_priorities(
+        &self,
+        hash: &[u8],
+        priorities: Vec<FilePriority>,
+    ) -> Result<(), Error> {
+        let hash = hash.to_vec();
+        self.call(|reply| Command::SetFilePriorities {
+            hash,
+            priorities,
+            reply,
+        })
+        .await?
+    }
+
+    /// Set whether torrent with `hash` downloads new pieces in order, rather than rarest-first.
+    pub async fn set_sequential(&self, hash: &[u8], sequential: bool) -> Result<(), Error> {
+        let hash = hash.to_vec();
+        self.call(|reply| Command::SetSequential {
+            hash,
+            sequential,
+            reply,
+        })
+        .await?
+    }
+
+    /// Set playhead of torrent with `hash` to byte `offset` of file `index`, so the pieces
+    /// following it are downloaded first, from the fastest peers.
+    pub async fn set_playhead(&self, hash: &[u8], index: usize, offset: u64) -> Result<(), Error> {
+        let hash = hash.to_vec();
+        self.call(|reply| Command::SetPlayhead {
+            hash,
+            playhead: Some((index, offset)),
+            reply,
+        })
+        .await?
+    }
+
+    /// Clear playhead of torrent with `hash`.
+    pub async fn clear_playhead(&self, hash: &[u8]) -> Result<(), Error> {
+        let hash = hash.to_vec();
+        self.call(|reply| Command::SetPlayhead {
+            hash,
+            playhead: None,
+            reply,
+        })
+        .await?
+    }
+
+    /// Open file `index` of torrent with `hash` for reading, waiting for pieces as needed.
+    pub async fn open_file(&self, hash: &[u8], index: usize) -> Result<TorrentFile, Error> {
+        let hash = hash.to_vec();
+        let shared = self.call(|reply| Command::Open { hash, reply }).await??;
+
+        TorrentFile::new(shared, index)
+    }
+
+    /// Limit how many torrents check or download at once, queueing the rest. `None` means no limit.
+    pub async fn set_max_active_downloads(&self, max: Option<usize>) -> Result<(), Error> {
+        self.call(|reply| Command::SetMaxActive { max, reply })
+            .await
+    }
+
+    /// Limit download and upload rates over all torrents.
+    pub async fn set_rate_limits(&self, limits: RateLimits) -> Result<(), Error> {
+        self.call(|reply| Command::SetRateLimits { limits, reply })
+            .await
+    }
+
+    /// Limit download and upload rates of torrent with `hash`, on top of the limits over all torrents.
+    pub async fn set_torrent_rate_limits(
+        &self,
+        hash: &[u8],
+        limits: RateLimits,
+    ) -> Result<(), Error> {
+        let hash = hash.to_vec();
+        self.call(|reply| Command::SetTorrentRateLimits {
+            hash,
+            limits,
+            reply,
+        })
+        .await?
+    }
+
+    /// Limit download and upload rates of each peer, including those already connected.
+    pub async fn set_peer_rate_limits(&self, limits: RateLimits) -> Result<(), Error> {
+        self.call(|reply| Command::SetPeerRateLimits { limits, reply })
+            .await
+    }
+
+    /// Get status of torrent with `hash`.
+    pub async fn status(&self, hash: &[u8]) -> Result<TorrentStatus, Error> {
+        let hash = hash.to_vec();
+        self.call(|reply| Command::Status { hash, reply }).await?
+    }
+
+    /// Get status of all torrents, in the order they were added.
+    pub async fn list(&self) -> Result<Vec<TorrentStatus>, Error> {
+        self.call(|reply| Command::List { reply }).await
+    }
+
+    /// Get statistics of all torrents, including transfer rates and progress of each file.
+    pub async fn stats(&self) -> Result<AgentStats, Error> {
+        self.call(|reply| Command::Stats { reply }).await
+    }
+
+    /// Fetch metadata of the torrent of `magnet` from peers of its trackers, the DHT if
+    /// enabled, and those it names, and get the torrent, ready to be added.
+    pub async fn fetch_metadata(&self, magnet: &Magnet) -> Result<Torrent, Error> {
+        let magnet = Box::new(magnet.clone());
+        self.call(|reply| Command::FetchMetadata { magnet, reply })
+            .await?
+    }
+
+    /// Connect torrent with `hash` to a peer at `addr`, in addition to those from its tracker.
+    pub async fn add_peer(&self, hash: &[u8], addr: SocketAddr) -> Result<(), Error> {
+        let hash = hash.to_vec();
+        self.call(|reply| Command::AddPeer { hash, addr, reply })
+            .await?
+    }
+
+    /// Add the DHT node at `addr`, which the DHT is joined through like its routers.
+    ///
+    /// Fails if the DHT is disabled, or the node doesn't respond.
+    pub async fn add_dht_node(&self, addr: SocketAddr) -> Result<(), Error> {
+        let dht = self.dht.as_ref().ok_or(AgentError::DhtDisabled)?;
+        match dht.add_node(addr).await {
+            true => Ok(()),
+            false => Err(AgentError::NodeUnreachable.into()),
         }
+    }
+
+    /// Wait until torrent with `hash` is done downloading.
+    pub async fn wait(&self, hash: &[u8]) -> Result<(), Error> {
+        let hash = hash.to_vec();
+        let mut state: watch::Receiver<TorrentState> = self
+            .call(|reply| Command::Subscribe { hash, reply })
+            .await??;
+
+        loop {
+            match &*state.borrow_and_update() {
+                TorrentState::Seeding => return Ok(()),
+                TorrentState::Error(reason) => {
+                    return Err(AgentError::Failed(reason.clone()).into())
+                }
+                _ => {}
+            }
+            state.changed().await.map_err(|_| AgentError::NotFound)?;
+        }
+    }
 
-        Ok(())
+    /// Stop all torrents and the background task.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        self.call(|reply| Command::Shutdown { reply }).await
     }
+}
 
-    /// Start a download process for all pending files.
-    pub async fn download(self, out: &Path) -> Result<(), Error> {
-        for (_, file) in &self.files {
-            self.futures.push(Box::pin(file.initiate(&self, out)));
+/// Bind a TCP listener and a uTP socket to the first port of `ports` free for both.
+async fn bind(ports: RangeInclusive<u16>) -> io::Result<(TcpListener, UtpSocket)> {
+    let attempts = match ports == (0..=0) {
+        true => ANY_PORT_ATTEMPTS,
+        false => 1,
+    };
+
+    let mut error = None;
+    for port in ports.flat_map(|port| std::iter::repeat(port).take(attempts)) {
+        let listener = match bind_socket(Type::STREAM, port).and_then(|socket| {
+            socket.listen(1024)?;
+            TcpListener::from_std(socket.into())
+        }) {
+            Ok(listener) => listener,
+            Err(e) => {
+                error = Some(e);
+                continue;
+            }
+        };
+        let port = listener.local_addr()?.port();
+        match bind_socket(Type::DGRAM, port).and_then(|socket| UtpSocket::from_std(socket.into())) {
+            Ok(utp) => return Ok((listener, utp)),
+            Err(e) => error = Some(e),
         }
+    }
 
-        try_join_all(self.futures.into_iter()).await?;
+    Err(error.expect("validated range isn't empty"))
+}
 
-        Ok(())
+/// Bind a socket of `kind` to `port` on all addresses, taking both IPv6 and IPv4 where the
+/// system allows that, or only IPv4 if it has no IPv6.
+fn bind_socket(kind: Type, port: u16) -> io::Result<Socket> {
+    let (socket, addr) = match Socket::new(Domain::IPV6, kind, None) {
+        Ok(socket) => {
+            // Fails where IPv6 sockets can't take IPv4 too, which leaves them IPv6-only.
+            let _ = socket.set_only_v6(false);
+            (socket, SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)))
+        }
+        Err(_) => {
+            let socket = Socket::new(Domain::IPV4, kind, None)?;
+            (socket, SocketAddr::from((Ipv4Addr::UNSPECIFIED, port)))
+        }
+    };
+    #[cfg(unix)]
+    if kind == Type::STREAM {
+        socket.set_reuse_address(true)?;
     }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+
+    Ok(socket)
 }
@@ -0,0 +1,127 @@
+use super::{RateLimits, TorrentStatus};
+use crate::torrent::FilePriority;
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Time span transfer rates are averaged over.
+const RATE_WINDOW: Duration = Duration::from_secs(5);
+
+/// Snapshot of all torrents of an [`Agent`](super::Agent), with transfer rates.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AgentStats {
+    /// Sum of download rates of all torrents, in bytes per second.
+    pub download_rate: u64,
+    /// Sum of upload rates of all torrents, in bytes per second.
+    pub upload_rate: u64,
+    /// Rate limits over all torrents.
+    pub rate_limits: RateLimits,
+    /// Statistics of each torrent, in the order they were added.
+    pub torrents: Vec<TorrentStats>,
+}
+
+/// Snapshot of a torrent, with transfer rates and per-file progress.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TorrentStats {
+    /// Status of the torrent.
+    pub status: TorrentStatus,
+    /// Download rate in bytes per second.
+    pub download_rate: u64,
+    /// Upload rate in bytes per second.
+    pub upload_rate: u64,
+    /// Estimated time until downloading wanted files is done, if it's downloading at all.
+    pub eta: Option<Duration>,
+    /// Progress of each file.
+    pub files: Vec<FileStats>,
+}
+
+/// Progress of a file of a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileStats {
+    /// Path on disk.
+    pub path: PathBuf,
+    /// Length in bytes.
+    pub length: u64,
+    /// Bytes of the file covered by verified pieces.
+    pub verified: u64,
+    /// Download priority.
+    pub priority: FilePriority,
+}
+
+impl TorrentStats {
+    /// Get fraction of the files that aren't skipped that is done, from 0 to 1.
+    pub fn progress(&self) -> f64 {
+        let (verified, total) = self.wanted();
+        match total {
+            0 => 1.0,
+            total => verified as f64 / total as f64,
+        }
+    }
+
+    /// Get verified and total bytes of the files that aren't skipped.
+    pub fn wanted(&self) -> (u64, u64) {
+        self.files
+            .iter()
+            .filter(|file| file.priority != FilePriority::Skip)
+            .fold((0, 0), |(verified, total), file| {
+                (verified + file.verified, total + file.length)
+            })
+    }
+}
+
+/// Measures transfer rates from samples of transfer totals.
+#[derive(Debug, Default)]
+pub(super) struct RateMeter {
+    /// Time, downloaded and uploaded totals of recent samples, oldest first.
+    samples: VecDeque<(Instant, u64, u64)>,
+}
+
+impl RateMeter {
+    /// Add a sample of `downloaded` and `uploaded` totals taken `now`.
+    pub fn sample(&mut self, now: Instant, downloaded: u64, uploaded: u64) {
+        while self
+            .samples
+            .front()
+            .is_some_and(|(at, _, _)| now.duration_since(*at) > RATE_WINDOW)
+        {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now, downloaded, uploaded));
+    }
+
+    /// Get download and upload rates, in bytes per second.
+    pub fn rates(&self) -> (u64, u64) {
+        let (Some(first), Some(last)) = (self.samples.front(), self.samples.back()) else {
+            return (0, 0);
+        };
+        let elapsed = last.0.duration_since(first.0).as_secs_f64();
+        if elapsed == 0.0 {
+            return (0, 0);
+        }
+
+        (
+            (last.1.saturating_sub(first.1) as f64 / elapsed) as u64,
+            (last.2.saturating_sub(first.2) as f64 / elapsed) as u64,
+        )
+    }
+}
+
+#[test]
+fn test_rate_meter() {
+    let start = Instant::now();
+    let mut meter = RateMeter::default();
+    assert_eq!(meter.rates(), (0, 0));
+
+    for second in 0..=10 {
+        meter.sample(
+            start + Duration::from_secs(second),
+            second * 1000,
+            second * 10,
+        );
+    }
+    assert_eq!(meter.rates(), (1000, 10));
+
+    // Samples older than the window are dropped.
+    meter.sample(start + Duration::from_secs(20), 10_000, 100);
+    assert_eq!(meter.rates(), (0, 0));
+}
@@ -1,10 +1,27 @@
+mod options;
 mod parser;
 
-use crate::error::Error;
+use crate::error::BencodeError;
 use crate::prelude::*;
 use parser::Decoder;
 
+pub use options::DecodeOptions;
+
 /// Decode Bencoded data.
-pub fn decode(data: &[u8]) -> Result<Value, Error> {
-    Decoder::with(data, 0).parse()
+pub fn decode(data: &[u8]) -> Result<Value, BencodeError> {
+    decode_with(data, DecodeOptions::default())
+}
+
+/// Decode Bencoded data, enforcing the limits in `options`.
+pub fn decode_with(data: &[u8], options: DecodeOptions) -> Result<Value, BencodeError> {
+    Decoder::with_options(data, 0, options).parse()
+}
+
+/// Decode the Bencoded value at the start of `data`, enforcing the limits in `options`,
+/// and get it with the number of bytes it took, for values followed by other data.
+pub fn decode_prefix(data: &[u8], options: DecodeOptions) -> Result<(Value, usize), BencodeError> {
+    let mut decoder = Decoder::with_options(data, 0, options);
+    let value = decoder.parse()?;
+
+    Ok((value, decoder.position()))
 }
@@ -0,0 +1,39 @@
+/// Limits applied while decoding Bencoded data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeOptions {
+    /// Maximum nesting depth of lists and dictionaries.
+    pub max_depth: usize,
+    /// Maximum length of a single byte string.
+    pub max_string_length: usize,
+    /// Maximum total number of decoded values.
+    pub max_items: usize,
+    /// Smallest accepted integer.
+    pub min_integer: i64,
+    /// Largest accepted integer.
+    pub max_integer: i64,
+}
+
+impl DecodeOptions {
+    /// Limits suited for untrusted network input (tracker responses, peer messages).
+    pub fn network() -> Self {
+        Self {
+            max_depth: 32,
+            max_string_length: 1 << 20,
+            max_items: 1 << 16,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for DecodeOptions {
+    /// Limits suited for trusted local input (such as torrent files).
+    fn default() -> Self {
+        Self {
+            max_depth: 256,
+            max_string_length: usize::MAX,
+            max_items: usize::MAX,
+            min_integer: i64::MIN,
+            max_integer: i64::MAX,
+        }
+    }
+}
@@ -1,23 +1,52 @@
-use crate::error::Error;
+use super::DecodeOptions;
+use crate::error::{BencodeError, BencodeErrorKind};
 use crate::prelude::*;
 use std::collections::BTreeMap;
+use std::num::IntErrorKind;
 use std::ops::Range;
 
 /// (Bencode) Decoder
 pub struct Decoder<'a> {
     data: &'a [u8],
     i: usize,
+    options: DecodeOptions,
+    depth: usize,
+    items: usize,
 }
 
 impl<'a> Decoder<'a> {
     /// Create a new `Decoder`.
+    #[cfg(test)]
     pub fn with(data: &'a [u8], i: usize) -> Self {
-        Self { data, i }
+        Self::with_options(data, i, DecodeOptions::default())
+    }
+
+    /// Create a new `Decoder` enforcing the limits in `options`.
+    pub fn with_options(data: &'a [u8], i: usize, options: DecodeOptions) -> Self {
+        Self {
+            data,
+            i,
+            options,
+            depth: 0,
+            items: 0,
+        }
+    }
+
+    /// Get index of the next byte to decode.
+    pub fn position(&self) -> usize {
+        self.i
+    }
+
+    /// Create an error at the current index.
+    fn error(&self, kind: BencodeErrorKind) -> BencodeError {
+        BencodeError::at(self.i, kind)
     }
 
     /// Get current byte at index.
-    fn at(&self) -> Result<&u8, Error> {
-        self.data.get(self.i).ok_or(Error::Bencode("empty".into()))
+    fn at(&self) -> Result<&u8, BencodeError> {
+        self.data
+            .get(self.i)
+            .ok_or(self.error(BencodeErrorKind::UnexpectedEof))
     }
 
     /// Skip n bytes.
@@ -26,59 +55,103 @@ impl<'a> Decoder<'a> {
     }
 
     /// Find index of byte from index.
-    fn find(&self, byte: u8) -> Result<usize, Error> {
+    fn find(&self, byte: u8) -> Result<usize, BencodeError> {
         for j in self.i..self.data.len() {
             if self.data[j] == byte {
                 return Ok(j - self.i);
             }
         }
 
-        Err(Error::Bencode("not found".into()))
+        Err(self.error(BencodeErrorKind::UnexpectedEof))
     }
 
     /// Take bytes in the range, relative to the index.
-    fn take(&mut self, range: Range<usize>) -> Result<&[u8], Error> {
-        match self.data.get(self.i + range.start..self.i + range.end) {
+    fn take(&mut self, range: Range<usize>) -> Result<&[u8], BencodeError> {
+        let start = self.i.checked_add(range.start);
+        let end = self.i.checked_add(range.end);
+
+        match start
+            .zip(end)
+            .and_then(|(start, end)| self.data.get(start..end))
+        {
             Some(out) => {
                 self.i += range.end;
                 Ok(out)
             }
-            _ => Err(Error::Bencode("out of range".into())),
+            _ => Err(self.error(BencodeErrorKind::UnexpectedEof)),
+        }
+    }
+
+    /// Count a decoded value against the item limit.
+    fn count_item(&mut self) -> Result<(), BencodeError> {
+        self.items += 1;
+        if self.items > self.options.max_items {
+            return Err(self.error(BencodeErrorKind::TooManyItems(self.options.max_items)));
         }
+
+        Ok(())
+    }
+
+    /// Enter a list or dictionary, checking the depth limit.
+    fn enter(&mut self) -> Result<(), BencodeError> {
+        self.depth += 1;
+        if self.depth > self.options.max_depth {
+            return Err(self.error(BencodeErrorKind::TooDeep(self.options.max_depth)));
+        }
+
+        Ok(())
+    }
+
+    /// Leave a list or dictionary.
+    fn leave(&mut self) {
+        self.depth -= 1;
     }
 
     /// Parse any.
-    pub fn parse(&mut self) -> Result<Value, Error> {
-        match self.at()? {
+    pub fn parse(&mut self) -> Result<Value, BencodeError> {
+        self.count_item()?;
+
+        match *self.at()? {
             b'i' => Ok(Value::Integer(self.parse_integer()?)),
             48..=57 => Ok(Value::ByteString(self.parse_byte_string()?)),
             b'l' => Ok(Value::List(self.parse_list()?)),
             b'd' => Ok(Value::Dictionary(self.parse_dictionary()?)),
-            _ => Err(Error::Bencode("unexpected byte".into())),
+            byte => Err(self.error(BencodeErrorKind::UnexpectedByte(byte))),
         }
     }
 
     /// Parse integer.
-    fn parse_integer(&mut self) -> Result<Integer, Error> {
+    fn parse_integer(&mut self) -> Result<Integer, BencodeError> {
+        let start = self.i;
         let end = self.find(b'e')?;
         let val = self
             .take(1..end)?
             .iter()
             .map(|b| *b as char)
             .collect::<String>();
-        if val.starts_with("0") && val.len() > 1 {
-            return Err(Error::Bencode("invalid integer".to_string()));
+        if (val.starts_with('0') && val.len() > 1) || val.starts_with("-0") {
+            return Err(BencodeError::at(start, BencodeErrorKind::InvalidInteger));
+        }
+        let val = val.parse::<i64>().map_err(|e| {
+            let kind = match e.kind() {
+                IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+                    BencodeErrorKind::IntegerOutOfRange
+                }
+                _ => BencodeErrorKind::InvalidInteger,
+            };
+            BencodeError::at(start, kind)
+        })?;
+        if val < self.options.min_integer || val > self.options.max_integer {
+            return Err(BencodeError::at(start, BencodeErrorKind::IntegerOutOfRange));
         }
-        let val = val
-            .parse::<isize>()
-            .map_err(|_| Error::Bencode("invalid integer".into()))?;
         self.skip(1);
 
         Ok(Integer(val))
     }
 
     /// Parse byte string.
-    fn parse_byte_string(&mut self) -> Result<ByteString, Error> {
+    fn parse_byte_string(&mut self) -> Result<ByteString, BencodeError> {
+        let start = self.i;
         let end = self.find(b':')?;
         let len = self
             .take(0..end)?
@@ -86,29 +159,38 @@ impl<'a> Decoder<'a> {
             .map(|b| *b as char)
             .collect::<String>()
             .parse::<usize>()
-            .map_err(|_| Error::Bencode("invalid length".into()))?;
-        let val = self.take(1..(len + 1))?.to_vec();
+            .map_err(|_| BencodeError::at(start, BencodeErrorKind::InvalidLength))?;
+        if len > self.options.max_string_length {
+            return Err(BencodeError::at(
+                start,
+                BencodeErrorKind::StringTooLong(self.options.max_string_length),
+            ));
+        }
+        let val = self.take(1..len.saturating_add(1))?.to_vec();
 
         Ok(ByteString(val))
     }
 
     /// Parse list.
-    fn parse_list(&mut self) -> Result<List, Error> {
+    fn parse_list(&mut self) -> Result<List, BencodeError> {
         let mut val = Vec::new();
 
+        self.enter()?;
         self.skip(1);
         while *self.at()? != b'e' {
             val.push(self.parse()?);
         }
         self.skip(1);
+        self.leave();
 
         Ok(List(val))
     }
 
     /// Parse dictionary.
-    fn parse_dictionary(&mut self) -> Result<Dictionary, Error> {
+    fn parse_dictionary(&mut self) -> Result<Dictionary, BencodeError> {
         let mut val = BTreeMap::new();
 
+        self.enter()?;
         self.skip(1);
         while *self.at()? != b'e' {
             let key = self.parse_byte_string()?;
@@ -116,6 +198,7 @@ impl<'a> Decoder<'a> {
             val.insert(key, value);
         }
         self.skip(1);
+        self.leave();
 
         Ok(Dictionary(val))
     }
@@ -131,3 +214,31 @@ fn test_value_parser() {
     assert_eq!(parser.find(10).unwrap(), 5);
     assert_eq!(parser.take(1..2).unwrap(), &[6]);
 }
+
+#[test]
+fn test_value_parser_limits() {
+    let options = DecodeOptions {
+        max_depth: 2,
+        max_string_length: 3,
+        max_items: 4,
+        min_integer: -10,
+        max_integer: 10,
+    };
+    let parse = |data: &[u8]| Decoder::with_options(data, 0, options).parse();
+
+    assert!(parse(b"llee").is_ok());
+    assert!(parse(b"llleee").is_err());
+    assert!(parse(b"3:abc").is_ok());
+    assert!(parse(b"4:abcd").is_err());
+    assert!(parse(b"li1ei2ei3ee").is_ok());
+    assert!(parse(b"li1ei2ei3ei4ee").is_err());
+    assert!(parse(b"i-10e").is_ok());
+    assert!(parse(b"i11e").is_err());
+    assert!(parse(b"18446744073709551615:a").is_err());
+
+    let error = parse(b"li1ei2ex").unwrap_err();
+    assert_eq!(
+        error,
+        BencodeError::at(7, BencodeErrorKind::UnexpectedByte(b'x'))
+    );
+}
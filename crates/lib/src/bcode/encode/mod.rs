@@ -1,9 +1,41 @@
 mod parser;
 
+use crate::error::Error;
 use crate::prelude::*;
 use parser::Encoder;
+use std::io::Write;
+use tokio::io::AsyncWrite;
 
 /// Encode data to Bencode.
 pub fn encode(data: &Value) -> Vec<u8> {
-    Encoder::with(data).parse()
+    let mut out = Vec::with_capacity(data.encoded_len());
+    Encoder::with(data)
+        .parse(&mut out)
+        .expect("writing to a `Vec` can't fail");
+
+    out
+}
+
+/// Encode data to Bencode, writing it to `writer`.
+///
+/// Every token is written separately, so unbuffered writers should be wrapped in a [`std::io::BufWriter`].
+pub fn encode_to<W: Write + ?Sized>(data: &Value, writer: &mut W) -> Result<(), Error> {
+    Ok(Encoder::with(data).parse(writer)?)
+}
+
+/// Encode data to Bencode, writing it to an asynchronous `writer`.
+///
+/// Every token is written separately, so unbuffered writers should be wrapped in a [`tokio::io::BufWriter`].
+pub async fn encode_to_async<W: AsyncWrite + Unpin + ?Sized>(
+    data: &Value,
+    writer: &mut W,
+) -> Result<(), Error> {
+    Ok(Encoder::with(data).parse_async(writer).await?)
+}
+
+impl Value {
+    /// Get the length of the Bencoded value in bytes, without encoding it.
+    pub fn encoded_len(&self) -> usize {
+        Encoder::with(self).len()
+    }
 }
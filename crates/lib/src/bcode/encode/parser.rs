@@ -1,74 +1,159 @@
 use crate::prelude::*;
-use std::collections::BTreeMap;
+use std::collections::btree_map;
+use std::io::{self, Cursor, Write};
+use std::slice;
+use tokio::io::AsyncWrite;
+
+/// Largest formatted token: `i-9223372036854775808e`.
+const SCRATCH_SIZE: usize = 24;
 
 /// (Bencode) Encoder.
+///
+/// Walks the value with an explicit stack and writes each token straight to the output,
+/// so no intermediate buffers are allocated and byte strings are never copied.
 pub struct Encoder<'a> {
-    value: &'a Value,
+    stack: Vec<Frame<'a>>,
 }
 
-impl<'a> Encoder<'a> {
-    /// Create a new `Encoder`.
-    pub fn with(value: &'a Value) -> Self {
-        Self { value }
-    }
+/// Pending work on the encoder stack.
+enum Frame<'a> {
+    /// A value that has not been started yet.
+    Value(&'a Value),
+    /// Remaining items of a list.
+    List(slice::Iter<'a, Value>),
+    /// Remaining entries of a dictionary.
+    Dictionary(btree_map::Iter<'a, ByteString, Value>),
+    /// A byte string, before its length prefix.
+    ByteString(&'a [u8]),
+    /// Contents of a byte string, after its length prefix.
+    Raw(&'a [u8]),
+}
+
+/// A single piece of encoded output.
+enum Token<'a> {
+    /// `l`, `d` or `e`.
+    Delimiter(u8),
+    /// `i<n>e`.
+    Integer(i64),
+    /// `<n>:`.
+    Length(usize),
+    /// Contents of a byte string.
+    Bytes(&'a [u8]),
+}
+
+impl<'a> Token<'a> {
+    /// Get encoded bytes, formatting numbers into `scratch`.
+    fn as_bytes<'b>(&self, scratch: &'b mut [u8; SCRATCH_SIZE]) -> &'b [u8]
+    where
+        'a: 'b,
+    {
+        let mut cursor = Cursor::new(&mut scratch[..]);
+        let written = match self {
+            Token::Delimiter(byte) => cursor.write_all(&[*byte]),
+            Token::Integer(n) => write!(cursor, "i{n}e"),
+            Token::Length(n) => write!(cursor, "{n}:"),
+            Token::Bytes(bytes) => return bytes,
+        };
+        written.expect("scratch buffer fits any token");
+        let len = cursor.position() as usize;
 
-    /// Parse any.
-    pub fn parse(&self) -> Vec<u8> {
-        Self::parse_any(self.value)
+        &scratch[..len]
     }
+}
 
-    /// Parse any.
-    fn parse_any(value: &Value) -> Vec<u8> {
-        match value {
-            Value::Integer(inner) => Self::parse_integer(&inner.0),
-            Value::ByteString(inner) => Self::parse_byte_string(&inner.0),
-            Value::List(inner) => Self::parse_list(&inner.0),
-            Value::Dictionary(inner) => Self::parse_dictionary(&inner.0),
+impl<'a> Encoder<'a> {
+    /// Create a new `Encoder`.
+    pub fn with(value: &'a Value) -> Self {
+        Self {
+            stack: vec![Frame::Value(value)],
         }
     }
 
-    /// Parse integer.
-    fn parse_integer(data: &isize) -> Vec<u8> {
-        let mut out = Vec::new();
-        out.push(b"i"[0]);
-        out.append(&mut data.to_string().as_bytes().to_vec());
-        out.push(b"e"[0]);
+    /// Parse any, writing to `writer`.
+    pub fn parse<W: Write + ?Sized>(mut self, writer: &mut W) -> io::Result<()> {
+        let mut scratch = [0; SCRATCH_SIZE];
+        while let Some(token) = self.next_token() {
+            writer.write_all(token.as_bytes(&mut scratch))?;
+        }
 
-        out
+        Ok(())
     }
 
-    /// Parse byte string.
-    fn parse_byte_string(data: &Vec<u8>) -> Vec<u8> {
-        let mut out = Vec::with_capacity(data.len());
-        out.append(&mut data.len().to_string().as_bytes().to_vec());
-        out.push(b":"[0]);
-        out.append(&mut data.clone());
+    /// Parse any, writing to an asynchronous `writer`.
+    pub async fn parse_async<W: AsyncWrite + Unpin + ?Sized>(
+        mut self,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        use tokio::io::AsyncWriteExt;
 
-        out
+        let mut scratch = [0; SCRATCH_SIZE];
+        while let Some(token) = self.next_token() {
+            writer.write_all(token.as_bytes(&mut scratch)).await?;
+        }
+
+        Ok(())
     }
 
-    /// Parse list.
-    fn parse_list(data: &Vec<Value>) -> Vec<u8> {
-        let mut out = Vec::with_capacity(data.len());
-        out.push(b"l"[0]);
-        for value in data {
-            out.append(&mut Self::parse_any(value));
+    /// Get the number of bytes the value encodes to.
+    pub fn len(mut self) -> usize {
+        let mut scratch = [0; SCRATCH_SIZE];
+        let mut len = 0;
+        while let Some(token) = self.next_token() {
+            len += token.as_bytes(&mut scratch).len();
         }
-        out.push(b"e"[0]);
 
-        out
+        len
     }
 
-    /// Parse dictionary.
-    fn parse_dictionary(data: &BTreeMap<ByteString, Value>) -> Vec<u8> {
-        let mut out = Vec::with_capacity(data.len());
-        out.push(b"d"[0]);
-        for (key, value) in data {
-            out.append(&mut Self::parse_byte_string(&key.0));
-            out.append(&mut Self::parse_any(value));
-        }
-        out.push(b"e"[0]);
+    /// Get next token of output.
+    fn next_token(&mut self) -> Option<Token<'a>> {
+        loop {
+            match self.stack.last_mut()? {
+                Frame::Value(value) => {
+                    let value = *value;
+                    self.stack.pop();
 
-        out
+                    match value {
+                        Value::Integer(inner) => return Some(Token::Integer(inner.0)),
+                        Value::ByteString(inner) => self.stack.push(Frame::ByteString(&inner.0)),
+                        Value::List(inner) => {
+                            self.stack.push(Frame::List(inner.0.iter()));
+                            return Some(Token::Delimiter(b'l'));
+                        }
+                        Value::Dictionary(inner) => {
+                            self.stack.push(Frame::Dictionary(inner.0.iter()));
+                            return Some(Token::Delimiter(b'd'));
+                        }
+                    }
+                }
+                Frame::List(iter) => match iter.next() {
+                    Some(value) => self.stack.push(Frame::Value(value)),
+                    None => {
+                        self.stack.pop();
+                        return Some(Token::Delimiter(b'e'));
+                    }
+                },
+                Frame::Dictionary(iter) => match iter.next() {
+                    Some((key, value)) => {
+                        self.stack.push(Frame::Value(value));
+                        self.stack.push(Frame::ByteString(&key.0));
+                    }
+                    None => {
+                        self.stack.pop();
+                        return Some(Token::Delimiter(b'e'));
+                    }
+                },
+                Frame::ByteString(data) => {
+                    let data = *data;
+                    *self.stack.last_mut()? = Frame::Raw(data);
+                    return Some(Token::Length(data.len()));
+                }
+                Frame::Raw(data) => {
+                    let data = *data;
+                    self.stack.pop();
+                    return Some(Token::Bytes(data));
+                }
+            }
+        }
     }
 }
@@ -0,0 +1,135 @@
+//! Conversion between Bencode and JSON.
+//!
+//! Byte strings that are valid UTF-8 become JSON strings. Other byte strings become
+//! `{"$hex": "<hex>"}`, and dictionary keys that aren't valid UTF-8 become `"$hex:<hex>"`.
+//! Keys that would be mistaken for one of these markers are hex-encoded as well, so
+//! converting to JSON and back is lossless.
+
+use crate::error::{BencodeError, BencodeErrorKind};
+use crate::prelude::*;
+use crate::util::{from_hex, to_hex};
+use serde_json::{Map, Number};
+use std::collections::BTreeMap;
+
+/// Marker key for byte string values that aren't valid UTF-8.
+const HEX_VALUE: &str = "$hex";
+/// Marker prefix for dictionary keys that aren't valid UTF-8.
+const HEX_KEY: &str = "$hex:";
+
+impl Value {
+    /// Convert to a JSON value.
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Value::Integer(inner) => serde_json::Value::Number(Number::from(inner.0)),
+            Value::ByteString(inner) => match std::str::from_utf8(&inner.0) {
+                Ok(text) => serde_json::Value::String(text.to_string()),
+                Err(_) => {
+                    let mut marker = Map::new();
+                    marker.insert(HEX_VALUE.to_string(), to_hex(&inner.0).into());
+                    serde_json::Value::Object(marker)
+                }
+            },
+            Value::List(inner) => inner.0.iter().map(Value::to_json).collect(),
+            Value::Dictionary(inner) => {
+                let is_marker = inner.0.len() == 1 && inner.has(HEX_VALUE);
+                let map = inner
+                    .0
+                    .iter()
+                    .map(|(key, value)| (key_to_json(&key.0, is_marker), value.to_json()))
+                    .collect();
+
+                serde_json::Value::Object(map)
+            }
+        }
+    }
+
+    /// Try converting from a JSON value.
+    pub fn from_json(json: &serde_json::Value) -> Result<Self, BencodeError> {
+        match json {
+            serde_json::Value::Number(number) => number
+                .as_i64()
+                .map(|n| Value::Integer(Integer(n)))
+                .ok_or_else(|| {
+                    BencodeError::from(BencodeErrorKind::Json(format!(
+                        "{number} is not a 64-bit integer"
+                    )))
+                }),
+            serde_json::Value::String(text) => {
+                Ok(Value::ByteString(ByteString(text.as_bytes().to_vec())))
+            }
+            serde_json::Value::Array(items) => Ok(Value::List(List(
+                items
+                    .iter()
+                    .map(Value::from_json)
+                    .collect::<Result<_, BencodeError>>()?,
+            ))),
+            serde_json::Value::Object(map) => {
+                if let (1, Some(serde_json::Value::String(hex))) = (map.len(), map.get(HEX_VALUE)) {
+                    return from_hex(hex)
+                        .map(|bytes| Value::ByteString(ByteString(bytes)))
+                        .ok_or_else(|| {
+                            BencodeError::from(BencodeErrorKind::Json(format!(
+                                "invalid hex {hex:?}"
+                            )))
+                        });
+                }
+
+                let map = map
+                    .iter()
+                    .map(|(key, value)| {
+                        Ok((ByteString(key_from_json(key)?), Value::from_json(value)?))
+                    })
+                    .collect::<Result<BTreeMap<_, _>, BencodeError>>()?;
+
+                Ok(Value::Dictionary(Dictionary(map)))
+            }
+            serde_json::Value::Bool(_) | serde_json::Value::Null => Err(BencodeError::from(
+                BencodeErrorKind::Json(format!("{json} has no Bencode equivalent")),
+            )),
+        }
+    }
+}
+
+/// Convert a dictionary key to a JSON object key.
+fn key_to_json(key: &[u8], escape: bool) -> String {
+    match std::str::from_utf8(key) {
+        Ok(text) if !escape && !text.starts_with(HEX_KEY) => text.to_string(),
+        _ => format!("{HEX_KEY}{}", to_hex(key)),
+    }
+}
+
+/// Convert a JSON object key to a dictionary key.
+fn key_from_json(key: &str) -> Result<Vec<u8>, BencodeError> {
+    match key.strip_prefix(HEX_KEY) {
+        Some(hex) => from_hex(hex).ok_or_else(|| {
+            BencodeError::from(BencodeErrorKind::Json(format!("invalid hex key {key:?}")))
+        }),
+        None => Ok(key.as_bytes().to_vec()),
+    }
+}
+
+#[test]
+fn test_json_round_trip() {
+    let value = decode(b"d4:$hexi1e3:bin2:\xff\x004:listli-1e3:abcee").unwrap();
+    let json = value.to_json();
+
+    assert_eq!(
+        json,
+        serde_json::json!({
+            "$hex": 1,
+            "bin": { "$hex": "ff00" },
+            "list": [-1, "abc"],
+        })
+    );
+    assert_eq!(Value::from_json(&json).unwrap(), value);
+
+    let marker = decode(b"d4:$hex4:abcde").unwrap();
+    assert_eq!(Value::from_json(&marker.to_json()).unwrap(), marker);
+}
+
+#[test]
+fn test_json_unsupported() {
+    assert!(Value::from_json(&serde_json::json!(1.5)).is_err());
+    assert!(Value::from_json(&serde_json::json!(null)).is_err());
+    assert!(Value::from_json(&serde_json::json!({ "$hex": "f" })).is_err());
+}
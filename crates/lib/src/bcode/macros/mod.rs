@@ -0,0 +1,47 @@
+/// Build a Bencoded dictionary [`Value`](crate::prelude::Value).
+///
+/// Keys are anything convertible to a [`ByteString`](crate::prelude::ByteString),
+/// and values anything convertible to a [`Value`](crate::prelude::Value).
+///
+/// ```
+/// # use rip_lib::prelude::*;
+/// let value = bdict! {
+///     "name" => "file.txt",
+///     "length" => 5,
+///     "path" => blist!["dir", "file.txt"],
+/// };
+///
+/// assert_eq!(value.as_dictionary().unwrap()["length"].as_int(), Some(5));
+/// ```
+#[macro_export]
+macro_rules! bdict {
+    ($($key:expr => $value:expr),* $(,)?) => {
+        $crate::prelude::Value::Dictionary($crate::prelude::Dictionary(
+            ::std::collections::BTreeMap::from([
+                $((
+                    $crate::prelude::ByteString::from($key),
+                    $crate::prelude::Value::from($value),
+                )),*
+            ]),
+        ))
+    };
+}
+
+/// Build a Bencoded list [`Value`](crate::prelude::Value).
+///
+/// Items are anything convertible to a [`Value`](crate::prelude::Value).
+///
+/// ```
+/// # use rip_lib::prelude::*;
+/// let value = blist![1, "two", b"\x03"];
+///
+/// assert_eq!(value.as_list().unwrap()[1].as_str(), Some("two"));
+/// ```
+#[macro_export]
+macro_rules! blist {
+    ($($value:expr),* $(,)?) => {
+        $crate::prelude::Value::List($crate::prelude::List(vec![
+            $($crate::prelude::Value::from($value)),*
+        ]))
+    };
+}
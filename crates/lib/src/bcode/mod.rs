@@ -3,8 +3,14 @@ mod tests;
 
 pub mod decode;
 pub mod encode;
+#[cfg(feature = "json")]
+pub mod json;
+mod macros;
+pub mod pretty;
 pub mod types;
 
+pub use crate::{bdict, blist};
 pub use decode::*;
 pub use encode::*;
+pub use pretty::*;
 pub use types::*;
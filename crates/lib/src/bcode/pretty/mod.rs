@@ -0,0 +1,100 @@
+use crate::prelude::*;
+use crate::util::to_hex;
+use std::fmt;
+
+/// Number of leading bytes shown for binary byte strings.
+const BINARY_PREVIEW: usize = 16;
+
+/// Human-readable representation of a [`Value`], created by [`Value::pretty`].
+///
+/// Byte strings that aren't printable text are shown as their length and a hex preview,
+/// so values such as `pieces` stay readable.
+pub struct Pretty<'a> {
+    value: &'a Value,
+}
+
+impl Value {
+    /// Get a human-readable representation, for use with `{}`.
+    pub fn pretty(&self) -> Pretty<'_> {
+        Pretty { value: self }
+    }
+}
+
+impl fmt::Display for Pretty<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_value(f, self.value, 0)
+    }
+}
+
+/// Write any.
+fn write_value(f: &mut fmt::Formatter<'_>, value: &Value, indent: usize) -> fmt::Result {
+    match value {
+        Value::Integer(inner) => write!(f, "{}", inner.0),
+        Value::ByteString(inner) => write_byte_string(f, &inner.0),
+        Value::List(inner) if inner.0.is_empty() => write!(f, "[]"),
+        Value::List(inner) => {
+            writeln!(f, "[")?;
+            for value in &inner.0 {
+                write!(f, "{:width$}", "", width = (indent + 1) * 2)?;
+                write_value(f, value, indent + 1)?;
+                writeln!(f, ",")?;
+            }
+            write!(f, "{:width$}]", "", width = indent * 2)
+        }
+        Value::Dictionary(inner) if inner.0.is_empty() => write!(f, "{{}}"),
+        Value::Dictionary(inner) => {
+            writeln!(f, "{{")?;
+            for (key, value) in &inner.0 {
+                write!(f, "{:width$}", "", width = (indent + 1) * 2)?;
+                write_byte_string(f, &key.0)?;
+                write!(f, ": ")?;
+                write_value(f, value, indent + 1)?;
+                writeln!(f, ",")?;
+            }
+            write!(f, "{:width$}}}", "", width = indent * 2)
+        }
+    }
+}
+
+/// Write byte string, as quoted text if printable and as a hex preview otherwise.
+fn write_byte_string(f: &mut fmt::Formatter<'_>, data: &[u8]) -> fmt::Result {
+    match std::str::from_utf8(data) {
+        Ok(text) if !text.chars().any(|c| c.is_control() && !c.is_whitespace()) => {
+            write!(f, "{text:?}")
+        }
+        _ if data.len() <= BINARY_PREVIEW => write!(f, "<{}>", to_hex(data)),
+        _ => write!(
+            f,
+            "<{} bytes: {}...>",
+            data.len(),
+            to_hex(&data[..BINARY_PREVIEW])
+        ),
+    }
+}
+
+#[test]
+fn test_pretty() {
+    let value =
+        decode(b"d4:infod6:lengthi5e6:pieces20:aaaaaaaaaaaaaaaaaaaae4:listle4:name3:\x00\x01\x02e")
+            .unwrap();
+
+    assert_eq!(
+        value.pretty().to_string(),
+        concat!(
+            "{\n",
+            "  \"info\": {\n",
+            "    \"length\": 5,\n",
+            "    \"pieces\": \"aaaaaaaaaaaaaaaaaaaa\",\n",
+            "  },\n",
+            "  \"list\": [],\n",
+            "  \"name\": <000102>,\n",
+            "}",
+        )
+    );
+
+    let pieces = Value::ByteString(ByteString(vec![0xff; 40]));
+    assert_eq!(
+        pieces.pretty().to_string(),
+        "<40 bytes: ffffffffffffffffffffffffffffffff...>"
+    );
+}
@@ -9,6 +9,18 @@ fn bcode_integer() {
     assert_eq!(integer, decoded);
 }
 
+#[test]
+fn bcode_large_integer() {
+    let integer = Value::Integer(Integer(5_000_000_000_000));
+    let encoded = encode(&integer);
+    let decoded = decode(&encoded).unwrap().try_as::<Integer>().unwrap();
+
+    assert_eq!(decoded.try_to::<u64>().unwrap(), 5_000_000_000_000);
+    assert!(decoded.try_to::<u16>().is_err());
+    assert!(Integer(-1).try_to::<u64>().is_err());
+    assert!(decode(b"i9223372036854775808e").is_err());
+}
+
 #[test]
 fn bcode_byte_string() {
     let byte_string = Value::ByteString(ByteString(b"3:abc".to_vec()));
@@ -32,5 +44,74 @@ fn bcode_list() {
 
 #[test]
 fn bcode_dictionary() {
-    // TODO
+    let dictionary = bdict! {
+        "integer" => -32,
+        "byte string" => b"3:abc",
+        "list" => blist![1, "a"],
+        "dictionary" => bdict! {},
+    };
+    let encoded = encode(&dictionary);
+    let decoded = decode(&encoded).unwrap();
+
+    assert_eq!(dictionary, decoded);
+    assert_eq!(
+        encoded,
+        b"d11:byte string5:3:abc10:dictionaryde7:integeri-32e4:listli1e1:aee"
+    );
+}
+
+#[test]
+fn bcode_accessors() {
+    let value = bdict! {
+        "name" => "rip",
+        "binary" => vec![0xff, 0xfe],
+        "length" => 5_000_000_000i64,
+        "files" => blist![bdict! { "length" => 1 }],
+    };
+    let dictionary = value.as_dictionary().unwrap();
+
+    assert_eq!(dictionary["name"].as_str(), Some("rip"));
+    assert_eq!(dictionary["name"].as_bytes(), Some(&b"rip"[..]));
+    assert_eq!(dictionary["binary"].as_str(), None);
+    assert_eq!(dictionary["length"].as_int(), Some(5_000_000_000));
+    assert_eq!(dictionary["files"].as_list().unwrap().len(), 1);
+    assert_eq!(dictionary.get("missing"), None);
+    assert_eq!(value.as_int(), None);
+    assert!(Value::try_from(u64::MAX).is_err());
+    assert_eq!(Value::try_from(7usize).unwrap(), Value::from(7));
+}
+
+#[test]
+fn bcode_encode_to() {
+    let list = Value::List(List(vec![
+        Value::Integer(Integer(i64::MIN)),
+        Value::ByteString(ByteString(b"abc".to_vec())),
+        Value::List(List(vec![Value::List(List(Vec::new()))])),
+    ]));
+    let mut out = Vec::new();
+    encode_to(&list, &mut out).unwrap();
+
+    assert_eq!(out, b"li-9223372036854775808e3:abclleee");
+    assert_eq!(out, encode(&list));
+    assert_eq!(list.encoded_len(), out.len());
+}
+
+#[tokio::test]
+async fn bcode_encode_to_async() {
+    let contents = include_bytes!("../../tests/torrents/ubuntu-23.04-desktop-amd64.iso.torrent");
+    let torrent = decode(contents).unwrap();
+    let mut out = Vec::new();
+    encode_to_async(&torrent, &mut out).await.unwrap();
+
+    assert_eq!(out, contents);
+    assert_eq!(torrent.encoded_len(), contents.len());
+}
+
+#[test]
+fn bcode_decode_prefix() {
+    let (value, len) = decode_prefix(b"d1:ai1eeDATA", DecodeOptions::network()).unwrap();
+
+    assert_eq!(value, bdict! { "a" => 1 });
+    assert_eq!(len, 8);
+    assert!(decode_prefix(b"d1:ai1e", DecodeOptions::network()).is_err());
 }
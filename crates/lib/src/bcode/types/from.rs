@@ -0,0 +1,153 @@
+use super::*;
+use std::collections::HashMap;
+
+macro_rules! impl_from_int {
+    ($($t:ty),*) => {
+        $(
+            impl From<$t> for Integer {
+                fn from(value: $t) -> Self {
+                    Integer(i64::from(value))
+                }
+            }
+
+            impl From<$t> for Value {
+                fn from(value: $t) -> Self {
+                    Value::Integer(Integer::from(value))
+                }
+            }
+        )*
+    };
+}
+
+macro_rules! impl_try_from_int {
+    ($($t:ty),*) => {
+        $(
+            impl TryFrom<$t> for Integer {
+                type Error = BencodeError;
+
+                fn try_from(value: $t) -> Result<Self, Self::Error> {
+                    i64::try_from(value)
+                        .map(Integer)
+                        .map_err(|_| BencodeErrorKind::IntegerOutOfRange.into())
+                }
+            }
+
+            impl TryFrom<$t> for Value {
+                type Error = BencodeError;
+
+                fn try_from(value: $t) -> Result<Self, Self::Error> {
+                    Integer::try_from(value).map(Value::Integer)
+                }
+            }
+        )*
+    };
+}
+
+impl_from_int!(i8, i16, i32, i64, u8, u16, u32);
+impl_try_from_int!(isize, usize, u64);
+
+impl From<&str> for ByteString {
+    fn from(value: &str) -> Self {
+        ByteString(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for ByteString {
+    fn from(value: String) -> Self {
+        ByteString(value.into_bytes())
+    }
+}
+
+impl From<&[u8]> for ByteString {
+    fn from(value: &[u8]) -> Self {
+        ByteString(value.to_vec())
+    }
+}
+
+impl<const N: usize> From<&[u8; N]> for ByteString {
+    fn from(value: &[u8; N]) -> Self {
+        ByteString(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for ByteString {
+    fn from(value: Vec<u8>) -> Self {
+        ByteString(value)
+    }
+}
+
+impl<T: Into<ByteString>> From<T> for Value {
+    fn from(value: T) -> Self {
+        Value::ByteString(value.into())
+    }
+}
+
+impl From<Integer> for Value {
+    fn from(value: Integer) -> Self {
+        Value::Integer(value)
+    }
+}
+
+impl From<List> for Value {
+    fn from(value: List) -> Self {
+        Value::List(value)
+    }
+}
+
+impl From<Dictionary> for Value {
+    fn from(value: Dictionary) -> Self {
+        Value::Dictionary(value)
+    }
+}
+
+impl From<Vec<Value>> for List {
+    fn from(value: Vec<Value>) -> Self {
+        List(value)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(value: Vec<Value>) -> Self {
+        Value::List(List(value))
+    }
+}
+
+impl<T: Into<Value>> FromIterator<T> for List {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        List(iter.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<K: Into<ByteString>, V: Into<Value>> FromIterator<(K, V)> for Dictionary {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        Dictionary(
+            iter.into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect(),
+        )
+    }
+}
+
+impl<K: Into<ByteString>, V: Into<Value>> From<BTreeMap<K, V>> for Dictionary {
+    fn from(value: BTreeMap<K, V>) -> Self {
+        value.into_iter().collect()
+    }
+}
+
+impl<K: Into<ByteString>, V: Into<Value>> From<HashMap<K, V>> for Dictionary {
+    fn from(value: HashMap<K, V>) -> Self {
+        value.into_iter().collect()
+    }
+}
+
+impl<K: Into<ByteString>, V: Into<Value>> From<BTreeMap<K, V>> for Value {
+    fn from(value: BTreeMap<K, V>) -> Self {
+        Value::Dictionary(value.into())
+    }
+}
+
+impl<K: Into<ByteString>, V: Into<Value>> From<HashMap<K, V>> for Value {
+    fn from(value: HashMap<K, V>) -> Self {
+        Value::Dictionary(value.into())
+    }
+}
@@ -1,5 +1,9 @@
-use crate::error::Error;
+mod from;
+
+use crate::error::{BencodeError, BencodeErrorKind};
+use std::borrow::Borrow;
 use std::collections::BTreeMap;
+use std::ops::Index;
 
 /// A Bencoded value
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -16,31 +20,95 @@ pub enum Value {
 
 impl Value {
     /// Try getting bencoded value as type `T`.
-    pub fn try_as<T: TryFrom<Value>>(self) -> Result<T, Error> {
-        T::try_from(self).map_err(|_| Error::Bencode("wrong type".to_string()))
+    pub fn try_as<T: TryFrom<Value, Error = BencodeError>>(self) -> Result<T, BencodeError> {
+        T::try_from(self)
     }
 
     /// Try getting bencoded value as list of type `T`.
-    pub fn as_list_of<T: TryFrom<Value>>(self) -> Result<Vec<T>, Error> {
+    pub fn as_list_of<T: TryFrom<Value, Error = BencodeError>>(
+        self,
+    ) -> Result<Vec<T>, BencodeError> {
         self.try_as::<List>()?
             .0
             .into_iter()
             .map(|v| v.try_as::<T>())
-            .collect::<Result<Vec<T>, Error>>()
+            .collect::<Result<Vec<T>, BencodeError>>()
+    }
+
+    /// Get name of the variant, for error messages.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Integer(_) => "integer",
+            Value::ByteString(_) => "byte string",
+            Value::List(_) => "list",
+            Value::Dictionary(_) => "dictionary",
+        }
+    }
+
+    /// Create a [`BencodeErrorKind::WrongType`] error for this value.
+    fn wrong_type(&self, expected: &'static str) -> BencodeError {
+        BencodeError::from(BencodeErrorKind::WrongType {
+            expected,
+            found: self.type_name(),
+        })
+    }
+
+    /// Get integer, if this is one.
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Integer(inner) => Some(inner.0),
+            _ => None,
+        }
+    }
+
+    /// Get bytes, if this is a byte string.
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::ByteString(inner) => Some(&inner.0),
+            _ => None,
+        }
+    }
+
+    /// Get text, if this is a byte string containing valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(self.as_bytes()?).ok()
+    }
+
+    /// Get items, if this is a list.
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(inner) => Some(&inner.0),
+            _ => None,
+        }
+    }
+
+    /// Get dictionary, if this is one.
+    pub fn as_dictionary(&self) -> Option<&Dictionary> {
+        match self {
+            Value::Dictionary(inner) => Some(inner),
+            _ => None,
+        }
     }
 }
 
 /// Bencoded integer.
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
-pub struct Integer(pub isize);
+pub struct Integer(pub i64);
+
+impl Integer {
+    /// Try converting to integer type `T`, failing if the value doesn't fit.
+    pub fn try_to<T: TryFrom<i64>>(&self) -> Result<T, BencodeError> {
+        T::try_from(self.0).map_err(|_| BencodeErrorKind::IntegerOutOfRange.into())
+    }
+}
 
 impl TryFrom<Value> for Integer {
-    type Error = Error;
+    type Error = BencodeError;
 
     fn try_from(value: Value) -> Result<Self, Self::Error> {
         match value {
             Value::Integer(out) => Ok(out),
-            _ => Err(Error::Bencode("not a integer".to_string())),
+            other => Err(other.wrong_type("integer")),
         }
     }
 }
@@ -50,27 +118,33 @@ impl TryFrom<Value> for Integer {
 pub struct ByteString(pub Vec<u8>);
 
 impl TryFrom<Value> for ByteString {
-    type Error = Error;
+    type Error = BencodeError;
 
     fn try_from(value: Value) -> Result<Self, Self::Error> {
         match value {
             Value::ByteString(out) => Ok(out),
-            _ => Err(Error::Bencode("not a byte string".to_string())),
+            other => Err(other.wrong_type("byte string")),
         }
     }
 }
 
+impl Borrow<[u8]> for ByteString {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
 /// Bencoded list.
 #[derive(Debug, Clone, PartialEq, PartialOrd, Eq, Ord)]
 pub struct List(pub Vec<Value>);
 
 impl TryFrom<Value> for List {
-    type Error = Error;
+    type Error = BencodeError;
 
     fn try_from(value: Value) -> Result<Self, Self::Error> {
         match value {
             Value::List(out) => Ok(out),
-            _ => Err(Error::Bencode("not a list".to_string())),
+            other => Err(other.wrong_type("list")),
         }
     }
 }
@@ -80,37 +154,58 @@ impl TryFrom<Value> for List {
 pub struct Dictionary(pub BTreeMap<ByteString, Value>);
 
 impl TryFrom<Value> for Dictionary {
-    type Error = Error;
+    type Error = BencodeError;
 
     fn try_from(value: Value) -> Result<Self, Self::Error> {
         match value {
             Value::Dictionary(out) => Ok(out),
-            _ => Err(Error::Bencode("not a dictionary".to_string())),
+            other => Err(other.wrong_type("dictionary")),
         }
     }
 }
 
 impl Dictionary {
     /// Try getting value from `key`.
-    pub fn try_get(&self, key: &str) -> Result<&Value, Error> {
-        let opt_value = self.0.get(&ByteString(key.as_bytes().to_vec()));
-        let out_value = opt_value.ok_or(Error::Bencode(format!("missing key {key:?}")))?;
+    pub fn try_get(&self, key: &str) -> Result<&Value, BencodeError> {
+        let opt_value = self.get(key);
+        let out_value = opt_value.ok_or_else(|| BencodeErrorKind::MissingKey(key.to_string()))?;
 
         Ok(out_value)
     }
 
     /// Try getting value as type `T` from `key`.
-    pub fn try_get_as<T: TryFrom<Value>>(&self, key: &str) -> Result<T, Error> {
-        let opt_value = self.0.get(&ByteString(key.as_bytes().to_vec()));
-        let res_value = opt_value.ok_or(Error::Bencode(format!("missing key {key:?}")))?;
+    pub fn try_get_as<T: TryFrom<Value, Error = BencodeError>>(
+        &self,
+        key: &str,
+    ) -> Result<T, BencodeError> {
+        let res_value = self.try_get(key)?;
         let res_value = res_value.clone();
-        let out_value = T::try_from(res_value).map_err(|_| Error::Bencode("invalid type".to_string()))?;
+        let out_value = T::try_from(res_value)?;
 
         Ok(out_value)
     }
 
+    /// Get value from `key`, if it exists.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.0.get(key.as_bytes())
+    }
+
     /// Check whether `key` exists.
     pub fn has(&self, key: &str) -> bool {
-        self.0.contains_key(&ByteString(key.as_bytes().to_vec()))
+        self.0.contains_key(key.as_bytes())
+    }
+}
+
+impl Index<&str> for Dictionary {
+    type Output = Value;
+
+    /// Get value from `key`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` doesn't exist.
+    fn index(&self, key: &str) -> &Self::Output {
+        self.get(key)
+            .unwrap_or_else(|| panic!("missing key {key:?}"))
     }
 }
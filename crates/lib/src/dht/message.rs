@@ -0,0 +1,380 @@
+//! KRPC messages of the DHT (BEP 5), with IPv6 nodes and the `want` parameter (BEP 32).
+
+use crate::prelude::*;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Length of a node in the compact format of `nodes`: ID, IPv4 address and port.
+const NODE_LEN: usize = 26;
+/// Length of a node in the compact format of `nodes6`: ID, IPv6 address and port.
+const NODE6_LEN: usize = 38;
+
+/// A DHT node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct Node {
+    pub id: [u8; 20],
+    pub addr: SocketAddr,
+}
+
+/// Address families of the nodes a querying node wants in the response (BEP 32).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Want {
+    pub v4: bool,
+    pub v6: bool,
+}
+
+impl Want {
+    /// Get the family of `addr`, which nodes want when they don't say.
+    pub fn family_of(addr: SocketAddr) -> Self {
+        Self {
+            v4: addr.is_ipv4(),
+            v6: addr.is_ipv6(),
+        }
+    }
+}
+
+/// Query of a node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Query {
+    Ping,
+    /// Ask for the nodes closest to `target`.
+    FindNode {
+        target: [u8; 20],
+        want: Option<Want>,
+    },
+    /// Ask for peers of the torrent with `info_hash`, or the nodes closest to it.
+    GetPeers {
+        info_hash: [u8; 20],
+        want: Option<Want>,
+    },
+    /// Say that the sender is a peer of the torrent with `info_hash`, listening on `port`,
+    /// or the port the query came from if `implied_port` is set.
+    AnnouncePeer {
+        info_hash: [u8; 20],
+        port: u16,
+        implied_port: bool,
+        token: Vec<u8>,
+    },
+}
+
+/// Response to a query. Fields a query doesn't call for are left empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Response {
+    /// Nodes closest to the target, of both families.
+    pub nodes: Vec<Node>,
+    /// Peers of the torrent.
+    pub values: Vec<SocketAddr>,
+    /// Token for announcing to the responding node.
+    pub token: Option<Vec<u8>>,
+}
+
+/// Body of a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Body {
+    Query(Query),
+    Response(Response),
+    Error { code: i64, message: String },
+}
+
+/// KRPC message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Message {
+    /// Transaction ID, echoed in the response.
+    pub transaction: Vec<u8>,
+    /// ID of the sending node, not set in errors.
+    pub id: [u8; 20],
+    pub body: Body,
+}
+
+impl Message {
+    /// Encode message.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dictionary = BTreeMap::<&str, Value>::new();
+        dictionary.insert("t", self.transaction.clone().into());
+        let mut args = BTreeMap::<&str, Value>::new();
+        args.insert("id", self.id.to_vec().into());
+
+        match &self.body {
+            Body::Query(query) => {
+                dictionary.insert("y", "q".into());
+                let name = match query {
+                    Query::Ping => "ping",
+                    Query::FindNode { target, want } => {
+                        args.insert("target", target.to_vec().into());
+                        insert_want(&mut args, *want);
+                        "find_node"
+                    }
+                    Query::GetPeers { info_hash, want } => {
+                        args.insert("info_hash", info_hash.to_vec().into());
+                        insert_want(&mut args, *want);
+                        "get_peers"
+                    }
+                    Query::AnnouncePeer {
+                        info_hash,
+                        port,
+                        implied_port,
+                        token,
+                    } => {
+                        args.insert("info_hash", info_hash.to_vec().into());
+                        args.insert("port", (*port).into());
+                        args.insert("implied_port", (*implied_port as u8).into());
+                        args.insert("token", token.clone().into());
+                        "announce_peer"
+                    }
+                };
+                dictionary.insert("q", name.into());
+                dictionary.insert("a", args.into());
+            }
+            Body::Response(response) => {
+                dictionary.insert("y", "r".into());
+                let (nodes, nodes6) = compact_nodes(&response.nodes);
+                if !nodes.is_empty() {
+                    args.insert("nodes", nodes.into());
+                }
+                if !nodes6.is_empty() {
+                    args.insert("nodes6", nodes6.into());
+                }
+                if !response.values.is_empty() {
+                    let values = response
+                        .values
+                        .iter()
+                        .map(|addr| compact_addr(*addr).into());
+                    args.insert("values", values.collect::<Vec<Value>>().into());
+                }
+                if let Some(token) = &response.token {
+                    args.insert("token", token.clone().into());
+                }
+                dictionary.insert("r", args.into());
+            }
+            Body::Error { code, message } => {
+                dictionary.insert("y", "e".into());
+                let error = vec![Value::Integer(Integer(*code)), message.as_str().into()];
+                dictionary.insert("e", error.into());
+            }
+        }
+
+        encode(&dictionary.into())
+    }
+
+    /// Decode message, or get `None` if it's malformed.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let dictionary = decode_with(bytes, DecodeOptions::network())
+            .ok()?
+            .try_as::<Dictionary>()
+            .ok()?;
+        let transaction = dictionary.get("t")?.as_bytes()?.to_vec();
+        let kind = dictionary.get("y")?.as_bytes()?;
+
+        if kind == b"e" {
+            let error = dictionary.get("e")?.as_list()?;
+            let code = error.first().and_then(Value::as_int).unwrap_or_default();
+            let message = error.get(1).and_then(Value::as_str).unwrap_or_default();
+            return Some(Self {
+                transaction,
+                id: [0; 20],
+                body: Body::Error {
+                    code,
+                    message: message.to_string(),
+                },
+            });
+        }
+
+        let key = if kind == b"q" { "a" } else { "r" };
+        let args = dictionary.get(key)?.as_dictionary()?;
+        let hash = |key: &str| <[u8; 20]>::try_from(args.get(key)?.as_bytes()?).ok();
+        let id = hash("id")?;
+        let body = match kind {
+            b"q" => Body::Query(match dictionary.get("q")?.as_bytes()? {
+                b"ping" => Query::Ping,
+                b"find_node" => Query::FindNode {
+                    target: hash("target")?,
+                    want: parse_want(args),
+                },
+                b"get_peers" => Query::GetPeers {
+                    info_hash: hash("info_hash")?,
+                    want: parse_want(args),
+                },
+                b"announce_peer" => Query::AnnouncePeer {
+                    info_hash: hash("info_hash")?,
+                    port: u16::try_from(args.get("port")?.as_int()?).ok()?,
+                    implied_port: args.get("implied_port").and_then(Value::as_int) == Some(1),
+                    token: args.get("token")?.as_bytes()?.to_vec(),
+                },
+                _ => return None,
+            }),
+            b"r" => {
+                let bytes = |key: &str| args.get(key).and_then(Value::as_bytes);
+                let mut nodes = parse_nodes(bytes("nodes").unwrap_or_default(), NODE_LEN);
+                nodes.extend(parse_nodes(bytes("nodes6").unwrap_or_default(), NODE6_LEN));
+                let values = args
+                    .get("values")
+                    .and_then(Value::as_list)
+                    .unwrap_or_default();
+                let values = values.iter().filter_map(parse_addr).collect();
+                let token = bytes("token").map(<[u8]>::to_vec);
+                Body::Response(Response {
+                    nodes,
+                    values,
+                    token,
+                })
+            }
+            _ => return None,
+        };
+
+        Some(Self {
+            transaction,
+            id,
+            body,
+        })
+    }
+}
+
+/// Add `want` to the arguments of a query, if set.
+fn insert_want(args: &mut BTreeMap<&str, Value>, want: Option<Want>) {
+    let Some(want) = want else {
+        return;
+    };
+    let families = [(want.v4, "n4"), (want.v6, "n6")];
+    let families = families.iter().filter(|(wanted, _)| *wanted);
+    let families = families
+        .map(|(_, name)| Value::from(*name))
+        .collect::<Vec<_>>();
+    args.insert("want", families.into());
+}
+
+/// Get `want` of the arguments of a query, if set.
+fn parse_want(args: &Dictionary) -> Option<Want> {
+    let families = args.get("want")?.as_list()?;
+    let wants = |name: &[u8]| {
+        families
+            .iter()
+            .any(|family| family.as_bytes() == Some(name))
+    };
+
+    Some(Want {
+        v4: wants(b"n4"),
+        v6: wants(b"n6"),
+    })
+}
+
+/// Encode `addr` in the compact format of peers, 6 bytes for IPv4 or 18 for IPv6.
+fn compact_addr(addr: SocketAddr) -> Vec<u8> {
+    let mut out = match addr.ip() {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    };
+    out.extend(addr.port().to_be_bytes());
+
+    out
+}
+
+/// Decode a peer in the compact format of `values`.
+fn parse_addr(value: &Value) -> Option<SocketAddr> {
+    let bytes = value.as_bytes()?;
+    let (ip, port) = bytes.split_at(bytes.len().checked_sub(2)?);
+    let ip = match ip.len() {
+        4 => IpAddr::from(<[u8; 4]>::try_from(ip).ok()?),
+        16 => IpAddr::from(<[u8; 16]>::try_from(ip).ok()?),
+        _ => return None,
+    };
+
+    Some(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])))
+}
+
+/// Encode `nodes` in the compact formats of `nodes` and `nodes6`.
+fn compact_nodes(nodes: &[Node]) -> (Vec<u8>, Vec<u8>) {
+    let (mut v4, mut v6) = (Vec::new(), Vec::new());
+    for node in nodes {
+        let out = if node.addr.is_ipv4() {
+            &mut v4
+        } else {
+            &mut v6
+        };
+        out.extend(node.id);
+        out.extend(compact_addr(node.addr));
+    }
+
+    (v4, v6)
+}
+
+/// Decode nodes in a compact format of `len` bytes per node, skipping a trailing partial one.
+fn parse_nodes(bytes: &[u8], len: usize) -> Vec<Node> {
+    bytes
+        .chunks_exact(len)
+        .map(|chunk| {
+            let (id, addr) = chunk.split_at(20);
+            let (ip, port) = addr.split_at(addr.len() - 2);
+            let ip = match ip.len() {
+                4 => IpAddr::from(Ipv4Addr::from(<[u8; 4]>::try_from(ip).unwrap())),
+                _ => IpAddr::from(Ipv6Addr::from(<[u8; 16]>::try_from(ip).unwrap())),
+            };
+            Node {
+                id: id.try_into().unwrap(),
+                addr: SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])),
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_dht_message_round_trip() {
+    let query = Message {
+        transaction: b"aa".to_vec(),
+        id: [1; 20],
+        body: Body::Query(Query::GetPeers {
+            info_hash: [2; 20],
+            want: Some(Want { v4: true, v6: true }),
+        }),
+    };
+    let bytes = query.to_bytes();
+    assert!(bytes.windows(15).any(|w| w == b"4:wantl2:n42:n6"));
+    assert_eq!(Message::from_bytes(&bytes), Some(query));
+
+    let response = Message {
+        transaction: b"aa".to_vec(),
+        id: [3; 20],
+        body: Body::Response(Response {
+            nodes: vec![
+                Node {
+                    id: [4; 20],
+                    addr: "10.0.0.1:6881".parse().unwrap(),
+                },
+                Node {
+                    id: [5; 20],
+                    addr: "[2001:db8::1]:6882".parse().unwrap(),
+                },
+            ],
+            values: vec![
+                "10.0.0.2:6881".parse().unwrap(),
+                "[2001:db8::2]:6881".parse().unwrap(),
+            ],
+            token: Some(b"token".to_vec()),
+        }),
+    };
+    let bytes = response.to_bytes();
+    assert!(bytes.windows(10).any(|w| w == b"6:nodes638"));
+    assert_eq!(Message::from_bytes(&bytes), Some(response));
+
+    let announce = Message {
+        transaction: b"bb".to_vec(),
+        id: [1; 20],
+        body: Body::Query(Query::AnnouncePeer {
+            info_hash: [2; 20],
+            port: 6881,
+            implied_port: true,
+            token: b"token".to_vec(),
+        }),
+    };
+    assert_eq!(Message::from_bytes(&announce.to_bytes()), Some(announce));
+
+    let error = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
+    assert_eq!(
+        Message::from_bytes(error).unwrap().body,
+        Body::Error {
+            code: 201,
+            message: "A Generic Error Ocurred".to_string()
+        }
+    );
+    assert_eq!(Message::from_bytes(b"d1:t2:aa1:y1:qe"), None);
+    assert_eq!(Message::from_bytes(b"li1ee"), None);
+}
@@ -0,0 +1,453 @@
+//! Distributed hash table (BEP 5) over IPv4 and IPv6 (BEP 32): finding peers of torrents
+//! without a tracker, and keeping track of the peers other nodes announce. Runs on the UDP
+//! socket of uTP, on the listen port.
+
+mod message;
+mod routing;
+
+use crate::peer::UtpSocket;
+use crate::util;
+use futures::stream::{FuturesUnordered, StreamExt};
+use message::{Body, Message, Node, Query, Response, Want};
+use rand::RngCore;
+use routing::{distance, RoutingTable, K};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinSet;
+
+/// Time a node has to respond to a query.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Queries in flight at once during a lookup.
+const ALPHA: usize = 3;
+/// Interval between lookups of our own ID, which keep the routing tables fresh.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Interval between changes of the secret tokens are made from. Tokens stay valid for two.
+const SECRET_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Time peers announced to us are kept.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+/// Most peers kept per torrent.
+const MAX_STORED_PEERS: usize = 500;
+/// Most peers in a response, so it fits in a datagram.
+const MAX_PEERS: usize = 50;
+/// Index of the routing table of IPv4 nodes.
+const V4: usize = 0;
+/// Index of the routing table of IPv6 nodes.
+const V6: usize = 1;
+
+/// DHT node of an agent, cheap to clone.
+#[derive(Clone)]
+pub(crate) struct Dht {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    id: [u8; 20],
+    utp: UtpSocket,
+    state: Mutex<State>,
+}
+
+/// Mutable state of a node. Never held across an `.await`.
+struct State {
+    /// Routing tables of IPv4 and IPv6 nodes.
+    tables: [RoutingTable; 2],
+    /// Queries waiting for a response, by transaction ID, with the node they went to.
+    pending: HashMap<u16, (SocketAddr, oneshot::Sender<Message>)>,
+    next_transaction: u16,
+    /// Peers announced to us, by info hash, with the time they last announced.
+    peers: HashMap<[u8; 20], HashMap<SocketAddr, Instant>>,
+    /// Current and previous secret, tokens are made from.
+    secrets: [[u8; 20]; 2],
+}
+
+/// Outcome of a lookup.
+struct Lookup {
+    /// Peers the nodes returned.
+    values: HashSet<SocketAddr>,
+    /// Nodes closest to the target that responded, closest first, with the tokens they gave.
+    closest: Vec<(Node, Option<Vec<u8>>)>,
+}
+
+impl fmt::Debug for Dht {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dht")
+            .field("id", &util::to_hex(&self.inner.id))
+            .finish_non_exhaustive()
+    }
+}
+
+impl Dht {
+    /// Create a node with a random ID, sending over `utp`.
+    pub fn new(utp: UtpSocket) -> Self {
+        let id = random();
+        let state = State {
+            tables: [RoutingTable::new(id), RoutingTable::new(id)],
+            pending: HashMap::new(),
+            next_transaction: 0,
+            peers: HashMap::new(),
+            secrets: [random(), random()],
+        };
+
+        Self {
+            inner: Arc::new(Inner {
+                id,
+                utp,
+                state: Mutex::new(state),
+            }),
+        }
+    }
+
+    /// Join the DHT through the nodes at `routers`, like `router.bittorrent.com:6881`, then
+    /// handle messages in `datagrams` and keep the routing tables fresh, until it closes.
+    pub async fn run(
+        self,
+        mut datagrams: mpsc::UnboundedReceiver<(Vec<u8>, SocketAddr)>,
+        routers: Vec<String>,
+    ) {
+        let mut background = JoinSet::new();
+        background.spawn(self.clone().bootstrap(routers));
+        let start = tokio::time::Instant::now();
+        let mut refresh = tokio::time::interval_at(start + REFRESH_INTERVAL, REFRESH_INTERVAL);
+        let mut secret = tokio::time::interval_at(start + SECRET_INTERVAL, SECRET_INTERVAL);
+
+        loop {
+            tokio::select! {
+                datagram = datagrams.recv() => match datagram {
+                    Some((bytes, addr)) => self.handle(&bytes, addr).await,
+                    None => return,
+                },
+                _ = refresh.tick() => {
+                    background.spawn(self.clone().refresh());
+                }
+                _ = secret.tick() => self.rotate(),
+                Some(_) = background.join_next() => {}
+            }
+        }
+    }
+
+    /// Ping the node at `addr`, adding it to the routing table if it responds, and get
+    /// whether it did.
+    pub async fn add_node(&self, addr: SocketAddr) -> bool {
+        let addr = util::canonical(addr);
+        self.query(addr, Query::Ping).await.is_some()
+    }
+
+    /// Get peers of the torrent with `info_hash` from IPv4 and IPv6 nodes. If `announce` is
+    /// set, the closest nodes are told that we're a peer too, listening on that port.
+    pub async fn get_peers(&self, info_hash: [u8; 20], announce: Option<u16>) -> Vec<SocketAddr> {
+        let (v4, v6) = futures::join!(
+            self.lookup(V4, info_hash, true),
+            self.lookup(V6, info_hash, true)
+        );
+
+        let mut announcing = FuturesUnordered::new();
+        for (node, token) in v4.closest.iter().chain(&v6.closest) {
+            if let (Some(port), Some(token)) = (announce, token) {
+                let query = Query::AnnouncePeer {
+                    info_hash,
+                    port,
+                    implied_port: false,
+                    token: token.clone(),
+                };
+                announcing.push(self.query(node.addr, query));
+            }
+        }
+        while announcing.next().await.is_some() {}
+
+        let peers = v4.values.into_iter().chain(v6.values);
+        let peers = peers.map(util::canonical).collect::<HashSet<_>>();
+
+        peers.into_iter().collect()
+    }
+
+    /// Check whether the routing tables are empty, as before joining the DHT.
+    pub fn is_empty(&self) -> bool {
+        let state = self.lock();
+        state.tables.iter().all(|table| table.len() == 0)
+    }
+
+    /// Lock mutable state.
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.inner.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Query the nodes at `routers`, so those that respond are in the routing tables, then
+    /// look up the nodes close to us through them.
+    async fn bootstrap(self, routers: Vec<String>) {
+        let mut querying = FuturesUnordered::new();
+        for router in routers {
+            let Ok(addrs) = tokio::net::lookup_host(router).await else {
+                continue;
+            };
+            for addr in addrs.map(util::canonical) {
+                let query = Query::FindNode {
+                    target: self.inner.id,
+                    want: Some(Want::family_of(addr)),
+                };
+                querying.push(self.query(addr, query));
+            }
+        }
+        while querying.next().await.is_some() {}
+        drop(querying);
+
+        self.refresh().await;
+    }
+
+    /// Look up the nodes closest to us, which fills the buckets around us.
+    async fn refresh(self) {
+        let id = self.inner.id;
+        futures::join!(self.lookup(V4, id, false), self.lookup(V6, id, false));
+    }
+
+    /// Change the token secret, and forget peers that didn't announce again in time.
+    fn rotate(&self) {
+        let mut state = self.lock();
+        state.secrets = [random(), state.secrets[0]];
+        state.peers.retain(|_, peers| {
+            peers.retain(|_, announced| announced.elapsed() < PEER_TTL);
+            !peers.is_empty()
+        });
+    }
+
+    /// Send `query` to the node at `addr` and wait for its response. Gets `None` if the node
+    /// sent an error, or didn't respond in time, which counts against it.
+    async fn query(&self, addr: SocketAddr, query: Query) -> Option<Response> {
+        let (reply, response) = oneshot::channel();
+        let transaction = {
+            let mut state = self.lock();
+            let transaction = state.next_transaction;
+            state.next_transaction = transaction.wrapping_add(1);
+            state.pending.insert(transaction, (addr, reply));
+            transaction
+        };
+        let message = Message {
+            transaction: transaction.to_be_bytes().to_vec(),
+            id: self.inner.id,
+            body: Body::Query(query),
+        };
+
+        let response = match self.inner.utp.send_to(&message.to_bytes(), addr).await {
+            Ok(()) => tokio::time::timeout(QUERY_TIMEOUT, response).await.ok(),
+            Err(_) => None,
+        };
+        let mut state = self.lock();
+        state.pending.remove(&transaction);
+        match response.and_then(Result::ok) {
+            Some(Message {
+                id,
+                body: Body::Response(response),
+                ..
+            }) => {
+                state.tables[family(addr)].insert(Node { id, addr });
+                Some(response)
+            }
+            Some(_) => None,
+            None => {
+                state.tables[family(addr)].failed(addr);
+                None
+            }
+        }
+    }
+
+    /// Look up the nodes of routing table `table` closest to `target`, asking them for peers
+    /// of the torrent with info hash `target` if `peers` is set.
+    async fn lookup(&self, table: usize, target: [u8; 20], peers: bool) -> Lookup {
+        let want = Some(Want {
+            v4: table == V4,
+            v6: table == V6,
+        });
+        let query = match peers {
+            true => Query::GetPeers {
+                info_hash: target,
+                want,
+            },
+            false => Query::FindNode { target, want },
+        };
+
+        let nodes = self.lock().tables[table].closest(&target, K);
+        let mut candidates = nodes
+            .into_iter()
+            .map(|node| (distance(&node.id, &target), node))
+            .collect::<BTreeMap<_, _>>();
+        let mut queried = HashSet::new();
+        let mut closest = BTreeMap::new();
+        let mut values = HashSet::new();
+        let mut running = FuturesUnordered::new();
+
+        loop {
+            while running.len() < ALPHA {
+                let Some((key, node)) = candidates.pop_first() else {
+                    break;
+                };
+                // Done once K nodes that responded are closer than any node left to query.
+                if closest.keys().nth(K - 1).is_some_and(|last| *last < key) {
+                    candidates.clear();
+                    break;
+                }
+                if queried.insert(node.id) {
+                    let query = query.clone();
+                    running.push(async move { (key, node, self.query(node.addr, query).await) });
+                }
+            }
+
+            let Some((key, node, response)) = running.next().await else {
+                break;
+            };
+            let Some(response) = response else {
+                continue;
+            };
+            values.extend(response.values);
+            for found in response.nodes {
+                let usable = family(found.addr) == table && found.addr.port() != 0;
+                if usable && !queried.contains(&found.id) {
+                    candidates.insert(distance(&found.id, &target), found);
+                }
+            }
+            closest.insert(key, (node, response.token));
+        }
+
+        Lookup {
+            values,
+            closest: closest.into_values().take(K).collect(),
+        }
+    }
+
+    /// Handle a message of the node at `addr`: answer a query, or pass a response on to the
+    /// query waiting for it.
+    async fn handle(&self, bytes: &[u8], addr: SocketAddr) {
+        let Some(message) = Message::from_bytes(bytes) else {
+            return;
+        };
+
+        let body = {
+            let mut state = self.lock();
+            let Body::Query(query) = message.body else {
+                let transaction = <[u8; 2]>::try_from(&message.transaction[..]);
+                let Ok(transaction) = transaction.map(u16::from_be_bytes) else {
+                    return;
+                };
+                // Only the node that was queried may respond.
+                if state.pending.get(&transaction).map(|(to, _)| *to) == Some(addr) {
+                    if let Some((_, reply)) = state.pending.remove(&transaction) {
+                        let _ = reply.send(message);
+                    }
+                }
+                return;
+            };
+            state.tables[family(addr)].insert(Node {
+                id: message.id,
+                addr,
+            });
+            state.answer(query, addr)
+        };
+
+        let response = Message {
+            transaction: message.transaction,
+            id: self.inner.id,
+            body,
+        };
+        let _ = self.inner.utp.send_to(&response.to_bytes(), addr).await;
+    }
+}
+
+impl State {
+    /// Get the body of the response to `query` of the node at `addr`.
+    fn answer(&mut self, query: Query, addr: SocketAddr) -> Body {
+        let response = match query {
+            Query::Ping => Response::default(),
+            Query::FindNode { target, want } => Response {
+                nodes: self.closest(&target, want.unwrap_or(Want::family_of(addr))),
+                ..Default::default()
+            },
+            Query::GetPeers { info_hash, want } => {
+                let peers = self.peers.get(&info_hash).into_iter().flatten();
+                let peers = peers.filter(|(peer, announced)| {
+                    family(**peer) == family(addr) && announced.elapsed() < PEER_TTL
+                });
+                let values = peers
+                    .map(|(peer, _)| *peer)
+                    .take(MAX_PEERS)
+                    .collect::<Vec<_>>();
+                let nodes = match values.is_empty() {
+                    true => self.closest(&info_hash, want.unwrap_or(Want::family_of(addr))),
+                    false => Vec::new(),
+                };
+                Response {
+                    nodes,
+                    values,
+                    token: Some(token(&self.secrets[0], addr.ip())),
+                }
+            }
+            Query::AnnouncePeer {
+                info_hash,
+                port,
+                implied_port,
+                token: sent,
+            } => {
+                if !self
+                    .secrets
+                    .iter()
+                    .any(|secret| token(secret, addr.ip()) == sent)
+                {
+                    return Body::Error {
+                        code: 203,
+                        message: "invalid token".to_string(),
+                    };
+                }
+                let port = if implied_port { addr.port() } else { port };
+                let peer = SocketAddr::new(addr.ip(), port);
+                let peers = self.peers.entry(info_hash).or_default();
+                if peers.len() < MAX_STORED_PEERS || peers.contains_key(&peer) {
+                    peers.insert(peer, Instant::now());
+                }
+                Response::default()
+            }
+        };
+
+        Body::Response(response)
+    }
+
+    /// Get the nodes closest to `target` of the families in `want`.
+    fn closest(&self, target: &[u8; 20], want: Want) -> Vec<Node> {
+        let mut nodes = Vec::new();
+        if want.v4 {
+            nodes.extend(self.tables[V4].closest(target, K));
+        }
+        if want.v6 {
+            nodes.extend(self.tables[V6].closest(target, K));
+        }
+
+        nodes
+    }
+}
+
+/// Get the routing table of nodes at `addr`.
+fn family(addr: SocketAddr) -> usize {
+    match addr {
+        SocketAddr::V4(_) => V4,
+        SocketAddr::V6(_) => V6,
+    }
+}
+
+/// Get the token a node at `ip` has to send to announce itself, made from `secret`.
+fn token(secret: &[u8; 20], ip: IpAddr) -> Vec<u8> {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(secret);
+    match ip {
+        IpAddr::V4(ip) => hasher.update(&ip.octets()),
+        IpAddr::V6(ip) => hasher.update(&ip.octets()),
+    }
+
+    hasher.digest().bytes()[..8].to_vec()
+}
+
+/// Get 20 random bytes, for a node ID or secret.
+fn random() -> [u8; 20] {
+    let mut out = [0; 20];
+    rand::thread_rng().fill_bytes(&mut out);
+
+    out
+}
@@ -0,0 +1,149 @@
+//! Routing table of the DHT: nodes kept in buckets by how long a prefix their ID shares
+//! with ours, so we know many nodes close to us and a few far away.
+
+use super::message::Node;
+use std::net::SocketAddr;
+
+/// Most nodes per bucket, and nodes returned for a lookup.
+pub(crate) const K: usize = 8;
+/// Queries a node may fail in a row before it's dropped.
+const MAX_FAILURES: u8 = 3;
+
+/// Node in the table.
+#[derive(Debug, Clone)]
+struct Entry {
+    node: Node,
+    /// Queries the node failed since it last responded.
+    failures: u8,
+}
+
+/// Routing table of the nodes of one address family.
+#[derive(Debug)]
+pub(crate) struct RoutingTable {
+    id: [u8; 20],
+    /// Nodes by the number of leading bits their ID shares with ours.
+    buckets: Vec<Vec<Entry>>,
+}
+
+impl RoutingTable {
+    /// Create empty table around our node ID `id`.
+    pub fn new(id: [u8; 20]) -> Self {
+        Self {
+            id,
+            buckets: vec![Vec::new(); 160],
+        }
+    }
+
+    /// Add `node` that responded, or note that it's still there. A full bucket only takes
+    /// it in place of a node that failed.
+    pub fn insert(&mut self, node: Node) {
+        if node.id == self.id {
+            return;
+        }
+
+        let bucket = &mut self.buckets[bucket_of(&self.id, &node.id)];
+        if let Some(entry) = bucket.iter_mut().find(|entry| entry.node.id == node.id) {
+            entry.node.addr = node.addr;
+            entry.failures = 0;
+            return;
+        }
+        if bucket.len() >= K {
+            match bucket.iter().position(|entry| entry.failures > 0) {
+                Some(index) => bucket.remove(index),
+                None => return,
+            };
+        }
+        bucket.push(Entry { node, failures: 0 });
+    }
+
+    /// Note that the node at `addr` didn't respond, dropping it after [`MAX_FAILURES`].
+    pub fn failed(&mut self, addr: SocketAddr) {
+        for bucket in &mut self.buckets {
+            if let Some(index) = bucket.iter().position(|entry| entry.node.addr == addr) {
+                bucket[index].failures += 1;
+                if bucket[index].failures >= MAX_FAILURES {
+                    bucket.remove(index);
+                }
+                return;
+            }
+        }
+    }
+
+    /// Get up to `count` nodes closest to `target`, closest first.
+    pub fn closest(&self, target: &[u8; 20], count: usize) -> Vec<Node> {
+        let mut nodes = self
+            .buckets
+            .iter()
+            .flatten()
+            .map(|entry| entry.node)
+            .collect::<Vec<_>>();
+        nodes.sort_by_key(|node| distance(&node.id, target));
+        nodes.truncate(count);
+
+        nodes
+    }
+
+    /// Get number of nodes.
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(Vec::len).sum()
+    }
+}
+
+/// Get XOR distance between node IDs `a` and `b`, which compares like a big-endian number.
+pub(crate) fn distance(a: &[u8; 20], b: &[u8; 20]) -> [u8; 20] {
+    let mut out = [0; 20];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = a[i] ^ b[i];
+    }
+
+    out
+}
+
+/// Get index of the bucket of node ID `other` in the table of `id`.
+fn bucket_of(id: &[u8; 20], other: &[u8; 20]) -> usize {
+    let distance = distance(id, other);
+    let zeros = match distance.iter().position(|byte| *byte != 0) {
+        Some(index) => index * 8 + distance[index].leading_zeros() as usize,
+        None => 160,
+    };
+
+    zeros.min(159)
+}
+
+#[test]
+fn test_routing_table() {
+    let node = |first: u8, last: u8| {
+        let mut id = [0; 20];
+        (id[0], id[19]) = (first, last);
+        Node {
+            id,
+            addr: SocketAddr::from(([10, 0, first, last], 6881)),
+        }
+    };
+    let mut table = RoutingTable::new([0; 20]);
+    assert_eq!(bucket_of(&[0; 20], &node(0x80, 0).id), 0);
+    assert_eq!(bucket_of(&[0; 20], &node(0, 1).id), 159);
+
+    table.insert(node(0, 0));
+    assert_eq!(table.len(), 0);
+    for last in 0..10 {
+        table.insert(node(0x80, last));
+    }
+    table.insert(node(0x01, 0));
+    assert_eq!(table.len(), K + 1);
+
+    let closest = table.closest(&[0; 20], 2);
+    assert_eq!(closest, [node(0x01, 0), node(0x80, 0)]);
+
+    // A full bucket takes new nodes only in place of failing ones.
+    table.failed(node(0x80, 3).addr);
+    table.insert(node(0x80, 9));
+    assert_eq!(table.len(), K + 1);
+    assert!(table.closest(&[0x80; 20], 10).contains(&node(0x80, 9)));
+    assert!(!table.closest(&[0x80; 20], 10).contains(&node(0x80, 3)));
+
+    for _ in 0..MAX_FAILURES {
+        table.failed(node(0x01, 0).addr);
+    }
+    assert_eq!(table.len(), K);
+}
@@ -0,0 +1,28 @@
+/// Error in the state of an [`Agent`](crate::prelude::Agent).
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum AgentError {
+    #[error("torrent not found")]
+    NotFound,
+    #[error("torrent already added")]
+    AlreadyAdded,
+    #[error("no file with index {0}")]
+    InvalidFile(usize),
+    #[error("expected a priority for each of the {0} files")]
+    InvalidPriorities(usize),
+    #[error("torrent failed: {0}")]
+    Failed(String),
+    #[error("extension {0:?} already added")]
+    DuplicateExtension(String),
+    #[error("too many extensions")]
+    TooManyExtensions,
+    #[error("storage doesn't match the files of the torrent")]
+    StorageMismatch,
+    #[error("no peer sent the metadata of the torrent")]
+    MetadataNotFound,
+    #[error("DHT is disabled")]
+    DhtDisabled,
+    #[error("DHT node didn't respond")]
+    NodeUnreachable,
+    #[error("agent was shut down")]
+    ShutDown,
+}
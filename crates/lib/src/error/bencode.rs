@@ -0,0 +1,65 @@
+use std::fmt;
+
+/// Error while decoding or reading Bencoded data.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub struct BencodeError {
+    /// Byte offset in the input where decoding failed, if the error happened while decoding.
+    pub offset: Option<usize>,
+    /// What went wrong.
+    pub kind: BencodeErrorKind,
+}
+
+/// Kind of [`BencodeError`].
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum BencodeErrorKind {
+    #[error("unexpected end of input")]
+    UnexpectedEof,
+    #[error("unexpected byte {0:#04x}")]
+    UnexpectedByte(u8),
+    #[error("invalid integer")]
+    InvalidInteger,
+    #[error("integer out of range")]
+    IntegerOutOfRange,
+    #[error("invalid byte string length")]
+    InvalidLength,
+    #[error("byte string longer than {0} bytes")]
+    StringTooLong(usize),
+    #[error("more than {0} items")]
+    TooManyItems(usize),
+    #[error("nested deeper than {0} levels")]
+    TooDeep(usize),
+    #[error("missing key {0:?}")]
+    MissingKey(String),
+    #[error("expected {expected}, found {found}")]
+    WrongType {
+        expected: &'static str,
+        found: &'static str,
+    },
+    #[error("invalid JSON: {0}")]
+    Json(String),
+}
+
+impl BencodeError {
+    /// Create a [`BencodeError`] that happened while decoding, at `offset`.
+    pub fn at(offset: usize, kind: BencodeErrorKind) -> Self {
+        Self {
+            offset: Some(offset),
+            kind,
+        }
+    }
+}
+
+impl From<BencodeErrorKind> for BencodeError {
+    fn from(kind: BencodeErrorKind) -> Self {
+        Self { offset: None, kind }
+    }
+}
+
+impl fmt::Display for BencodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.offset {
+            Some(offset) => write!(f, "{} at offset {offset}", self.kind),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
@@ -0,0 +1,19 @@
+/// Invalid [`AgentConfig`](crate::prelude::AgentConfig) setting.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid setting {key:?}: {reason}")]
+pub struct ConfigError {
+    /// Name of the setting, as in configuration files.
+    pub key: &'static str,
+    /// Why the value is invalid.
+    pub reason: String,
+}
+
+impl ConfigError {
+    /// Create a [`ConfigError`] for setting `key`.
+    pub fn new(key: &'static str, reason: impl Into<String>) -> Self {
+        Self {
+            key,
+            reason: reason.into(),
+        }
+    }
+}
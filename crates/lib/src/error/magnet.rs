@@ -0,0 +1,12 @@
+/// Error in a magnet URI.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum MagnetError {
+    #[error("not a magnet URI")]
+    NotMagnet,
+    #[error("missing BitTorrent info hash (xt=urn:btih:)")]
+    MissingInfoHash,
+    #[error("invalid info hash {0:?}")]
+    InvalidInfoHash(String),
+    #[error("invalid encoding of parameter {0:?}")]
+    InvalidEncoding(String),
+}
@@ -0,0 +1,21 @@
+use super::BencodeError;
+
+/// Error in the contents of a torrent (metainfo) file.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum MetainfoError {
+    #[error("missing field {0:?}")]
+    MissingField(&'static str),
+    #[error("invalid field {field:?}: {source}")]
+    InvalidField {
+        field: &'static str,
+        source: BencodeError,
+    },
+    #[error("invalid UTF-8 in field {0:?}")]
+    InvalidUtf8(&'static str),
+    #[error("piece length must be positive")]
+    InvalidPieceLength,
+    #[error("piece hashes don't match total length")]
+    InvalidPieces,
+    #[error("unsafe file path {0:?}")]
+    UnsafePath(String),
+}
@@ -1,3 +1,21 @@
+mod agent;
+mod bencode;
+mod config;
+mod magnet;
+mod metainfo;
+mod peer;
+mod storage;
+mod tracker;
+
+pub use agent::AgentError;
+pub use bencode::{BencodeError, BencodeErrorKind};
+pub use config::ConfigError;
+pub use magnet::MagnetError;
+pub use metainfo::MetainfoError;
+pub use peer::PeerError;
+pub use storage::StorageError;
+pub use tracker::TrackerError;
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error(transparent)]
@@ -11,13 +29,21 @@ pub enum Error {
     #[error(transparent)]
     Reqwest(#[from] reqwest::Error),
     #[error("bencode error: {0}")]
-    Bencode(String),
-    #[error("torrent error: {0}")]
-    Torrent(String),
+    Bencode(#[from] BencodeError),
+    #[error("metainfo error: {0}")]
+    Metainfo(#[from] MetainfoError),
+    #[error("magnet error: {0}")]
+    Magnet(#[from] MagnetError),
     #[error("tracker error: {0}")]
-    Tracker(String),
+    Tracker(#[from] TrackerError),
+    #[error("peer error: {0}")]
+    Peer(#[from] PeerError),
     #[error("agent error: {0}")]
-    Agent(String),
+    Agent(#[from] AgentError),
+    #[error("config error: {0}")]
+    Config(#[from] ConfigError),
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
     #[error("unknown error")]
     Unknown,
 }
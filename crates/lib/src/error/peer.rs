@@ -0,0 +1,38 @@
+/// Error caused by a peer, or while talking to one.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum PeerError {
+    #[error("invalid port {0}")]
+    InvalidPort(i64),
+    #[error("invalid compact peer list of {0} bytes")]
+    InvalidCompact(usize),
+    #[error("invalid handshake")]
+    InvalidHandshake,
+    #[error("invalid encryption handshake")]
+    InvalidEncryption,
+    #[error("connection not allowed by encryption policy")]
+    EncryptionPolicy,
+    #[error("handshake for unknown torrent")]
+    UnknownTorrent,
+    #[error("connected to ourselves")]
+    SelfConnection,
+    #[error("peer was banned")]
+    Banned,
+    #[error("invalid message with id {0}")]
+    InvalidMessage(u8),
+    #[error("message of an extension that wasn't negotiated")]
+    NotNegotiated,
+    #[error("message of {0} bytes is too large")]
+    MessageTooLarge(usize),
+    #[error("invalid bitfield")]
+    InvalidBitfield,
+    #[error("invalid piece index {0}")]
+    InvalidPiece(u32),
+    #[error("invalid block request")]
+    InvalidRequest,
+    #[error("invalid metadata")]
+    InvalidMetadata,
+    #[error("peer rejected metadata request")]
+    MetadataRejected,
+    #[error("peer timed out")]
+    Timeout,
+}
@@ -0,0 +1,6 @@
+/// Error in storing the data of a torrent.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum StorageError {
+    #[error("not enough disk space: {needed} bytes needed, {available} available")]
+    NotEnoughSpace { needed: u64, available: u64 },
+}
@@ -0,0 +1,15 @@
+/// Error reported by, or while talking to, a tracker.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum TrackerError {
+    #[error("tracker failure: {reason}")]
+    Failure {
+        /// Human-readable reason given by the tracker.
+        reason: String,
+        /// Minutes to wait before retrying, if the tracker allows retrying (BEP 31).
+        retry_in: Option<u64>,
+    },
+    #[error("tracker {0} doesn't support scraping")]
+    ScrapeUnsupported(String),
+    #[error("tracker has no statistics for this torrent")]
+    NotScraped,
+}
@@ -1,14 +1,19 @@
 mod agent;
 mod bcode;
+mod dht;
 mod error;
 mod peer;
+mod storage;
 mod torrent;
+mod util;
 
 pub mod prelude {
     use super::*;
 
     pub use agent::*;
     pub use bcode::*;
+    pub use error::*;
     pub use peer::*;
+    pub use storage::*;
     pub use torrent::*;
 }
@@ -0,0 +1,240 @@
+//! Extension protocol (BEP 10): extensions added to an [`Agent`](crate::prelude::Agent)
+//! exchange their own messages with peers that announce the same extensions.
+
+use crate::error::{AgentError, Error};
+use crate::prelude::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use tokio::sync::mpsc;
+
+/// Extension of the peer protocol, announced to peers under its name.
+///
+/// Callbacks run on the task of a peer connection, so they shouldn't block. Work that waits
+/// can be spawned with a clone of the [`ExtensionPeer`].
+pub trait Extension: Send + Sync + 'static {
+    /// Get name the extension is announced under, like `ut_metadata`.
+    fn name(&self) -> &str;
+
+    /// Get entries to add to the extension handshake sent to peers of the torrent with
+    /// `info_hash`.
+    fn handshake(&self, _info_hash: &[u8; 20]) -> BTreeMap<String, Value> {
+        BTreeMap::new()
+    }
+
+    /// Called once `peer` announced the extension in its extension handshake.
+    fn on_peer_connected(&self, _peer: &ExtensionPeer) {}
+
+    /// Handle message `payload` of the extension from `peer`. An error closes the connection.
+    fn on_message(&self, peer: &ExtensionPeer, payload: &[u8]) -> Result<(), Error>;
+}
+
+/// Extension handshake, the payload of extended message 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedHandshake {
+    /// Message IDs of the extensions the sender supports, by name (`m`).
+    pub extensions: BTreeMap<String, u8>,
+    /// Client name and version (`v`).
+    pub client: Option<String>,
+    /// Number of outstanding requests the sender allows (`reqq`).
+    pub max_requests: Option<usize>,
+    /// Address of the receiver, as the sender sees it (`yourip`).
+    pub your_ip: Option<IpAddr>,
+    /// Port the sender listens on (`p`).
+    pub port: Option<u16>,
+    /// Other entries, added by extensions.
+    pub extra: BTreeMap<String, Value>,
+}
+
+impl ExtendedHandshake {
+    /// Encode handshake.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dictionary = self
+            .extra
+            .iter()
+            .map(|(key, value)| (ByteString::from(key.as_str()), value.clone()))
+            .collect::<BTreeMap<_, _>>();
+        let extensions = self
+            .extensions
+            .iter()
+            .map(|(name, id)| (name.as_str(), *id))
+            .collect::<BTreeMap<_, _>>();
+        dictionary.insert("m".into(), extensions.into());
+        if let Some(client) = &self.client {
+            dictionary.insert("v".into(), client.as_str().into());
+        }
+        if let Some(max_requests) = self.max_requests {
+            dictionary.insert("reqq".into(), Value::Integer(Integer(max_requests as i64)));
+        }
+        match self.your_ip {
+            Some(IpAddr::V4(ip)) => dictionary.insert("yourip".into(), (&ip.octets()).into()),
+            Some(IpAddr::V6(ip)) => dictionary.insert("yourip".into(), (&ip.octets()).into()),
+            None => None,
+        };
+        if let Some(port) = self.port {
+            dictionary.insert("p".into(), port.into());
+        }
+
+        encode(&Value::Dictionary(Dictionary(dictionary)))
+    }
+
+    /// Decode handshake, ignoring entries of the wrong type.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let mut dictionary = decode_with(bytes, DecodeOptions::network())?
+            .try_as::<Dictionary>()?
+            .0;
+        let mut take = |key: &str| dictionary.remove(key.as_bytes());
+
+        // An ID of 0 means the extension is disabled.
+        let extensions = take("m")
+            .and_then(|m| m.try_as::<Dictionary>().ok())
+            .map(|m| m.0)
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(name, id)| {
+                let name = String::from_utf8(name.0).ok()?;
+                let id = id.as_int().and_then(|id| u8::try_from(id).ok())?;
+                (id != 0).then_some((name, id))
+            })
+            .collect();
+        let client = take("v").and_then(|v| v.as_str().map(str::to_string));
+        let max_requests = take("reqq").and_then(|reqq| usize::try_from(reqq.as_int()?).ok());
+        let your_ip = take("yourip").and_then(|ip| match *ip.as_bytes()? {
+            [a, b, c, d] => Some(IpAddr::V4(Ipv4Addr::new(a, b, c, d))),
+            ref bytes => Some(IpAddr::V6(Ipv6Addr::from(
+                <[u8; 16]>::try_from(bytes).ok()?,
+            ))),
+        });
+        let port = take("p").and_then(|p| u16::try_from(p.as_int()?).ok());
+        let extra = dictionary
+            .into_iter()
+            .filter_map(|(key, value)| Some((String::from_utf8(key.0).ok()?, value)))
+            .collect();
+
+        Ok(Self {
+            extensions,
+            client,
+            max_requests,
+            your_ip,
+            port,
+            extra,
+        })
+    }
+}
+
+/// Handle to a peer that supports an extension, for that extension to send it messages.
+#[derive(Debug, Clone)]
+pub struct ExtensionPeer {
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    handshake: Arc<ExtendedHandshake>,
+    /// Index of the extension among those of the connection.
+    index: usize,
+    outbox: mpsc::UnboundedSender<(usize, Vec<u8>)>,
+}
+
+impl ExtensionPeer {
+    pub(crate) fn new(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        handshake: Arc<ExtendedHandshake>,
+        index: usize,
+        outbox: mpsc::UnboundedSender<(usize, Vec<u8>)>,
+    ) -> Self {
+        Self {
+            addr,
+            info_hash,
+            handshake,
+            index,
+            outbox,
+        }
+    }
+
+    /// Get address of the peer.
+    pub fn get_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Get info hash of the torrent the connection is for.
+    pub fn get_info_hash(&self) -> [u8; 20] {
+        self.info_hash
+    }
+
+    /// Get extension handshake of the peer.
+    pub fn get_handshake(&self) -> &ExtendedHandshake {
+        &self.handshake
+    }
+
+    /// Send message `payload` of the extension to the peer.
+    ///
+    /// Gets whether the connection is still open.
+    pub fn send(&self, payload: Vec<u8>) -> bool {
+        self.outbox.send((self.index, payload)).is_ok()
+    }
+
+    /// Check whether the connection is closed.
+    pub fn is_closed(&self) -> bool {
+        self.outbox.is_closed()
+    }
+}
+
+/// Extensions added to an agent, in order. Extension `i` gets message ID `i + 1`.
+#[derive(Default)]
+pub(crate) struct Extensions(RwLock<Vec<Arc<dyn Extension>>>);
+
+impl Extensions {
+    /// Add `extension`, unless one with the same name was added.
+    pub fn add(&self, extension: Arc<dyn Extension>) -> Result<(), AgentError> {
+        let mut extensions = self.0.write().unwrap_or_else(|e| e.into_inner());
+        if extensions.len() >= u8::MAX as usize {
+            return Err(AgentError::TooManyExtensions);
+        }
+        if extensions
+            .iter()
+            .any(|added| added.name() == extension.name())
+        {
+            return Err(AgentError::DuplicateExtension(extension.name().to_string()));
+        }
+        extensions.push(extension);
+
+        Ok(())
+    }
+
+    /// Get all extensions.
+    pub fn get(&self) -> Vec<Arc<dyn Extension>> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let extensions = self.get();
+        f.debug_list()
+            .entries(extensions.iter().map(|extension| extension.name()))
+            .finish()
+    }
+}
+
+#[test]
+fn test_extended_handshake_round_trip() {
+    let handshake = ExtendedHandshake {
+        extensions: BTreeMap::from([("ut_metadata".to_string(), 3)]),
+        client: Some("rip 0.0.0".to_string()),
+        max_requests: Some(250),
+        your_ip: Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))),
+        port: Some(6881),
+        extra: BTreeMap::from([("metadata_size".to_string(), 31235.into())]),
+    };
+    let bytes = handshake.to_bytes();
+    assert_eq!(ExtendedHandshake::from_bytes(&bytes).unwrap(), handshake);
+
+    let bytes = b"d1:md11:ut_metadatai0e6:ut_pexi1ee1:pi-1e4:reqq3:abce";
+    let handshake = ExtendedHandshake::from_bytes(bytes).unwrap();
+    assert_eq!(
+        handshake.extensions,
+        BTreeMap::from([("ut_pex".to_string(), 1)])
+    );
+    assert_eq!((handshake.port, handshake.max_requests), (None, None));
+    assert!(ExtendedHandshake::from_bytes(b"li1ee").is_err());
+}
@@ -0,0 +1,124 @@
+use rand::RngCore;
+use std::fmt;
+
+/// Azureus-style client codes, and the clients they belong to.
+const CLIENTS: [(&str, &str); 16] = [
+    ("RP", "rip"),
+    ("AZ", "Vuze"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("SD", "Thunder"),
+    ("TR", "Transmission"),
+    ("UM", "uTorrent for Mac"),
+    ("UT", "uTorrent"),
+    ("WW", "WebTorrent"),
+];
+
+/// Create a peer ID for this client, `-RPxyzz-` followed by random bytes.
+///
+/// The version digits are taken from the crate version, `x.y.zz`.
+pub fn new_peer_id() -> [u8; 20] {
+    let digit = |version: &str, modulo: u32| version.parse::<u32>().unwrap_or(0) % modulo;
+    let prefix = format!(
+        "-RP{}{}{:02}-",
+        digit(env!("CARGO_PKG_VERSION_MAJOR"), 10),
+        digit(env!("CARGO_PKG_VERSION_MINOR"), 10),
+        digit(env!("CARGO_PKG_VERSION_PATCH"), 100),
+    );
+
+    let mut peer_id = [0; 20];
+    peer_id[..8].copy_from_slice(prefix.as_bytes());
+    rand::thread_rng().fill_bytes(&mut peer_id[8..]);
+
+    peer_id
+}
+
+/// Client of a peer, as identified by the prefix of its peer ID.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientId {
+    /// Name of the client, or its code if it's unknown.
+    pub name: String,
+    /// Version of the client.
+    pub version: String,
+}
+
+impl ClientId {
+    /// Identify the client of `peer_id`, in the Azureus (`-TR2940-`) or Mainline (`M7-4-0--`) style.
+    pub fn parse(peer_id: &[u8]) -> Option<Self> {
+        let prefix = std::str::from_utf8(peer_id.get(..8)?).ok()?;
+
+        if prefix.starts_with('-') && prefix.ends_with('-') {
+            let (code, version) = prefix[1..7].split_at(2);
+            if !code.chars().all(|c| c.is_ascii_alphanumeric())
+                || !version.chars().all(|c| c.is_ascii_alphanumeric())
+            {
+                return None;
+            }
+            let name = CLIENTS
+                .iter()
+                .find(|(known, _)| *known == code)
+                .map_or(code, |(_, name)| name);
+            let version = version
+                .chars()
+                .map(|c| c.to_digit(36).unwrap_or(0).to_string())
+                .collect::<Vec<_>>()
+                .join(".");
+
+            return Some(Self {
+                name: name.to_string(),
+                version,
+            });
+        }
+
+        if let Some(rest) = prefix.strip_prefix('M') {
+            let version = rest.trim_end_matches('-').split('-').collect::<Vec<_>>();
+            if version.len() == 3 && version.iter().all(|v| v.parse::<u32>().is_ok()) {
+                return Some(Self {
+                    name: "Mainline".to_string(),
+                    version: version.join("."),
+                });
+            }
+        }
+
+        None
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.version)
+    }
+}
+
+#[test]
+fn test_peer_id() {
+    let first = new_peer_id();
+    let second = new_peer_id();
+    assert_eq!(&first[..3], b"-RP");
+    assert_eq!(first[..8], second[..8]);
+    assert_ne!(first[8..], second[8..]);
+
+    let client = ClientId::parse(&first).unwrap();
+    assert_eq!(client.name, "rip");
+
+    let client = ClientId::parse(b"-TR2940-abcdefghijkl").unwrap();
+    assert_eq!(client.to_string(), "Transmission 2.9.4.0");
+    let client = ClientId::parse(b"-qB4250-abcdefghijkl").unwrap();
+    assert_eq!(client.to_string(), "qBittorrent 4.2.5.0");
+    let client = ClientId::parse(b"-ZZ1000-abcdefghijkl").unwrap();
+    assert_eq!(client.name, "ZZ");
+    let client = ClientId::parse(b"M7-4-0--abcdefghijkl").unwrap();
+    assert_eq!(client.to_string(), "Mainline 7.4.0");
+    let client = ClientId::parse(b"M10-20-3-abcdefghijk").unwrap();
+    assert_eq!(client.to_string(), "Mainline 10.20.3");
+
+    assert_eq!(ClientId::parse(&[0; 20]), None);
+    assert_eq!(ClientId::parse(b"short"), None);
+}
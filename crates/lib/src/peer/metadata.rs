@@ -0,0 +1,352 @@
+//! Metadata exchange (BEP 9): sending the info dictionary of our torrents to peers, and
+//! fetching it from them for torrents added from a [`Magnet`] URI.
+
+use super::wire::{Handshake, Message};
+use super::{mse, ExtendedHandshake, Extension, ExtensionPeer, PeerStream};
+use crate::agent::EncryptionPolicy;
+use crate::error::{AgentError, Error, PeerError};
+use crate::prelude::*;
+use crate::torrent::engine::Context;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+/// Name the extension is announced under.
+const NAME: &str = "ut_metadata";
+/// Size of the pieces metadata is sent in, all but the last.
+const PIECE_SIZE: usize = 16 * 1024;
+/// Largest metadata accepted from peers.
+const MAX_SIZE: usize = 8 * 1024 * 1024;
+/// Largest message accepted while fetching, enough for a piece or a bitfield.
+const MAX_MESSAGE: usize = 256 * 1024;
+/// ID peers send us metadata under while fetching.
+const FETCH_ID: u8 = 1;
+/// Number of peers asked for metadata at the same time.
+const FETCH_PEERS: usize = 8;
+/// Time allowed for getting metadata from one peer, or peers from one tracker.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Message of the extension.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MetadataMessage {
+    /// Ask for a piece.
+    Request(u32),
+    /// Piece with its data, and the size of the whole metadata.
+    Data {
+        piece: u32,
+        total_size: usize,
+        data: Vec<u8>,
+    },
+    /// Refuse to send a piece.
+    Reject(u32),
+}
+
+impl MetadataMessage {
+    /// Encode message, a dictionary followed by the data of a piece.
+    fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Self::Request(piece) => encode(&bdict! { "msg_type" => 0, "piece" => *piece }),
+            Self::Data {
+                piece,
+                total_size,
+                data,
+            } => {
+                let total_size = Value::Integer(Integer(*total_size as i64));
+                let dictionary = bdict! {
+                    "msg_type" => 1,
+                    "piece" => *piece,
+                    "total_size" => total_size,
+                };
+                [encode(&dictionary), data.clone()].concat()
+            }
+            Self::Reject(piece) => encode(&bdict! { "msg_type" => 2, "piece" => *piece }),
+        }
+    }
+
+    /// Decode message.
+    fn from_bytes(payload: &[u8]) -> Result<Self, Error> {
+        let (dictionary, len) = decode_prefix(payload, DecodeOptions::network())?;
+        let dictionary = dictionary.try_as::<Dictionary>()?;
+        let int = |key: &str| dictionary.get(key).and_then(Value::as_int);
+        let piece = int("piece")
+            .and_then(|piece| u32::try_from(piece).ok())
+            .ok_or(PeerError::InvalidMetadata)?;
+
+        match int("msg_type") {
+            Some(0) => Ok(Self::Request(piece)),
+            Some(1) => Ok(Self::Data {
+                piece,
+                total_size: int("total_size")
+                    .and_then(|size| usize::try_from(size).ok())
+                    .ok_or(PeerError::InvalidMetadata)?,
+                data: payload[len..].to_vec(),
+            }),
+            Some(2) => Ok(Self::Reject(piece)),
+            _ => Err(PeerError::InvalidMetadata.into()),
+        }
+    }
+}
+
+/// Extension sending the metadata of the torrents of an agent to peers that ask for it.
+#[derive(Debug, Default)]
+pub(crate) struct UtMetadata {
+    /// Metadata of each torrent, by info hash.
+    torrents: RwLock<HashMap<[u8; 20], Arc<[u8]>>>,
+}
+
+impl UtMetadata {
+    /// Send `metadata` of the torrent with `info_hash` to peers.
+    pub fn insert(&self, info_hash: [u8; 20], metadata: &[u8]) {
+        let mut torrents = self.torrents.write().unwrap_or_else(|e| e.into_inner());
+        torrents.insert(info_hash, metadata.into());
+    }
+
+    /// Stop sending metadata of the torrent with `info_hash`.
+    pub fn remove(&self, info_hash: &[u8; 20]) {
+        let mut torrents = self.torrents.write().unwrap_or_else(|e| e.into_inner());
+        torrents.remove(info_hash);
+    }
+
+    /// Get metadata of the torrent with `info_hash`.
+    fn get(&self, info_hash: &[u8; 20]) -> Option<Arc<[u8]>> {
+        let torrents = self.torrents.read().unwrap_or_else(|e| e.into_inner());
+        torrents.get(info_hash).cloned()
+    }
+}
+
+impl Extension for UtMetadata {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    fn handshake(&self, info_hash: &[u8; 20]) -> BTreeMap<String, Value> {
+        let size = self.get(info_hash).map(|metadata| metadata.len() as i64);
+
+        size.map(|size| ("metadata_size".to_string(), Value::Integer(Integer(size))))
+            .into_iter()
+            .collect()
+    }
+
+    /// Answer requests for pieces. Pieces that peers send are ignored, as metadata is
+    /// fetched on connections of its own.
+    fn on_message(&self, peer: &ExtensionPeer, payload: &[u8]) -> Result<(), Error> {
+        let MetadataMessage::Request(piece) = MetadataMessage::from_bytes(payload)? else {
+            return Ok(());
+        };
+
+        let metadata = self
+            .get(&peer.get_info_hash())
+            .unwrap_or_else(|| Arc::new([]));
+        let reply = match metadata.chunks(PIECE_SIZE).nth(piece as usize) {
+            Some(data) => MetadataMessage::Data {
+                piece,
+                total_size: metadata.len(),
+                data: data.to_vec(),
+            },
+            None => MetadataMessage::Reject(piece),
+        };
+        peer.send(reply.to_bytes());
+
+        Ok(())
+    }
+}
+
+/// Fetch metadata of the torrent of `magnet` from peers its trackers and the DHT return and
+/// the peers it names, as an agent with `context`. The metadata is checked against the info hash.
+pub(crate) async fn fetch(context: Arc<Context>, magnet: &Magnet) -> Result<Vec<u8>, Error> {
+    let mut addrs = magnet.peers.clone();
+    for url in &magnet.trackers {
+        let request = TrackerRequest {
+            announce: url.clone(),
+            info_hash: magnet.info_hash.to_vec(),
+            peer_id: context.peer_id,
+            ip: None,
+            ipv4: None,
+            ipv6: None,
+            port: context.port,
+            uploaded: 0,
+            downloaded: 0,
+            // Unknown until the metadata arrives, but not 0, so we aren't taken for a seed.
+            left: PIECE_SIZE as u64,
+            event: None,
+        };
+        if let Ok(Ok(response)) = timeout(FETCH_TIMEOUT, request.send_with(&context.http)).await {
+            addrs.extend(response.peers.iter().filter_map(Peer::addr));
+        }
+    }
+    if let Some(dht) = &context.dht {
+        addrs.extend(dht.get_peers(magnet.info_hash, None).await);
+    }
+
+    let mut seen = HashSet::new();
+    let mut addrs = addrs
+        .into_iter()
+        .map(crate::util::canonical)
+        .filter(|addr| seen.insert(*addr) && !context.is_banned(addr.ip()));
+    let mut tasks = JoinSet::new();
+    loop {
+        while tasks.len() < FETCH_PEERS {
+            let Some(addr) = addrs.next() else {
+                break;
+            };
+            let fetching = fetch_from(Arc::clone(&context), addr, magnet.info_hash);
+            tasks.spawn(timeout(FETCH_TIMEOUT, fetching));
+        }
+
+        match tasks.join_next().await {
+            Some(Ok(Ok(Ok(metadata)))) => return Ok(metadata),
+            Some(_) => {}
+            None => return Err(AgentError::MetadataNotFound.into()),
+        }
+    }
+}
+
+/// Fetch metadata of the torrent with `info_hash` from peer at `addr`, encrypting the
+/// connection as the encryption policy says.
+async fn fetch_from(
+    context: Arc<Context>,
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+) -> Result<Vec<u8>, Error> {
+    let mut stream = match context.config.encryption {
+        EncryptionPolicy::Disabled => open(&context, addr, info_hash, None).await?,
+        EncryptionPolicy::Enabled => {
+            let encrypt = Some(mse::RC4 | mse::PLAINTEXT);
+            match open(&context, addr, info_hash, encrypt).await {
+                Ok(stream) => stream,
+                Err(_) => open(&context, addr, info_hash, None).await?,
+            }
+        }
+        EncryptionPolicy::Forced => open(&context, addr, info_hash, Some(mse::RC4)).await?,
+    };
+
+    let handshake = ExtendedHandshake {
+        extensions: BTreeMap::from([(NAME.to_string(), FETCH_ID)]),
+        client: Some(format!("rip {}", env!("CARGO_PKG_VERSION"))),
+        max_requests: None,
+        your_ip: Some(addr.ip()),
+        port: Some(context.port),
+        extra: BTreeMap::new(),
+    };
+    let payload = handshake.to_bytes();
+    Message::Extended { id: 0, payload }
+        .write(&mut stream)
+        .await?;
+
+    let mut metadata = Vec::new();
+    let mut missing = HashSet::new();
+    loop {
+        match Message::read(&mut stream, MAX_MESSAGE).await? {
+            Message::Extended { id: 0, payload } if metadata.is_empty() => {
+                let remote = ExtendedHandshake::from_bytes(&payload)?;
+                let id = *remote
+                    .extensions
+                    .get(NAME)
+                    .ok_or(PeerError::NotNegotiated)?;
+                let size = remote.extra.get("metadata_size").and_then(Value::as_int);
+                let size = size
+                    .and_then(|size| usize::try_from(size).ok())
+                    .filter(|size| (1..=MAX_SIZE).contains(size))
+                    .ok_or(PeerError::InvalidMetadata)?;
+
+                metadata = vec![0; size];
+                missing = (0..(size + PIECE_SIZE - 1) / PIECE_SIZE).collect();
+                for piece in &missing {
+                    let payload = MetadataMessage::Request(*piece as u32).to_bytes();
+                    Message::Extended { id, payload }.write(&mut stream).await?;
+                }
+            }
+            Message::Extended {
+                id: FETCH_ID,
+                payload,
+            } if !metadata.is_empty() => match MetadataMessage::from_bytes(&payload)? {
+                MetadataMessage::Data {
+                    piece,
+                    total_size,
+                    data,
+                } => {
+                    let start = piece as usize * PIECE_SIZE;
+                    let length = metadata.len().saturating_sub(start).min(PIECE_SIZE);
+                    if total_size != metadata.len() || length == 0 || data.len() != length {
+                        return Err(PeerError::InvalidMetadata.into());
+                    }
+                    metadata[start..start + length].copy_from_slice(&data);
+                    missing.remove(&(piece as usize));
+                    if missing.is_empty() {
+                        break;
+                    }
+                }
+                MetadataMessage::Reject(_) => return Err(PeerError::MetadataRejected.into()),
+                // We have no metadata to send, and didn't announce its size.
+                MetadataMessage::Request(_) => {}
+            },
+            _ => {}
+        }
+    }
+
+    if sha1_smol::Sha1::from(&metadata).digest().bytes() != info_hash {
+        return Err(PeerError::InvalidMetadata.into());
+    }
+
+    Ok(metadata)
+}
+
+/// Connect to peer at `addr` and exchange handshakes for the torrent with `info_hash`,
+/// after an encryption handshake offering the methods in `encrypt` if set.
+async fn open(
+    context: &Context,
+    addr: SocketAddr,
+    info_hash: [u8; 20],
+    encrypt: Option<u32>,
+) -> Result<PeerStream, Error> {
+    let mut stream = PeerStream::tcp(TcpStream::connect(addr).await?);
+    if let Some(provide) = encrypt {
+        mse::initiate(&mut stream, info_hash, provide).await?;
+    }
+    let handshake = Handshake::new(info_hash, context.peer_id).with_extension_protocol();
+    match context.dht {
+        Some(_) => handshake.with_dht(),
+        None => handshake,
+    }
+    .write(&mut stream)
+    .await?;
+
+    let handshake = Handshake::read(&mut stream).await?;
+    if handshake.info_hash != info_hash {
+        return Err(PeerError::UnknownTorrent.into());
+    }
+    if handshake.peer_id == context.peer_id {
+        return Err(PeerError::SelfConnection.into());
+    }
+    if !handshake.has_extension_protocol() {
+        return Err(PeerError::NotNegotiated.into());
+    }
+
+    Ok(stream)
+}
+
+#[test]
+fn test_metadata_message_round_trip() {
+    let data = MetadataMessage::Data {
+        piece: 1,
+        total_size: 20_000,
+        data: b"d4:name".to_vec(),
+    };
+    for message in [
+        MetadataMessage::Request(3),
+        MetadataMessage::Reject(0),
+        data,
+    ] {
+        let bytes = message.to_bytes();
+        assert_eq!(MetadataMessage::from_bytes(&bytes).unwrap(), message);
+    }
+
+    let bytes = b"d8:msg_typei0ee";
+    assert!(MetadataMessage::from_bytes(bytes).is_err());
+    let bytes = b"d8:msg_typei7e5:piecei0ee";
+    assert!(MetadataMessage::from_bytes(bytes).is_err());
+}
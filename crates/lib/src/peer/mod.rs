@@ -1,15 +1,33 @@
-use crate::error::Error;
+mod extension;
+mod id;
+pub(crate) mod metadata;
+pub(crate) mod mse;
+mod pex;
+mod stream;
+mod utp;
+pub mod wire;
+
+pub(crate) use extension::Extensions;
+pub use extension::{ExtendedHandshake, Extension, ExtensionPeer};
+pub use id::{new_peer_id, ClientId};
+pub(crate) use metadata::UtMetadata;
+pub(crate) use pex::UtPex;
+pub(crate) use stream::{PeerStream, PeerWriter};
+pub(crate) use utp::{UtpSocket, UtpStream};
+
+use crate::error::{Error, PeerError};
 use crate::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 /// Torrent peer.
 #[derive(Debug)]
 pub struct Peer {
     /// Peer ID.
-    id: Vec<u8>,
+    pub id: Vec<u8>,
     /// IP address.
-    ip: Vec<u8>,
+    pub ip: Vec<u8>,
     /// IP port.
-    port: u16,
+    pub port: u16,
 }
 
 impl Peer {
@@ -17,8 +35,53 @@ impl Peer {
     pub fn from_dictionary(dictionary: &Dictionary) -> Result<Self, Error> {
         let id = dictionary.try_get_as::<ByteString>("peer id")?.0;
         let ip = dictionary.try_get_as::<ByteString>("ip")?.0;
-        let port = dictionary.try_get_as::<Integer>("port")?.0 as u16;
+        let port = dictionary.try_get_as::<Integer>("port")?;
+        let port = port.try_to().map_err(|_| PeerError::InvalidPort(port.0))?;
 
         Ok(Self { id, ip, port })
     }
+
+    /// Create a list of [`Peer`]s from the compact format, 6 bytes per peer.
+    pub fn from_compact(bytes: &[u8]) -> Result<Vec<Self>, Error> {
+        if bytes.len() % 6 != 0 {
+            return Err(PeerError::InvalidCompact(bytes.len()).into());
+        }
+
+        Ok(bytes
+            .chunks_exact(6)
+            .map(|chunk| Self {
+                id: Vec::new(),
+                ip: Ipv4Addr::new(chunk[0], chunk[1], chunk[2], chunk[3])
+                    .to_string()
+                    .into_bytes(),
+                port: u16::from_be_bytes([chunk[4], chunk[5]]),
+            })
+            .collect())
+    }
+
+    /// Create a list of IPv6 [`Peer`]s from the compact format of `peers6` (BEP 7),
+    /// 18 bytes per peer.
+    pub fn from_compact6(bytes: &[u8]) -> Result<Vec<Self>, Error> {
+        if bytes.len() % 18 != 0 {
+            return Err(PeerError::InvalidCompact(bytes.len()).into());
+        }
+
+        Ok(bytes
+            .chunks_exact(18)
+            .map(|chunk| Self {
+                id: Vec::new(),
+                ip: Ipv6Addr::from(<[u8; 16]>::try_from(&chunk[..16]).unwrap())
+                    .to_string()
+                    .into_bytes(),
+                port: u16::from_be_bytes([chunk[16], chunk[17]]),
+            })
+            .collect())
+    }
+
+    /// Get socket address, if `ip` is a valid IP address.
+    pub fn addr(&self) -> Option<SocketAddr> {
+        let ip = std::str::from_utf8(&self.ip).ok()?.parse::<IpAddr>().ok()?;
+
+        Some(SocketAddr::new(ip, self.port))
+    }
 }
@@ -0,0 +1,314 @@
+//! Message Stream Encryption: a Diffie-Hellman key exchange, after which the connection is
+//! obfuscated with RC4, or continues in plaintext if both sides agree to it.
+
+use super::stream::PeerStream;
+use crate::error::{Error, PeerError};
+use num_bigint::BigUint;
+use rand::{Rng, RngCore};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Prime of the key exchange, 768 bits.
+const PRIME: &[u8] = b"FFFFFFFFFFFFFFFFC90FDAA22168C234C4C6628B80DC1CD129024E088A67CC74\
+    020BBEA63B139B22514A08798E3404DDEF9519B3CD3A431B302B0A6DF25F1437\
+    4FE1356D6D51C245E485B576625E7EC6F44C42E9A63A36210000000000090563";
+/// Size of public keys and the shared secret.
+const KEY_SIZE: usize = 96;
+/// Maximum length of the random padding after a public key, or within the handshake.
+const MAX_PAD: usize = 512;
+/// Verification constant, sent encrypted so the other side can find the start of its stream.
+const VC: [u8; 8] = [0; 8];
+/// Number of keystream bytes discarded before use.
+const DISCARD: usize = 1024;
+
+/// Methods of obfuscating the connection after the handshake, as bits of `crypto_provide`
+/// and `crypto_select`.
+pub(crate) const PLAINTEXT: u32 = 0x01;
+pub(crate) const RC4: u32 = 0x02;
+
+/// RC4 stream cipher.
+#[derive(Clone)]
+pub(crate) struct Rc4 {
+    state: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    /// Create [`Rc4`] with `key`, with the start of its keystream discarded.
+    pub fn new(key: &[u8]) -> Self {
+        let mut cipher = Self::with_key(key);
+        cipher.apply(&mut [0; DISCARD]);
+
+        cipher
+    }
+
+    /// Create [`Rc4`] with `key`, keeping the whole keystream.
+    fn with_key(key: &[u8]) -> Self {
+        let mut state = [0; 256];
+        for (i, value) in state.iter_mut().enumerate() {
+            *value = i as u8;
+        }
+        let mut j = 0u8;
+        for i in 0..256 {
+            j = j.wrapping_add(state[i]).wrapping_add(key[i % key.len()]);
+            state.swap(i, j as usize);
+        }
+
+        Self { state, i: 0, j: 0 }
+    }
+
+    /// Encrypt or decrypt `data` in place.
+    pub fn apply(&mut self, data: &mut [u8]) {
+        for byte in data {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.state[self.i as usize]);
+            self.state.swap(self.i as usize, self.j as usize);
+            let index = self.state[self.i as usize].wrapping_add(self.state[self.j as usize]);
+            *byte ^= self.state[index as usize];
+        }
+    }
+}
+
+impl std::fmt::Debug for Rc4 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Rc4")
+    }
+}
+
+/// Diffie-Hellman key pair.
+struct KeyPair {
+    private: BigUint,
+    public: [u8; KEY_SIZE],
+}
+
+impl KeyPair {
+    /// Create a random [`KeyPair`].
+    fn new() -> Self {
+        let mut private = [0; 20];
+        rand::thread_rng().fill_bytes(&mut private);
+        let private = BigUint::from_bytes_be(&private);
+        let public = BigUint::from(2u32).modpow(&private, &prime());
+
+        Self {
+            private,
+            public: to_key(&public),
+        }
+    }
+
+    /// Get secret shared with the owner of `public`.
+    fn secret(&self, public: &[u8]) -> [u8; KEY_SIZE] {
+        let public = BigUint::from_bytes_be(public);
+
+        to_key(&public.modpow(&self.private, &prime()))
+    }
+}
+
+fn prime() -> BigUint {
+    BigUint::parse_bytes(PRIME, 16).expect("valid prime")
+}
+
+/// Encode `value` as a key of [`KEY_SIZE`] bytes, big-endian.
+fn to_key(value: &BigUint) -> [u8; KEY_SIZE] {
+    let bytes = value.to_bytes_be();
+    let mut key = [0; KEY_SIZE];
+    key[KEY_SIZE - bytes.len()..].copy_from_slice(&bytes);
+
+    key
+}
+
+/// SHA1 hash of `parts` joined.
+fn hash(parts: &[&[u8]]) -> [u8; 20] {
+    let mut hasher = sha1_smol::Sha1::new();
+    for part in parts {
+        hasher.update(part);
+    }
+
+    hasher.digest().bytes()
+}
+
+fn xor(a: [u8; 20], b: [u8; 20]) -> [u8; 20] {
+    let mut out = a;
+    for (out, b) in out.iter_mut().zip(b) {
+        *out ^= b;
+    }
+
+    out
+}
+
+/// Create random padding of up to [`MAX_PAD`] bytes.
+fn padding() -> Vec<u8> {
+    let mut pad = vec![0; rand::thread_rng().gen_range(0..=MAX_PAD)];
+    rand::thread_rng().fill_bytes(&mut pad);
+
+    pad
+}
+
+/// Read from `stream` until `pattern` was read, skipping at most `max_skip` bytes before it.
+async fn sync(stream: &mut PeerStream, pattern: &[u8], max_skip: usize) -> Result<(), Error> {
+    let mut window = Vec::new();
+    loop {
+        if let Some(position) = window.windows(pattern.len()).position(|w| w == pattern) {
+            stream.unread(&window[position + pattern.len()..]);
+            return Ok(());
+        }
+        if window.len() >= max_skip + pattern.len() {
+            return Err(PeerError::InvalidEncryption.into());
+        }
+
+        let mut chunk = [0; 256];
+        let len = stream.read(&mut chunk).await?;
+        if len == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+        window.extend_from_slice(&chunk[..len]);
+    }
+}
+
+/// Read `len` bytes from `stream`, decrypting them with `cipher`.
+async fn read_encrypted(
+    stream: &mut PeerStream,
+    cipher: &mut Rc4,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data).await?;
+    cipher.apply(&mut data);
+
+    Ok(data)
+}
+
+/// Read data preceded by its length as a big-endian `u16` from `stream`, decrypting both
+/// with `cipher`.
+async fn read_sized(
+    stream: &mut PeerStream,
+    cipher: &mut Rc4,
+    max_len: usize,
+) -> Result<Vec<u8>, Error> {
+    let len = read_encrypted(stream, cipher, 2).await?;
+    let len = u16::from_be_bytes([len[0], len[1]]) as usize;
+    if len > max_len {
+        return Err(PeerError::InvalidEncryption.into());
+    }
+
+    read_encrypted(stream, cipher, len).await
+}
+
+/// Encrypt the connection to a peer we connected to, for the torrent with `info_hash`,
+/// offering the methods in `provide`.
+pub(crate) async fn initiate(
+    stream: &mut PeerStream,
+    info_hash: [u8; 20],
+    provide: u32,
+) -> Result<(), Error> {
+    let keys = KeyPair::new();
+    stream
+        .write_all(&[&keys.public[..], &padding()].concat())
+        .await?;
+    stream.flush().await?;
+
+    let mut public = [0; KEY_SIZE];
+    stream.read_exact(&mut public).await?;
+    let secret = keys.secret(&public);
+    let mut encrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut decrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    let mut request = [&VC[..], &provide.to_be_bytes(), &[0, 0], &[0, 0]].concat();
+    encrypt.apply(&mut request);
+    let skey = xor(hash(&[b"req2", &info_hash]), hash(&[b"req3", &secret]));
+    stream
+        .write_all(&[&hash(&[b"req1", &secret])[..], &skey, &request].concat())
+        .await?;
+    stream.flush().await?;
+
+    // The answer starts after padding of unknown length, found by its encrypted VC.
+    let mut vc = VC;
+    decrypt.apply(&mut vc);
+    sync(stream, &vc, MAX_PAD).await?;
+    let select = read_encrypted(stream, &mut decrypt, 4).await?;
+    let select = u32::from_be_bytes([select[0], select[1], select[2], select[3]]);
+    read_sized(stream, &mut decrypt, MAX_PAD).await?;
+
+    match select {
+        RC4 if provide & RC4 != 0 => stream.set_ciphers(encrypt, decrypt),
+        PLAINTEXT if provide & PLAINTEXT != 0 => {}
+        _ => return Err(PeerError::InvalidEncryption.into()),
+    }
+
+    Ok(())
+}
+
+/// Accept an encrypted connection from a peer, for one of the torrents with `info_hashes`,
+/// choosing from the methods it offers one of those in `accept`, RC4 if possible.
+///
+/// Gets the info hash of the torrent the peer asked for.
+pub(crate) async fn respond(
+    stream: &mut PeerStream,
+    info_hashes: &[[u8; 20]],
+    accept: u32,
+) -> Result<[u8; 20], Error> {
+    let mut public = [0; KEY_SIZE];
+    stream.read_exact(&mut public).await?;
+    let keys = KeyPair::new();
+    stream
+        .write_all(&[&keys.public[..], &padding()].concat())
+        .await?;
+    stream.flush().await?;
+
+    let secret = keys.secret(&public);
+    sync(stream, &hash(&[b"req1", &secret]), MAX_PAD).await?;
+    let mut skey = [0; 20];
+    stream.read_exact(&mut skey).await?;
+    let skey = xor(skey, hash(&[b"req3", &secret]));
+    let info_hash = *info_hashes
+        .iter()
+        .find(|info_hash| hash(&[b"req2", &info_hash[..]]) == skey)
+        .ok_or(PeerError::UnknownTorrent)?;
+    let mut decrypt = Rc4::new(&hash(&[b"keyA", &secret, &info_hash]));
+    let mut encrypt = Rc4::new(&hash(&[b"keyB", &secret, &info_hash]));
+
+    if read_encrypted(stream, &mut decrypt, VC.len()).await? != VC {
+        return Err(PeerError::InvalidEncryption.into());
+    }
+    let provide = read_encrypted(stream, &mut decrypt, 4).await?;
+    let provide = u32::from_be_bytes([provide[0], provide[1], provide[2], provide[3]]);
+    read_sized(stream, &mut decrypt, MAX_PAD).await?;
+    let initial = read_sized(stream, &mut decrypt, u16::MAX as usize).await?;
+
+    let select = match provide & accept {
+        methods if methods & RC4 != 0 => RC4,
+        methods if methods & PLAINTEXT != 0 => PLAINTEXT,
+        _ => return Err(PeerError::EncryptionPolicy.into()),
+    };
+    let mut answer = [&VC[..], &select.to_be_bytes(), &[0, 0]].concat();
+    encrypt.apply(&mut answer);
+    stream.write_all(&answer).await?;
+    stream.flush().await?;
+
+    if select == RC4 {
+        stream.set_ciphers(encrypt, decrypt);
+    }
+    stream.unread(&initial);
+
+    Ok(info_hash)
+}
+
+#[test]
+fn test_rc4() {
+    let mut data = *b"Plaintext";
+    Rc4::with_key(b"Key").apply(&mut data);
+    assert_eq!(data, [0xbb, 0xf3, 0x16, 0xe8, 0xd9, 0x40, 0xaf, 0x0a, 0xd3]);
+
+    let mut data = *b"secret data";
+    Rc4::new(b"key").apply(&mut data);
+    assert_ne!(&data, b"secret data");
+    Rc4::new(b"key").apply(&mut data);
+    assert_eq!(&data, b"secret data");
+}
+
+#[test]
+fn test_key_exchange() {
+    let a = KeyPair::new();
+    let b = KeyPair::new();
+    assert_eq!(a.secret(&b.public), b.secret(&a.public));
+    assert_ne!(a.public, b.public);
+}
@@ -0,0 +1,243 @@
+//! Peer exchange (BEP 11): telling peers of a torrent which other peers we're connected to,
+//! over IPv4 and IPv6, and connecting to the peers they tell us about.
+
+use super::{Extension, ExtensionPeer, Peer};
+use crate::error::Error;
+use crate::prelude::*;
+use crate::torrent::engine::EngineCommand;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+/// Name the extension is announced under.
+const NAME: &str = "ut_pex";
+/// Interval between messages to a peer, the shortest BEP 11 allows.
+const INTERVAL: Duration = Duration::from_secs(60);
+/// Most peers added or dropped in one message.
+const MAX_PEERS: usize = 50;
+
+/// Message of the extension: peers connected and disconnected since the previous one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct PexMessage {
+    added: Vec<SocketAddr>,
+    dropped: Vec<SocketAddr>,
+}
+
+impl PexMessage {
+    /// Get message taking `sent`, the peers the receiver knows from us, to `current`, with
+    /// up to [`MAX_PEERS`] of each change.
+    fn between(sent: &HashSet<SocketAddr>, current: &HashSet<SocketAddr>) -> Self {
+        Self {
+            added: current.difference(sent).copied().take(MAX_PEERS).collect(),
+            dropped: sent.difference(current).copied().take(MAX_PEERS).collect(),
+        }
+    }
+
+    /// Check whether the message has no changes, so it needn't be sent.
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.dropped.is_empty()
+    }
+
+    /// Encode message, with IPv4 and IPv6 peers in separate compact lists. Flags of added
+    /// peers are left unset.
+    fn to_bytes(&self) -> Vec<u8> {
+        let (added, added6) = compact(&self.added);
+        let (dropped, dropped6) = compact(&self.dropped);
+        let dictionary = BTreeMap::from([
+            ("added.f", vec![0; added.len() / 6]),
+            ("added6.f", vec![0; added6.len() / 18]),
+            ("added", added),
+            ("added6", added6),
+            ("dropped", dropped),
+            ("dropped6", dropped6),
+        ]);
+
+        encode(&dictionary.into())
+    }
+
+    /// Decode message, skipping peers that aren't valid addresses.
+    fn from_bytes(payload: &[u8]) -> Result<Self, Error> {
+        let dictionary = decode_with(payload, DecodeOptions::network())?.try_as::<Dictionary>()?;
+        let peers = |key: &str, key6: &str| -> Result<Vec<SocketAddr>, Error> {
+            let bytes = |key: &str| dictionary.get(key).and_then(Value::as_bytes);
+            let mut peers = Peer::from_compact(bytes(key).unwrap_or_default())?;
+            peers.extend(Peer::from_compact6(bytes(key6).unwrap_or_default())?);
+            Ok(peers.iter().filter_map(Peer::addr).collect())
+        };
+
+        Ok(Self {
+            added: peers("added", "added6")?,
+            dropped: peers("dropped", "dropped6")?,
+        })
+    }
+}
+
+/// Encode `addrs` in the compact formats of IPv4 and IPv6 peers.
+fn compact(addrs: &[SocketAddr]) -> (Vec<u8>, Vec<u8>) {
+    let (mut v4, mut v6) = (Vec::new(), Vec::new());
+    for addr in addrs {
+        match addr {
+            SocketAddr::V4(addr) => {
+                v4.extend(addr.ip().octets());
+                v4.extend(addr.port().to_be_bytes());
+            }
+            SocketAddr::V6(addr) => {
+                v6.extend(addr.ip().octets());
+                v6.extend(addr.port().to_be_bytes());
+            }
+        }
+    }
+
+    (v4, v6)
+}
+
+/// Peer exchange of a running torrent.
+struct Swarm {
+    /// Engine of the torrent, told about the peers we hear of.
+    commands: mpsc::UnboundedSender<EngineCommand>,
+    /// Addresses the connected peers listen on, as far as they're known.
+    peers: HashSet<SocketAddr>,
+}
+
+/// Extension exchanging peers of the running torrents of an agent with their peers.
+///
+/// Private torrents (BEP 27) are never registered, so their peers are kept to themselves.
+#[derive(Default)]
+pub(crate) struct UtPex {
+    /// Running torrents, by info hash.
+    torrents: Arc<RwLock<HashMap<[u8; 20], Swarm>>>,
+}
+
+impl UtPex {
+    /// Exchange peers of the torrent with `info_hash`, sending the ones we hear of to its
+    /// engine through `commands`.
+    pub fn start(&self, info_hash: [u8; 20], commands: mpsc::UnboundedSender<EngineCommand>) {
+        let mut torrents = self.torrents.write().unwrap_or_else(|e| e.into_inner());
+        let peers = HashSet::new();
+        torrents.insert(info_hash, Swarm { commands, peers });
+    }
+
+    /// Stop exchanging peers of the torrent with `info_hash`.
+    pub fn stop(&self, info_hash: &[u8; 20]) {
+        let mut torrents = self.torrents.write().unwrap_or_else(|e| e.into_inner());
+        torrents.remove(info_hash);
+    }
+
+    /// Take note that a peer listening on `addr` connected to the torrent with `info_hash`.
+    pub fn connected(&self, info_hash: &[u8; 20], addr: SocketAddr) {
+        let mut torrents = self.torrents.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(swarm) = torrents.get_mut(info_hash) {
+            swarm.peers.insert(addr);
+        }
+    }
+
+    /// Take note that the peer listening on `addr` left the torrent with `info_hash`.
+    pub fn disconnected(&self, info_hash: &[u8; 20], addr: SocketAddr) {
+        let mut torrents = self.torrents.write().unwrap_or_else(|e| e.into_inner());
+        if let Some(swarm) = torrents.get_mut(info_hash) {
+            swarm.peers.remove(&addr);
+        }
+    }
+}
+
+/// Get addresses of the peers connected to the torrent with `info_hash` in `torrents`.
+fn peers(torrents: &RwLock<HashMap<[u8; 20], Swarm>>, info_hash: &[u8; 20]) -> HashSet<SocketAddr> {
+    let torrents = torrents.read().unwrap_or_else(|e| e.into_inner());
+    let swarm = torrents.get(info_hash);
+
+    swarm.map(|swarm| swarm.peers.clone()).unwrap_or_default()
+}
+
+impl Extension for UtPex {
+    fn name(&self) -> &str {
+        NAME
+    }
+
+    /// Send the peer the other connected peers straight away, then the changes once every
+    /// [`INTERVAL`], until the connection closes.
+    fn on_peer_connected(&self, peer: &ExtensionPeer) {
+        let torrents = Arc::clone(&self.torrents);
+        let peer = peer.clone();
+        let info_hash = peer.get_info_hash();
+        let listen = peer
+            .get_handshake()
+            .port
+            .map(|port| SocketAddr::new(peer.get_addr().ip(), port));
+
+        tokio::spawn(async move {
+            let mut sent = HashSet::new();
+            let mut interval = tokio::time::interval(INTERVAL);
+            while !peer.is_closed() {
+                interval.tick().await;
+                let mut current = peers(&torrents, &info_hash);
+                current.remove(&peer.get_addr());
+                current.retain(|addr| Some(*addr) != listen);
+
+                let message = PexMessage::between(&sent, &current);
+                if message.is_empty() {
+                    continue;
+                }
+                sent.extend(&message.added);
+                for addr in &message.dropped {
+                    sent.remove(addr);
+                }
+                if !peer.send(message.to_bytes()) {
+                    return;
+                }
+            }
+        });
+    }
+
+    /// Pass the peers that were added on to the engine, which connects to them as it sees fit.
+    fn on_message(&self, peer: &ExtensionPeer, payload: &[u8]) -> Result<(), Error> {
+        let message = PexMessage::from_bytes(payload)?;
+
+        let torrents = self.torrents.read().unwrap_or_else(|e| e.into_inner());
+        if let Some(swarm) = torrents.get(&peer.get_info_hash()) {
+            for addr in message.added.into_iter().take(MAX_PEERS) {
+                let addr = crate::util::canonical(addr);
+                let _ = swarm.commands.send(EngineCommand::Connect(addr));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[test]
+fn test_pex_message_round_trip() {
+    let message = PexMessage {
+        added: vec![
+            "10.0.0.2:6881".parse().unwrap(),
+            "[2001:db8::1]:6882".parse().unwrap(),
+        ],
+        dropped: vec!["[2001:db8::2]:6883".parse().unwrap()],
+    };
+    let bytes = message.to_bytes();
+    assert_eq!(PexMessage::from_bytes(&bytes).unwrap(), message);
+
+    let dictionary = decode(&bytes).unwrap().try_as::<Dictionary>().unwrap();
+    assert_eq!(
+        dictionary.get("added.f").and_then(Value::as_bytes),
+        Some(&[0][..])
+    );
+    assert_eq!(
+        dictionary
+            .get("added6")
+            .and_then(Value::as_bytes)
+            .map(<[u8]>::len),
+        Some(18)
+    );
+
+    let sent = HashSet::from([message.added[0], message.dropped[0]]);
+    let current = HashSet::from([message.added[0], message.added[1]]);
+    let changes = PexMessage::between(&sent, &current);
+    assert_eq!(changes.added, [message.added[1]]);
+    assert_eq!(changes.dropped, message.dropped);
+    assert!(PexMessage::between(&current, &current).is_empty());
+
+    assert!(PexMessage::from_bytes(b"d5:added5:abcdee").is_err());
+    assert!(PexMessage::from_bytes(b"de").unwrap().is_empty());
+}
@@ -0,0 +1,218 @@
+use super::mse::Rc4;
+use super::utp::UtpStream;
+use crate::agent::Transport;
+use std::fmt;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpStream;
+
+/// Connection to a peer over TCP or uTP, either plain or encrypted with RC4 after an
+/// MSE handshake.
+#[derive(Debug)]
+pub(crate) struct PeerStream {
+    reader: PeerReader,
+    writer: PeerWriter,
+    transport: Transport,
+}
+
+/// Reading half of a [`PeerStream`].
+pub(crate) struct PeerReader {
+    inner: Box<dyn AsyncRead + Send + Unpin>,
+    cipher: Option<Rc4>,
+    /// Data that was read ahead, already decrypted, given out before reading more.
+    buffered: Vec<u8>,
+}
+
+/// Writing half of a [`PeerStream`].
+pub(crate) struct PeerWriter {
+    inner: Box<dyn AsyncWrite + Send + Unpin>,
+    cipher: Option<Rc4>,
+    /// Encrypted data that wasn't written yet.
+    pending: Vec<u8>,
+}
+
+impl PeerStream {
+    /// Create a plain [`PeerStream`] over TCP `stream`.
+    pub fn tcp(stream: TcpStream) -> Self {
+        let (reader, writer) = stream.into_split();
+
+        Self::new(Box::new(reader), Box::new(writer), Transport::Tcp)
+    }
+
+    /// Create a plain [`PeerStream`] over uTP `stream`.
+    pub fn utp(stream: UtpStream) -> Self {
+        let (reader, writer) = tokio::io::split(stream);
+
+        Self::new(Box::new(reader), Box::new(writer), Transport::Utp)
+    }
+
+    fn new(
+        reader: Box<dyn AsyncRead + Send + Unpin>,
+        writer: Box<dyn AsyncWrite + Send + Unpin>,
+        transport: Transport,
+    ) -> Self {
+        Self {
+            reader: PeerReader {
+                inner: reader,
+                cipher: None,
+                buffered: Vec::new(),
+            },
+            writer: PeerWriter {
+                inner: writer,
+                cipher: None,
+                pending: Vec::new(),
+            },
+            transport,
+        }
+    }
+
+    /// Put `data` back in front of data still to be read.
+    pub fn unread(&mut self, data: &[u8]) {
+        self.reader.buffered.splice(0..0, data.iter().copied());
+    }
+
+    /// Encrypt data written from now on with `encrypt`, and decrypt data read from now on,
+    /// including data that was read ahead, with `decrypt`.
+    pub fn set_ciphers(&mut self, encrypt: Rc4, mut decrypt: Rc4) {
+        decrypt.apply(&mut self.reader.buffered);
+        self.reader.cipher = Some(decrypt);
+        self.writer.cipher = Some(encrypt);
+    }
+
+    /// Check whether data is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.writer.cipher.is_some()
+    }
+
+    /// Get transport the connection runs over.
+    pub fn get_transport(&self) -> Transport {
+        self.transport
+    }
+
+    /// Split into halves that can be used by different tasks.
+    pub fn into_split(self) -> (PeerReader, PeerWriter) {
+        (self.reader, self.writer)
+    }
+}
+
+impl AsyncRead for PeerReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if !self.buffered.is_empty() {
+            let len = self.buffered.len().min(buf.remaining());
+            buf.put_slice(&self.buffered[..len]);
+            self.buffered.drain(..len);
+            return Poll::Ready(Ok(()));
+        }
+
+        let start = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if let Some(cipher) = &mut self.cipher {
+            cipher.apply(&mut buf.filled_mut()[start..]);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl PeerWriter {
+    /// Write out all pending data.
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.pending.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.pending))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.pending.drain(..written);
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for PeerWriter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.cipher.is_none() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+
+        // Encrypted data can't be taken back, so it's accepted whole and written out later
+        // if the socket isn't ready for all of it.
+        ready!(this.poll_drain(cx))?;
+        this.pending.extend_from_slice(buf);
+        if let Some(cipher) = &mut this.cipher {
+            cipher.apply(&mut this.pending);
+        }
+        if let Poll::Ready(Err(e)) = this.poll_drain(cx) {
+            return Poll::Ready(Err(e));
+        }
+
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_drain(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl fmt::Debug for PeerReader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerReader")
+            .field("cipher", &self.cipher)
+            .field("buffered", &self.buffered.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl fmt::Debug for PeerWriter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerWriter")
+            .field("cipher", &self.cipher)
+            .field("pending", &self.pending.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for PeerStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PeerStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,701 @@
+use super::packet::{Packet, PacketType};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::task::Waker;
+use tokio::time::{Duration, Instant};
+
+/// Largest payload of a packet, so packets fit in common path MTUs.
+pub(super) const PACKET_SIZE: usize = 1400;
+/// Bytes written but not yet sent that are accepted before writes wait.
+const SEND_BUFFER: usize = 256 * 1024;
+/// Bytes received but not yet read that we advertise room for.
+const RECV_BUFFER: usize = 1024 * 1024;
+/// Queuing delay LEDBAT aims for, in microseconds.
+const TARGET_DELAY: f64 = 100_000.0;
+/// Largest growth of the congestion window per round trip, in bytes.
+const MAX_WINDOW_INCREASE: f64 = 3000.0;
+/// Bounds of the congestion window, in bytes.
+const MIN_WINDOW: f64 = PACKET_SIZE as f64;
+const MAX_WINDOW: f64 = 1024.0 * 1024.0;
+/// Congestion window of a new connection.
+const INITIAL_WINDOW: f64 = 4.0 * PACKET_SIZE as f64;
+/// Bounds of the retransmission timeout.
+const MIN_TIMEOUT: Duration = Duration::from_millis(500);
+const MAX_TIMEOUT: Duration = Duration::from_secs(30);
+/// Retransmission timeout before the round-trip time is known.
+const INITIAL_TIMEOUT: Duration = Duration::from_secs(1);
+/// Times a packet is sent before the connection is given up on.
+const MAX_TRANSMISSIONS: u32 = 6;
+/// Times a SYN is sent before connecting fails, so peers without uTP are given up on quickly.
+const MAX_SYN_TRANSMISSIONS: u32 = 3;
+/// Number of packets acknowledged past a missing one before it's considered lost.
+const DUPLICATE_ACKS: usize = 3;
+/// How far ahead of the last in-order packet received packets are buffered.
+const MAX_OUT_OF_ORDER: u16 = 1024;
+/// Time over which the lowest delay is remembered as the base delay.
+const BASE_DELAY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// State of a [`Connection`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    SynSent,
+    Connected,
+    Closed,
+}
+
+/// A packet sent, or queued to be sent, that wasn't acknowledged yet.
+#[derive(Debug)]
+struct Sent {
+    packet: Packet,
+    sent_at: Instant,
+    transmissions: u32,
+    /// Whether it's due to be sent (again).
+    due: bool,
+    /// Whether it was already resent because later packets were acknowledged.
+    fast_resent: bool,
+}
+
+/// Lowest delays measured over the current and the previous interval.
+#[derive(Debug)]
+struct DelayHistory {
+    current: u32,
+    previous: u32,
+    started: Instant,
+}
+
+impl DelayHistory {
+    fn add(&mut self, delay: u32, now: Instant) {
+        if now.duration_since(self.started) > BASE_DELAY_INTERVAL {
+            self.previous = self.current;
+            self.current = u32::MAX;
+            self.started = now;
+        }
+        if self.current == u32::MAX || earlier(delay, self.current) {
+            self.current = delay;
+        }
+    }
+
+    /// Get delay without queuing, the lowest one measured recently.
+    fn base(&self) -> u32 {
+        match self.previous == u32::MAX || earlier(self.current, self.previous) {
+            true => self.current,
+            false => self.previous,
+        }
+    }
+}
+
+/// State machine of a uTP connection, without any I/O: packets received are passed in and
+/// packets to send are taken out, and time is passed in as `now`.
+///
+/// The congestion window follows LEDBAT: it grows while the one-way delay of our packets
+/// is below [`TARGET_DELAY`] above the base delay, and shrinks when it's above, so we give
+/// way to other traffic that fills up queues.
+#[derive(Debug)]
+pub(super) struct Connection {
+    state: State,
+    /// ID of packets we receive.
+    recv_id: u16,
+    /// ID of packets we send.
+    send_id: u16,
+    /// Sequence number of the next data packet.
+    seq_nr: u16,
+    /// Sequence number of the last packet received in order.
+    ack_nr: u16,
+    /// Start of our clock for timestamps.
+    epoch: Instant,
+    sent: VecDeque<Sent>,
+    /// Data written that isn't in a packet yet.
+    send_buffer: VecDeque<u8>,
+    /// Data received in order that wasn't read yet.
+    recv_buffer: VecDeque<u8>,
+    /// Packets received ahead of a missing one.
+    out_of_order: HashMap<u16, Packet>,
+    /// Whether the peer's FIN was received in order, so nothing more will be.
+    eof: bool,
+    /// Whether the stream was shut down for writing.
+    closing: bool,
+    fin_sent: bool,
+    /// Whether the handle was dropped, so nothing more will be read.
+    dropped: bool,
+    error: Option<io::ErrorKind>,
+    /// Congestion window, in bytes.
+    window: f64,
+    /// Receive window of the peer, in bytes.
+    peer_window: u32,
+    rtt: Option<Duration>,
+    rtt_var: Duration,
+    timeout: Duration,
+    delays: DelayHistory,
+    /// Timestamp difference to send back, for the peer's delay measurement.
+    reply_micros: u32,
+    /// Last cumulative ack received, and how many acks in a row repeated it.
+    last_ack: u16,
+    duplicate_acks: usize,
+    last_decrease: Instant,
+    /// Whether the peer should be sent an acknowledgement.
+    ack_due: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+/// Check whether sequence number `a` comes before `b`, accounting for wrapping.
+fn before(a: u16, b: u16) -> bool {
+    (b.wrapping_sub(a) as i16) > 0
+}
+
+/// Check whether delay `a` is lower than `b`, accounting for wrapping, since delays include
+/// the difference between the clocks of both sides.
+fn earlier(a: u32, b: u32) -> bool {
+    (b.wrapping_sub(a) as i32) > 0
+}
+
+impl Connection {
+    fn new(recv_id: u16, send_id: u16, seq_nr: u16, now: Instant) -> Self {
+        Self {
+            state: State::SynSent,
+            recv_id,
+            send_id,
+            seq_nr,
+            ack_nr: 0,
+            epoch: now,
+            sent: VecDeque::new(),
+            send_buffer: VecDeque::new(),
+            recv_buffer: VecDeque::new(),
+            out_of_order: HashMap::new(),
+            eof: false,
+            closing: false,
+            fin_sent: false,
+            dropped: false,
+            error: None,
+            window: INITIAL_WINDOW,
+            peer_window: 0,
+            rtt: None,
+            rtt_var: Duration::ZERO,
+            timeout: INITIAL_TIMEOUT,
+            delays: DelayHistory {
+                current: u32::MAX,
+                previous: u32::MAX,
+                started: now,
+            },
+            reply_micros: 0,
+            last_ack: seq_nr.wrapping_sub(1),
+            duplicate_acks: 0,
+            last_decrease: now,
+            ack_due: false,
+            read_waker: None,
+            write_waker: None,
+        }
+    }
+
+    /// Create a [`Connection`] that opens with a SYN, receiving packets with `recv_id`.
+    pub fn connect(recv_id: u16, now: Instant) -> Self {
+        let mut connection = Self::new(recv_id, recv_id.wrapping_add(1), 1, now);
+        connection.queue(PacketType::Syn, Vec::new(), now);
+
+        connection
+    }
+
+    /// Create a [`Connection`] accepting `syn`.
+    pub fn accept(syn: &Packet, seq_nr: u16, now: Instant) -> Self {
+        let mut connection = Self::new(
+            syn.connection_id.wrapping_add(1),
+            syn.connection_id,
+            seq_nr,
+            now,
+        );
+        connection.state = State::Connected;
+        connection.ack_nr = syn.seq_nr;
+        connection.peer_window = syn.window;
+        connection.reply_micros = connection.micros(now).wrapping_sub(syn.timestamp);
+        connection.ack_due = true;
+
+        connection
+    }
+
+    /// Get ID of packets we receive.
+    pub fn get_recv_id(&self) -> u16 {
+        self.recv_id
+    }
+
+    /// Get our clock in microseconds, wrapping around.
+    fn micros(&self, now: Instant) -> u32 {
+        now.duration_since(self.epoch).as_micros() as u32
+    }
+
+    /// Queue packet of `kind` with the next sequence number.
+    fn queue(&mut self, kind: PacketType, payload: Vec<u8>, now: Instant) {
+        self.sent.push_back(Sent {
+            packet: Packet::new(kind, self.seq_nr, payload),
+            sent_at: now,
+            transmissions: 0,
+            due: true,
+            fast_resent: false,
+        });
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+    }
+
+    /// Get bytes of payload sent, or queued to be sent, that weren't acknowledged.
+    fn in_flight(&self) -> usize {
+        self.sent.iter().map(|sent| sent.packet.payload.len()).sum()
+    }
+
+    /// Get selective ACK of packets received out of order, if there are any.
+    fn selective_ack(&self) -> Option<Vec<u8>> {
+        let offsets = self
+            .out_of_order
+            .keys()
+            .map(|seq_nr| seq_nr.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize)
+            .collect::<Vec<_>>();
+        let len = (offsets.iter().max()? / 32 + 1) * 4;
+        let mut mask = vec![0; len];
+        for offset in offsets {
+            mask[offset / 8] |= 1 << (offset % 8);
+        }
+
+        Some(mask)
+    }
+
+    /// Take packets to send now.
+    pub fn transmit(&mut self, now: Instant) -> Vec<Packet> {
+        if self.state == State::Closed {
+            return Vec::new();
+        }
+
+        if self.state == State::Connected {
+            let window = (self.window as usize).min(self.peer_window as usize);
+            let before = self.send_buffer.len();
+            while !self.send_buffer.is_empty() {
+                let size = PACKET_SIZE.min(self.send_buffer.len());
+                let in_flight = self.in_flight();
+                // A single packet is always allowed, so a closed window is probed.
+                if in_flight > 0 && in_flight + size > window {
+                    break;
+                }
+                let payload = self.send_buffer.drain(..size).collect();
+                self.queue(PacketType::Data, payload, now);
+            }
+            if self.send_buffer.len() < before {
+                wake(&mut self.write_waker);
+            }
+            if self.closing && self.send_buffer.is_empty() && !self.fin_sent {
+                self.fin_sent = true;
+                self.queue(PacketType::Fin, Vec::new(), now);
+            }
+        }
+
+        let mut out = Vec::new();
+        for sent in self.sent.iter_mut().filter(|sent| sent.due) {
+            sent.due = false;
+            sent.transmissions += 1;
+            sent.sent_at = now;
+            out.push(sent.packet.clone());
+        }
+        if out.is_empty() && self.ack_due {
+            out.push(Packet::new(PacketType::State, self.seq_nr, Vec::new()));
+        }
+        self.ack_due = false;
+
+        let selective_ack = self.selective_ack();
+        let window = RECV_BUFFER.saturating_sub(self.recv_buffer.len()) as u32;
+        for packet in &mut out {
+            packet.connection_id = match packet.kind {
+                PacketType::Syn => self.recv_id,
+                _ => self.send_id,
+            };
+            packet.timestamp = self.micros(now);
+            packet.timestamp_difference = self.reply_micros;
+            packet.window = window;
+            packet.ack_nr = self.ack_nr;
+            packet.selective_ack = selective_ack.clone();
+        }
+
+        out
+    }
+
+    /// Handle `packet` from the peer.
+    pub fn receive(&mut self, packet: Packet, now: Instant) {
+        if self.state == State::Closed {
+            return;
+        }
+        self.reply_micros = self.micros(now).wrapping_sub(packet.timestamp);
+        self.peer_window = packet.window;
+
+        match packet.kind {
+            PacketType::Reset => return self.fail(io::ErrorKind::ConnectionReset),
+            // Our answer to it was lost.
+            PacketType::Syn => {
+                self.ack_due = true;
+                return;
+            }
+            _ => {}
+        }
+        if self.state == State::SynSent {
+            self.state = State::Connected;
+            self.ack_nr = packet.seq_nr.wrapping_sub(1);
+            wake(&mut self.write_waker);
+        }
+
+        self.handle_ack(&packet, now);
+        if matches!(packet.kind, PacketType::Data | PacketType::Fin) {
+            self.handle_data(packet);
+        }
+    }
+
+    /// Remove packets `packet` acknowledges, and adjust the window.
+    fn handle_ack(&mut self, packet: &Packet, now: Instant) {
+        // Acks of packets we haven't sent are ignored.
+        if !before(packet.ack_nr, self.seq_nr) {
+            return;
+        }
+
+        let mut acked = 0;
+        let mut acked_bytes = 0;
+        let mut rtt = None;
+        let mut take = |sent: Sent| {
+            acked += 1;
+            acked_bytes += sent.packet.payload.len();
+            if sent.transmissions == 1 {
+                rtt = Some(now.duration_since(sent.sent_at));
+            }
+        };
+        while let Some(sent) = self.sent.front() {
+            if sent.transmissions == 0 || before(packet.ack_nr, sent.packet.seq_nr) {
+                break;
+            }
+            take(self.sent.pop_front().unwrap());
+        }
+
+        // Packets acknowledged past the oldest one still missing.
+        let mut selected = 0;
+        if let Some(mask) = &packet.selective_ack {
+            let is_set = |offset: usize| {
+                mask.get(offset / 8)
+                    .is_some_and(|byte| byte & (1 << (offset % 8)) != 0)
+            };
+            let seq_nr = |offset: usize| packet.ack_nr.wrapping_add(2).wrapping_add(offset as u16);
+            let offset = |seq_nr: u16| seq_nr.wrapping_sub(packet.ack_nr).wrapping_sub(2) as usize;
+            let mut kept = VecDeque::new();
+            for sent in self.sent.drain(..) {
+                match sent.transmissions > 0 && is_set(offset(sent.packet.seq_nr)) {
+                    true => take(sent),
+                    false => kept.push_back(sent),
+                }
+            }
+            self.sent = kept;
+            if let Some(first) = self.sent.front() {
+                selected = (0..mask.len() * 8)
+                    .filter(|&i| is_set(i) && before(first.packet.seq_nr, seq_nr(i)))
+                    .count();
+            }
+        }
+
+        if packet.ack_nr != self.last_ack {
+            self.duplicate_acks = 0;
+        } else if acked == 0 && packet.kind == PacketType::State {
+            self.duplicate_acks += 1;
+        }
+        self.last_ack = packet.ack_nr;
+        if selected >= DUPLICATE_ACKS || self.duplicate_acks >= DUPLICATE_ACKS {
+            self.resend_first(now);
+        }
+
+        if let Some(sample) = rtt {
+            self.update_rtt(sample);
+        }
+        if acked > 0 {
+            if packet.timestamp_difference != 0 {
+                self.delays.add(packet.timestamp_difference, now);
+                self.update_window(packet.timestamp_difference, acked_bytes);
+            }
+            wake(&mut self.write_waker);
+        }
+    }
+
+    /// Resend the oldest unacknowledged packet, considered lost, and halve the window
+    /// at most once per round trip.
+    fn resend_first(&mut self, now: Instant) {
+        let Some(sent) = self.sent.front_mut() else {
+            return;
+        };
+        if sent.fast_resent || sent.transmissions == 0 {
+            return;
+        }
+        sent.fast_resent = true;
+        sent.due = true;
+        self.duplicate_acks = 0;
+
+        let rtt = self.rtt.unwrap_or(INITIAL_TIMEOUT);
+        if now.duration_since(self.last_decrease) > rtt {
+            self.window = (self.window / 2.0).max(MIN_WINDOW);
+            self.last_decrease = now;
+        }
+    }
+
+    fn update_rtt(&mut self, sample: Duration) {
+        match self.rtt {
+            Some(rtt) => {
+                let delta = if rtt > sample {
+                    rtt - sample
+                } else {
+                    sample - rtt
+                };
+                self.rtt_var = (self.rtt_var * 3 + delta) / 4;
+                self.rtt = Some((rtt * 7 + sample) / 8);
+            }
+            None => {
+                self.rtt = Some(sample);
+                self.rtt_var = sample / 2;
+            }
+        }
+        self.timeout =
+            (self.rtt.unwrap_or_default() + self.rtt_var * 4).clamp(MIN_TIMEOUT, MAX_TIMEOUT);
+    }
+
+    /// Grow or shrink the window for `acked_bytes` acknowledged, given the `delay` the peer
+    /// measured for our packet.
+    fn update_window(&mut self, delay: u32, acked_bytes: usize) {
+        let queuing = (delay.wrapping_sub(self.delays.base()) as i32).max(0) as f64;
+        let off_target = ((TARGET_DELAY - queuing) / TARGET_DELAY).clamp(-1.0, 1.0);
+        let gain = MAX_WINDOW_INCREASE * off_target * acked_bytes as f64 / self.window;
+        self.window = (self.window + gain).clamp(MIN_WINDOW, MAX_WINDOW);
+    }
+
+    /// Take in data or FIN `packet`, in order.
+    fn handle_data(&mut self, packet: Packet) {
+        self.ack_due = true;
+        let seq_nr = packet.seq_nr;
+        if self.eof
+            || !before(self.ack_nr, seq_nr)
+            || seq_nr.wrapping_sub(self.ack_nr) > MAX_OUT_OF_ORDER
+        {
+            return;
+        }
+
+        self.out_of_order.insert(seq_nr, packet);
+        while let Some(packet) = self.out_of_order.remove(&self.ack_nr.wrapping_add(1)) {
+            self.ack_nr = packet.seq_nr;
+            if packet.kind == PacketType::Fin {
+                self.eof = true;
+                self.out_of_order.clear();
+                break;
+            }
+            self.recv_buffer.extend(packet.payload);
+        }
+        wake(&mut self.read_waker);
+    }
+
+    /// Get when [`Connection::expire`] has to be called next.
+    pub fn deadline(&self) -> Option<Instant> {
+        let sent = self.sent.front().filter(|sent| sent.transmissions > 0)?;
+
+        Some(sent.sent_at + self.timeout)
+    }
+
+    /// Resend the oldest packet if it timed out, or fail if it was sent too often.
+    pub fn expire(&mut self, now: Instant) {
+        let Some(deadline) = self.deadline() else {
+            return;
+        };
+        if now < deadline {
+            return;
+        }
+
+        let Some(sent) = self.sent.front_mut() else {
+            return;
+        };
+        let max = match sent.packet.kind {
+            PacketType::Syn => MAX_SYN_TRANSMISSIONS,
+            _ => MAX_TRANSMISSIONS,
+        };
+        if sent.transmissions >= max {
+            return self.fail(io::ErrorKind::TimedOut);
+        }
+        sent.due = true;
+        self.window = MIN_WINDOW;
+        self.timeout = (self.timeout * 2).min(MAX_TIMEOUT);
+    }
+
+    /// Close with `error`, waking up readers and writers.
+    pub fn fail(&mut self, error: io::ErrorKind) {
+        self.error = Some(error);
+        self.state = State::Closed;
+        wake(&mut self.read_waker);
+        wake(&mut self.write_waker);
+    }
+
+    /// Check whether nothing more will be sent or received.
+    pub fn is_closed(&mut self) -> bool {
+        let finished = self.fin_sent && self.sent.is_empty() && (self.eof || self.dropped);
+        // Nobody is waiting for a connection that was never made.
+        let abandoned = self.dropped && self.state == State::SynSent;
+        if finished || abandoned {
+            self.state = State::Closed;
+            wake(&mut self.read_waker);
+        }
+
+        self.state == State::Closed
+    }
+
+    /// Get error closing the connection, or `kind` if it was closed otherwise.
+    fn error(&self, kind: io::ErrorKind) -> io::Error {
+        self.error.unwrap_or(kind).into()
+    }
+
+    /// Wait until the connection is made.
+    pub fn poll_connect(&mut self, waker: &Waker) -> Option<io::Result<()>> {
+        match self.state {
+            State::SynSent => {
+                self.write_waker = Some(waker.clone());
+                None
+            }
+            State::Connected => Some(Ok(())),
+            State::Closed => Some(Err(self.error(io::ErrorKind::ConnectionRefused))),
+        }
+    }
+
+    /// Read up to `buf.len()` bytes, or `None` if there's nothing to read yet.
+    ///
+    /// Gets whether the peer should be told about the receive window opening up.
+    pub fn read(&mut self, buf: &mut [u8], waker: &Waker) -> Option<io::Result<(usize, bool)>> {
+        if self.recv_buffer.is_empty() {
+            if self.eof || (self.state == State::Closed && self.error.is_none()) {
+                return Some(Ok((0, false)));
+            }
+            if let Some(error) = self.error {
+                return Some(Err(error.into()));
+            }
+            self.read_waker = Some(waker.clone());
+            return None;
+        }
+
+        let full = self.recv_buffer.len() >= RECV_BUFFER / 2;
+        let len = buf.len().min(self.recv_buffer.len());
+        for (byte, data) in buf.iter_mut().zip(self.recv_buffer.drain(..len)) {
+            *byte = data;
+        }
+        if full {
+            self.ack_due = true;
+        }
+
+        Some(Ok((len, full)))
+    }
+
+    /// Queue up to `data.len()` bytes to be sent, or `None` if the send buffer is full.
+    pub fn write(&mut self, data: &[u8], waker: &Waker) -> Option<io::Result<usize>> {
+        if self.state == State::Closed || self.closing {
+            return Some(Err(self.error(io::ErrorKind::BrokenPipe)));
+        }
+        let room = SEND_BUFFER.saturating_sub(self.send_buffer.len());
+        if room == 0 || self.state == State::SynSent {
+            self.write_waker = Some(waker.clone());
+            return None;
+        }
+
+        let len = data.len().min(room);
+        self.send_buffer.extend(&data[..len]);
+
+        Some(Ok(len))
+    }
+
+    /// Send a FIN once all data written was sent.
+    pub fn shutdown(&mut self) {
+        self.closing = true;
+    }
+
+    /// Stop reading, and close once the FIN was acknowledged.
+    pub fn drop_handle(&mut self) {
+        self.closing = true;
+        self.dropped = true;
+    }
+}
+
+fn wake(waker: &mut Option<Waker>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+#[test]
+fn test_connection() {
+    let now = Instant::now();
+    let waker = futures::task::noop_waker();
+    let mut a = Connection::connect(10, now);
+    let syn = a.transmit(now);
+    assert_eq!(syn.len(), 1);
+    assert_eq!(
+        (syn[0].kind, syn[0].connection_id, syn[0].seq_nr),
+        (PacketType::Syn, 10, 1)
+    );
+    assert!(a.write(b"hello", &waker).is_none());
+
+    // Unanswered SYNs are resent with backoff, then connecting fails.
+    let mut unanswered = Connection::connect(20, now);
+    let mut at = now;
+    while unanswered.poll_connect(&waker).is_none() {
+        unanswered.transmit(at);
+        at = unanswered.deadline().unwrap();
+        unanswered.expire(at);
+    }
+    assert_eq!(at, now + Duration::from_secs(7));
+    assert!(unanswered.is_closed());
+
+    let mut b = Connection::accept(&syn[0], 500, now);
+    assert_eq!(b.get_recv_id(), 11);
+    let state = b.transmit(now);
+    assert_eq!(
+        (state[0].kind, state[0].connection_id),
+        (PacketType::State, 10)
+    );
+    a.receive(state[0].clone(), now);
+    assert!(matches!(a.poll_connect(&waker), Some(Ok(()))));
+    assert!(a.sent.is_empty());
+
+    // Packets 2 and 3 are sent, 2 is lost and resent after the others are acknowledged.
+    let data = vec![7; PACKET_SIZE * 5];
+    assert_eq!(a.write(&data, &waker).unwrap().unwrap(), data.len());
+    let packets = a.transmit(now);
+    assert_eq!(packets.len(), 4);
+    for packet in &packets[1..] {
+        b.receive(packet.clone(), now);
+    }
+    let mut buf = vec![0; data.len()];
+    assert!(b.read(&mut buf, &waker).is_none());
+    let ack = b.transmit(now);
+    assert_eq!(ack[0].ack_nr, 1);
+    assert_eq!(ack[0].selective_ack, Some(vec![0b111, 0, 0, 0]));
+    a.receive(ack[0].clone(), now);
+    let resent = a.transmit(now);
+    let seq_nrs = resent
+        .iter()
+        .map(|packet| packet.seq_nr)
+        .collect::<Vec<_>>();
+    assert_eq!(seq_nrs, [2, 6]);
+    b.receive(resent[0].clone(), now);
+    assert_eq!(
+        b.read(&mut buf, &waker).unwrap().unwrap().0,
+        PACKET_SIZE * 4
+    );
+    a.receive(b.transmit(now).remove(0), now);
+
+    // The last packet was lost too, times out, and is resent.
+    assert!(a.deadline().is_some());
+    let later = now + Duration::from_secs(2);
+    a.expire(later);
+    let resent = a.transmit(later);
+    assert_eq!(resent[0].seq_nr, 6);
+    b.receive(resent[0].clone(), later);
+    a.receive(b.transmit(later).remove(0), later);
+    assert!(a.sent.is_empty());
+
+    // Closing sends a FIN, which ends reading on the other side.
+    a.shutdown();
+    let fin = a.transmit(later);
+    assert_eq!(fin[0].kind, PacketType::Fin);
+    b.receive(fin[0].clone(), later);
+    assert_eq!(b.read(&mut buf, &waker).unwrap().unwrap().0, PACKET_SIZE);
+    assert_eq!(b.read(&mut buf, &waker).unwrap().unwrap().0, 0);
+    a.receive(b.transmit(later).remove(0), later);
+    b.drop_handle();
+    a.drop_handle();
+    assert!(a.is_closed());
+}
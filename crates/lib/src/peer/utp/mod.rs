@@ -0,0 +1,354 @@
+//! uTP (BEP 29): reliable, ordered streams over UDP, with LEDBAT congestion control that
+//! yields to other traffic.
+
+mod connection;
+mod packet;
+
+use crate::util;
+use connection::Connection;
+use packet::{Packet, PacketType};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+
+/// Largest datagram received.
+const MAX_DATAGRAM: usize = 64 * 1024;
+
+/// UDP socket carrying uTP connections, cheap to clone.
+#[derive(Debug, Clone)]
+pub(crate) struct UtpSocket {
+    endpoint: Arc<Endpoint>,
+}
+
+#[derive(Debug)]
+struct Endpoint {
+    udp: UdpSocket,
+    /// Channels to the tasks of connections, by peer address and the ID of packets we receive.
+    connections: Mutex<HashMap<(SocketAddr, u16), mpsc::UnboundedSender<Packet>>>,
+    /// Whether the socket is IPv6, which reaches IPv4 peers at IPv4-mapped addresses.
+    ipv6: bool,
+    /// Percentage of packets dropped instead of sent.
+    #[cfg(test)]
+    loss: std::sync::atomic::AtomicU32,
+}
+
+impl Endpoint {
+    async fn send(&self, packet: &Packet, addr: SocketAddr) {
+        #[cfg(test)]
+        {
+            use rand::Rng;
+            let loss = self.loss.load(std::sync::atomic::Ordering::Relaxed);
+            if rand::thread_rng().gen_range(0..100) < loss {
+                return;
+            }
+        }
+
+        // Lost datagrams are resent by the connection.
+        let _ = self
+            .udp
+            .send_to(&packet.to_bytes(), self.target(addr))
+            .await;
+    }
+
+    /// Get address to send datagrams for `addr` to, IPv4-mapped on an IPv6 socket.
+    fn target(&self, addr: SocketAddr) -> SocketAddr {
+        match (self.ipv6, addr) {
+            (true, SocketAddr::V4(v4)) => {
+                SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+            }
+            _ => addr,
+        }
+    }
+}
+
+impl UtpSocket {
+    /// Create [`UtpSocket`] bound to `addr`.
+    #[cfg(test)]
+    async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Self::new(UdpSocket::bind(addr).await?)
+    }
+
+    /// Create [`UtpSocket`] from a bound, non-blocking `socket`.
+    pub fn from_std(socket: std::net::UdpSocket) -> io::Result<Self> {
+        Self::new(UdpSocket::from_std(socket)?)
+    }
+
+    fn new(udp: UdpSocket) -> io::Result<Self> {
+        Ok(Self {
+            endpoint: Arc::new(Endpoint {
+                ipv6: udp.local_addr()?.is_ipv6(),
+                udp,
+                connections: Mutex::new(HashMap::new()),
+                #[cfg(test)]
+                loss: std::sync::atomic::AtomicU32::new(0),
+            }),
+        })
+    }
+
+    /// Get address the socket is bound to.
+    #[cfg(test)]
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.endpoint.udp.local_addr()
+    }
+
+    /// Drop `percent` of packets sent, to test recovering from loss.
+    #[cfg(test)]
+    fn set_loss(&self, percent: u32) {
+        let loss = &self.endpoint.loss;
+        loss.store(percent, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// Send datagram `data` of another protocol sharing the socket to `addr`.
+    pub async fn send_to(&self, data: &[u8], addr: SocketAddr) -> io::Result<()> {
+        if addr.is_ipv6() && !self.endpoint.ipv6 {
+            return Err(io::ErrorKind::AddrNotAvailable.into());
+        }
+        let endpoint = &self.endpoint;
+        endpoint.udp.send_to(data, endpoint.target(addr)).await?;
+
+        Ok(())
+    }
+
+    /// Open a connection to `addr`.
+    pub async fn connect(&self, addr: SocketAddr) -> io::Result<UtpStream> {
+        if addr.is_ipv6() && !self.endpoint.ipv6 {
+            return Err(io::ErrorKind::AddrNotAvailable.into());
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        let recv_id = {
+            let mut connections = self.endpoint.connections.lock().unwrap();
+            let recv_id = loop {
+                let recv_id = rand::random::<u16>();
+                if !connections.contains_key(&(addr, recv_id)) {
+                    break recv_id;
+                }
+            };
+            connections.insert((addr, recv_id), tx);
+            recv_id
+        };
+
+        let stream = UtpStream::new(Connection::connect(recv_id, Instant::now()));
+        let inner = Arc::clone(&stream.inner);
+        tokio::spawn(drive(Arc::clone(&self.endpoint), addr, inner, rx));
+        futures::future::poll_fn(|cx| {
+            let mut connection = stream.inner.connection.lock().unwrap();
+            match connection.poll_connect(cx.waker()) {
+                Some(result) => Poll::Ready(result),
+                None => Poll::Pending,
+            }
+        })
+        .await?;
+
+        Ok(stream)
+    }
+
+    /// Receive packets and pass them to their connections, sending connections peers open
+    /// to `accepted`, until the socket fails. Datagrams that start like a bencoded dictionary,
+    /// as DHT messages do, go to `others` instead.
+    pub async fn run(
+        self,
+        accepted: mpsc::UnboundedSender<(UtpStream, SocketAddr)>,
+        others: mpsc::UnboundedSender<(Vec<u8>, SocketAddr)>,
+    ) {
+        let mut buf = vec![0; MAX_DATAGRAM];
+
+        loop {
+            let (len, addr) = match self.endpoint.udp.recv_from(&mut buf).await {
+                Ok(received) => received,
+                // Reported for an earlier datagram that couldn't be delivered, on some systems.
+                Err(e) if e.kind() == io::ErrorKind::ConnectionReset => continue,
+                Err(_) => return,
+            };
+            let addr = util::canonical(addr);
+            // No uTP packet starts with `d`, as its type and version would be invalid.
+            if buf[..len].starts_with(b"d") {
+                let _ = others.send((buf[..len].to_vec(), addr));
+                continue;
+            }
+            let Some(packet) = Packet::from_bytes(&buf[..len]) else {
+                continue;
+            };
+
+            let is_syn = packet.kind == PacketType::Syn;
+            let key = match is_syn {
+                true => (addr, packet.connection_id.wrapping_add(1)),
+                false => (addr, packet.connection_id),
+            };
+            let mut connections = self.endpoint.connections.lock().unwrap();
+            if let Some(tx) = connections.get(&key) {
+                let _ = tx.send(packet);
+            } else if is_syn {
+                let connection = Connection::accept(&packet, rand::random(), Instant::now());
+                let (tx, rx) = mpsc::unbounded_channel();
+                connections.insert(key, tx);
+                let stream = UtpStream::new(connection);
+                let inner = Arc::clone(&stream.inner);
+                tokio::spawn(drive(Arc::clone(&self.endpoint), addr, inner, rx));
+                let _ = accepted.send((stream, addr));
+            }
+        }
+    }
+}
+
+/// Run connection with `inner` to `addr`: take in `packets`, send packets when they're due,
+/// and stop once the connection is closed.
+async fn drive(
+    endpoint: Arc<Endpoint>,
+    addr: SocketAddr,
+    inner: Arc<Inner>,
+    mut packets: mpsc::UnboundedReceiver<Packet>,
+) {
+    let recv_id = inner.connection.lock().unwrap().get_recv_id();
+
+    loop {
+        let (out, deadline, closed) = {
+            let mut connection = inner.connection.lock().unwrap();
+            let now = Instant::now();
+            connection.expire(now);
+            let out = connection.transmit(now);
+            (out, connection.deadline(), connection.is_closed())
+        };
+        for packet in &out {
+            endpoint.send(packet, addr).await;
+        }
+        if closed {
+            break;
+        }
+
+        tokio::select! {
+            packet = packets.recv() => {
+                let Some(packet) = packet else {
+                    break;
+                };
+                let mut connection = inner.connection.lock().unwrap();
+                connection.receive(packet, Instant::now());
+                while let Ok(packet) = packets.try_recv() {
+                    connection.receive(packet, Instant::now());
+                }
+            }
+            _ = inner.notify.notified() => {}
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {}
+        }
+    }
+
+    let mut connections = endpoint.connections.lock().unwrap();
+    connections.remove(&(addr, recv_id));
+}
+
+#[derive(Debug)]
+struct Inner {
+    connection: Mutex<Connection>,
+    /// Wakes up the connection's task when there's something to send.
+    notify: Notify,
+}
+
+/// Connection to a peer over uTP.
+#[derive(Debug)]
+pub(crate) struct UtpStream {
+    inner: Arc<Inner>,
+}
+
+impl UtpStream {
+    fn new(connection: Connection) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                connection: Mutex::new(connection),
+                notify: Notify::new(),
+            }),
+        }
+    }
+}
+
+impl AsyncRead for UtpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut connection = self.inner.connection.lock().unwrap();
+        match connection.read(buf.initialize_unfilled(), cx.waker()) {
+            Some(Ok((len, opened))) => {
+                buf.advance(len);
+                if opened {
+                    self.inner.notify.notify_one();
+                }
+                Poll::Ready(Ok(()))
+            }
+            Some(Err(e)) => Poll::Ready(Err(e)),
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for UtpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut connection = self.inner.connection.lock().unwrap();
+        match connection.write(buf, cx.waker()) {
+            Some(result) => {
+                self.inner.notify.notify_one();
+                Poll::Ready(result)
+            }
+            None => Poll::Pending,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.inner.connection.lock().unwrap().shutdown();
+        self.inner.notify.notify_one();
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.inner.connection.lock().unwrap().drop_handle();
+        self.inner.notify.notify_one();
+    }
+}
+
+#[tokio::test]
+async fn test_utp_transfer() {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let localhost = SocketAddr::from(([127, 0, 0, 1], 0));
+    let a = UtpSocket::bind(localhost).await.unwrap();
+    let b = UtpSocket::bind(localhost).await.unwrap();
+    let b_addr = b.local_addr().unwrap();
+    let (accepted_tx, mut accepted_rx) = mpsc::unbounded_channel();
+    let (others, _) = mpsc::unbounded_channel();
+    tokio::spawn(a.clone().run(mpsc::unbounded_channel().0, others.clone()));
+    tokio::spawn(b.clone().run(accepted_tx, others));
+
+    let data = (0..256 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let mut outgoing = a.connect(b_addr).await.unwrap();
+    a.set_loss(5);
+    b.set_loss(5);
+    let sent = data.clone();
+    let sender = tokio::spawn(async move {
+        outgoing.write_all(&sent).await.unwrap();
+        outgoing.shutdown().await.unwrap();
+    });
+
+    let (mut incoming, addr) = accepted_rx.recv().await.unwrap();
+    assert_eq!(addr, a.local_addr().unwrap());
+    let mut received = Vec::new();
+    incoming.read_to_end(&mut received).await.unwrap();
+    assert!(received == data);
+    sender.await.unwrap();
+}
@@ -0,0 +1,160 @@
+/// Size of the fixed packet header.
+pub(super) const HEADER_SIZE: usize = 20;
+/// Protocol version in every header.
+const VERSION: u8 = 1;
+/// Extension type of a selective ACK.
+const SELECTIVE_ACK: u8 = 1;
+
+/// Type of a uTP packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum PacketType {
+    /// Payload data.
+    Data = 0,
+    /// The sender won't send data past this packet.
+    Fin = 1,
+    /// Acknowledgement without data.
+    State = 2,
+    /// The connection is terminated forcefully.
+    Reset = 3,
+    /// Opens a connection.
+    Syn = 4,
+}
+
+/// uTP packet (BEP 29).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Packet {
+    pub kind: PacketType,
+    pub connection_id: u16,
+    /// Time of sending, in microseconds of the sender's clock.
+    pub timestamp: u32,
+    /// Difference between the time the sender received its last packet and that packet's
+    /// timestamp, in microseconds.
+    pub timestamp_difference: u32,
+    /// Bytes the sender can still receive.
+    pub window: u32,
+    pub seq_nr: u16,
+    /// Last sequence number received in order.
+    pub ack_nr: u16,
+    /// Bit `i` is set if packet `ack_nr + 2 + i` was received, in bytes of 8 bits each.
+    pub selective_ack: Option<Vec<u8>>,
+    pub payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Create a [`Packet`] of `kind` with `seq_nr` and `payload`, other fields are set
+    /// when it's sent.
+    pub fn new(kind: PacketType, seq_nr: u16, payload: Vec<u8>) -> Self {
+        Self {
+            kind,
+            connection_id: 0,
+            timestamp: 0,
+            timestamp_difference: 0,
+            window: 0,
+            seq_nr,
+            ack_nr: 0,
+            selective_ack: None,
+            payload,
+        }
+    }
+
+    /// Encode packet.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let sack_len = self.selective_ack.as_ref().map_or(0, |mask| mask.len() + 2);
+        let mut out = Vec::with_capacity(HEADER_SIZE + sack_len + self.payload.len());
+        out.push(((self.kind as u8) << 4) | VERSION);
+        out.push(match self.selective_ack {
+            Some(_) => SELECTIVE_ACK,
+            None => 0,
+        });
+        out.extend_from_slice(&self.connection_id.to_be_bytes());
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.timestamp_difference.to_be_bytes());
+        out.extend_from_slice(&self.window.to_be_bytes());
+        out.extend_from_slice(&self.seq_nr.to_be_bytes());
+        out.extend_from_slice(&self.ack_nr.to_be_bytes());
+        if let Some(mask) = &self.selective_ack {
+            out.push(0);
+            out.push(mask.len() as u8);
+            out.extend_from_slice(mask);
+        }
+        out.extend_from_slice(&self.payload);
+
+        out
+    }
+
+    /// Decode packet, or `None` if it isn't a valid uTP packet.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < HEADER_SIZE || bytes[0] & 0x0f != VERSION {
+            return None;
+        }
+        let kind = match bytes[0] >> 4 {
+            0 => PacketType::Data,
+            1 => PacketType::Fin,
+            2 => PacketType::State,
+            3 => PacketType::Reset,
+            4 => PacketType::Syn,
+            _ => return None,
+        };
+        let u16_at = |at: usize| u16::from_be_bytes([bytes[at], bytes[at + 1]]);
+        let u32_at = |at: usize| {
+            u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+
+        let mut selective_ack = None;
+        let mut extension = bytes[1];
+        let mut at = HEADER_SIZE;
+        while extension != 0 {
+            let (next, len) = (*bytes.get(at)?, *bytes.get(at + 1)? as usize);
+            let data = bytes.get(at + 2..at + 2 + len)?;
+            if extension == SELECTIVE_ACK {
+                if len == 0 || len % 4 != 0 {
+                    return None;
+                }
+                selective_ack = Some(data.to_vec());
+            }
+            extension = next;
+            at += 2 + len;
+        }
+
+        Some(Self {
+            kind,
+            connection_id: u16_at(2),
+            timestamp: u32_at(4),
+            timestamp_difference: u32_at(8),
+            window: u32_at(12),
+            seq_nr: u16_at(16),
+            ack_nr: u16_at(18),
+            selective_ack,
+            payload: bytes[at..].to_vec(),
+        })
+    }
+}
+
+#[test]
+fn test_packet_round_trip() {
+    let mut packet = Packet::new(PacketType::Data, 513, b"payload".to_vec());
+    packet.connection_id = 7;
+    packet.timestamp = 1_000_000;
+    packet.timestamp_difference = 2500;
+    packet.window = 65536;
+    packet.ack_nr = 40;
+    let bytes = packet.to_bytes();
+    assert_eq!(bytes[0], 0x01);
+    assert_eq!(bytes.len(), HEADER_SIZE + 7);
+    assert_eq!(Packet::from_bytes(&bytes), Some(packet.clone()));
+
+    packet.kind = PacketType::State;
+    packet.selective_ack = Some(vec![0b101, 0, 0, 0]);
+    packet.payload.clear();
+    let bytes = packet.to_bytes();
+    assert_eq!(bytes[0], 0x21);
+    assert_eq!(bytes[1], SELECTIVE_ACK);
+    assert_eq!(Packet::from_bytes(&bytes), Some(packet));
+
+    assert_eq!(Packet::from_bytes(&bytes[..HEADER_SIZE - 1]), None);
+    assert_eq!(Packet::from_bytes(&[0x02; HEADER_SIZE]), None);
+    assert_eq!(Packet::from_bytes(&[0x51; HEADER_SIZE]), None);
+    let mut truncated = bytes.clone();
+    truncated.truncate(HEADER_SIZE + 3);
+    assert_eq!(Packet::from_bytes(&truncated), None);
+}
@@ -0,0 +1,67 @@
+use std::net::IpAddr;
+
+/// Get the `k` pieces a peer at `ip` may request while choked, for a torrent with `info_hash`
+/// and `piece_count` pieces (BEP 6).
+///
+/// The set only depends on the peer's /24 network, so peers can't get more pieces for free
+/// by reconnecting from other addresses in it. BEP 6 only defines it for IPv4, IPv6 peers
+/// get a set for their /48 network in the same way.
+pub fn allowed_fast_set(
+    ip: IpAddr,
+    info_hash: &[u8; 20],
+    piece_count: usize,
+    k: usize,
+) -> Vec<u32> {
+    let network = match ip {
+        IpAddr::V4(ip) => [&ip.octets()[..3], &[0][..]].concat(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => [&ip.octets()[..3], &[0][..]].concat(),
+            None => [&ip.octets()[..6], &[0; 10][..]].concat(),
+        },
+    };
+    let k = k.min(piece_count);
+
+    let mut allowed = Vec::with_capacity(k);
+    let mut x = [&network[..], &info_hash[..]].concat();
+    while allowed.len() < k {
+        x = sha1_smol::Sha1::from(&x).digest().bytes().to_vec();
+        for chunk in x.chunks_exact(4) {
+            if allowed.len() == k {
+                break;
+            }
+            let y = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let index = y % piece_count as u32;
+            if !allowed.contains(&index) {
+                allowed.push(index);
+            }
+        }
+    }
+
+    allowed
+}
+
+#[test]
+fn test_allowed_fast_set() {
+    let ip = IpAddr::from([80, 4, 4, 200]);
+    let info_hash = [0xaa; 20];
+    assert_eq!(
+        allowed_fast_set(ip, &info_hash, 1313, 7),
+        [1059, 431, 808, 1217, 287, 376, 1188]
+    );
+    assert_eq!(
+        allowed_fast_set(ip, &info_hash, 1313, 9),
+        [1059, 431, 808, 1217, 287, 376, 1188, 353, 508]
+    );
+    assert_eq!(allowed_fast_set(ip, &info_hash, 3, 10).len(), 3);
+
+    let mapped = "::ffff:80.4.4.1".parse().unwrap();
+    assert_eq!(
+        allowed_fast_set(mapped, &info_hash, 1313, 7),
+        [1059, 431, 808, 1217, 287, 376, 1188]
+    );
+    let ip = "2001:db8:1::1".parse().unwrap();
+    let allowed = allowed_fast_set(ip, &info_hash, 1313, 7);
+    assert_eq!(allowed.len(), 7);
+    let neighbour = "2001:db8:1:ff::2".parse().unwrap();
+    assert_eq!(allowed_fast_set(neighbour, &info_hash, 1313, 7), allowed);
+}
@@ -0,0 +1,132 @@
+use crate::error::{Error, PeerError};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Protocol string sent at the start of every handshake.
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+/// Reserved byte and bit announcing the Fast Extension (BEP 6).
+const FAST_EXTENSION: (usize, u8) = (7, 0x04);
+/// Reserved byte and bit announcing the extension protocol (BEP 10).
+const EXTENSION_PROTOCOL: (usize, u8) = (5, 0x10);
+/// Reserved byte and bit announcing a DHT node (BEP 5).
+const DHT: (usize, u8) = (7, 0x01);
+
+/// Peer wire handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    /// Reserved bytes, used to announce extensions.
+    pub reserved: [u8; 8],
+    /// Info hash of the torrent.
+    pub info_hash: [u8; 20],
+    /// Peer ID of the sender.
+    pub peer_id: [u8; 20],
+}
+
+impl Handshake {
+    /// Create a [`Handshake`] without any extensions.
+    pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
+        Self {
+            reserved: [0; 8],
+            info_hash,
+            peer_id,
+        }
+    }
+
+    /// Announce support for the Fast Extension (BEP 6).
+    pub fn with_fast_extension(mut self) -> Self {
+        self.reserved[FAST_EXTENSION.0] |= FAST_EXTENSION.1;
+        self
+    }
+
+    /// Check whether the sender supports the Fast Extension (BEP 6).
+    pub fn has_fast_extension(&self) -> bool {
+        self.reserved[FAST_EXTENSION.0] & FAST_EXTENSION.1 != 0
+    }
+
+    /// Announce support for the extension protocol (BEP 10).
+    pub fn with_extension_protocol(mut self) -> Self {
+        self.reserved[EXTENSION_PROTOCOL.0] |= EXTENSION_PROTOCOL.1;
+        self
+    }
+
+    /// Check whether the sender supports the extension protocol (BEP 10).
+    pub fn has_extension_protocol(&self) -> bool {
+        self.reserved[EXTENSION_PROTOCOL.0] & EXTENSION_PROTOCOL.1 != 0
+    }
+
+    /// Announce that the sender runs a DHT node (BEP 5).
+    pub fn with_dht(mut self) -> Self {
+        self.reserved[DHT.0] |= DHT.1;
+        self
+    }
+
+    /// Check whether the sender runs a DHT node (BEP 5).
+    pub fn has_dht(&self) -> bool {
+        self.reserved[DHT.0] & DHT.1 != 0
+    }
+
+    /// Encode handshake.
+    pub fn to_bytes(&self) -> [u8; 68] {
+        let mut out = [0; 68];
+        out[0] = PROTOCOL.len() as u8;
+        out[1..20].copy_from_slice(PROTOCOL);
+        out[20..28].copy_from_slice(&self.reserved);
+        out[28..48].copy_from_slice(&self.info_hash);
+        out[48..68].copy_from_slice(&self.peer_id);
+
+        out
+    }
+
+    /// Check whether `bytes`, the first 20 bytes of a connection, start a handshake.
+    pub fn is_start(bytes: &[u8; 20]) -> bool {
+        bytes[0] as usize == PROTOCOL.len() && &bytes[1..] == PROTOCOL
+    }
+
+    /// Read a handshake from `reader`.
+    pub async fn read<R: AsyncRead + Unpin + ?Sized>(reader: &mut R) -> Result<Self, Error> {
+        let mut bytes = [0; 68];
+        reader.read_exact(&mut bytes).await?;
+
+        if bytes[0] as usize != PROTOCOL.len() || &bytes[1..20] != PROTOCOL {
+            return Err(PeerError::InvalidHandshake.into());
+        }
+
+        let mut out = Self::new([0; 20], [0; 20]);
+        out.reserved.copy_from_slice(&bytes[20..28]);
+        out.info_hash.copy_from_slice(&bytes[28..48]);
+        out.peer_id.copy_from_slice(&bytes[48..68]);
+
+        Ok(out)
+    }
+
+    /// Write handshake to `writer`.
+    pub async fn write<W: AsyncWrite + Unpin + ?Sized>(&self, writer: &mut W) -> Result<(), Error> {
+        writer.write_all(&self.to_bytes()).await?;
+        writer.flush().await?;
+
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_round_trip() {
+    let handshake = Handshake::new([1; 20], [2; 20]);
+    let bytes = handshake.to_bytes();
+
+    assert_eq!(Handshake::read(&mut &bytes[..]).await.unwrap(), handshake);
+    assert!(!handshake.has_fast_extension());
+
+    let handshake = handshake.with_fast_extension();
+    assert_eq!(handshake.to_bytes()[27], 0x04);
+    assert!(handshake.has_fast_extension());
+    assert!(!handshake.has_extension_protocol());
+
+    let handshake = handshake.with_extension_protocol();
+    assert_eq!(handshake.to_bytes()[25], 0x10);
+    assert!(handshake.has_extension_protocol());
+    assert!(!handshake.has_dht());
+
+    let handshake = handshake.with_dht();
+    assert_eq!(handshake.to_bytes()[27], 0x05);
+    assert!(handshake.has_dht());
+    assert!(Handshake::read(&mut &[0u8; 68][..]).await.is_err());
+}
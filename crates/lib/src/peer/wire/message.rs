@@ -0,0 +1,272 @@
+use crate::error::{Error, PeerError};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Size of the blocks pieces are requested in.
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+/// A block of a piece.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Block {
+    /// Piece index.
+    pub index: u32,
+    /// Byte offset within the piece.
+    pub begin: u32,
+    /// Length in bytes.
+    pub length: u32,
+}
+
+/// Peer wire message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    Request(Block),
+    Piece {
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    },
+    Cancel(Block),
+    Port(u16),
+    /// Hint that downloading a piece from the sender is a good idea (BEP 6).
+    SuggestPiece(u32),
+    /// The sender has all pieces, instead of a bitfield (BEP 6).
+    HaveAll,
+    /// The sender has no pieces, instead of a bitfield (BEP 6).
+    HaveNone,
+    /// A request won't be answered (BEP 6).
+    RejectRequest(Block),
+    /// A piece may be requested even while the sender chokes the receiver (BEP 6).
+    AllowedFast(u32),
+    /// Message of an extension, or the extension handshake if `id` is 0 (BEP 10).
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// Message with an id this implementation doesn't handle.
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl Message {
+    /// Encode message, including its length prefix.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let id = match self {
+            Message::KeepAlive => return vec![0; 4],
+            Message::Choke => 0,
+            Message::Unchoke => 1,
+            Message::Interested => 2,
+            Message::NotInterested => 3,
+            Message::Have(index) => {
+                payload.extend_from_slice(&index.to_be_bytes());
+                4
+            }
+            Message::Bitfield(bytes) => {
+                payload.extend_from_slice(bytes);
+                5
+            }
+            Message::Request(block) => {
+                write_block(&mut payload, block);
+                6
+            }
+            Message::Piece { index, begin, data } => {
+                payload.extend_from_slice(&index.to_be_bytes());
+                payload.extend_from_slice(&begin.to_be_bytes());
+                payload.extend_from_slice(data);
+                7
+            }
+            Message::Cancel(block) => {
+                write_block(&mut payload, block);
+                8
+            }
+            Message::Port(port) => {
+                payload.extend_from_slice(&port.to_be_bytes());
+                9
+            }
+            Message::SuggestPiece(index) => {
+                payload.extend_from_slice(&index.to_be_bytes());
+                0x0d
+            }
+            Message::HaveAll => 0x0e,
+            Message::HaveNone => 0x0f,
+            Message::RejectRequest(block) => {
+                write_block(&mut payload, block);
+                0x10
+            }
+            Message::AllowedFast(index) => {
+                payload.extend_from_slice(&index.to_be_bytes());
+                0x11
+            }
+            Message::Extended { id, payload: bytes } => {
+                payload.push(*id);
+                payload.extend_from_slice(bytes);
+                20
+            }
+            Message::Unknown { id, payload: bytes } => {
+                payload.extend_from_slice(bytes);
+                *id
+            }
+        };
+
+        let mut out = Vec::with_capacity(payload.len() + 5);
+        out.extend_from_slice(&(payload.len() as u32 + 1).to_be_bytes());
+        out.push(id);
+        out.append(&mut payload);
+
+        out
+    }
+
+    /// Decode message from its id and payload (without length prefix).
+    pub fn from_payload(id: u8, payload: &[u8]) -> Result<Self, PeerError> {
+        let invalid = || PeerError::InvalidMessage(id);
+        let expect_len = |len: usize| match payload.len() == len {
+            true => Ok(()),
+            false => Err(invalid()),
+        };
+
+        let message = match id {
+            0 => expect_len(0).map(|_| Message::Choke)?,
+            1 => expect_len(0).map(|_| Message::Unchoke)?,
+            2 => expect_len(0).map(|_| Message::Interested)?,
+            3 => expect_len(0).map(|_| Message::NotInterested)?,
+            4 => expect_len(4).map(|_| Message::Have(read_u32(payload, 0)))?,
+            5 => Message::Bitfield(payload.to_vec()),
+            6 => expect_len(12).map(|_| Message::Request(read_block(payload)))?,
+            7 if payload.len() >= 8 => Message::Piece {
+                index: read_u32(payload, 0),
+                begin: read_u32(payload, 4),
+                data: payload[8..].to_vec(),
+            },
+            7 => return Err(invalid()),
+            8 => expect_len(12).map(|_| Message::Cancel(read_block(payload)))?,
+            9 => expect_len(2)
+                .map(|_| Message::Port(u16::from_be_bytes([payload[0], payload[1]])))?,
+            0x0d => expect_len(4).map(|_| Message::SuggestPiece(read_u32(payload, 0)))?,
+            0x0e => expect_len(0).map(|_| Message::HaveAll)?,
+            0x0f => expect_len(0).map(|_| Message::HaveNone)?,
+            0x10 => expect_len(12).map(|_| Message::RejectRequest(read_block(payload)))?,
+            0x11 => expect_len(4).map(|_| Message::AllowedFast(read_u32(payload, 0)))?,
+            20 if !payload.is_empty() => Message::Extended {
+                id: payload[0],
+                payload: payload[1..].to_vec(),
+            },
+            20 => return Err(invalid()),
+            id => Message::Unknown {
+                id,
+                payload: payload.to_vec(),
+            },
+        };
+
+        Ok(message)
+    }
+
+    /// Read a message from `reader`, rejecting messages longer than `max_len`.
+    pub async fn read<R: AsyncRead + Unpin + ?Sized>(
+        reader: &mut R,
+        max_len: usize,
+    ) -> Result<Self, Error> {
+        let len = reader.read_u32().await? as usize;
+        if len == 0 {
+            return Ok(Message::KeepAlive);
+        }
+        if len > max_len {
+            return Err(PeerError::MessageTooLarge(len).into());
+        }
+
+        let id = reader.read_u8().await?;
+        let mut payload = vec![0; len - 1];
+        reader.read_exact(&mut payload).await?;
+
+        Ok(Self::from_payload(id, &payload)?)
+    }
+
+    /// Write message to `writer`.
+    pub async fn write<W: AsyncWrite + Unpin + ?Sized>(&self, writer: &mut W) -> Result<(), Error> {
+        writer.write_all(&self.to_bytes()).await?;
+        writer.flush().await?;
+
+        Ok(())
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn read_block(bytes: &[u8]) -> Block {
+    Block {
+        index: read_u32(bytes, 0),
+        begin: read_u32(bytes, 4),
+        length: read_u32(bytes, 8),
+    }
+}
+
+fn write_block(out: &mut Vec<u8>, block: &Block) {
+    out.extend_from_slice(&block.index.to_be_bytes());
+    out.extend_from_slice(&block.begin.to_be_bytes());
+    out.extend_from_slice(&block.length.to_be_bytes());
+}
+
+#[tokio::test]
+async fn test_message_round_trip() {
+    let messages = vec![
+        Message::KeepAlive,
+        Message::Choke,
+        Message::Unchoke,
+        Message::Interested,
+        Message::NotInterested,
+        Message::Have(7),
+        Message::Bitfield(vec![0xff, 0x80]),
+        Message::Request(Block {
+            index: 1,
+            begin: BLOCK_SIZE,
+            length: BLOCK_SIZE,
+        }),
+        Message::Piece {
+            index: 1,
+            begin: 0,
+            data: vec![1, 2, 3],
+        },
+        Message::Port(6881),
+        Message::SuggestPiece(3),
+        Message::HaveAll,
+        Message::HaveNone,
+        Message::RejectRequest(Block {
+            index: 2,
+            begin: 0,
+            length: BLOCK_SIZE,
+        }),
+        Message::AllowedFast(9),
+        Message::Extended {
+            id: 0,
+            payload: b"de".to_vec(),
+        },
+        Message::Unknown {
+            id: 21,
+            payload: vec![0],
+        },
+    ];
+
+    let mut bytes = Vec::new();
+    for message in &messages {
+        message.write(&mut bytes).await.unwrap();
+    }
+
+    let mut reader = &bytes[..];
+    for message in messages {
+        assert_eq!(Message::read(&mut reader, 1024).await.unwrap(), message);
+    }
+
+    let mut too_large = &[0, 0, 4, 1, 7][..];
+    assert!(Message::read(&mut too_large, 1024).await.is_err());
+    assert!(Message::from_payload(4, &[0, 0]).is_err());
+    assert!(Message::from_payload(20, &[]).is_err());
+}
@@ -0,0 +1,10 @@
+//! Peer wire protocol (BEP 3), with the Fast Extension (BEP 6) and the extension
+//! protocol (BEP 10).
+
+mod fast;
+mod handshake;
+mod message;
+
+pub use fast::allowed_fast_set;
+pub use handshake::Handshake;
+pub use message::{Block, Message, BLOCK_SIZE};
@@ -0,0 +1,253 @@
+//! Blocks kept in memory: received ones until their piece is verified and written in one go,
+//! and pieces read ahead for peers that download from us.
+
+use super::disk::DiskPool;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Arc;
+
+/// Received blocks of pieces that aren't verified yet, by piece index and offset.
+///
+/// Blocks count against the memory budget of the [`DiskPool`]. When it's exceeded, blocks
+/// are spilled: written to disk ahead of time, and read back to hash their piece.
+#[derive(Debug)]
+pub(super) struct WriteCache {
+    pieces: HashMap<u32, BTreeMap<u32, Slot>>,
+    /// Bytes of blocks held in memory.
+    bytes: usize,
+    disk: DiskPool,
+}
+
+/// A cached block.
+#[derive(Debug, Clone)]
+pub(super) enum Slot {
+    Memory(Arc<Vec<u8>>),
+    /// Being written to disk, still held in memory until that's done.
+    Spilling(Arc<Vec<u8>>),
+    /// Written to disk, with this length.
+    Spilled(usize),
+}
+
+impl WriteCache {
+    /// Create an empty [`WriteCache`], counting against the budget of `disk`.
+    pub fn new(disk: DiskPool) -> Self {
+        Self {
+            pieces: HashMap::new(),
+            bytes: 0,
+            disk,
+        }
+    }
+
+    /// Add block of piece `index` at offset `begin`, replacing any block there.
+    pub fn insert(&mut self, index: u32, begin: u32, data: Vec<u8>) {
+        self.hold(data.len());
+        let slot = Slot::Memory(Arc::new(data));
+        if let Some(old) = self.pieces.entry(index).or_default().insert(begin, slot) {
+            self.drop_slot(&old);
+        }
+    }
+
+    /// Pick blocks to spill, at least `amount` bytes if there are enough, and mark them as
+    /// being spilled. Blocks of the pieces with the fewest blocks go first, as those pieces
+    /// take the longest to complete.
+    pub fn take_spill(&mut self, amount: usize) -> Vec<(u32, u32, Arc<Vec<u8>>)> {
+        let mut indices = self.pieces.keys().copied().collect::<Vec<_>>();
+        indices.sort_unstable_by_key(|index| (self.pieces[index].len(), *index));
+
+        let mut taken = Vec::new();
+        let mut total = 0;
+        for index in indices {
+            for (begin, slot) in self.pieces.get_mut(&index).into_iter().flatten() {
+                if total >= amount {
+                    return taken;
+                }
+                if let Slot::Memory(data) = slot {
+                    total += data.len();
+                    taken.push((index, *begin, Arc::clone(data)));
+                    *slot = Slot::Spilling(Arc::clone(data));
+                }
+            }
+        }
+
+        taken
+    }
+
+    /// Register that `data` of the block of piece `index` at `begin` was written to disk,
+    /// unless the block was replaced or dropped meanwhile.
+    pub fn spilled(&mut self, index: u32, begin: u32, data: &Arc<Vec<u8>>) {
+        let slot = self
+            .pieces
+            .get_mut(&index)
+            .and_then(|blocks| blocks.get_mut(&begin));
+        let Some(slot) = slot else {
+            return;
+        };
+        if !matches!(&*slot, Slot::Spilling(spilling) if Arc::ptr_eq(spilling, data)) {
+            return;
+        }
+        *slot = Slot::Spilled(data.len());
+        self.release(data.len());
+    }
+
+    /// Keep block of piece `index` at `begin` in memory after writing `data` failed.
+    pub fn unspill(&mut self, index: u32, begin: u32, data: &Arc<Vec<u8>>) {
+        let slot = self
+            .pieces
+            .get_mut(&index)
+            .and_then(|blocks| blocks.get_mut(&begin));
+        if let Some(slot) = slot {
+            if matches!(&*slot, Slot::Spilling(spilling) if Arc::ptr_eq(spilling, data)) {
+                *slot = Slot::Memory(Arc::clone(data));
+            }
+        }
+    }
+
+    /// Check whether any block is being spilled.
+    pub fn is_spilling(&self) -> bool {
+        self.pieces
+            .values()
+            .flat_map(|blocks| blocks.values())
+            .any(|slot| matches!(slot, Slot::Spilling(_)))
+    }
+
+    /// Get blocks of piece `index` with their offsets, in order.
+    pub fn get(&self, index: u32) -> Vec<(u32, Slot)> {
+        let blocks = self.pieces.get(&index).into_iter().flatten();
+        blocks.map(|(begin, slot)| (*begin, slot.clone())).collect()
+    }
+
+    /// Remove blocks of piece `index`, getting the ones still in memory with their offsets.
+    pub fn remove(&mut self, index: u32) -> Vec<(u32, Arc<Vec<u8>>)> {
+        let blocks = self.pieces.remove(&index).unwrap_or_default();
+        blocks
+            .into_iter()
+            .filter_map(|(begin, slot)| {
+                self.drop_slot(&slot);
+                match slot {
+                    Slot::Memory(data) | Slot::Spilling(data) => Some((begin, data)),
+                    Slot::Spilled(_) => None,
+                }
+            })
+            .collect()
+    }
+
+    /// Stop counting the memory of dropped `slot`.
+    fn drop_slot(&mut self, slot: &Slot) {
+        if let Slot::Memory(data) | Slot::Spilling(data) = slot {
+            self.release(data.len());
+        }
+    }
+
+    /// Count `amount` more bytes held in memory.
+    fn hold(&mut self, amount: usize) {
+        self.bytes += amount;
+        self.disk.reserve(amount);
+    }
+
+    /// Count `amount` bytes held in memory less.
+    fn release(&mut self, amount: usize) {
+        self.bytes -= amount;
+        self.disk.release(amount);
+    }
+}
+
+impl Drop for WriteCache {
+    fn drop(&mut self) {
+        self.disk.release(self.bytes);
+    }
+}
+
+/// Most recently read pieces, up to a number of bytes.
+#[derive(Debug)]
+pub(super) struct ReadCache {
+    /// Pieces by index, most recently used last.
+    pieces: VecDeque<(u32, Arc<Vec<u8>>)>,
+    bytes: usize,
+    capacity: usize,
+}
+
+impl ReadCache {
+    /// Create an empty [`ReadCache`] of up to `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            pieces: VecDeque::new(),
+            bytes: 0,
+            capacity,
+        }
+    }
+
+    /// Get data of piece `index`, if it's cached.
+    pub fn get(&mut self, index: u32) -> Option<Arc<Vec<u8>>> {
+        let position = self.pieces.iter().position(|(i, _)| *i == index)?;
+        let entry = self.pieces.remove(position)?;
+        let data = Arc::clone(&entry.1);
+        self.pieces.push_back(entry);
+
+        Some(data)
+    }
+
+    /// Add `data` of piece `index`, dropping the least recently used pieces to make room.
+    pub fn insert(&mut self, index: u32, data: Arc<Vec<u8>>) {
+        self.remove(index);
+        self.bytes += data.len();
+        self.pieces.push_back((index, data));
+        while self.bytes > self.capacity {
+            let Some((_, data)) = self.pieces.pop_front() else {
+                break;
+            };
+            self.bytes -= data.len();
+        }
+    }
+
+    /// Drop piece `index`, if it's cached.
+    pub fn remove(&mut self, index: u32) {
+        if let Some(position) = self.pieces.iter().position(|(i, _)| *i == index) {
+            if let Some((_, data)) = self.pieces.remove(position) {
+                self.bytes -= data.len();
+            }
+        }
+    }
+}
+
+#[test]
+fn test_write_cache() {
+    let disk = DiskPool::new(1, 6).unwrap();
+    let mut cache = WriteCache::new(disk.clone());
+    cache.insert(0, 0, vec![1; 4]);
+    cache.insert(0, 4, vec![2; 4]);
+    cache.insert(1, 0, vec![3; 4]);
+    cache.insert(0, 4, vec![4; 4]);
+    assert_eq!(disk.get_excess(), 6);
+
+    // Blocks of the piece with the fewest go first.
+    let spill = cache.take_spill(disk.get_excess());
+    let spilled = spill.iter().map(|(i, b, _)| (*i, *b)).collect::<Vec<_>>();
+    assert_eq!(spilled, [(1, 0), (0, 0)]);
+    assert!(cache.take_spill(1).iter().any(|(i, _, _)| *i == 0));
+    assert!(cache.is_spilling());
+    let (index, begin, data) = &spill[0];
+    cache.spilled(*index, *begin, data);
+    assert_eq!(disk.get_excess(), 2);
+    assert!(matches!(cache.get(1)[..], [(0, Slot::Spilled(4))]));
+
+    let blocks = cache.remove(0);
+    assert_eq!(blocks.len(), 2);
+    assert_eq!(*blocks[1].1, [4; 4]);
+    cache.insert(2, 0, vec![5; 8]);
+    drop(cache);
+    assert_eq!(disk.get_excess(), 0);
+}
+
+#[test]
+fn test_read_cache() {
+    let mut cache = ReadCache::new(8);
+    cache.insert(0, Arc::new(vec![0; 4]));
+    cache.insert(1, Arc::new(vec![1; 4]));
+    assert!(cache.get(0).is_some());
+    cache.insert(2, Arc::new(vec![2; 4]));
+    assert!(cache.get(1).is_none());
+    assert_eq!(*cache.get(0).unwrap(), [0; 4]);
+
+    cache.remove(0);
+    cache.insert(3, Arc::new(vec![3; 16]));
+    assert!(cache.get(2).is_none() && cache.get(3).is_none());
+}
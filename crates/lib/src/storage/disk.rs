@@ -0,0 +1,222 @@
+//! Thread pool for blocking disk work: positional reads and writes, and hashing pieces.
+
+use std::fs::File;
+use std::io;
+use std::panic::AssertUnwindSafe;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use tokio::sync::futures::Notified;
+use tokio::sync::{oneshot, Notify};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads running disk jobs for all torrents of an agent, cheap to clone.
+///
+/// Also keeps count of the memory that torrents use to cache blocks before writing them,
+/// which should stay within a budget.
+#[derive(Debug, Clone)]
+pub(crate) struct DiskPool {
+    inner: Arc<PoolInner>,
+}
+
+#[derive(Debug)]
+struct PoolInner {
+    jobs: mpsc::Sender<Job>,
+    /// Bytes of blocks that may be cached in memory.
+    budget: usize,
+    /// Bytes of blocks cached in memory.
+    cached: AtomicUsize,
+    /// Notified when cached blocks are written or dropped.
+    released: Notify,
+}
+
+impl DiskPool {
+    /// Create [`DiskPool`] with `threads` threads, allowing `budget` bytes of cached blocks.
+    pub fn new(threads: usize, budget: usize) -> io::Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = Arc::clone(&receiver);
+            std::thread::Builder::new()
+                .name("rip-disk".to_string())
+                .spawn(move || loop {
+                    // Threads stop once every handle to the pool is gone.
+                    let job = receiver.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })?;
+        }
+
+        Ok(Self {
+            inner: Arc::new(PoolInner {
+                jobs,
+                budget,
+                cached: AtomicUsize::new(0),
+                released: Notify::new(),
+            }),
+        })
+    }
+
+    /// Run `job` on a thread of the pool, waiting until it's done.
+    pub async fn run<T, F>(&self, job: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job = Box::new(move || {
+            let _ = tx.send(std::panic::catch_unwind(AssertUnwindSafe(job)));
+        });
+        self.inner
+            .jobs
+            .send(job)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "disk pool stopped"))?;
+
+        match rx.await {
+            Ok(Ok(result)) => result,
+            _ => Err(io::Error::new(io::ErrorKind::Other, "disk job panicked")),
+        }
+    }
+
+    /// Get SHA1 hash of `data`, computed on the pool.
+    pub async fn hash(&self, data: Vec<u8>) -> io::Result<[u8; 20]> {
+        self.run(move || Ok(sha1_smol::Sha1::from(&data).digest().bytes()))
+            .await
+    }
+
+    /// Count `amount` more bytes of cached blocks.
+    pub fn reserve(&self, amount: usize) {
+        self.inner.cached.fetch_add(amount, Ordering::Relaxed);
+    }
+
+    /// Count `amount` bytes of cached blocks less, waking up tasks waiting for memory.
+    pub fn release(&self, amount: usize) {
+        self.inner.cached.fetch_sub(amount, Ordering::Relaxed);
+        self.inner.released.notify_waiters();
+    }
+
+    /// Get number of bytes cached beyond the budget.
+    pub fn get_excess(&self) -> usize {
+        let cached = self.inner.cached.load(Ordering::Relaxed);
+        cached.saturating_sub(self.inner.budget)
+    }
+
+    /// Get future that completes once cached blocks are released after this call.
+    pub fn released(&self) -> Notified<'_> {
+        self.inner.released.notified()
+    }
+}
+
+/// Open file at `path` for reading, and for writing if `writable`, in which case it's
+/// created with its parent directories if needed.
+pub(super) fn open(path: &Path, writable: bool) -> io::Result<File> {
+    if !writable {
+        return File::open(path);
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+}
+
+/// Fill `buf` from `file` at `offset`.
+#[cfg(unix)]
+pub(super) fn read_at(file: &File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+/// Fill `buf` from `file` at `offset`.
+#[cfg(windows)]
+pub(super) fn read_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Write all of `data` to `file` at `offset`.
+#[cfg(unix)]
+pub(super) fn write_at(file: &File, data: &[u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::write_all_at(file, data, offset)
+}
+
+/// Write all of `data` to `file` at `offset`.
+#[cfg(windows)]
+pub(super) fn write_at(file: &File, mut data: &[u8], mut offset: u64) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+
+    while !data.is_empty() {
+        match file.seek_write(data, offset) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                data = &data[n..];
+                offset += n as u64;
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_disk_pool() {
+    let pool = DiskPool::new(2, 10).unwrap();
+    let path = std::env::temp_dir().join(format!("rip-disk-{}", std::process::id()));
+
+    let file = Arc::new(open(&path, true).unwrap());
+    let handle = Arc::clone(&file);
+    pool.run(move || write_at(&handle, b"world", 6))
+        .await
+        .unwrap();
+    let handle = Arc::clone(&file);
+    pool.run(move || write_at(&handle, b"hello ", 0))
+        .await
+        .unwrap();
+    let read = pool
+        .run(move || {
+            let mut buf = vec![0; 11];
+            read_at(&file, &mut buf, 0)?;
+            Ok(buf)
+        })
+        .await
+        .unwrap();
+    assert_eq!(read, b"hello world");
+    assert_eq!(
+        pool.hash(read).await.unwrap(),
+        sha1_smol::Sha1::from("hello world").digest().bytes()
+    );
+    std::fs::remove_file(path).unwrap();
+
+    // A panicking job fails, but the pool keeps running.
+    assert!(pool.run(|| -> io::Result<()> { panic!() }).await.is_err());
+    assert_eq!(pool.run(|| Ok(1)).await.unwrap(), 1);
+
+    pool.reserve(15);
+    assert_eq!(pool.get_excess(), 5);
+    let released = pool.released();
+    pool.release(15);
+    released.await;
+    assert_eq!(pool.get_excess(), 0);
+}
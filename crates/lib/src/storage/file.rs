@@ -0,0 +1,758 @@
+//! Default [`Storage`]: files in a directory.
+
+use super::cache::{ReadCache, Slot, WriteCache};
+use super::disk::{self, DiskPool};
+use super::part::PartFile;
+use super::{layout, safe_component, PieceHashes, Storage, StorageFile};
+use crate::error::{Error, StorageError};
+use crate::prelude::*;
+use futures::future::BoxFuture;
+use std::collections::VecDeque;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+/// Number of open files kept per torrent.
+const MAX_HANDLES: usize = 64;
+/// Bytes of pieces read ahead per torrent, for peers that download from us.
+const READ_CACHE_SIZE: usize = 8 * 1024 * 1024;
+
+/// Open files with their index and whether they're open for writing, most recently used last.
+type Handles = VecDeque<(usize, Arc<std::fs::File>, bool)>;
+
+/// Maps the contiguous byte range of a torrent onto its files in a directory.
+///
+/// Data of skipped files that don't exist on disk goes to a partfile instead. Files are
+/// read and written on the [`DiskPool`], and received blocks are cached until their piece
+/// is verified, then written in one go.
+#[derive(Debug, Clone)]
+pub(crate) struct FileStorage {
+    /// Path of the top-level file or directory.
+    root: PathBuf,
+    files: Vec<StorageFile>,
+    piece_length: u64,
+    /// Whether each file is skipped.
+    skipped: Arc<RwLock<Vec<bool>>>,
+    /// Whether data of each file goes to the partfile, as it was skipped before it existed.
+    parked: Arc<RwLock<Vec<bool>>>,
+    parts: Arc<PartFile>,
+    disk: DiskPool,
+    handles: Arc<Mutex<Handles>>,
+    write_cache: Arc<Mutex<WriteCache>>,
+    read_cache: Arc<Mutex<ReadCache>>,
+}
+
+impl FileStorage {
+    /// Create [`FileStorage`] for torrent `info`, placed in `out`, doing disk work on `disk`.
+    pub fn new(info: &TorrentInfo, out: &Path, disk: DiskPool) -> Result<Self, Error> {
+        let (root, files) = layout(info, out)?;
+        let mut part_name = std::ffi::OsString::from(".");
+        part_name.push(safe_component(&info.name)?);
+        part_name.push(".parts");
+        let piece_length = info.piece_length as u64;
+        let parts = PartFile::new(out.join(part_name), piece_length, info.piece_count());
+
+        Ok(Self {
+            root,
+            skipped: Arc::new(RwLock::new(vec![false; files.len()])),
+            parked: Arc::new(RwLock::new(vec![false; files.len()])),
+            files,
+            piece_length,
+            parts: Arc::new(parts),
+            handles: Arc::default(),
+            write_cache: Arc::new(Mutex::new(WriteCache::new(disk.clone()))),
+            read_cache: Arc::new(Mutex::new(ReadCache::new(READ_CACHE_SIZE))),
+            disk,
+        })
+    }
+
+    /// Get torrent offset of `begin` in piece `index`.
+    fn offset(&self, index: u32, begin: u32) -> u64 {
+        index as u64 * self.piece_length + begin as u64
+    }
+
+    /// Get total length of the torrent.
+    fn length(&self) -> u64 {
+        self.files
+            .last()
+            .map_or(0, |file| file.offset + file.length)
+    }
+
+    /// Get the parts of the files covering `length` bytes from torrent `offset`,
+    /// as `(file index, file, offset in file, offset in range, length)`.
+    fn spans(
+        &self,
+        offset: u64,
+        length: u64,
+    ) -> impl Iterator<Item = (usize, &StorageFile, u64, usize, usize)> + '_ {
+        let end = offset + length;
+        self.files
+            .iter()
+            .enumerate()
+            .filter(move |(_, file)| file.offset < end && file.offset + file.length > offset)
+            .map(move |(index, file)| {
+                let start = offset.max(file.offset);
+                let stop = end.min(file.offset + file.length);
+                (
+                    index,
+                    file,
+                    start - file.offset,
+                    (start - offset) as usize,
+                    (stop - start) as usize,
+                )
+            })
+    }
+
+    /// Get the parts of the pieces covering `length` bytes from torrent `offset`,
+    /// as `(piece, offset in piece, offset in range, length)`.
+    fn piece_spans(&self, offset: u64, length: usize) -> Vec<(u32, u64, usize, usize)> {
+        let mut spans = Vec::new();
+        let mut at = 0;
+        while at < length {
+            let position = offset + at as u64;
+            let begin = position % self.piece_length;
+            let len = ((self.piece_length - begin) as usize).min(length - at);
+            spans.push(((position / self.piece_length) as u32, begin, at, len));
+            at += len;
+        }
+
+        spans
+    }
+
+    /// Check whether data of file `index` goes to the partfile.
+    fn is_parked(&self, index: usize) -> bool {
+        self.parked.read().unwrap_or_else(|e| e.into_inner())[index]
+    }
+
+    /// Read `length` bytes at torrent `offset`.
+    pub async fn read(&self, offset: u64, length: usize) -> Result<Vec<u8>, Error> {
+        let mut out = vec![0; length];
+        for (index, file, file_offset, at, len) in self.spans(offset, length as u64) {
+            if self.is_parked(index) {
+                let start = file.offset + file_offset;
+                for (piece, begin, part_at, part_len) in self.piece_spans(start, len) {
+                    let out = &mut out[at + part_at..at + part_at + part_len];
+                    self.parts.read(piece, begin, out).await?;
+                }
+                continue;
+            }
+
+            let handle = self.handle(index, false).await?;
+            let data = self
+                .disk
+                .run(move || {
+                    let mut data = vec![0; len];
+                    disk::read_at(&handle, &mut data, file_offset)?;
+                    Ok(data)
+                })
+                .await?;
+            out[at..at + len].copy_from_slice(&data);
+        }
+
+        Ok(out)
+    }
+
+    /// Write `data` at torrent `offset`, creating files and directories as needed.
+    pub async fn write(&self, offset: u64, data: &[u8]) -> Result<(), Error> {
+        for (index, file, file_offset, at, len) in self.spans(offset, data.len() as u64) {
+            if self.is_parked(index) {
+                let start = file.offset + file_offset;
+                for (piece, begin, part_at, part_len) in self.piece_spans(start, len) {
+                    let data = &data[at + part_at..at + part_at + part_len];
+                    self.parts.write(piece, begin, data).await?;
+                }
+                continue;
+            }
+
+            let handle = self.handle(index, true).await?;
+            let data = data[at..at + len].to_vec();
+            self.disk
+                .run(move || disk::write_at(&handle, &data, file_offset))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Get handle of file `index`, open for writing too if `writable`.
+    async fn handle(&self, index: usize, writable: bool) -> Result<Arc<std::fs::File>, Error> {
+        {
+            let mut handles = self.handles.lock().unwrap_or_else(|e| e.into_inner());
+            let position = handles
+                .iter()
+                .position(|(i, _, can_write)| *i == index && (*can_write || !writable));
+            if let Some(entry) = position.and_then(|position| handles.remove(position)) {
+                let handle = Arc::clone(&entry.1);
+                handles.push_back(entry);
+                return Ok(handle);
+            }
+        }
+
+        let path = self.files[index].path.clone();
+        let handle = self.disk.run(move || disk::open(&path, writable)).await?;
+        let handle = Arc::new(handle);
+        let mut handles = self.handles.lock().unwrap_or_else(|e| e.into_inner());
+        handles.retain(|(i, ..)| *i != index);
+        if handles.len() >= MAX_HANDLES {
+            handles.pop_front();
+        }
+        handles.push_back((index, Arc::clone(&handle), writable));
+
+        Ok(handle)
+    }
+
+    /// Write cached blocks to disk ahead of time while the memory budget is exceeded, or wait
+    /// for blocks being written, so peers are slowed down when the disk falls behind.
+    async fn spill(&self) -> Result<(), Error> {
+        loop {
+            let excess = self.disk.get_excess();
+            if excess == 0 {
+                return Ok(());
+            }
+            let released = self.disk.released();
+            let (blocks, spilling) = {
+                let cache = &mut self.write_cache.lock().unwrap_or_else(|e| e.into_inner());
+                (cache.take_spill(excess), cache.is_spilling())
+            };
+            if blocks.is_empty() {
+                if !spilling {
+                    return Ok(());
+                }
+                released.await;
+                continue;
+            }
+
+            for (i, (index, begin, data)) in blocks.iter().enumerate() {
+                if let Err(e) = self.write(self.offset(*index, *begin), data).await {
+                    let cache = &mut self.write_cache.lock().unwrap_or_else(|e| e.into_inner());
+                    for (index, begin, data) in &blocks[i..] {
+                        cache.unspill(*index, *begin, data);
+                    }
+                    return Err(e);
+                }
+                self.write_cache
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .spilled(*index, *begin, data);
+            }
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn files(&self) -> &[StorageFile] {
+        &self.files
+    }
+
+    /// Read `length` bytes at `begin` of verified piece `index` for a peer, reading the rest
+    /// of the piece ahead, as the peer likely requests it next.
+    fn read_block(
+        &self,
+        index: u32,
+        begin: u32,
+        length: usize,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        Box::pin(async move {
+            let offset = self.offset(index, 0);
+            let size = (self.length() - offset).min(self.piece_length) as usize;
+            if size > READ_CACHE_SIZE {
+                return self.read(offset + begin as u64, length).await;
+            }
+
+            let cached = self
+                .read_cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(index);
+            let piece = match cached {
+                Some(piece) => piece,
+                None => {
+                    let piece = Arc::new(self.read(offset, size).await?);
+                    self.read_cache
+                        .lock()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .insert(index, Arc::clone(&piece));
+                    piece
+                }
+            };
+
+            let begin = begin as usize;
+            match piece.get(begin..begin + length) {
+                Some(data) => Ok(data.to_vec()),
+                None => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+            }
+        })
+    }
+
+    fn read_piece(&self, index: u32, length: usize) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        Box::pin(self.read(self.offset(index, 0), length))
+    }
+
+    /// Cache block, writing cached blocks ahead of time if there are too many.
+    fn write_block(
+        &self,
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        self.write_cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(index, begin, data);
+
+        Box::pin(self.spill())
+    }
+
+    /// Hash cached piece `index` on the disk pool, reading back blocks that were spilled.
+    fn hash_piece(&self, index: u32) -> BoxFuture<'_, Result<PieceHashes, Error>> {
+        Box::pin(async move {
+            let slots = self
+                .write_cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .get(index);
+            let mut blocks = Vec::with_capacity(slots.len());
+            for (begin, slot) in slots {
+                blocks.push(match slot {
+                    Slot::Memory(data) | Slot::Spilling(data) => data,
+                    Slot::Spilled(length) => {
+                        Arc::new(self.read(self.offset(index, begin), length).await?)
+                    }
+                });
+            }
+
+            let hashes = self
+                .disk
+                .run(move || Ok(PieceHashes::new(blocks.iter().map(|block| &block[..]))))
+                .await?;
+
+            Ok(hashes)
+        })
+    }
+
+    /// Write cached piece `index` to disk, with one write per run of blocks in memory.
+    fn flush(&self, index: u32) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            let blocks = self
+                .write_cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(index);
+            self.read_cache
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(index);
+
+            let mut runs = Vec::<(u32, Vec<u8>)>::new();
+            for (begin, data) in blocks {
+                match runs.last_mut() {
+                    Some((start, run)) if *start as usize + run.len() == begin as usize => {
+                        run.extend_from_slice(&data);
+                    }
+                    _ => runs.push((begin, data.to_vec())),
+                }
+            }
+            for (begin, run) in runs {
+                self.write(self.offset(index, begin), &run).await?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn discard(&self, index: u32) -> BoxFuture<'_, Result<(), Error>> {
+        self.write_cache
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(index);
+
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Set which files are skipped, parking data of the skipped ones that don't exist on disk
+    /// in the partfile, and moving data of other files that don't exist out of it.
+    fn set_skipped(&self, skipped: Vec<bool>) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            let pieces = self.parts.pieces().await?;
+            let mut parked = vec![false; self.files.len()];
+            for (index, file) in self.files.iter().enumerate() {
+                if tokio::fs::metadata(&file.path).await.is_ok() {
+                    continue;
+                }
+                if skipped[index] {
+                    parked[index] = true;
+                    continue;
+                }
+
+                let end = file.offset + file.length;
+                for piece in &pieces {
+                    let piece_start = *piece as u64 * self.piece_length;
+                    let start = piece_start.max(file.offset);
+                    let stop = (piece_start + self.piece_length).min(end);
+                    if start >= stop {
+                        continue;
+                    }
+
+                    let mut data = vec![0; (stop - start) as usize];
+                    self.parts
+                        .read(*piece, start - piece_start, &mut data)
+                        .await?;
+                    let mut handle = open_for_writing(&file.path).await?;
+                    handle.seek(SeekFrom::Start(start - file.offset)).await?;
+                    handle.write_all(&data).await?;
+                }
+            }
+
+            let any_skipped = skipped.contains(&true);
+            *self.skipped.write().unwrap_or_else(|e| e.into_inner()) = skipped;
+            *self.parked.write().unwrap_or_else(|e| e.into_inner()) = parked;
+            if !any_skipped {
+                self.parts.delete().await?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Check that the rest of the files that aren't skipped fit on disk, then allocate them
+    /// as `mode` says.
+    fn allocate(&self, mode: AllocationMode) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            let skipped = self
+                .skipped
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone();
+            let mut needed = 0;
+            for (file, _) in self
+                .files
+                .iter()
+                .zip(&skipped)
+                .filter(|(_, skipped)| !**skipped)
+            {
+                let size = tokio::fs::metadata(&file.path).await.map_or(0, |m| m.len());
+                needed += file.length.saturating_sub(size);
+            }
+
+            let root = self.root.clone();
+            let available = self
+                .disk
+                .run(move || {
+                    // The download directory may not exist yet.
+                    let dir = root.ancestors().find(|dir| dir.exists());
+                    fs2::available_space(dir.unwrap_or(Path::new(".")))
+                })
+                .await?;
+            if needed > available {
+                return Err(StorageError::NotEnoughSpace { needed, available }.into());
+            }
+
+            if mode == AllocationMode::Compact {
+                return Ok(());
+            }
+            for (index, file) in self.files.iter().enumerate() {
+                if skipped[index] {
+                    continue;
+                }
+                let handle = self.handle(index, true).await?;
+                let length = file.length;
+                self.disk
+                    .run(move || match mode {
+                        AllocationMode::Full => fs2::FileExt::allocate(&*handle, length),
+                        _ if handle.metadata()?.len() < length => handle.set_len(length),
+                        _ => Ok(()),
+                    })
+                    .await?;
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Check whether any file or the partfile exists.
+    fn exists(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async move {
+            for path in self.files.iter().map(|file| file.path.as_path()) {
+                if tokio::fs::metadata(path).await.is_ok() {
+                    return true;
+                }
+            }
+
+            tokio::fs::metadata(self.parts.path()).await.is_ok()
+        })
+    }
+
+    /// Create any files that don't exist yet and aren't skipped, such as empty files that
+    /// never get written to.
+    fn create_missing(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            for (index, file) in self.files.iter().enumerate() {
+                if self.skipped.read().unwrap_or_else(|e| e.into_inner())[index] {
+                    continue;
+                }
+                if tokio::fs::metadata(&file.path).await.is_err() {
+                    open_for_writing(&file.path).await?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Delete the files of the torrent and the partfile, ignoring files that don't exist,
+    /// then the directories under the root that are left empty.
+    fn delete(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            self.handles
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .clear();
+            *self.read_cache.lock().unwrap_or_else(|e| e.into_inner()) =
+                ReadCache::new(READ_CACHE_SIZE);
+            self.parts.delete().await?;
+
+            for file in &self.files {
+                match tokio::fs::remove_file(&file.path).await {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+            // None of the skipped files exist anymore.
+            let skipped = self
+                .skipped
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone();
+            *self.parked.write().unwrap_or_else(|e| e.into_inner()) = skipped;
+
+            // Deepest first, so parents are empty once their children are gone.
+            let mut dirs: Vec<&Path> = self
+                .files
+                .iter()
+                .flat_map(|file| {
+                    let ancestors = file.path.ancestors().skip(1);
+                    ancestors.take_while(|dir| dir.starts_with(&self.root))
+                })
+                .collect();
+            dirs.sort_by_key(|dir| (std::cmp::Reverse(dir.components().count()), *dir));
+            dirs.dedup();
+            for dir in dirs {
+                // Fails for directories that still hold other files, which are kept.
+                let _ = tokio::fs::remove_dir(dir).await;
+            }
+
+            Ok(())
+        })
+    }
+}
+
+/// Open file for writing, creating it and its parent directories if needed.
+async fn open_for_writing(path: &Path) -> Result<tokio::fs::File, Error> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+
+    Ok(OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .await?)
+}
+
+#[tokio::test]
+async fn test_storage_spans() {
+    let out = std::env::temp_dir().join(format!("rip-storage-{}", std::process::id()));
+    let info = TorrentInfo {
+        files: vec![
+            File {
+                length: 3,
+                path: vec![b"a".to_vec()],
+                md5sum: None,
+            },
+            File {
+                length: 0,
+                path: vec![b"empty".to_vec()],
+                md5sum: None,
+            },
+            File {
+                length: 5,
+                path: vec![b"dir".to_vec(), b"b".to_vec()],
+                md5sum: None,
+            },
+        ],
+        name: b"name".to_vec(),
+        piece_length: 4,
+        pieces: vec![0; 40],
+        private: None,
+        is_single_file: false,
+    };
+    let storage = FileStorage::new(&info, &out, DiskPool::new(1, 0).unwrap()).unwrap();
+
+    storage.write(0, b"abcdefgh").await.unwrap();
+    storage.create_missing().await.unwrap();
+    assert_eq!(storage.read(2, 4).await.unwrap(), b"cdef");
+    assert_eq!(std::fs::read(out.join("name/a")).unwrap(), b"abc");
+    assert_eq!(std::fs::read(out.join("name/empty")).unwrap(), b"");
+    assert_eq!(std::fs::read(out.join("name/dir/b")).unwrap(), b"defgh");
+
+    storage.delete().await.unwrap();
+    assert!(!out.join("name").exists());
+
+    // Files that aren't part of the torrent are kept, with their directories.
+    storage.write(0, b"abcdefgh").await.unwrap();
+    std::fs::write(out.join("name/dir/other"), b"other").unwrap();
+    storage.delete().await.unwrap();
+    assert!(!out.join("name/a").exists());
+    assert!(!out.join("name/dir/b").exists());
+    assert_eq!(std::fs::read(out.join("name/dir/other")).unwrap(), b"other");
+    std::fs::remove_dir_all(out).unwrap();
+
+    let mut unsafe_info = info.clone();
+    unsafe_info.files[0].path = vec![b"..".to_vec()];
+    let disk = DiskPool::new(1, 0).unwrap();
+    assert!(FileStorage::new(&unsafe_info, Path::new("."), disk).is_err());
+}
+
+#[tokio::test]
+async fn test_storage_cache() {
+    let out = std::env::temp_dir().join(format!("rip-storage-cache-{}", std::process::id()));
+    let info = TorrentInfo {
+        files: vec![File {
+            length: 10,
+            path: Vec::new(),
+            md5sum: None,
+        }],
+        name: b"name".to_vec(),
+        piece_length: 8,
+        pieces: vec![0; 40],
+        private: None,
+        is_single_file: true,
+    };
+    let disk = DiskPool::new(2, 4).unwrap();
+    let storage = FileStorage::new(&info, &out, disk.clone()).unwrap();
+
+    // Blocks beyond the budget are written early, and read back to hash their piece.
+    storage.write_block(0, 0, b"abcd".to_vec()).await.unwrap();
+    storage.write_block(0, 4, b"efgh".to_vec()).await.unwrap();
+    assert_eq!(disk.get_excess(), 0);
+    let hashes = storage.hash_piece(0).await.unwrap();
+    assert_eq!(
+        hashes.piece,
+        sha1_smol::Sha1::from("abcdefgh").digest().bytes()
+    );
+    assert_eq!(hashes.blocks.len(), 2);
+    assert_eq!(
+        hashes.blocks[1],
+        sha1_smol::Sha1::from("efgh").digest().bytes()
+    );
+
+    storage.write_block(1, 0, b"ij".to_vec()).await.unwrap();
+    storage.flush(0).await.unwrap();
+    storage.flush(1).await.unwrap();
+    assert_eq!(std::fs::read(out.join("name")).unwrap(), b"abcdefghij");
+    assert_eq!(storage.read_block(0, 2, 4).await.unwrap(), b"cdef");
+    assert_eq!(storage.read_block(1, 0, 2).await.unwrap(), b"ij");
+    assert!(storage.read_block(1, 1, 2).await.is_err());
+
+    // Checking pieces doesn't push the ones peers read out of the cache.
+    *storage.read_cache.lock().unwrap() = ReadCache::new(READ_CACHE_SIZE);
+    assert_eq!(storage.read_piece(0, 8).await.unwrap(), b"abcdefgh");
+    assert!(storage.read_cache.lock().unwrap().get(0).is_none());
+
+    storage.write_block(1, 0, b"xx".to_vec()).await.unwrap();
+    storage.discard(1).await.unwrap();
+    assert!(storage.hash_piece(1).await.unwrap().blocks.is_empty());
+
+    storage.delete().await.unwrap();
+    std::fs::remove_dir_all(out).unwrap();
+}
+
+#[tokio::test]
+async fn test_storage_handles() {
+    let out = std::env::temp_dir().join(format!("rip-storage-handles-{}", std::process::id()));
+    let info = TorrentInfo {
+        files: (0..=MAX_HANDLES)
+            .map(|i| File {
+                length: 1,
+                path: vec![format!("{i}").into_bytes()],
+                md5sum: None,
+            })
+            .collect(),
+        name: b"name".to_vec(),
+        piece_length: 16,
+        pieces: vec![0; 100],
+        private: None,
+        is_single_file: false,
+    };
+    let storage = FileStorage::new(&info, &out, DiskPool::new(1, 0).unwrap()).unwrap();
+    storage.write(0, &[1; MAX_HANDLES + 1]).await.unwrap();
+    storage.handles.lock().unwrap().clear();
+
+    // The least recently used file is closed first, keeping the one in use open.
+    for i in 1..=MAX_HANDLES as u64 {
+        assert_eq!(storage.read(0, 1).await.unwrap(), [1]);
+        assert_eq!(storage.read(i, 1).await.unwrap(), [1]);
+    }
+    let handles = storage.handles.lock().unwrap().clone();
+    assert_eq!(handles.len(), MAX_HANDLES);
+    assert!(handles.iter().any(|(i, ..)| *i == 0));
+    assert!(handles.iter().all(|(i, ..)| *i != 1));
+
+    storage.delete().await.unwrap();
+    std::fs::remove_dir_all(out).unwrap();
+}
+
+#[tokio::test]
+async fn test_storage_allocate() {
+    let out = std::env::temp_dir().join(format!("rip-storage-allocate-{}", std::process::id()));
+    let file = |length, name: &[u8]| File {
+        length,
+        path: vec![name.to_vec()],
+        md5sum: None,
+    };
+    let info = TorrentInfo {
+        files: vec![file(1000, b"a"), file(10, b"b")],
+        name: b"name".to_vec(),
+        piece_length: 16,
+        pieces: vec![0; 20 * 64],
+        private: None,
+        is_single_file: false,
+    };
+    let storage = FileStorage::new(&info, &out, DiskPool::new(1, 0).unwrap()).unwrap();
+    let length = |name| std::fs::metadata(out.join("name").join(name)).map(|m| m.len());
+
+    // Compact mode leaves files to grow, the others create them at full length.
+    storage.allocate(AllocationMode::Compact).await.unwrap();
+    assert!(!storage.exists().await);
+    storage.set_skipped(vec![false, true]).await.unwrap();
+    storage.allocate(AllocationMode::Sparse).await.unwrap();
+    assert_eq!(length("a").unwrap(), 1000);
+    assert!(length("b").is_err());
+
+    // Data of the skipped file that doesn't exist is parked, until it's wanted again.
+    storage.write(992, b"abcdefghijklmnopqr").await.unwrap();
+    assert!(length("b").is_err());
+    assert_eq!(storage.read(998, 4).await.unwrap(), b"ghij");
+    storage.set_skipped(vec![false, false]).await.unwrap();
+    assert_eq!(std::fs::read(out.join("name/b")).unwrap(), b"ijklmnopqr");
+    storage.allocate(AllocationMode::Full).await.unwrap();
+    assert_eq!(length("b").unwrap(), 10);
+    storage.delete().await.unwrap();
+    std::fs::remove_dir_all(&out).unwrap();
+
+    let mut huge = info.clone();
+    huge.files[0].length = u64::MAX / 2;
+    let storage = FileStorage::new(&huge, &out, DiskPool::new(1, 0).unwrap()).unwrap();
+    let error = storage.allocate(AllocationMode::Compact).await.unwrap_err();
+    assert!(matches!(
+        error,
+        Error::Storage(StorageError::NotEnoughSpace { .. })
+    ));
+}
@@ -0,0 +1,180 @@
+//! [`Storage`] that keeps all data in memory.
+
+use super::{layout, PieceHashes, Storage, StorageFile};
+use crate::error::Error;
+use crate::peer::wire::BLOCK_SIZE;
+use crate::prelude::*;
+use futures::future::BoxFuture;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/// Keeps the data of a torrent in a buffer, such as to download without touching disk.
+#[derive(Debug)]
+pub struct MemoryStorage {
+    root: PathBuf,
+    files: Vec<StorageFile>,
+    piece_length: u64,
+    data: RwLock<Vec<u8>>,
+    /// Whether any data was written or given.
+    written: AtomicBool,
+}
+
+impl MemoryStorage {
+    /// Create empty [`MemoryStorage`] for torrent `info`.
+    pub fn new(info: &TorrentInfo) -> Result<Self, Error> {
+        let storage = Self::with_data(info, Vec::new())?;
+        storage.written.store(false, Ordering::Relaxed);
+
+        Ok(storage)
+    }
+
+    /// Create [`MemoryStorage`] for torrent `info` holding `data`, such as to seed it.
+    /// Missing data is filled with zeros.
+    pub fn with_data(info: &TorrentInfo, mut data: Vec<u8>) -> Result<Self, Error> {
+        let (root, files) = layout(info, Path::new(""))?;
+        data.resize(info.total_length() as usize, 0);
+
+        Ok(Self {
+            root,
+            files,
+            piece_length: info.piece_length as u64,
+            data: RwLock::new(data),
+            written: AtomicBool::new(true),
+        })
+    }
+
+    /// Get a copy of all data.
+    pub fn get_data(&self) -> Vec<u8> {
+        self.data.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Get the range of `length` bytes at `begin` of piece `index` within the data.
+    fn range(&self, index: u32, begin: u32, length: usize) -> Result<Range<usize>, Error> {
+        let start = (index as u64 * self.piece_length + begin as u64) as usize;
+        let total = self.data.read().unwrap_or_else(|e| e.into_inner()).len();
+        if begin as u64 + length as u64 > self.piece_length || start + length > total {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        Ok(start..start + length)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn root(&self) -> &Path {
+        &self.root
+    }
+
+    fn files(&self) -> &[StorageFile] {
+        &self.files
+    }
+
+    fn read_block(
+        &self,
+        index: u32,
+        begin: u32,
+        length: usize,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        Box::pin(async move {
+            let range = self.range(index, begin, length)?;
+            let data = self.data.read().unwrap_or_else(|e| e.into_inner());
+
+            Ok(data[range].to_vec())
+        })
+    }
+
+    fn write_block(
+        &self,
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async move {
+            let range = self.range(index, begin, data.len())?;
+            let mut stored = self.data.write().unwrap_or_else(|e| e.into_inner());
+            stored[range].copy_from_slice(&data);
+            self.written.store(true, Ordering::Relaxed);
+
+            Ok(())
+        })
+    }
+
+    /// Hash piece `index` off the async executor, taking blocks to be [`BLOCK_SIZE`] long.
+    fn hash_piece(&self, index: u32) -> BoxFuture<'_, Result<PieceHashes, Error>> {
+        Box::pin(async move {
+            let total = self.data.read().unwrap_or_else(|e| e.into_inner()).len() as u64;
+            let start = index as u64 * self.piece_length;
+            let size = self.piece_length.min(total.saturating_sub(start)) as usize;
+            let piece = self.read_block(index, 0, size).await?;
+
+            Ok(tokio::task::spawn_blocking(move || {
+                PieceHashes::new(piece.chunks(BLOCK_SIZE as usize))
+            })
+            .await?)
+        })
+    }
+
+    fn flush(&self, _index: u32) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn exists(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async { self.written.load(Ordering::Relaxed) })
+    }
+
+    fn delete(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async {
+            self.data.write().unwrap_or_else(|e| e.into_inner()).fill(0);
+            self.written.store(false, Ordering::Relaxed);
+
+            Ok(())
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_memory_storage() {
+    let info = TorrentInfo {
+        files: vec![
+            File {
+                length: 3,
+                path: vec![b"a".to_vec()],
+                md5sum: None,
+            },
+            File {
+                length: 5,
+                path: vec![b"dir".to_vec(), b"b".to_vec()],
+                md5sum: None,
+            },
+        ],
+        name: b"name".to_vec(),
+        piece_length: 6,
+        pieces: vec![0; 40],
+        private: None,
+        is_single_file: false,
+    };
+    let storage = MemoryStorage::new(&info).unwrap();
+    assert_eq!(storage.files()[1].path, Path::new("name/dir/b"));
+    assert_eq!(storage.files()[1].offset, 3);
+    assert!(!storage.exists().await);
+
+    storage.write_block(1, 0, b"gh".to_vec()).await.unwrap();
+    storage.write_block(0, 0, b"abcdef".to_vec()).await.unwrap();
+    assert!(storage.write_block(1, 1, b"gh".to_vec()).await.is_err());
+    assert!(storage.exists().await);
+    assert_eq!(storage.read_block(0, 2, 3).await.unwrap(), b"cde");
+    assert_eq!(storage.get_data(), b"abcdefgh");
+
+    let hashes = storage.hash_piece(1).await.unwrap();
+    assert_eq!(hashes.piece, sha1_smol::Sha1::from("gh").digest().bytes());
+    assert_eq!(hashes.blocks, [hashes.piece]);
+
+    storage.delete().await.unwrap();
+    assert!(!storage.exists().await);
+    let seed = MemoryStorage::with_data(&info, b"abc".to_vec()).unwrap();
+    assert_eq!(seed.get_data(), b"abc\0\0\0\0\0");
+    assert!(seed.exists().await);
+}
@@ -0,0 +1,164 @@
+//! Where the data of torrents goes: files in a directory by default, or any backend that
+//! implements [`Storage`].
+
+mod cache;
+mod disk;
+mod file;
+mod memory;
+mod part;
+
+use crate::error::{Error, MetainfoError};
+use crate::prelude::*;
+use futures::future::BoxFuture;
+use std::path::{Component, Path, PathBuf};
+
+pub(crate) use disk::DiskPool;
+pub(crate) use file::FileStorage;
+pub use memory::MemoryStorage;
+
+/// Backend storing the data of a torrent.
+///
+/// Blocks are written as they arrive, then their piece is hashed, and flushed once it
+/// passes or discarded once it fails. Only flushed pieces are read.
+pub trait Storage: Send + Sync + 'static {
+    /// Get path of the top-level file or directory, which needn't be on disk.
+    fn root(&self) -> &Path;
+
+    /// Get files, in torrent order.
+    fn files(&self) -> &[StorageFile];
+
+    /// Read `length` bytes at `begin` of piece `index`.
+    fn read_block(
+        &self,
+        index: u32,
+        begin: u32,
+        length: usize,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>>;
+
+    /// Read all `length` bytes of piece `index` to check it, bypassing any cache kept for
+    /// peers, as each piece is read once.
+    fn read_piece(&self, index: u32, length: usize) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        self.read_block(index, 0, length)
+    }
+
+    /// Write block `data` of piece `index` at `begin`, before the piece is verified.
+    ///
+    /// May wait while the backend falls behind, which slows down the peer that sent it.
+    fn write_block(
+        &self,
+        index: u32,
+        begin: u32,
+        data: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>>;
+
+    /// Hash piece `index`, all of whose blocks were written.
+    fn hash_piece(&self, index: u32) -> BoxFuture<'_, Result<PieceHashes, Error>>;
+
+    /// Keep piece `index` for good, as it passed its hash.
+    fn flush(&self, index: u32) -> BoxFuture<'_, Result<(), Error>>;
+
+    /// Drop blocks of piece `index`, as it failed its hash.
+    fn discard(&self, _index: u32) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Check whether any data may be stored already, so it's worth checking.
+    fn exists(&self) -> BoxFuture<'_, bool> {
+        Box::pin(async { true })
+    }
+
+    /// Set which files are skipped, which needn't be stored.
+    fn set_skipped(&self, _skipped: Vec<bool>) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Make room for the files that aren't skipped before downloading, as `mode` says.
+    fn allocate(&self, _mode: AllocationMode) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Create files that never get written, such as empty ones, once all pieces are done.
+    fn create_missing(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// Delete all data.
+    fn delete(&self) -> BoxFuture<'_, Result<(), Error>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+/// A file of a torrent, placed in a [`Storage`].
+#[derive(Debug, Clone)]
+pub struct StorageFile {
+    /// Path on disk.
+    pub path: PathBuf,
+    /// Offset of the file within the torrent, in bytes.
+    pub offset: u64,
+    /// Length in bytes.
+    pub length: u64,
+}
+
+/// Hashes of a downloaded piece.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PieceHashes {
+    pub piece: [u8; 20],
+    /// Hash of each block, in order.
+    pub blocks: Vec<[u8; 20]>,
+}
+
+impl PieceHashes {
+    /// Hash the piece made up of `blocks`.
+    pub fn new<'a>(blocks: impl IntoIterator<Item = &'a [u8]>) -> Self {
+        let mut piece = sha1_smol::Sha1::new();
+        let blocks = blocks
+            .into_iter()
+            .map(|block| {
+                piece.update(block);
+                sha1_smol::Sha1::from(block).digest().bytes()
+            })
+            .collect();
+
+        Self {
+            piece: piece.digest().bytes(),
+            blocks,
+        }
+    }
+}
+
+/// Get the top-level path of torrent `info` placed in `out`, and its files.
+fn layout(info: &TorrentInfo, out: &Path) -> Result<(PathBuf, Vec<StorageFile>), Error> {
+    let root = out.join(safe_component(&info.name)?);
+    let mut files = Vec::with_capacity(info.files.len());
+    let mut offset = 0;
+
+    for file in &info.files {
+        let mut path = root.clone();
+        for component in &file.path {
+            path.push(safe_component(component)?);
+        }
+        if !info.is_single_file && file.path.is_empty() {
+            return Err(MetainfoError::UnsafePath(String::new()).into());
+        }
+
+        files.push(StorageFile {
+            path,
+            offset,
+            length: file.length,
+        });
+        offset += file.length;
+    }
+
+    Ok((root, files))
+}
+
+/// Convert a name from the metainfo to a single, safe path component.
+fn safe_component(name: &[u8]) -> Result<PathBuf, Error> {
+    let name = String::from_utf8_lossy(name);
+    let mut components = Path::new(name.as_ref()).components();
+
+    match (components.next(), components.next()) {
+        (Some(Component::Normal(component)), None) => Ok(PathBuf::from(component)),
+        _ => Err(MetainfoError::UnsafePath(name.into_owned()).into()),
+    }
+}
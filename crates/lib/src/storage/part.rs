@@ -0,0 +1,151 @@
+use crate::error::Error;
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+/// Size of a slot number in the header.
+const ENTRY_SIZE: u64 = 4;
+
+/// File holding the parts of pieces that belong to skipped files, so those files aren't
+/// created on disk.
+///
+/// Starts with a header of one big-endian `u32` per piece, its slot plus one or 0 if it
+/// has none, followed by a slot of `piece_length` bytes for each piece with parts.
+#[derive(Debug)]
+pub(super) struct PartFile {
+    path: PathBuf,
+    piece_length: u64,
+    piece_count: usize,
+    /// Slot of each piece that has one, loaded from the header on first use.
+    slots: Mutex<Option<HashMap<u32, u32>>>,
+}
+
+impl PartFile {
+    /// Create [`PartFile`] at `path`, for pieces of `piece_length` bytes.
+    pub fn new(path: PathBuf, piece_length: u64, piece_count: usize) -> Self {
+        Self {
+            path,
+            piece_length,
+            piece_count,
+            slots: Mutex::new(None),
+        }
+    }
+
+    /// Get path on disk.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Get offset of `begin` within piece at `slot`.
+    fn position(&self, slot: u32, begin: u64) -> u64 {
+        self.piece_count as u64 * ENTRY_SIZE + slot as u64 * self.piece_length + begin
+    }
+
+    /// Read the header, if the file exists.
+    async fn load(&self) -> Result<HashMap<u32, u32>, Error> {
+        let mut slots = HashMap::new();
+        let mut header = vec![0; self.piece_count * ENTRY_SIZE as usize];
+        match tokio::fs::File::open(&self.path).await {
+            Ok(mut handle) => {
+                handle.read_exact(&mut header).await?;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(slots),
+            Err(e) => return Err(e.into()),
+        }
+
+        for (piece, entry) in header.chunks_exact(ENTRY_SIZE as usize).enumerate() {
+            let entry = u32::from_be_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            if entry > 0 {
+                slots.insert(piece as u32, entry - 1);
+            }
+        }
+
+        Ok(slots)
+    }
+
+    /// Get `slots`, reading them from the header first if that wasn't done yet.
+    async fn loaded<'a>(
+        &self,
+        slots: &'a mut Option<HashMap<u32, u32>>,
+    ) -> Result<&'a mut HashMap<u32, u32>, Error> {
+        if slots.is_none() {
+            *slots = Some(self.load().await?);
+        }
+
+        Ok(slots.get_or_insert_with(HashMap::new))
+    }
+
+    /// Get pieces that have parts stored.
+    pub async fn pieces(&self) -> Result<Vec<u32>, Error> {
+        let mut slots = self.slots.lock().await;
+
+        Ok(self.loaded(&mut slots).await?.keys().copied().collect())
+    }
+
+    /// Read `out.len()` bytes at `begin` of `piece`.
+    pub async fn read(&self, piece: u32, begin: u64, out: &mut [u8]) -> Result<(), Error> {
+        let mut slots = self.slots.lock().await;
+        let Some(slot) = self.loaded(&mut slots).await?.get(&piece).copied() else {
+            let message = format!("piece {piece} isn't in the partfile");
+            return Err(std::io::Error::new(std::io::ErrorKind::NotFound, message).into());
+        };
+
+        let mut handle = tokio::fs::File::open(&self.path).await?;
+        handle
+            .seek(SeekFrom::Start(self.position(slot, begin)))
+            .await?;
+        handle.read_exact(out).await?;
+
+        Ok(())
+    }
+
+    /// Write `data` at `begin` of `piece`, giving the piece a slot if it has none yet.
+    pub async fn write(&self, piece: u32, begin: u64, data: &[u8]) -> Result<(), Error> {
+        let mut slots = self.slots.lock().await;
+        let slots = self.loaded(&mut slots).await?;
+
+        let mut handle = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)
+            .await?;
+        let slot = match slots.get(&piece) {
+            Some(slot) => *slot,
+            None => {
+                let slot = slots.len() as u32;
+                if handle.metadata().await?.len() == 0 {
+                    handle.set_len(self.position(0, 0)).await?;
+                }
+                handle
+                    .seek(SeekFrom::Start(piece as u64 * ENTRY_SIZE))
+                    .await?;
+                handle.write_all(&(slot + 1).to_be_bytes()).await?;
+                slots.insert(piece, slot);
+                slot
+            }
+        };
+
+        handle
+            .seek(SeekFrom::Start(self.position(slot, begin)))
+            .await?;
+        handle.write_all(data).await?;
+
+        Ok(())
+    }
+
+    /// Delete the file, if it exists.
+    pub async fn delete(&self) -> Result<(), Error> {
+        let mut slots = self.slots.lock().await;
+        *slots = Some(HashMap::new());
+
+        match tokio::fs::remove_file(&self.path).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
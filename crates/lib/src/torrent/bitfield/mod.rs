@@ -0,0 +1,106 @@
+/// Set of pieces, stored as in the peer wire `bitfield` message (high bit first).
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: usize,
+}
+
+impl Bitfield {
+    /// Create an empty [`Bitfield`] for `len` pieces.
+    pub fn new(len: usize) -> Self {
+        Self {
+            bytes: vec![0; (len + 7) / 8],
+            len,
+        }
+    }
+
+    /// Create a full [`Bitfield`] for `len` pieces.
+    pub fn full(len: usize) -> Self {
+        let mut out = Self::new(len);
+        (0..len).for_each(|i| out.set(i));
+
+        out
+    }
+
+    /// Create [`Bitfield`] for `len` pieces from wire bytes, failing if the length or spare bits are wrong.
+    pub fn from_bytes(bytes: &[u8], len: usize) -> Option<Self> {
+        let out = Self {
+            bytes: bytes.to_vec(),
+            len,
+        };
+        let spare_bits_clear = (len..out.bytes.len() * 8).all(|i| !out.get_unchecked(i));
+
+        (bytes.len() == (len + 7) / 8 && spare_bits_clear).then_some(out)
+    }
+
+    /// Get wire bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Get number of pieces.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check whether there are no pieces at all.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Check whether piece `index` is set.
+    pub fn get(&self, index: usize) -> bool {
+        index < self.len && self.get_unchecked(index)
+    }
+
+    /// Set piece `index`, ignoring indices out of range.
+    pub fn set(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] |= 0x80 >> (index % 8);
+        }
+    }
+
+    /// Clear piece `index`, ignoring indices out of range.
+    pub fn clear(&mut self, index: usize) {
+        if index < self.len {
+            self.bytes[index / 8] &= !(0x80 >> (index % 8));
+        }
+    }
+
+    /// Get number of pieces set.
+    pub fn count(&self) -> usize {
+        self.bytes.iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    /// Check whether all pieces are set.
+    pub fn is_full(&self) -> bool {
+        self.count() == self.len
+    }
+
+    /// Iterate over indices of pieces set.
+    pub fn iter_set(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(|&i| self.get_unchecked(i))
+    }
+
+    fn get_unchecked(&self, index: usize) -> bool {
+        self.bytes[index / 8] & (0x80 >> (index % 8)) != 0
+    }
+}
+
+#[test]
+fn test_bitfield() {
+    let mut bitfield = Bitfield::new(10);
+    bitfield.set(0);
+    bitfield.set(9);
+    bitfield.set(10);
+
+    assert_eq!(bitfield.as_bytes(), &[0b1000_0000, 0b0100_0000]);
+    assert_eq!(bitfield.count(), 2);
+    assert_eq!(bitfield.iter_set().collect::<Vec<_>>(), vec![0, 9]);
+    assert!(!bitfield.get(10));
+    assert!(Bitfield::full(10).is_full());
+
+    assert_eq!(Bitfield::from_bytes(&[0x80, 0x40], 10), Some(bitfield));
+    assert_eq!(Bitfield::from_bytes(&[0x80, 0x20], 10), None);
+    assert_eq!(Bitfield::from_bytes(&[0x80], 10), None);
+}
@@ -0,0 +1,180 @@
+use crate::agent::RateLimits;
+use crate::peer::wire::BLOCK_SIZE;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use tokio::time::{Duration, Instant};
+
+/// Token bucket limiting a rate in bytes per second.
+///
+/// Taking more than is available is allowed, and makes the taker wait until the debt is paid,
+/// so transfers are paced rather than let through in bursts.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Bytes per second, or `None` for no limit.
+    rate: Option<u64>,
+    /// Bytes that can be taken right away, negative when in debt.
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    /// Add tokens for the time passed since the last update.
+    fn refill(&mut self, rate: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        let capacity = (rate / 10).max(BLOCK_SIZE as u64) as f64;
+        self.tokens = (self.tokens + elapsed * rate as f64).min(capacity);
+        self.updated = now;
+    }
+}
+
+impl RateLimiter {
+    /// Create a [`RateLimiter`] for `rate` bytes per second.
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            bucket: Mutex::new(Bucket {
+                rate,
+                tokens: 0.0,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Get rate in bytes per second.
+    pub fn get_rate(&self) -> Option<u64> {
+        self.bucket.lock().unwrap().rate
+    }
+
+    /// Change rate to `rate` bytes per second, taking effect for the next take.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().unwrap();
+        if let Some(old) = bucket.rate {
+            bucket.refill(old);
+        }
+        if rate.is_none() {
+            bucket.tokens = 0.0;
+        }
+        bucket.updated = Instant::now();
+        bucket.rate = rate;
+    }
+
+    /// Take `amount` bytes, and get how long to wait before using them.
+    fn take(&self, amount: u64) -> Duration {
+        let mut bucket = self.bucket.lock().unwrap();
+        let Some(rate) = bucket.rate else {
+            return Duration::ZERO;
+        };
+        bucket.refill(rate);
+        bucket.tokens -= amount as f64;
+
+        match bucket.tokens < 0.0 {
+            true => Duration::from_secs_f64(-bucket.tokens / rate as f64),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Take `amount` bytes from all `limiters`, waiting until the slowest of them allows it.
+pub(crate) async fn acquire(limiters: &[&RateLimiter], amount: u64) {
+    let wait = limiters
+        .iter()
+        .map(|limiter| limiter.take(amount))
+        .max()
+        .unwrap_or_default();
+    if !wait.is_zero() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Download and upload limiters of a level: the agent, a torrent or a peer.
+#[derive(Debug)]
+pub(crate) struct Limiters {
+    pub download: RateLimiter,
+    pub upload: RateLimiter,
+}
+
+impl Limiters {
+    /// Create [`Limiters`] with `limits`.
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            download: RateLimiter::new(limits.download),
+            upload: RateLimiter::new(limits.upload),
+        }
+    }
+
+    /// Get limits.
+    pub fn get(&self) -> RateLimits {
+        RateLimits {
+            download: self.download.get_rate(),
+            upload: self.upload.get_rate(),
+        }
+    }
+
+    /// Change limits to `limits`.
+    pub fn set(&self, limits: RateLimits) {
+        self.download.set_rate(limits.download);
+        self.upload.set_rate(limits.upload);
+    }
+}
+
+/// Whether `ip` is on the local network: loopback, link-local or a private range.
+pub(crate) fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || (first & 0xfe00) == 0xfc00
+                || (first & 0xffc0) == 0xfe80
+                || ip.to_ipv4_mapped().is_some_and(|ip| is_local(ip.into()))
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_limiter() {
+    let limiter = RateLimiter::new(Some(100_000));
+    let start = Instant::now();
+    for _ in 0..10 {
+        acquire(&[&limiter], 50_000).await;
+    }
+    // Debt of 500 KB at 100 KB/s, less what the bucket held at the start.
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(4800), "{elapsed:?}");
+    assert!(elapsed <= Duration::from_millis(5100), "{elapsed:?}");
+
+    // The slowest limiter decides.
+    let fast = RateLimiter::new(Some(1_000_000));
+    let slow = RateLimiter::new(Some(10_000));
+    tokio::time::sleep(Duration::from_secs(10)).await;
+    let start = Instant::now();
+    for _ in 0..4 {
+        acquire(&[&fast, &slow], 16_384).await;
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_secs(4), "{elapsed:?}");
+
+    // Unlimited after changing the rate.
+    slow.set_rate(None);
+    let start = Instant::now();
+    acquire(&[&slow], 1_000_000_000).await;
+    assert_eq!(start.elapsed(), Duration::ZERO);
+}
+
+#[test]
+fn test_is_local() {
+    assert!(is_local("127.0.0.1".parse().unwrap()));
+    assert!(is_local("192.168.1.20".parse().unwrap()));
+    assert!(is_local("10.1.2.3".parse().unwrap()));
+    assert!(is_local("172.20.0.1".parse().unwrap()));
+    assert!(is_local("fe80::1".parse().unwrap()));
+    assert!(is_local("fd00::1".parse().unwrap()));
+    assert!(is_local("::ffff:192.168.0.1".parse().unwrap()));
+    assert!(!is_local("8.8.8.8".parse().unwrap()));
+    assert!(!is_local("2001:db8::1".parse().unwrap()));
+}
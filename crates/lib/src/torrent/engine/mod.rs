@@ -0,0 +1,610 @@
+//! Runtime of a single torrent: checking existing data, announcing to the tracker,
+//! connecting to peers and exchanging pieces with them.
+
+mod limit;
+mod peer;
+mod swarm;
+
+use super::bitfield::Bitfield;
+use super::picker::Picker;
+use crate::agent::{AgentConfig, AgentEvent, RateLimits};
+use crate::dht::Dht;
+use crate::error::{Error, TrackerError};
+use crate::peer::wire::Handshake;
+use crate::peer::{Extensions, PeerStream, UtMetadata, UtPex, UtpSocket};
+use crate::prelude::*;
+use crate::storage::{DiskPool, Storage};
+use crate::torrent::TrackerRequest;
+use crate::util;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{broadcast, mpsc, watch, Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinSet;
+
+pub(crate) use limit::Limiters;
+use swarm::{SmartBan, Swarm};
+
+/// Number of pieces from the playhead on that get deadlines.
+const PLAYHEAD_PIECES: u64 = 8;
+/// Time between the deadlines of consecutive pieces from the playhead on.
+const PLAYHEAD_INTERVAL: Duration = Duration::from_secs(1);
+/// Interval between attempts to connect to more peers.
+const CONNECT_INTERVAL: Duration = Duration::from_secs(1);
+/// Time allowed for telling the tracker that a torrent stopped.
+const STOPPED_TIMEOUT: Duration = Duration::from_secs(5);
+/// Interval between lookups of peers in the DHT.
+const DHT_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// Interval between lookups of peers in the DHT until it's joined.
+const DHT_RETRY: Duration = Duration::from_secs(10);
+
+/// State of a torrent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TorrentState {
+    /// Waiting for a download slot.
+    Queued,
+    /// Verifying data already on disk.
+    Checking,
+    /// Downloading missing pieces.
+    Downloading,
+    /// All pieces are done, uploading to other peers.
+    Seeding,
+    /// Stopped by the user.
+    Paused,
+    /// Stopped because of an error.
+    Error(String),
+}
+
+/// Transfer counters of a torrent, kept across pauses.
+#[derive(Debug, Default)]
+pub(crate) struct Counters {
+    /// Bytes of piece data received.
+    pub downloaded: AtomicU64,
+    /// Bytes of piece data sent.
+    pub uploaded: AtomicU64,
+    /// Bytes of verified pieces.
+    pub verified: AtomicU64,
+    /// Number of connected peers.
+    pub peers: AtomicUsize,
+    /// Number of connected peers that have all pieces.
+    pub seeds: AtomicUsize,
+}
+
+/// Command sent to a running engine.
+pub(crate) enum EngineCommand {
+    /// Connect to a peer.
+    Connect(SocketAddr),
+    /// Take over an incoming connection, whose handshake has already been read.
+    Incoming(Box<PeerStream>, SocketAddr, Handshake),
+}
+
+/// Settings and resources shared by all torrents of an agent.
+pub(crate) struct Context {
+    pub peer_id: [u8; 20],
+    pub port: u16,
+    pub config: Arc<AgentConfig>,
+    /// Client for tracker requests.
+    pub http: reqwest::Client,
+    /// Permits for peer connections, limiting them over all torrents.
+    pub connections: Arc<Semaphore>,
+    /// Rate limits over all torrents.
+    pub limiters: Limiters,
+    /// Rate limits of each peer, followed by its session.
+    pub peer_limits: watch::Sender<RateLimits>,
+    /// Info hashes of all torrents, to find the one an encrypted incoming connection is for.
+    pub info_hashes: RwLock<HashSet<[u8; 20]>>,
+    /// Socket for uTP connections, on the listen port.
+    pub utp: UtpSocket,
+    /// Extensions of the peer protocol, added to the agent.
+    pub extensions: Arc<Extensions>,
+    /// Extension sending metadata of the torrents to peers, among `extensions`.
+    pub metadata: Arc<UtMetadata>,
+    /// Extension exchanging peers of the running torrents, among `extensions` if enabled.
+    pub pex: Arc<UtPex>,
+    /// DHT node, if enabled.
+    pub dht: Option<Dht>,
+    /// IPs of peers that sent corrupt data, which aren't connected to in any torrent.
+    pub banned: RwLock<HashSet<IpAddr>>,
+    /// Newly banned IPs, so sessions with them are closed.
+    pub bans: broadcast::Sender<IpAddr>,
+    /// Threads doing disk work for all torrents.
+    pub disk: DiskPool,
+    pub events: broadcast::Sender<AgentEvent>,
+}
+
+impl Context {
+    /// Check whether peers at `ip` are banned.
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let banned = self.banned.read().unwrap_or_else(|e| e.into_inner());
+        banned.contains(&ip)
+    }
+}
+
+/// State of a torrent, shared by the agent and the tasks of its engine.
+///
+/// Outlives the engine when it's paused, so a resumed torrent doesn't have to be checked again.
+pub(crate) struct Shared {
+    pub torrent: Arc<Torrent>,
+    pub info_hash: [u8; 20],
+    pub context: Arc<Context>,
+    pub storage: Arc<dyn Storage>,
+    pub counters: Counters,
+    /// Rate limits of this torrent.
+    pub limiters: Limiters,
+    pub state: watch::Sender<TorrentState>,
+    pub inner: Mutex<Inner>,
+    /// Indices of newly verified pieces, for `have` messages.
+    pub haves: broadcast::Sender<u32>,
+    /// Changed when file priorities change, so peers are asked for newly wanted pieces.
+    pub wanted: watch::Sender<()>,
+    /// Notified once all pieces are done.
+    pub completed: Notify,
+    /// Whether the tracker may know we're in the swarm, so it should be told when we leave.
+    pub announced: AtomicBool,
+}
+
+/// Mutable state of a torrent. Never held across an `.await`.
+pub(crate) struct Inner {
+    pub picker: Picker,
+    /// Whether data on disk was checked yet.
+    pub checked: bool,
+    /// Number of peers we're currently not choking.
+    pub unchoked: usize,
+    /// Priority of each file.
+    pub priorities: Vec<FilePriority>,
+    /// Whether new pieces are downloaded in order.
+    pub sequential: bool,
+    /// Download rate of each peer that sent us data, in bytes per second.
+    pub rates: HashMap<SocketAddr, u64>,
+    pub smart_ban: SmartBan,
+}
+
+impl Inner {
+    /// Check whether peer at `addr` is among the faster half of peers, which get pieces
+    /// with deadlines. Peers whose rate isn't known yet are only fast if no rates are.
+    pub fn is_fast(&self, addr: SocketAddr) -> bool {
+        let mut rates = self.rates.values().copied().collect::<Vec<_>>();
+        if rates.is_empty() {
+            return true;
+        }
+        rates.sort_unstable();
+        let median = rates[(rates.len() - 1) / 2];
+
+        self.rates.get(&addr).is_some_and(|rate| *rate >= median)
+    }
+}
+
+impl Shared {
+    /// Create [`Shared`] state for `torrent`, stored in `storage`.
+    pub fn new(torrent: Arc<Torrent>, storage: Arc<dyn Storage>, context: Arc<Context>) -> Self {
+        let mut info_hash = [0; 20];
+        info_hash.copy_from_slice(torrent.get_hash());
+        let picker = Picker::new(&torrent.info, Bitfield::new(torrent.info.piece_count()));
+        let priorities = vec![FilePriority::Normal; torrent.info.files.len()];
+
+        Self {
+            torrent,
+            info_hash,
+            context,
+            storage,
+            counters: Counters::default(),
+            limiters: Limiters::new(RateLimits::default()),
+            state: watch::channel(TorrentState::Queued).0,
+            inner: Mutex::new(Inner {
+                picker,
+                checked: false,
+                unchoked: 0,
+                priorities,
+                sequential: false,
+                rates: HashMap::new(),
+                smart_ban: SmartBan::default(),
+            }),
+            haves: broadcast::channel(64).0,
+            wanted: watch::channel(()).0,
+            completed: Notify::new(),
+            announced: AtomicBool::new(false),
+        }
+    }
+
+    /// Lock mutable state.
+    pub fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Send `event` to subscribers, if there are any.
+    pub fn emit(&self, event: AgentEvent) {
+        let _ = self.context.events.send(event);
+    }
+
+    /// Get info hash as a `Vec`, for events.
+    pub fn hash(&self) -> Vec<u8> {
+        self.info_hash.to_vec()
+    }
+
+    /// Ban peers at `ip` from all torrents, and disconnect them.
+    pub fn ban(&self, ip: IpAddr) {
+        let banned = &mut self
+            .context
+            .banned
+            .write()
+            .unwrap_or_else(|e| e.into_inner());
+        banned.insert(ip);
+        let _ = self.context.bans.send(ip);
+        self.emit(AgentEvent::PeerBanned {
+            hash: self.hash(),
+            ip,
+        });
+    }
+
+    /// Change state, and tell subscribers about it.
+    pub fn set_state(&self, state: TorrentState) {
+        self.state.send_replace(state.clone());
+        self.emit(AgentEvent::StateChanged {
+            hash: self.hash(),
+            state,
+        });
+    }
+
+    /// Get priority of each piece, the highest of the files it covers, given `priorities` of files.
+    fn piece_priorities(&self, priorities: &[FilePriority]) -> Vec<FilePriority> {
+        let mut pieces = vec![FilePriority::Skip; self.torrent.info.piece_count()];
+        for (index, priority) in priorities.iter().enumerate() {
+            for piece in self.file_pieces(index).into_iter().flatten() {
+                let piece = &mut pieces[piece as usize];
+                *piece = (*piece).max(*priority);
+            }
+        }
+
+        pieces
+    }
+
+    /// Set priority of each file, moving between downloading and seeding if that changes
+    /// whether all wanted pieces are done.
+    pub async fn set_file_priorities(&self, priorities: Vec<FilePriority>) -> Result<(), Error> {
+        let skipped = priorities
+            .iter()
+            .map(|priority| *priority == FilePriority::Skip)
+            .collect();
+        self.storage.set_skipped(skipped).await?;
+
+        let complete = {
+            let inner = &mut self.lock();
+            let pieces = self.piece_priorities(&priorities);
+            inner.picker.set_priorities(pieces);
+            inner.priorities = priorities;
+            inner.picker.is_complete()
+        };
+        self.wanted.send_replace(());
+        let state = self.state.borrow().clone();
+        match (state, complete) {
+            (TorrentState::Seeding, false) => self.set_state(TorrentState::Downloading),
+            (TorrentState::Downloading, true) => self.finish().await?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Set whether new pieces are downloaded in order, rather than rarest-first.
+    pub fn set_sequential(&self, sequential: bool) {
+        let inner = &mut self.lock();
+        inner.sequential = sequential;
+        inner.picker.set_sequential(sequential);
+    }
+
+    /// Give deadlines to the pieces following byte `offset` of file `index`, so they're
+    /// downloaded first, or clear all deadlines if `playhead` is `None`.
+    pub fn set_playhead(&self, playhead: Option<(usize, u64)>) {
+        let now = Instant::now();
+        let piece_length = self.torrent.info.piece_length as u64;
+        let pieces = playhead.and_then(|(index, offset)| {
+            let last = *self.file_pieces(index)?.end();
+            let first = (self.storage.files()[index].offset + offset) / piece_length;
+            Some(first..=last.min(first + PLAYHEAD_PIECES - 1))
+        });
+        let deadlines = pieces
+            .into_iter()
+            .flatten()
+            .zip(0..)
+            .map(|(piece, i)| (piece as u32, now + PLAYHEAD_INTERVAL * i));
+
+        self.lock().picker.set_deadlines(deadlines);
+        self.wanted.send_replace(());
+    }
+
+    /// Move from downloading to seeding once all wanted pieces are done.
+    pub async fn finish(&self) -> Result<(), Error> {
+        self.storage.create_missing().await?;
+        let finished = self.state.send_if_modified(|state| {
+            let downloading = *state == TorrentState::Downloading;
+            if downloading {
+                *state = TorrentState::Seeding;
+            }
+            downloading
+        });
+        if finished {
+            self.completed.notify_one();
+            self.emit(AgentEvent::StateChanged {
+                hash: self.hash(),
+                state: TorrentState::Seeding,
+            });
+            self.emit(AgentEvent::TorrentFinished { hash: self.hash() });
+        }
+
+        Ok(())
+    }
+
+    /// Get range of pieces covering file `index`, or `None` for empty files.
+    fn file_pieces(&self, index: usize) -> Option<std::ops::RangeInclusive<u64>> {
+        let file = &self.storage.files()[index];
+        let piece_length = self.torrent.info.piece_length as u64;
+
+        (file.length > 0)
+            .then(|| file.offset / piece_length..=(file.offset + file.length - 1) / piece_length)
+    }
+
+    /// Get bytes of each file covered by pieces in `have`.
+    pub fn file_progress(&self, have: &Bitfield) -> Vec<u64> {
+        let piece_length = self.torrent.info.piece_length as u64;
+
+        (0..self.storage.files().len())
+            .map(|index| {
+                let file = &self.storage.files()[index];
+                self.file_pieces(index)
+                    .into_iter()
+                    .flatten()
+                    .filter(|piece| have.get(*piece as usize))
+                    .map(|piece| {
+                        let start = (piece * piece_length).max(file.offset);
+                        let end = ((piece + 1) * piece_length).min(file.offset + file.length);
+                        end - start
+                    })
+                    .sum()
+            })
+            .collect()
+    }
+
+    /// Get files that piece `index` completes, given that the pieces in `have` are done.
+    pub fn completed_files(&self, index: u32, have: &Bitfield) -> Vec<usize> {
+        (0..self.storage.files().len())
+            .filter(|file| {
+                self.file_pieces(*file).is_some_and(|mut pieces| {
+                    pieces.contains(&(index as u64)) && pieces.all(|piece| have.get(piece as usize))
+                })
+            })
+            .collect()
+    }
+}
+
+/// Run torrent until an error occurs, or the task is aborted.
+pub(crate) async fn run(
+    shared: Arc<Shared>,
+    mut commands: mpsc::UnboundedReceiver<EngineCommand>,
+) -> Result<(), Error> {
+    if !shared.lock().checked {
+        shared.set_state(TorrentState::Checking);
+        let disk = &shared.context.disk;
+        let have = check(&shared.torrent, &*shared.storage, disk).await?;
+        let inner = &mut shared.lock();
+        inner.picker = Picker::new(&shared.torrent.info, have);
+        let pieces = shared.piece_priorities(&inner.priorities);
+        inner.picker.set_priorities(pieces);
+        let sequential = inner.sequential;
+        inner.picker.set_sequential(sequential);
+        inner.checked = true;
+        shared
+            .counters
+            .verified
+            .store(inner.picker.have_bytes(), Ordering::Relaxed);
+    }
+
+    if shared.lock().picker.is_complete() {
+        shared.storage.create_missing().await?;
+        shared.set_state(TorrentState::Seeding);
+    } else {
+        let allocation = shared.context.config.allocation;
+        shared.storage.allocate(allocation).await?;
+        shared.set_state(TorrentState::Downloading);
+    }
+
+    let mut peers = JoinSet::new();
+    let mut swarm = Swarm::default();
+    let (found_tx, mut found_rx) = mpsc::unbounded_channel();
+    let mut background = JoinSet::new();
+    if let Some(dht) = &shared.context.dht {
+        // Peers of private torrents (BEP 27) only come from their tracker.
+        if shared.torrent.info.private != Some(true) {
+            let dht = dht.clone();
+            background.spawn(lookup(Arc::clone(&shared), dht, found_tx.clone()));
+        }
+    }
+    background.spawn(announce(Arc::clone(&shared), found_tx));
+    let mut tick = tokio::time::interval(CONNECT_INTERVAL);
+
+    loop {
+        tokio::select! {
+            command = commands.recv() => match command {
+                Some(EngineCommand::Connect(addr)) => swarm.add(addr),
+                Some(EngineCommand::Incoming(stream, addr, handshake)) => {
+                    if let Some(permit) = admit(&shared, &mut swarm, addr) {
+                        let shared = Arc::clone(&shared);
+                        peers.spawn(peer::accept(shared, stream, addr, handshake, permit));
+                    }
+                }
+                None => return Ok(()),
+            },
+            Some(addr) = found_rx.recv() => swarm.add(addr),
+            Some(result) = peers.join_next() => {
+                if let Ok((addr, outcome)) = result {
+                    swarm.closed(addr, outcome, Instant::now());
+                }
+            }
+            _ = tick.tick() => {}
+        }
+
+        // Connect to the best known peers, while there are connections to spare.
+        while let Some(addr) = swarm.next(Instant::now(), |ip| shared.context.is_banned(ip)) {
+            let Some(permit) = admit(&shared, &mut swarm, addr) else {
+                break;
+            };
+            peers.spawn(peer::connect(Arc::clone(&shared), addr, permit));
+        }
+    }
+}
+
+/// Check whether a connection with `addr` is allowed, and if so, register it as connected.
+fn admit(shared: &Shared, swarm: &mut Swarm, addr: SocketAddr) -> Option<OwnedSemaphorePermit> {
+    if swarm.get_connected() >= shared.context.config.max_connections_per_torrent
+        || shared.context.is_banned(addr.ip())
+    {
+        return None;
+    }
+    let permit = Arc::clone(&shared.context.connections)
+        .try_acquire_owned()
+        .ok()?;
+
+    swarm.connect(addr).then_some(permit)
+}
+
+/// Check which pieces of the torrent are already in `storage`, hashing them on `disk`.
+pub(crate) async fn check(
+    torrent: &Torrent,
+    storage: &dyn Storage,
+    disk: &DiskPool,
+) -> Result<Bitfield, Error> {
+    let info = &torrent.info;
+    let mut have = Bitfield::new(info.piece_count());
+
+    if !storage.exists().await {
+        return Ok(have);
+    }
+
+    for index in 0..info.piece_count() {
+        let size = info.piece_size(index) as usize;
+
+        if let Ok(data) = storage.read_piece(index as u32, size).await {
+            let expected = info.piece_hash(index).unwrap_or_default();
+            if disk.hash(data).await? == expected {
+                have.set(index);
+            }
+        }
+    }
+
+    Ok(have)
+}
+
+/// Get public addresses of this host, which the tracker may not see, as it's reached over
+/// only one IP version.
+async fn public_ips() -> (Option<Ipv4Addr>, Option<Ipv6Addr>) {
+    // Documentation addresses, which are routed like any other public address.
+    let ipv4 = match util::source_ip(Ipv4Addr::new(192, 0, 2, 1).into()).await {
+        Some(IpAddr::V4(ip)) if !limit::is_local(ip.into()) => Some(ip),
+        _ => None,
+    };
+    let ipv6 = Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1);
+    let ipv6 = match util::source_ip(ipv6.into()).await {
+        Some(IpAddr::V6(ip)) if !limit::is_local(ip.into()) => Some(ip),
+        _ => None,
+    };
+
+    (ipv4, ipv6)
+}
+
+/// Create a request announcing `event` to the tracker of torrent `shared`.
+async fn announce_request(shared: &Shared, event: Option<&str>) -> TrackerRequest {
+    let counters = &shared.counters;
+    let verified = counters.verified.load(Ordering::Relaxed);
+    let mut request = Tracker::create_request(
+        &shared.torrent,
+        shared.context.peer_id,
+        shared.context.port,
+        counters.uploaded.load(Ordering::Relaxed),
+        counters.downloaded.load(Ordering::Relaxed),
+        shared.torrent.info.total_length().saturating_sub(verified),
+    );
+    request.event = event.map(str::to_string);
+    (request.ipv4, request.ipv6) = public_ips().await;
+
+    request
+}
+
+/// Announce to the tracker regularly, sending peers it returns to `found`.
+async fn announce(shared: Arc<Shared>, found: mpsc::UnboundedSender<SocketAddr>) {
+    let mut event = Some("started");
+    let mut failures = 0;
+    // Torrents that are complete from the start never send `completed`.
+    let mut completed = *shared.state.borrow() == TorrentState::Seeding;
+
+    loop {
+        if event.is_none() && !completed && *shared.state.borrow() == TorrentState::Seeding {
+            event = Some("completed");
+        }
+        let request = announce_request(&shared, event).await;
+        shared.announced.store(true, Ordering::Relaxed);
+        let wait = match request.send_with(&shared.context.http).await {
+            Ok(response) => {
+                failures = 0;
+                completed |= event == Some("completed");
+                event = None;
+                shared.emit(AgentEvent::Announced {
+                    hash: shared.hash(),
+                    peers: response.peers.len(),
+                });
+                for peer in &response.peers {
+                    if let Some(addr) = peer.addr() {
+                        let _ = found.send(addr);
+                    }
+                }
+                Duration::from_secs(response.interval.clamp(60, 3600) as u64)
+            }
+            Err(error) => {
+                failures += 1;
+                shared.emit(AgentEvent::AnnounceFailed {
+                    hash: shared.hash(),
+                    reason: error.to_string(),
+                });
+                match error {
+                    Error::Tracker(TrackerError::Failure {
+                        retry_in: Some(minutes),
+                        ..
+                    }) => Duration::from_secs(minutes.saturating_mul(60)),
+                    _ => Duration::from_secs(15 * 2u64.pow(failures.min(6))),
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = shared.completed.notified() => {}
+        }
+    }
+}
+
+/// Look up peers in the DHT regularly, announcing that we're one of them, and send those
+/// found to `found`.
+async fn lookup(shared: Arc<Shared>, dht: Dht, found: mpsc::UnboundedSender<SocketAddr>) {
+    loop {
+        let port = shared.context.port;
+        for addr in dht.get_peers(shared.info_hash, Some(port)).await {
+            let _ = found.send(addr);
+        }
+        let wait = match dht.is_empty() {
+            true => DHT_RETRY,
+            false => DHT_INTERVAL,
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// Tell the tracker that torrent `shared` left the swarm once its engine is stopped, if it
+/// may know we're in it. Gives up quickly, as the tracker forgets us eventually anyway.
+pub(crate) async fn stopped(shared: Arc<Shared>) {
+    if !shared.announced.swap(false, Ordering::Relaxed) {
+        return;
+    }
+
+    let request = announce_request(&shared, Some("stopped")).await;
+    let _ = tokio::time::timeout(STOPPED_TIMEOUT, request.send_with(&shared.context.http)).await;
+}
@@ -0,0 +1,880 @@
+use super::limit::{self, Limiters};
+use super::swarm::Outcome;
+use super::Shared;
+use crate::agent::{AgentEvent, EncryptionPolicy, RateLimits, Transport};
+use crate::error::{Error, PeerError};
+use crate::peer::wire::{allowed_fast_set, Block, Handshake, Message};
+use crate::peer::{mse, ExtendedHandshake, Extension, ExtensionPeer, PeerStream, PeerWriter};
+use crate::prelude::*;
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+use tokio::sync::{broadcast, mpsc, watch, OwnedSemaphorePermit};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+/// Number of block requests kept in flight per peer.
+const PIPELINE: usize = 16;
+/// Largest block a peer may request from us.
+const MAX_REQUEST: u32 = 128 * 1024;
+/// Time allowed for connecting and exchanging handshakes.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time after which a silent peer is dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+/// Interval between keep-alives and choke reviews.
+const TICK: Duration = Duration::from_secs(30);
+/// Shortest time over which a peer's download rate is measured.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+/// Number of pieces a peer may request while choked, with the Fast Extension.
+const ALLOWED_FAST: usize = 10;
+/// Number of most recent piece suggestions of a peer that are kept.
+const MAX_SUGGESTED: usize = 8;
+/// Number of outstanding requests we allow a peer, announced in the extension handshake.
+const MAX_PEER_REQUESTS: usize = 250;
+
+/// Connect to peer at `addr` and exchange pieces until either side disconnects,
+/// holding a connection `permit` meanwhile. Gets the address back with how it went.
+///
+/// The preferred transport is tried first, then the other one. Connections are encrypted
+/// as the encryption policy says, falling back to a plain connection if it allows that and
+/// the peer doesn't support encryption.
+pub(super) async fn connect(
+    shared: Arc<Shared>,
+    addr: SocketAddr,
+    permit: OwnedSemaphorePermit,
+) -> (SocketAddr, Outcome) {
+    let transport = shared.context.config.preferred_transport;
+    let opened = match open_with(&shared, addr, transport).await {
+        Ok(opened) => Ok(opened),
+        Err(_) => open_with(&shared, addr, transport.other()).await,
+    };
+    let outcome = match opened {
+        Ok((stream, handshake)) => run(shared, stream, addr, handshake, true).await,
+        Err(_) => Outcome::Failed,
+    };
+    drop(permit);
+
+    (addr, outcome)
+}
+
+/// Open connection to peer at `addr` over `transport`, encrypted as the encryption
+/// policy says.
+async fn open_with(
+    shared: &Shared,
+    addr: SocketAddr,
+    transport: Transport,
+) -> Result<(PeerStream, Handshake), Error> {
+    match shared.context.config.encryption {
+        EncryptionPolicy::Disabled => open(shared, addr, transport, None).await,
+        EncryptionPolicy::Enabled => {
+            let encrypt = Some(mse::RC4 | mse::PLAINTEXT);
+            match open(shared, addr, transport, encrypt).await {
+                Ok(opened) => Ok(opened),
+                Err(_) => open(shared, addr, transport, None).await,
+            }
+        }
+        EncryptionPolicy::Forced => open(shared, addr, transport, Some(mse::RC4)).await,
+    }
+}
+
+/// Open connection to peer at `addr` over `transport` and exchange handshakes, after an
+/// encryption handshake offering the methods in `encrypt` if set.
+async fn open(
+    shared: &Shared,
+    addr: SocketAddr,
+    transport: Transport,
+    encrypt: Option<u32>,
+) -> Result<(PeerStream, Handshake), Error> {
+    let mut stream = timeout(CONNECT_TIMEOUT, async {
+        match transport {
+            Transport::Tcp => TcpStream::connect(addr).await.map(PeerStream::tcp),
+            Transport::Utp => shared.context.utp.connect(addr).await.map(PeerStream::utp),
+        }
+    })
+    .await
+    .map_err(|_| PeerError::Timeout)??;
+
+    let handshake = timeout(CONNECT_TIMEOUT, async {
+        if let Some(provide) = encrypt {
+            mse::initiate(&mut stream, shared.info_hash, provide).await?;
+        }
+        our_handshake(shared).write(&mut stream).await?;
+        Handshake::read(&mut stream).await
+    })
+    .await
+    .map_err(|_| PeerError::Timeout)??;
+    if handshake.info_hash != shared.info_hash {
+        return Err(PeerError::UnknownTorrent.into());
+    }
+
+    Ok((stream, handshake))
+}
+
+/// Get our handshake for the torrent of `shared`.
+fn our_handshake(shared: &Shared) -> Handshake {
+    let handshake = Handshake::new(shared.info_hash, shared.context.peer_id)
+        .with_fast_extension()
+        .with_extension_protocol();
+
+    match shared.context.dht {
+        Some(_) => handshake.with_dht(),
+        None => handshake,
+    }
+}
+
+/// Take over incoming connection from `addr`, whose `handshake` was already read.
+pub(super) async fn accept(
+    shared: Arc<Shared>,
+    mut stream: Box<PeerStream>,
+    addr: SocketAddr,
+    handshake: Handshake,
+    permit: OwnedSemaphorePermit,
+) -> (SocketAddr, Outcome) {
+    let outcome = match our_handshake(&shared).write(&mut *stream).await {
+        Ok(()) => run(shared, *stream, addr, handshake, false).await,
+        Err(_) => Outcome::Failed,
+    };
+    drop(permit);
+
+    (addr, outcome)
+}
+
+/// Run session with a peer after handshakes were exchanged, unless the peer is ourselves.
+/// Connections we opened are `outgoing`.
+async fn run(
+    shared: Arc<Shared>,
+    stream: PeerStream,
+    addr: SocketAddr,
+    handshake: Handshake,
+    outgoing: bool,
+) -> Outcome {
+    if handshake.peer_id == shared.context.peer_id {
+        return Outcome::Own;
+    }
+
+    let piece_count = shared.torrent.info.piece_count();
+    let max_len = MAX_REQUEST as usize + 9 + piece_count / 8 + 1;
+    let limited = !(shared.context.config.exempt_local_peers && limit::is_local(addr.ip()));
+    let peer_limits = shared.context.peer_limits.subscribe();
+    let limiters = Arc::new(Limiters::new(*peer_limits.borrow()));
+
+    let encrypted = stream.is_encrypted();
+    let transport = stream.get_transport();
+    let (mut reader, writer) = stream.into_split();
+    let (tx, messages) = mpsc::channel(64);
+    let reader = tokio::spawn({
+        let shared = Arc::clone(&shared);
+        let limiters = Arc::clone(&limiters);
+        async move {
+            loop {
+                let message = Message::read(&mut reader, max_len).await;
+                let failed = message.is_err();
+                // Waiting before reading on slows the peer down through TCP flow control.
+                if let (true, Ok(Message::Piece { data, .. })) = (limited, &message) {
+                    let levels = [
+                        &shared.context.limiters.download,
+                        &shared.limiters.download,
+                        &limiters.download,
+                    ];
+                    limit::acquire(&levels, data.len() as u64).await;
+                }
+                if tx.send(message).await.is_err() || failed {
+                    break;
+                }
+            }
+        }
+    });
+
+    shared.counters.peers.fetch_add(1, Ordering::Relaxed);
+    shared.emit(AgentEvent::PeerConnected {
+        hash: shared.hash(),
+        addr,
+        client: ClientId::parse(&handshake.peer_id),
+        encrypted,
+        transport,
+    });
+    let haves = shared.haves.subscribe();
+    let wanted = shared.wanted.subscribe();
+    let bans = shared.context.bans.subscribe();
+    let (outbox, extension_messages) = mpsc::unbounded_channel();
+    let extensions = shared.context.extensions.get();
+    let dht = handshake.has_dht() && shared.context.dht.is_some();
+    let mut session = Session {
+        bitfield: Bitfield::new(piece_count),
+        shared,
+        addr,
+        outgoing,
+        listen_addr: None,
+        fast_extension: handshake.has_fast_extension(),
+        allowed_fast: Vec::new(),
+        allowed_to_peer: Vec::new(),
+        suggested: Vec::new(),
+        extension_protocol: handshake.has_extension_protocol(),
+        dht,
+        extensions,
+        remote: None,
+        outbox,
+        max_requests: PIPELINE,
+        writer,
+        reader,
+        limited,
+        limiters,
+        is_seed: false,
+        am_choking: true,
+        am_interested: false,
+        peer_choking: true,
+        peer_interested: false,
+        outstanding: Vec::new(),
+        received: 0,
+        window: Instant::now(),
+        delivered: 0,
+        writing: None,
+    };
+
+    let result = session
+        .run(
+            messages,
+            extension_messages,
+            haves,
+            wanted,
+            bans,
+            peer_limits,
+        )
+        .await;
+    session.shared.emit(AgentEvent::PeerDisconnected {
+        hash: session.shared.hash(),
+        addr,
+        reason: result.as_ref().err().map(Error::to_string),
+    });
+
+    Outcome::Closed {
+        delivered: session.delivered,
+    }
+}
+
+/// Connection to a peer. Gives back its requests and slots when dropped.
+struct Session {
+    shared: Arc<Shared>,
+    addr: SocketAddr,
+    /// Whether we opened the connection, to the address the peer listens on.
+    outgoing: bool,
+    /// Address the peer listens on, once known, for peer exchange.
+    listen_addr: Option<SocketAddr>,
+    writer: PeerWriter,
+    reader: JoinHandle<()>,
+    /// Whether rate limits apply to the peer, they don't to local peers if so configured.
+    limited: bool,
+    /// Rate limits of the peer.
+    limiters: Arc<Limiters>,
+    /// Whether both sides support the Fast Extension (BEP 6).
+    fast_extension: bool,
+    /// Pieces we may request while the peer chokes us.
+    allowed_fast: Vec<u32>,
+    /// Pieces the peer may request while we choke it.
+    allowed_to_peer: Vec<u32>,
+    /// Pieces the peer suggested, most recent last.
+    suggested: Vec<u32>,
+    /// Whether both sides support the extension protocol (BEP 10).
+    extension_protocol: bool,
+    /// Whether both sides run a DHT node (BEP 5).
+    dht: bool,
+    /// Extensions of the agent when the connection opened, extension `i` has message ID `i + 1`.
+    extensions: Vec<Arc<dyn Extension>>,
+    /// Extension handshake of the peer, once received.
+    remote: Option<Arc<ExtendedHandshake>>,
+    /// Messages extensions send to the peer, by index of the extension.
+    outbox: mpsc::UnboundedSender<(usize, Vec<u8>)>,
+    /// Number of block requests kept in flight, the peer may allow fewer than usual.
+    max_requests: usize,
+    /// Pieces the peer has.
+    bitfield: Bitfield,
+    /// Whether the peer has all pieces, and is counted as a seed.
+    is_seed: bool,
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+    /// Blocks requested from the peer that haven't arrived yet.
+    outstanding: Vec<Block>,
+    /// Bytes of piece data received since `window` started, for the download rate.
+    received: u64,
+    window: Instant,
+    /// Bytes of piece data received over the whole session.
+    delivered: u64,
+    /// Block being written to storage, reserved in the picker meanwhile.
+    writing: Option<Block>,
+}
+
+impl Session {
+    /// Exchange messages with the peer until either side closes the connection.
+    async fn run(
+        &mut self,
+        mut messages: mpsc::Receiver<Result<Message, Error>>,
+        mut extension_messages: mpsc::UnboundedReceiver<(usize, Vec<u8>)>,
+        mut haves: broadcast::Receiver<u32>,
+        mut wanted: watch::Receiver<()>,
+        mut bans: broadcast::Receiver<IpAddr>,
+        mut peer_limits: watch::Receiver<RateLimits>,
+    ) -> Result<(), Error> {
+        if self.outgoing {
+            self.set_listen_addr(self.addr);
+        }
+        let have = self.shared.lock().picker.have().clone();
+        match (self.fast_extension, have.count()) {
+            (true, 0) => self.send(Message::HaveNone).await?,
+            (true, _) if have.is_full() => self.send(Message::HaveAll).await?,
+            (false, 0) => {}
+            _ => {
+                self.send(Message::Bitfield(have.as_bytes().to_vec()))
+                    .await?
+            }
+        }
+        if self.fast_extension {
+            let info_hash = &self.shared.info_hash;
+            self.allowed_to_peer =
+                allowed_fast_set(self.addr.ip(), info_hash, have.len(), ALLOWED_FAST);
+            for index in self.allowed_to_peer.clone() {
+                self.send(Message::AllowedFast(index)).await?;
+            }
+        }
+        if self.extension_protocol {
+            let payload = self.local_handshake().to_bytes();
+            self.send(Message::Extended { id: 0, payload }).await?;
+        }
+        if self.dht {
+            self.send(Message::Port(self.shared.context.port)).await?;
+        }
+
+        let mut tick = tokio::time::interval(TICK);
+        let mut last_received = Instant::now();
+
+        loop {
+            tokio::select! {
+                message = messages.recv() => match message {
+                    Some(message) => {
+                        last_received = Instant::now();
+                        self.handle(message?).await?;
+                    }
+                    None => return Ok(()),
+                },
+                index = haves.recv() => match index {
+                    Ok(index) => {
+                        self.send(Message::Have(index)).await?;
+                        self.update_interest().await?;
+                    }
+                    Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                Some((index, payload)) = extension_messages.recv() => {
+                    self.send_extended(index, payload).await?;
+                }
+                Ok(()) = wanted.changed() => self.update_interest().await?,
+                ip = bans.recv() => match ip {
+                    Ok(ip) if ip == self.addr.ip() => return Err(PeerError::Banned.into()),
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => {}
+                    Err(broadcast::error::RecvError::Closed) => return Ok(()),
+                },
+                Ok(()) = peer_limits.changed() => {
+                    self.limiters.set(*peer_limits.borrow());
+                }
+                _ = tick.tick() => {
+                    if last_received.elapsed() > IDLE_TIMEOUT {
+                        return Err(PeerError::Timeout.into());
+                    }
+                    self.send(Message::KeepAlive).await?;
+                    self.update_choke().await?;
+                }
+            }
+
+            self.request_more().await?;
+        }
+    }
+
+    /// Send `message` to the peer.
+    async fn send(&mut self, message: Message) -> Result<(), Error> {
+        message.write(&mut self.writer).await
+    }
+
+    /// Handle `message` received from the peer.
+    async fn handle(&mut self, message: Message) -> Result<(), Error> {
+        match message {
+            Message::KeepAlive | Message::Unknown { .. } => {}
+            // The DHT node of the peer listens on `port`, add it to the routing table.
+            Message::Port(port) => {
+                if let (Some(dht), true) = (&self.shared.context.dht, port != 0) {
+                    let dht = dht.clone();
+                    let addr = SocketAddr::new(self.addr.ip(), port);
+                    tokio::spawn(async move { dht.add_node(addr).await });
+                }
+            }
+            Message::SuggestPiece(_)
+            | Message::HaveAll
+            | Message::HaveNone
+            | Message::RejectRequest(_)
+            | Message::AllowedFast(_)
+                if !self.fast_extension =>
+            {
+                return Err(PeerError::NotNegotiated.into());
+            }
+            Message::Extended { .. } if !self.extension_protocol => {
+                return Err(PeerError::NotNegotiated.into());
+            }
+            Message::Extended { id: 0, payload } => self.handle_handshake(&payload)?,
+            Message::Extended { id, payload } => self.handle_extended(id, &payload)?,
+            // With the Fast Extension, requests are only dropped by rejecting each one.
+            Message::Choke => {
+                self.peer_choking = true;
+                if !self.fast_extension {
+                    self.cancel_outstanding();
+                }
+            }
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => {
+                self.peer_interested = true;
+                self.update_choke().await?;
+            }
+            Message::NotInterested => {
+                self.peer_interested = false;
+                self.update_choke().await?;
+            }
+            Message::Have(index) => {
+                if index as usize >= self.bitfield.len() {
+                    return Err(PeerError::InvalidPiece(index).into());
+                }
+                if !self.bitfield.get(index as usize) {
+                    self.bitfield.set(index as usize);
+                    self.shared.lock().picker.add_availability(index);
+                }
+                self.update_interest().await?;
+            }
+            Message::Bitfield(bytes) => {
+                let bitfield = Bitfield::from_bytes(&bytes, self.bitfield.len())
+                    .ok_or(PeerError::InvalidBitfield)?;
+                self.set_bitfield(bitfield).await?;
+            }
+            Message::HaveAll => {
+                self.set_bitfield(Bitfield::full(self.bitfield.len()))
+                    .await?
+            }
+            Message::HaveNone => {
+                self.set_bitfield(Bitfield::new(self.bitfield.len()))
+                    .await?
+            }
+            Message::SuggestPiece(index) => {
+                if index as usize >= self.bitfield.len() {
+                    return Err(PeerError::InvalidPiece(index).into());
+                }
+                self.suggested.retain(|suggested| *suggested != index);
+                self.suggested.push(index);
+                if self.suggested.len() > MAX_SUGGESTED {
+                    self.suggested.remove(0);
+                }
+            }
+            Message::AllowedFast(index) => {
+                if index as usize >= self.bitfield.len() {
+                    return Err(PeerError::InvalidPiece(index).into());
+                }
+                if !self.allowed_fast.contains(&index) {
+                    self.allowed_fast.push(index);
+                }
+            }
+            Message::RejectRequest(block) => {
+                if let Some(position) = self.outstanding.iter().position(|b| *b == block) {
+                    self.outstanding.swap_remove(position);
+                    self.shared.lock().picker.cancel(block);
+                }
+            }
+            Message::Request(block) => self.serve(block).await?,
+            Message::Piece { index, begin, data } => {
+                let block = Block {
+                    index,
+                    begin,
+                    length: data.len() as u32,
+                };
+                self.receive(block, data).await?;
+            }
+            Message::Cancel(_) => {}
+        }
+
+        Ok(())
+    }
+
+    /// Get our extension handshake.
+    fn local_handshake(&self) -> ExtendedHandshake {
+        let mut extra = BTreeMap::new();
+        for extension in &self.extensions {
+            extra.extend(extension.handshake(&self.shared.info_hash));
+        }
+
+        ExtendedHandshake {
+            extensions: (self.extensions.iter().enumerate())
+                .map(|(index, extension)| (extension.name().to_string(), index as u8 + 1))
+                .collect(),
+            client: Some(format!("rip {}", env!("CARGO_PKG_VERSION"))),
+            max_requests: Some(MAX_PEER_REQUESTS),
+            your_ip: Some(self.addr.ip()),
+            port: Some(self.shared.context.port),
+            extra,
+        }
+    }
+
+    /// Take in extension handshake of the peer, which may be sent again to update it.
+    fn handle_handshake(&mut self, payload: &[u8]) -> Result<(), Error> {
+        let handshake = Arc::new(ExtendedHandshake::from_bytes(payload)?);
+        if let Some(max_requests) = handshake.max_requests {
+            self.max_requests = max_requests.clamp(1, PIPELINE);
+        }
+        if let (false, Some(port)) = (self.outgoing, handshake.port) {
+            self.set_listen_addr(SocketAddr::new(self.addr.ip(), port));
+        }
+        let previous = self.remote.replace(Arc::clone(&handshake));
+
+        for (index, extension) in self.extensions.iter().enumerate() {
+            let name = extension.name();
+            let was_supported = previous
+                .as_ref()
+                .is_some_and(|previous| previous.extensions.contains_key(name));
+            if handshake.extensions.contains_key(name) && !was_supported {
+                extension.on_peer_connected(&self.extension_peer(index, &handshake));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Take note that the peer listens on `addr`, so it's shared with other peers.
+    fn set_listen_addr(&mut self, addr: SocketAddr) {
+        let pex = &self.shared.context.pex;
+        if let Some(previous) = self.listen_addr.replace(addr) {
+            pex.disconnected(&self.shared.info_hash, previous);
+        }
+        pex.connected(&self.shared.info_hash, addr);
+    }
+
+    /// Pass message of our extension with `id` to it. Messages before the peer's extension
+    /// handshake, or with IDs we didn't announce, are ignored.
+    fn handle_extended(&mut self, id: u8, payload: &[u8]) -> Result<(), Error> {
+        let index = id as usize - 1;
+        let (Some(extension), Some(handshake)) = (self.extensions.get(index), &self.remote) else {
+            return Ok(());
+        };
+
+        extension.on_message(&self.extension_peer(index, handshake), payload)
+    }
+
+    /// Send message of our extension at `index` to the peer, under the ID the peer gave it.
+    /// Dropped if the peer doesn't support the extension.
+    async fn send_extended(&mut self, index: usize, payload: Vec<u8>) -> Result<(), Error> {
+        let name = self.extensions[index].name();
+        let remote = self.remote.as_ref();
+        let Some(&id) = remote.and_then(|remote| remote.extensions.get(name)) else {
+            return Ok(());
+        };
+
+        self.send(Message::Extended { id, payload }).await
+    }
+
+    /// Get handle to the peer for our extension at `index`.
+    fn extension_peer(&self, index: usize, handshake: &Arc<ExtendedHandshake>) -> ExtensionPeer {
+        ExtensionPeer::new(
+            self.addr,
+            self.shared.info_hash,
+            Arc::clone(handshake),
+            index,
+            self.outbox.clone(),
+        )
+    }
+
+    /// Replace the pieces the peer has with `bitfield`.
+    async fn set_bitfield(&mut self, bitfield: Bitfield) -> Result<(), Error> {
+        {
+            let picker = &mut self.shared.lock().picker;
+            picker.remove_availability(&self.bitfield);
+            for index in bitfield.iter_set() {
+                picker.add_availability(index as u32);
+            }
+        }
+        self.bitfield = bitfield;
+        self.update_interest().await
+    }
+
+    /// Send a block the peer requested, if we're not choking it or it's allowed fast.
+    ///
+    /// With the Fast Extension, requests that aren't answered are rejected.
+    async fn serve(&mut self, block: Block) -> Result<(), Error> {
+        let info = &self.shared.torrent.info;
+        let index = block.index as usize;
+        let valid = index < info.piece_count()
+            && block.length > 0
+            && block.length <= MAX_REQUEST
+            && block.begin as u64 + block.length as u64 <= info.piece_size(index);
+        if !valid {
+            return Err(PeerError::InvalidRequest.into());
+        }
+        let allowed = !self.am_choking || self.allowed_to_peer.contains(&block.index);
+        if !allowed || !self.shared.lock().picker.have().get(index) {
+            if self.fast_extension {
+                self.send(Message::RejectRequest(block)).await?;
+            }
+            return Ok(());
+        }
+
+        if self.limited {
+            let levels = [
+                &self.shared.context.limiters.upload,
+                &self.shared.limiters.upload,
+                &self.limiters.upload,
+            ];
+            limit::acquire(&levels, block.length as u64).await;
+        }
+
+        let data = self
+            .shared
+            .storage
+            .read_block(block.index, block.begin, block.length as usize)
+            .await?;
+        self.send(Message::Piece {
+            index: block.index,
+            begin: block.begin,
+            data,
+        })
+        .await?;
+        self.shared
+            .counters
+            .uploaded
+            .fetch_add(block.length as u64, Ordering::Relaxed);
+
+        Ok(())
+    }
+
+    /// Write a received block to storage, and verify and flush its piece once complete.
+    ///
+    /// Waits while the storage falls behind, which stops reading messages and so slows the
+    /// peer down.
+    async fn receive(&mut self, block: Block, data: Vec<u8>) -> Result<(), Error> {
+        let Some(position) = self.outstanding.iter().position(|b| *b == block) else {
+            return Ok(());
+        };
+        self.outstanding.swap_remove(position);
+        self.shared
+            .counters
+            .downloaded
+            .fetch_add(data.len() as u64, Ordering::Relaxed);
+        self.update_rate(data.len() as u64);
+        self.delivered += data.len() as u64;
+
+        if !self.shared.lock().picker.reserve(block) {
+            return Ok(());
+        }
+        self.writing = Some(block);
+        let storage = Arc::clone(&self.shared.storage);
+        storage.write_block(block.index, block.begin, data).await?;
+        self.writing = None;
+        let received = self.shared.lock().picker.receive(block, self.addr.ip());
+        let Some(senders) = received else {
+            return Ok(());
+        };
+
+        let info = &self.shared.torrent.info;
+        let index = block.index;
+        let hashes = storage.hash_piece(index).await?;
+        if info.piece_hash(index as usize) != Some(&hashes.piece[..]) {
+            storage.discard(index).await?;
+            let banned = {
+                let inner = &mut self.shared.lock();
+                inner.picker.piece_failed(index);
+                inner
+                    .smart_ban
+                    .piece_failed(index, &hashes.blocks, &senders)
+            };
+            self.shared.emit(AgentEvent::PieceFailed {
+                hash: self.shared.hash(),
+                index,
+            });
+            for ip in banned {
+                self.shared.ban(ip);
+            }
+            return Ok(());
+        }
+        let banned = self
+            .shared
+            .lock()
+            .smart_ban
+            .piece_passed(index, &hashes.blocks);
+        for ip in banned {
+            self.shared.ban(ip);
+        }
+
+        storage.flush(index).await?;
+
+        let (have, complete) = {
+            let picker = &mut self.shared.lock().picker;
+            picker.piece_verified(index);
+            (picker.have().clone(), picker.is_complete())
+        };
+        self.shared
+            .counters
+            .verified
+            .fetch_add(info.piece_size(index as usize), Ordering::Relaxed);
+        let _ = self.shared.haves.send(index);
+
+        let hash = self.shared.hash();
+        self.shared.emit(AgentEvent::PieceVerified {
+            hash: hash.clone(),
+            index,
+        });
+        for file in self.shared.completed_files(index, &have) {
+            self.shared.emit(AgentEvent::FileCompleted {
+                hash: hash.clone(),
+                index: file,
+                path: self.shared.storage.files()[file].path.clone(),
+            });
+        }
+
+        if complete {
+            self.shared.finish().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Fill the request pipeline, if the peer lets us download, only with pieces allowed
+    /// fast while it chokes us. Pieces it suggested are started first.
+    async fn request_more(&mut self) -> Result<(), Error> {
+        if !self.am_interested || (self.peer_choking && self.allowed_fast.is_empty()) {
+            return Ok(());
+        }
+
+        let bitfield = match self.peer_choking {
+            true => only(&self.bitfield, &self.allowed_fast),
+            false => self.bitfield.clone(),
+        };
+        let suggested = only(&bitfield, &self.suggested);
+        while self.outstanding.len() < self.max_requests {
+            let block = {
+                let inner = &mut self.shared.lock();
+                let fast = inner.is_fast(self.addr);
+                let picker = &mut inner.picker;
+                picker
+                    .pick_missing(&suggested, fast)
+                    .or_else(|| picker.pick(&bitfield, &self.outstanding, fast))
+            };
+            let Some(block) = block else {
+                break;
+            };
+            self.outstanding.push(block);
+            self.send(Message::Request(block)).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Count `amount` bytes received, and update the peer's download rate once the window is over.
+    fn update_rate(&mut self, amount: u64) {
+        self.received += amount;
+        let elapsed = self.window.elapsed();
+        if elapsed >= RATE_WINDOW {
+            let rate = (self.received as f64 / elapsed.as_secs_f64()) as u64;
+            self.shared.lock().rates.insert(self.addr, rate);
+            self.received = 0;
+            self.window = Instant::now();
+        }
+    }
+
+    /// Tell the peer whether we're interested in its pieces, if that changed.
+    async fn update_interest(&mut self) -> Result<(), Error> {
+        if self.bitfield.is_full() != self.is_seed {
+            self.is_seed = !self.is_seed;
+            match self.is_seed {
+                true => self.shared.counters.seeds.fetch_add(1, Ordering::Relaxed),
+                false => self.shared.counters.seeds.fetch_sub(1, Ordering::Relaxed),
+            };
+        }
+
+        let interested = self.shared.lock().picker.is_interesting(&self.bitfield);
+        if interested != self.am_interested {
+            self.am_interested = interested;
+            let message = match interested {
+                true => Message::Interested,
+                false => Message::NotInterested,
+            };
+            self.send(message).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Unchoke the peer if it's interested and an upload slot is free, and choke it otherwise.
+    async fn update_choke(&mut self) -> Result<(), Error> {
+        let choke = {
+            let inner = &mut self.shared.lock();
+            if self.am_choking
+                && self.peer_interested
+                && inner.unchoked < self.shared.context.config.upload_slots
+            {
+                inner.unchoked += 1;
+                false
+            } else if !self.am_choking && !self.peer_interested {
+                inner.unchoked -= 1;
+                true
+            } else {
+                return Ok(());
+            }
+        };
+
+        self.am_choking = choke;
+        let message = match choke {
+            true => Message::Choke,
+            false => Message::Unchoke,
+        };
+        self.send(message).await
+    }
+
+    /// Give back all outstanding requests to the picker.
+    fn cancel_outstanding(&mut self) {
+        let picker = &mut self.shared.lock().picker;
+        for block in self.outstanding.drain(..) {
+            picker.cancel(block);
+        }
+        if let Some(block) = self.writing.take() {
+            picker.unreserve(block);
+        }
+    }
+}
+
+/// Get the pieces of `pieces` that are in `bitfield`.
+fn only(bitfield: &Bitfield, pieces: &[u32]) -> Bitfield {
+    let mut out = Bitfield::new(bitfield.len());
+    for &index in pieces {
+        if bitfield.get(index as usize) {
+            out.set(index as usize);
+        }
+    }
+
+    out
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.cancel_outstanding();
+        if let Some(addr) = self.listen_addr {
+            let pex = &self.shared.context.pex;
+            pex.disconnected(&self.shared.info_hash, addr);
+        }
+
+        let inner = &mut self.shared.lock();
+        inner.picker.remove_availability(&self.bitfield);
+        inner.rates.remove(&self.addr);
+        if !self.am_choking {
+            inner.unchoked -= 1;
+        }
+        self.shared.counters.peers.fetch_sub(1, Ordering::Relaxed);
+        if self.is_seed {
+            self.shared.counters.seeds.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
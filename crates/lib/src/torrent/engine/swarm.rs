@@ -0,0 +1,257 @@
+//! Peers of a torrent that we know of: which to connect to next, when to retry ones that
+//! failed, and which to ban for sending corrupt data.
+
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Time before retrying a peer after a failed connection, doubled for each failure in a row.
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+/// Longest time before retrying a peer.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30 * 60);
+/// Number of failed connections in a row after which a peer is forgotten.
+const MAX_FAILURES: u32 = 8;
+/// Time before connecting to a peer again after a session with it ended.
+const RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// Number of corrupt pieces or blocks after which the sender is banned.
+const MAX_STRIKES: u32 = 2;
+
+/// How a connection with a peer ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Outcome {
+    /// Connecting or exchanging handshakes failed.
+    Failed,
+    /// The peer turned out to be ourselves.
+    Own,
+    /// A session ran, in which the peer sent `delivered` bytes of piece data.
+    Closed { delivered: u64 },
+}
+
+/// Known peers of a torrent, from any source, and the ones we're connected to.
+#[derive(Debug, Default)]
+pub(super) struct Swarm {
+    candidates: HashMap<SocketAddr, Candidate>,
+    connected: HashSet<SocketAddr>,
+    /// Addresses that turned out to be our own, which are never connected to.
+    own: HashSet<SocketAddr>,
+}
+
+#[derive(Debug, Default)]
+struct Candidate {
+    /// Number of failed connections in a row.
+    failures: u32,
+    /// When the peer may be connected to again.
+    retry_at: Option<Instant>,
+    /// Bytes of piece data the peer sent over all sessions.
+    delivered: u64,
+}
+
+impl Swarm {
+    /// Add peer at `addr` to connect to, unless it's already known.
+    pub fn add(&mut self, addr: SocketAddr) {
+        if !self.own.contains(&addr) {
+            self.candidates.entry(addr).or_default();
+        }
+    }
+
+    /// Get number of connections, including ones still being opened.
+    pub fn get_connected(&self) -> usize {
+        self.connected.len()
+    }
+
+    /// Register connection with `addr`, unless there is one already.
+    pub fn connect(&mut self, addr: SocketAddr) -> bool {
+        !self.own.contains(&addr) && self.connected.insert(addr)
+    }
+
+    /// Get the best peer to connect to at `now`, other than `banned` ones: the ones that
+    /// sent the most data first, then the ones that failed the least.
+    pub fn next(&self, now: Instant, banned: impl Fn(IpAddr) -> bool) -> Option<SocketAddr> {
+        self.candidates
+            .iter()
+            .filter(|(addr, candidate)| {
+                !self.connected.contains(addr)
+                    && candidate.retry_at.map_or(true, |at| at <= now)
+                    && !banned(addr.ip())
+            })
+            .max_by_key(|(_, candidate)| (candidate.delivered, Reverse(candidate.failures)))
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Register that the connection with `addr` ended at `now` with `outcome`.
+    pub fn closed(&mut self, addr: SocketAddr, outcome: Outcome, now: Instant) {
+        self.connected.remove(&addr);
+        if outcome == Outcome::Own {
+            self.candidates.remove(&addr);
+            self.own.insert(addr);
+            return;
+        }
+        let Some(candidate) = self.candidates.get_mut(&addr) else {
+            return;
+        };
+
+        match outcome {
+            Outcome::Closed { delivered } => {
+                candidate.failures = 0;
+                candidate.delivered += delivered;
+                candidate.retry_at = Some(now + RECONNECT_DELAY);
+            }
+            _ if candidate.failures + 1 >= MAX_FAILURES => {
+                self.candidates.remove(&addr);
+            }
+            _ => {
+                let delay = RETRY_DELAY * 2u32.pow(candidate.failures);
+                candidate.failures += 1;
+                candidate.retry_at = Some(now + delay.min(MAX_RETRY_DELAY));
+            }
+        }
+    }
+}
+
+/// Finds peers that send corrupt data (smart ban).
+///
+/// The sender of a corrupt piece gets a strike, if it sent all of it. Otherwise the blocks
+/// are remembered with their senders, and once the piece passes, senders of blocks that
+/// differ from the good data get a strike.
+#[derive(Debug, Default)]
+pub(crate) struct SmartBan {
+    /// Hash of the blocks of corrupt pieces by block index and sender, by piece index.
+    /// Only the latest block each sender sent is kept.
+    failed: HashMap<u32, HashMap<(usize, IpAddr), [u8; 20]>>,
+    strikes: HashMap<IpAddr, u32>,
+}
+
+impl SmartBan {
+    /// Take note of piece `index` that failed its hash, with the hashes of its `blocks`,
+    /// which came from `senders`. Gets senders to ban.
+    pub fn piece_failed(
+        &mut self,
+        index: u32,
+        blocks: &[[u8; 20]],
+        senders: &[IpAddr],
+    ) -> Vec<IpAddr> {
+        if let [sender, rest @ ..] = senders {
+            if rest.iter().all(|other| other == sender) {
+                return self.strike(*sender).into_iter().collect();
+            }
+        }
+
+        let blocks = blocks
+            .iter()
+            .enumerate()
+            .zip(senders)
+            .map(|((i, hash), sender)| ((i, *sender), *hash));
+        self.failed.entry(index).or_default().extend(blocks);
+
+        Vec::new()
+    }
+
+    /// Take note of piece `index` that passed its hash, with the hashes of its `good`
+    /// blocks. Gets senders to ban.
+    pub fn piece_passed(&mut self, index: u32, good: &[[u8; 20]]) -> Vec<IpAddr> {
+        let Some(blocks) = self.failed.remove(&index) else {
+            return Vec::new();
+        };
+
+        let mut corrupt = blocks
+            .into_iter()
+            .filter(|((i, _), hash)| good.get(*i) != Some(hash))
+            .map(|((_, sender), _)| sender)
+            .collect::<Vec<_>>();
+        corrupt.sort_unstable();
+        corrupt.dedup();
+
+        corrupt
+            .into_iter()
+            .filter_map(|sender| self.strike(sender))
+            .collect()
+    }
+
+    /// Forget the blocks of corrupt pieces, keeping the strikes, as when the torrent stops.
+    pub fn clear(&mut self) {
+        self.failed.clear();
+    }
+
+    /// Give `sender` a strike, getting it back if that's one too many.
+    fn strike(&mut self, sender: IpAddr) -> Option<IpAddr> {
+        let strikes = self.strikes.entry(sender).or_default();
+        *strikes += 1;
+
+        (*strikes == MAX_STRIKES).then_some(sender)
+    }
+}
+
+#[test]
+fn test_swarm() {
+    let mut swarm = Swarm::default();
+    let (a, b, c) = (
+        SocketAddr::from(([10, 0, 0, 1], 6881)),
+        SocketAddr::from(([10, 0, 0, 2], 6881)),
+        SocketAddr::from(([10, 0, 0, 3], 6881)),
+    );
+    let now = Instant::now();
+    for addr in [a, b, c, a] {
+        swarm.add(addr);
+    }
+
+    // Peers that sent data are preferred, the ones that failed are retried later and later.
+    assert!(swarm.connect(a) && !swarm.connect(a));
+    swarm.closed(a, Outcome::Closed { delivered: 100 }, now);
+    assert!(swarm.connect(b));
+    swarm.closed(b, Outcome::Failed, now);
+    assert!(swarm.connect(c));
+    swarm.closed(c, Outcome::Own, now);
+    assert_eq!(swarm.get_connected(), 0);
+    assert_eq!(swarm.next(now, |_| false), None);
+    assert_eq!(swarm.next(now + RETRY_DELAY, |_| false), Some(b));
+    assert_eq!(swarm.next(now + RECONNECT_DELAY, |_| false), Some(a));
+    assert_eq!(
+        swarm.next(now + RECONNECT_DELAY, |ip| ip == a.ip()),
+        Some(b)
+    );
+
+    assert!(swarm.connect(b));
+    swarm.closed(b, Outcome::Failed, now);
+    assert_eq!(swarm.next(now + RETRY_DELAY, |ip| ip == a.ip()), None);
+    assert_eq!(
+        swarm.next(now + RETRY_DELAY * 2, |ip| ip == a.ip()),
+        Some(b)
+    );
+    for _ in 2..MAX_FAILURES {
+        swarm.closed(b, Outcome::Failed, now);
+    }
+    assert_eq!(swarm.next(now + MAX_RETRY_DELAY, |ip| ip == a.ip()), None);
+
+    // Our own address isn't added again.
+    swarm.add(c);
+    assert!(!swarm.connect(c));
+}
+
+#[test]
+fn test_smart_ban() {
+    let mut smart_ban = SmartBan::default();
+    let (a, b) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+    let hash = |block: &[u8]| sha1_smol::Sha1::from(block).digest().bytes();
+    let good = [hash(&[1; 4]), hash(&[2; 4]), hash(&[3; 2])];
+    let mut bad = good;
+    bad[1] = hash(&[0; 4]);
+
+    // Only the sender of the corrupt block is struck, once the piece passes.
+    assert!(smart_ban.piece_failed(0, &bad, &[a, b, a]).is_empty());
+    assert!(smart_ban.piece_passed(0, &good).is_empty());
+    assert_eq!(smart_ban.strikes.get(&a), None);
+    assert!(smart_ban.piece_passed(0, &good).is_empty());
+
+    // A piece sent by one peer alone counts against it straight away.
+    assert_eq!(smart_ban.piece_failed(1, &bad, &[b, b, b]), [b]);
+    assert!(smart_ban.piece_failed(1, &bad, &[b]).is_empty());
+
+    // Pieces that keep failing remember one block per index and sender.
+    for _ in 0..10 {
+        assert!(smart_ban.piece_failed(2, &bad, &[a, b, a]).is_empty());
+    }
+    assert_eq!(smart_ban.failed[&2].len(), 3);
+    smart_ban.clear();
+    assert!(smart_ban.failed.is_empty());
+}
@@ -1,4 +1,5 @@
-use crate::error::Error;
+use super::parse::MetainfoFields;
+use crate::error::{Error, MetainfoError};
 use crate::prelude::*;
 
 /// Torrent info.
@@ -22,7 +23,7 @@ pub struct TorrentInfo {
 #[derive(Debug, Clone)]
 pub struct File {
     /// Length in bytes.
-    pub length: usize,
+    pub length: u64,
     /// (In multi-file mode) Subdirectory names, where the last element is the file name.
     pub path: Vec<Vec<u8>>,
     /// Optional MD5 sum.
@@ -33,18 +34,18 @@ impl TorrentInfo {
     /// Create [`TorrentInfo`] from bencoded dictionary.
     pub fn from_dictionary(info: Dictionary) -> Result<Self, Error> {
         let mut files = Vec::new();
-        let name = info.try_get_as::<ByteString>("name")?.0;
-        let piece_length = info.try_get_as::<Integer>("piece length")?.0 as usize;
-        let pieces = info.try_get_as::<ByteString>("pieces")?.0;
+        let name = info.field::<ByteString>("name")?.0;
+        let piece_length = info.int_field("piece length")?;
+        let pieces = info.field::<ByteString>("pieces")?.0;
         let private = info
-            .try_get_as::<Integer>("private")
+            .field::<Integer>("private")
             .ok()
             .map(|v| v != Integer(0));
         let is_single_file = !info.has("files");
 
         if is_single_file {
-            let length = info.try_get_as::<Integer>("length")?.0 as usize;
-            let md5sum = info.try_get_as::<ByteString>("md5sum").ok().map(|v| v.0);
+            let length = info.int_field("length")?;
+            let md5sum = info.field::<ByteString>("md5sum").ok().map(|v| v.0);
 
             files.push(File {
                 length,
@@ -53,20 +54,31 @@ impl TorrentInfo {
             });
         } else {
             let mut tmp = info
-                .try_get_as::<List>("files")?
+                .field::<List>("files")?
                 .0
                 .into_iter()
                 .map(|v| {
-                    let d = v.try_as::<Dictionary>()?;
-                    let length = d.try_get_as::<Integer>("length")?.0 as usize;
+                    let d =
+                        v.try_as::<Dictionary>()
+                            .map_err(|source| MetainfoError::InvalidField {
+                                field: "files",
+                                source,
+                            })?;
+                    let length = d.int_field("length")?;
                     let path = d
-                        .try_get("path")?
-                        .clone()
-                        .as_list_of::<ByteString>()?
+                        .field::<List>("path")?
+                        .0
+                        .into_iter()
+                        .map(|v| v.try_as::<ByteString>())
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|source| MetainfoError::InvalidField {
+                            field: "path",
+                            source,
+                        })?
                         .into_iter()
                         .map(|v| v.0)
                         .collect();
-                    let md5sum = d.try_get_as::<ByteString>("md5sum").ok().map(|v| v.0);
+                    let md5sum = d.field::<ByteString>("md5sum").ok().map(|v| v.0);
 
                     Ok::<File, Error>(File {
                         length,
@@ -79,13 +91,47 @@ impl TorrentInfo {
             files.append(&mut tmp);
         }
 
-        Ok(Self {
+        let out = Self {
             files,
             name,
             piece_length,
             pieces,
             private,
             is_single_file,
-        })
+        };
+
+        if out.piece_length == 0 {
+            return Err(MetainfoError::InvalidPieceLength.into());
+        }
+        let piece_length = out.piece_length as u64;
+        let expected_pieces = (out.total_length() + piece_length - 1) / piece_length;
+        if out.pieces.len() % 20 != 0 || out.piece_count() as u64 != expected_pieces {
+            return Err(MetainfoError::InvalidPieces.into());
+        }
+
+        Ok(out)
+    }
+
+    /// Get total length of all files in bytes.
+    pub fn total_length(&self) -> u64 {
+        self.files.iter().map(|file| file.length).sum()
+    }
+
+    /// Get number of pieces.
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len() / 20
+    }
+
+    /// Get length of piece `index` in bytes (the last piece may be shorter).
+    pub fn piece_size(&self, index: usize) -> u64 {
+        let start = index as u64 * self.piece_length as u64;
+        let end = (start + self.piece_length as u64).min(self.total_length());
+
+        end.saturating_sub(start)
+    }
+
+    /// Get SHA1 hash of piece `index`.
+    pub fn piece_hash(&self, index: usize) -> Option<&[u8]> {
+        self.pieces.get(index * 20..index * 20 + 20)
     }
 }
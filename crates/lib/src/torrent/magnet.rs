@@ -0,0 +1,135 @@
+use super::Torrent;
+use crate::error::{Error, MagnetError, PeerError};
+use crate::prelude::*;
+use std::net::SocketAddr;
+
+/// Magnet URI, naming a torrent by its info hash, whose metadata is fetched from peers (BEP 9).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Magnet {
+    /// SHA1 hash of the info dictionary (`xt=urn:btih:`).
+    pub info_hash: [u8; 20],
+    /// Optional name to show until the metadata is known (`dn`).
+    pub name: Option<String>,
+    /// Tracker URLs (`tr`).
+    pub trackers: Vec<String>,
+    /// Addresses of peers (`x.pe`), other than host names.
+    pub peers: Vec<SocketAddr>,
+}
+
+impl Magnet {
+    /// Parse magnet URI `uri`, with its info hash as hexadecimal or base32.
+    pub fn parse(uri: &str) -> Result<Self, Error> {
+        let query = uri.strip_prefix("magnet:?").ok_or(MagnetError::NotMagnet)?;
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+        for (key, value) in query.split('&').filter_map(|param| param.split_once('=')) {
+            let value = urlencoding::decode(value)
+                .map_err(|_| MagnetError::InvalidEncoding(key.to_string()))?;
+            // Parameters given more than once may be numbered, like `tr.1`.
+            let key = match key.rsplit_once('.') {
+                Some((key, n)) if n.bytes().all(|b| b.is_ascii_digit()) => key,
+                _ => key,
+            };
+
+            match key {
+                "xt" if info_hash.is_none() => {
+                    // Other hashes, like those of BitTorrent v2 (`urn:btmh:`), are skipped.
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(parse_info_hash(hash)?);
+                    }
+                }
+                "dn" => name = Some(value.into_owned()),
+                "tr" => trackers.push(value.into_owned()),
+                "x.pe" => peers.extend(value.parse::<SocketAddr>().ok()),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            name,
+            trackers,
+            peers,
+        })
+    }
+
+    /// Create [`Torrent`] with `metadata`, the bencoded info dictionary peers sent, and the
+    /// trackers of the magnet. Fails if it doesn't match the info hash.
+    pub fn to_torrent(&self, metadata: &[u8]) -> Result<Torrent, Error> {
+        if sha1_smol::Sha1::from(metadata).digest().bytes() != self.info_hash {
+            return Err(PeerError::InvalidMetadata.into());
+        }
+        // Trusted like a torrent file, as it's what the info hash names.
+        let info = decode(metadata)?.try_as::<Dictionary>()?;
+        let announce_list = (self.trackers.len() > 1)
+            .then(|| self.trackers.iter().map(|url| vec![url.clone()]).collect());
+
+        Ok(Torrent {
+            info: TorrentInfo::from_dictionary(info)?,
+            announce: self.trackers.first().cloned().unwrap_or_default(),
+            announce_list,
+            creation_date: None,
+            comment: None,
+            created_by: None,
+            encoding: None,
+
+            info_hash: self.info_hash.to_vec(),
+            metadata: metadata.to_vec(),
+        })
+    }
+}
+
+/// Parse info hash of a magnet URI, as 40 hexadecimal or 32 base32 characters.
+fn parse_info_hash(text: &str) -> Result<[u8; 20], MagnetError> {
+    let hash = match text.len() {
+        40 => crate::util::from_hex(text),
+        32 => crate::util::from_base32(text),
+        _ => None,
+    };
+
+    hash.and_then(|hash| hash.try_into().ok())
+        .ok_or_else(|| MagnetError::InvalidInfoHash(text.to_string()))
+}
+
+#[test]
+fn test_magnet_parse() {
+    let hex = "c9e15763f722f23e98a29decdfae341b98d53056";
+    let uri = format!(
+        "magnet:?xt=urn:btih:{hex}&dn=Some%20Name&tr=http%3A%2F%2Fa%2Fannounce\
+         &tr.1=udp%3A%2F%2Fb%3A80&x.pe=10.0.0.2:6881&x.pe=[::1]:6882&x.pe=host:1"
+    );
+    let magnet = Magnet::parse(&uri).unwrap();
+    assert_eq!(crate::util::to_hex(&magnet.info_hash), hex);
+    assert_eq!(magnet.name.as_deref(), Some("Some Name"));
+    assert_eq!(magnet.trackers, ["http://a/announce", "udp://b:80"]);
+    assert_eq!(
+        magnet.peers,
+        [
+            "10.0.0.2:6881".parse().unwrap(),
+            "[::1]:6882".parse().unwrap()
+        ]
+    );
+
+    let base32 = crate::util::to_base32(&magnet.info_hash);
+    let magnet = Magnet::parse(&format!("magnet:?xt=urn:btih:{base32}")).unwrap();
+    assert_eq!(crate::util::to_hex(&magnet.info_hash), hex);
+    assert!(magnet.trackers.is_empty());
+
+    let error = |uri| match Magnet::parse(uri).unwrap_err() {
+        Error::Magnet(error) => error,
+        error => panic!("unexpected error {error}"),
+    };
+    assert_eq!(error("http://a"), MagnetError::NotMagnet);
+    assert_eq!(error("magnet:?dn=a"), MagnetError::MissingInfoHash);
+    assert!(matches!(
+        error("magnet:?xt=urn:btih:00"),
+        MagnetError::InvalidInfoHash(_)
+    ));
+    assert!(matches!(
+        error(&format!("magnet:?xt=urn:btih:+{}", &hex[1..])),
+        MagnetError::InvalidInfoHash(_)
+    ));
+}
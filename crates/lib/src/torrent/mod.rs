@@ -1,18 +1,23 @@
 // https://wiki.theory.org/BitTorrentSpecification
 
+mod bitfield;
+pub(crate) mod engine;
 mod info;
+mod magnet;
 mod parse;
+mod picker;
 mod tracker;
 
-use super::agent::traits::Download;
 use super::error::Error;
-use crate::prelude::*;
-use std::future::Future;
-use std::pin::Pin;
-use info::TorrentInfo;
+use crate::storage::{DiskPool, FileStorage};
 use std::path::Path;
 
-pub use tracker::Tracker;
+pub use bitfield::Bitfield;
+pub use engine::TorrentState;
+pub use info::{File, TorrentInfo};
+pub use magnet::Magnet;
+pub use picker::FilePriority;
+pub use tracker::{ScrapeRequest, ScrapeResponse, Tracker, TrackerRequest, TrackerResponse};
 
 /// Torrent.
 #[derive(Debug, Clone)]
@@ -24,7 +29,7 @@ pub struct Torrent {
     /// Optional announce list.
     pub announce_list: Option<Vec<Vec<String>>>,
     /// Optional creation date.
-    pub creation_date: Option<usize>,
+    pub creation_date: Option<u64>,
     /// Optional comment.
     pub comment: Option<String>,
     /// Optional creator name.
@@ -34,6 +39,8 @@ pub struct Torrent {
 
     /// SHA1 hash of info dictionary.
     info_hash: Vec<u8>,
+    /// Bencoded info dictionary, sent to peers that fetch it for a magnet URI.
+    metadata: Vec<u8>,
 }
 
 impl Torrent {
@@ -46,39 +53,48 @@ impl Torrent {
     pub fn get_hash(&self) -> &[u8] {
         self.info_hash.as_slice()
     }
-}
-
-impl Download for Torrent {
-    type Error = Error;
 
-    fn initiate(
-        &self,
-        agent: &Agent,
-        out: &Path,
-    ) -> Pin<Box<dyn Future<Output = Result<(), Self::Error>>>> {
-        
-        let tracker_request = Tracker::create_request(&self, agent);
-        Box::pin(async move {
-            let tracker_response = tracker_request?.send().await?;
-            todo!("continue...");
+    /// Get `metadata`, the bencoded info dictionary.
+    pub fn get_metadata(&self) -> &[u8] {
+        &self.metadata
+    }
 
-            Ok(())
-        
-        })
+    /// Get `info_hash` as lowercase hexadecimal.
+    pub fn get_hash_hex(&self) -> String {
+        crate::util::to_hex(&self.info_hash)
     }
 
-    fn get_uploaded(&self) -> usize {
-        0
-        //todo!()
+    /// Get `info_hash` as base32, as used by some magnet URIs.
+    pub fn get_hash_base32(&self) -> String {
+        crate::util::to_base32(&self.info_hash)
     }
 
-    fn get_downloaded(&self) -> usize {
-        0
-        //todo!()
+    /// Get a magnet URI with the info hash, name and trackers of this torrent.
+    pub fn magnet_uri(&self) -> String {
+        let mut uri = format!(
+            "magnet:?xt=urn:btih:{}&dn={}",
+            self.get_hash_hex(),
+            urlencoding::encode_binary(&self.info.name)
+        );
+
+        let mut trackers = vec![&self.announce];
+        for url in self.announce_list.iter().flatten().flatten() {
+            if !trackers.contains(&url) {
+                trackers.push(url);
+            }
+        }
+        for url in trackers.into_iter().filter(|url| !url.is_empty()) {
+            uri.push_str(&format!("&tr={}", urlencoding::encode(url)));
+        }
+
+        uri
     }
 
-    fn get_left(&self) -> usize {
-        99999999
-        //todo!()
+    /// Hash-check data of this torrent saved in directory `out`, returning the pieces that are intact.
+    pub async fn verify(&self, out: &Path) -> Result<Bitfield, Error> {
+        let disk = DiskPool::new(1, 0)?;
+        let storage = FileStorage::new(&self.info, out, disk.clone())?;
+
+        engine::check(self, &storage, &disk).await
     }
 }
@@ -1,21 +1,34 @@
 use super::*;
+use crate::error::{BencodeError, BencodeErrorKind, MetainfoError};
 use crate::prelude::*;
 
 impl Torrent {
     /// Create [`Torrent`] from bencoded bytes.
     pub fn parse(contents: &[u8]) -> Result<Self, Error> {
         let dictionary = decode(contents)?.try_as::<Dictionary>()?;
-        let info = dictionary.try_get_as::<Dictionary>("info")?;
-        let announce = String::from_utf8(dictionary.try_get_as::<ByteString>("announce")?.0)?;
-        let announce_list = None;
-        let creation_date = None;
-        let comment = None;
-        let created_by = None;
-        let encoding = None;
-        let info_hash = sha1_smol::Sha1::from(encode(&Value::Dictionary(info.clone())))
-            .digest()
-            .bytes()
-            .to_vec();
+        let info = dictionary.field::<Dictionary>("info")?;
+        let announce = dictionary.string_field("announce")?;
+        let announce_list = dictionary.optional("announce-list", |d, key| {
+            d.field::<List>(key)?
+                .0
+                .into_iter()
+                .map(|tier| {
+                    let tier = tier
+                        .as_list_of::<ByteString>()
+                        .map_err(|source| MetainfoError::InvalidField { field: key, source })?;
+                    tier.into_iter()
+                        .map(|url| String::from_utf8(url.0))
+                        .collect::<Result<Vec<_>, _>>()
+                        .map_err(|_| MetainfoError::InvalidUtf8(key))
+                })
+                .collect()
+        })?;
+        let creation_date = dictionary.optional("creation date", Dictionary::int_field)?;
+        let comment = dictionary.optional("comment", Dictionary::string_field)?;
+        let created_by = dictionary.optional("created by", Dictionary::string_field)?;
+        let encoding = dictionary.optional("encoding", Dictionary::string_field)?;
+        let metadata = encode(&Value::Dictionary(info.clone()));
+        let info_hash = sha1_smol::Sha1::from(&metadata).digest().bytes().to_vec();
 
         Ok(Torrent {
             info: TorrentInfo::from_dictionary(info)?,
@@ -27,6 +40,85 @@ impl Torrent {
             encoding,
 
             info_hash,
+            metadata,
         })
     }
 }
+
+/// Reading metainfo fields from a [`Dictionary`], with errors naming the field.
+pub(crate) trait MetainfoFields {
+    /// Get field `key` as type `T`.
+    fn field<T: TryFrom<Value, Error = BencodeError>>(
+        &self,
+        key: &'static str,
+    ) -> Result<T, MetainfoError>;
+
+    /// Get integer field `key` as integer type `T`.
+    fn int_field<T: TryFrom<i64>>(&self, key: &'static str) -> Result<T, MetainfoError> {
+        self.field::<Integer>(key)?
+            .try_to()
+            .map_err(|source| MetainfoError::InvalidField { field: key, source })
+    }
+
+    /// Get UTF-8 byte string field `key` as a [`String`].
+    fn string_field(&self, key: &'static str) -> Result<String, MetainfoError> {
+        String::from_utf8(self.field::<ByteString>(key)?.0)
+            .map_err(|_| MetainfoError::InvalidUtf8(key))
+    }
+
+    /// Get optional field `key` with `get`, or `None` if it doesn't exist.
+    fn optional<T>(
+        &self,
+        key: &'static str,
+        get: impl FnOnce(&Self, &'static str) -> Result<T, MetainfoError>,
+    ) -> Result<Option<T>, MetainfoError> {
+        match get(self, key) {
+            Ok(out) => Ok(Some(out)),
+            Err(MetainfoError::MissingField(_)) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+}
+
+impl MetainfoFields for Dictionary {
+    fn field<T: TryFrom<Value, Error = BencodeError>>(
+        &self,
+        key: &'static str,
+    ) -> Result<T, MetainfoError> {
+        match self.try_get_as::<T>(key) {
+            Ok(out) => Ok(out),
+            Err(BencodeError {
+                kind: BencodeErrorKind::MissingKey(_),
+                ..
+            }) => Err(MetainfoError::MissingField(key)),
+            Err(source) => Err(MetainfoError::InvalidField { field: key, source }),
+        }
+    }
+}
+
+#[test]
+fn test_torrent_parse_errors() {
+    let missing = Torrent::parse(b"d8:announce3:abce").unwrap_err();
+    assert!(matches!(
+        missing,
+        Error::Metainfo(MetainfoError::MissingField("info"))
+    ));
+
+    let invalid = Torrent::parse(b"d8:announce3:abc4:infoi1ee").unwrap_err();
+    assert!(matches!(
+        invalid,
+        Error::Metainfo(MetainfoError::InvalidField { field: "info", .. })
+    ));
+
+    let negative = Torrent::parse(
+        b"d8:announce3:abc4:infod6:lengthi-1e4:name1:a12:piece lengthi1e6:pieces0:ee",
+    )
+    .unwrap_err();
+    assert!(matches!(
+        negative,
+        Error::Metainfo(MetainfoError::InvalidField {
+            field: "length",
+            ..
+        })
+    ));
+}
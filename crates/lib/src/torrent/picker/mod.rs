@@ -0,0 +1,521 @@
+use super::bitfield::Bitfield;
+use crate::peer::wire::{Block, BLOCK_SIZE};
+use crate::prelude::*;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Priority of a file of a torrent. Pieces of higher priority files are picked first,
+/// and pieces only covering skipped files aren't picked at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum FilePriority {
+    Skip,
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Chooses which blocks to request, and collects them into pieces.
+///
+/// Pieces with a deadline are picked first, in order of their deadlines, but only for fast
+/// peers unless the deadline passed. Other new pieces are picked by priority, then
+/// rarest-first, or lowest index first in sequential mode. Pieces that are already in
+/// progress are finished before new ones are started, and once every block has been
+/// requested, blocks that are still outstanding are handed out again (end game).
+#[derive(Debug)]
+pub struct Picker {
+    piece_length: u64,
+    total_length: u64,
+    have: Bitfield,
+    /// Priority of each piece, the highest of the files it covers.
+    priorities: Vec<FilePriority>,
+    /// Number of connected peers that have each piece.
+    availability: Vec<u32>,
+    partial: HashMap<u32, PartialPiece>,
+    /// Whether new pieces are picked in order, rather than rarest-first.
+    sequential: bool,
+    /// Pieces that are needed soon, and when.
+    deadlines: HashMap<u32, Instant>,
+}
+
+/// A piece being downloaded, whose received blocks are cached by the storage.
+#[derive(Debug)]
+struct PartialPiece {
+    blocks: Vec<BlockState>,
+    /// Address of the peer each received block came from.
+    senders: Vec<Option<IpAddr>>,
+}
+
+/// State of a block of a [`PartialPiece`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BlockState {
+    Missing,
+    /// Requested from this many peers.
+    Requested(u16),
+    /// Received from a peer, and being written.
+    Writing,
+    Received,
+}
+
+impl Picker {
+    /// Create a [`Picker`] for torrent `info`, where the pieces in `have` are already done.
+    pub fn new(info: &TorrentInfo, have: Bitfield) -> Self {
+        Self {
+            piece_length: info.piece_length as u64,
+            total_length: info.total_length(),
+            availability: vec![0; have.len()],
+            priorities: vec![FilePriority::Normal; have.len()],
+            have,
+            partial: HashMap::new(),
+            sequential: false,
+            deadlines: HashMap::new(),
+        }
+    }
+
+    /// Set whether new pieces are picked in order.
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    /// Replace deadlines of pieces. Pieces we have are ignored.
+    pub fn set_deadlines(&mut self, deadlines: impl IntoIterator<Item = (u32, Instant)>) {
+        self.deadlines = deadlines
+            .into_iter()
+            .filter(|(index, _)| !self.have.get(*index as usize))
+            .collect();
+    }
+
+    /// Get sort key of piece `index` when picking: pieces with deadlines first, by deadline,
+    /// then by index.
+    fn urgency(&self, index: u32) -> (bool, Option<Instant>, u32) {
+        let deadline = self.deadlines.get(&index).copied();
+
+        (deadline.is_none(), deadline, index)
+    }
+
+    /// Check whether piece `index` may be picked for a peer, depending on whether it's `fast`.
+    /// Pieces with a deadline are only picked for fast peers, unless it has passed.
+    fn allows(&self, index: u32, fast: bool) -> bool {
+        match self.deadlines.get(&index) {
+            Some(deadline) => fast || *deadline <= Instant::now(),
+            None => self.is_wanted(index as usize),
+        }
+    }
+
+    /// Get pieces that are done.
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    /// Set priority of each piece.
+    pub fn set_priorities(&mut self, priorities: Vec<FilePriority>) {
+        self.priorities = priorities;
+    }
+
+    /// Check whether piece `index` is wanted, that is, not only covering skipped files.
+    fn is_wanted(&self, index: usize) -> bool {
+        self.priorities[index] != FilePriority::Skip
+    }
+
+    /// Check whether all wanted pieces are done.
+    pub fn is_complete(&self) -> bool {
+        (0..self.have.len()).all(|index| self.have.get(index) || !self.is_wanted(index))
+    }
+
+    /// Get number of bytes of verified pieces.
+    pub fn have_bytes(&self) -> u64 {
+        self.have
+            .iter_set()
+            .map(|index| self.piece_size(index as u32))
+            .sum()
+    }
+
+    /// Get length of piece `index`.
+    pub fn piece_size(&self, index: u32) -> u64 {
+        let start = index as u64 * self.piece_length;
+        (start + self.piece_length).min(self.total_length) - start
+    }
+
+    /// Register that a peer has piece `index`.
+    pub fn add_availability(&mut self, index: u32) {
+        if let Some(count) = self.availability.get_mut(index as usize) {
+            *count += 1;
+        }
+    }
+
+    /// Register that a peer with pieces `bitfield` went away.
+    pub fn remove_availability(&mut self, bitfield: &Bitfield) {
+        for index in bitfield.iter_set() {
+            self.availability[index] = self.availability[index].saturating_sub(1);
+        }
+    }
+
+    /// Check whether a peer with pieces `bitfield` has anything we need.
+    pub fn is_interesting(&self, bitfield: &Bitfield) -> bool {
+        bitfield.iter_set().any(|index| {
+            !self.have.get(index)
+                && (self.is_wanted(index) || self.deadlines.contains_key(&(index as u32)))
+        })
+    }
+
+    /// Pick a block to request from a peer with pieces `bitfield`, skipping blocks in `outstanding`.
+    /// Pieces with deadlines are only picked if the peer is `fast`, or the deadline has passed.
+    pub fn pick(
+        &mut self,
+        bitfield: &Bitfield,
+        outstanding: &[Block],
+        fast: bool,
+    ) -> Option<Block> {
+        if let Some(block) = self.pick_missing(bitfield, fast) {
+            return Some(block);
+        }
+
+        // End game: request outstanding blocks from more peers.
+        let mut candidates = self
+            .partial
+            .iter()
+            .filter(|(index, _)| bitfield.get(**index as usize) && self.allows(**index, fast))
+            .flat_map(|(index, piece)| {
+                piece
+                    .blocks
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, state)| matches!(state, BlockState::Requested(_)))
+                    .map(|(i, _)| self.block(*index, i))
+            })
+            .filter(|block| !outstanding.contains(block))
+            .collect::<Vec<_>>();
+        candidates.sort_by_key(|block| (self.urgency(block.index), *block));
+        let block = candidates.into_iter().next()?;
+        self.mark_requested(block);
+
+        Some(block)
+    }
+
+    /// Pick a block nobody requested yet from a peer with pieces `bitfield`, preferring
+    /// pieces already in progress.
+    pub fn pick_missing(&mut self, bitfield: &Bitfield, fast: bool) -> Option<Block> {
+        if let Some(block) = self.pick_partial(bitfield, fast) {
+            return Some(block);
+        }
+
+        let index = self.pick_new_piece(bitfield, fast)?;
+        let size = self.piece_size(index);
+        let block_count = ((size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64) as usize;
+        self.partial.insert(
+            index,
+            PartialPiece {
+                blocks: vec![BlockState::Missing; block_count],
+                senders: vec![None; block_count],
+            },
+        );
+
+        self.pick_partial(bitfield, fast)
+    }
+
+    /// Pick a missing block in a piece already in progress, most urgent first.
+    fn pick_partial(&mut self, bitfield: &Bitfield, fast: bool) -> Option<Block> {
+        let mut indices = self
+            .partial
+            .keys()
+            .filter(|index| bitfield.get(**index as usize) && self.allows(**index, fast))
+            .copied()
+            .collect::<Vec<_>>();
+        indices.sort_unstable_by_key(|index| self.urgency(*index));
+
+        let block = indices.into_iter().find_map(|index| {
+            let piece = &self.partial[&index];
+            let i = piece
+                .blocks
+                .iter()
+                .position(|state| *state == BlockState::Missing)?;
+            Some(self.block(index, i))
+        })?;
+        self.mark_requested(block);
+
+        Some(block)
+    }
+
+    /// Pick a piece the peer has that we want but neither have nor are downloading: the one
+    /// with the earliest deadline, or else the highest priority, rarest (or lowest) one.
+    fn pick_new_piece(&self, bitfield: &Bitfield, fast: bool) -> Option<u32> {
+        let urgent = self
+            .deadlines
+            .keys()
+            .filter(|index| {
+                bitfield.get(**index as usize)
+                    && !self.partial.contains_key(index)
+                    && self.allows(**index, fast)
+            })
+            .min_by_key(|index| self.urgency(**index));
+        if let Some(index) = urgent {
+            return Some(*index);
+        }
+
+        let candidates = bitfield
+            .iter_set()
+            .filter(|index| {
+                !self.have.get(*index)
+                    && self.is_wanted(*index)
+                    && !self.deadlines.contains_key(&(*index as u32))
+                    && !self.partial.contains_key(&(*index as u32))
+            })
+            .collect::<Vec<_>>();
+        let highest = candidates
+            .iter()
+            .map(|index| self.priorities[*index])
+            .max()?;
+        let candidates = candidates
+            .into_iter()
+            .filter(|index| self.priorities[*index] == highest)
+            .collect::<Vec<_>>();
+        if self.sequential {
+            return candidates.first().map(|index| *index as u32);
+        }
+
+        let rarest = candidates
+            .iter()
+            .map(|index| self.availability[*index])
+            .min()?;
+        let rarest = candidates
+            .into_iter()
+            .filter(|index| self.availability[*index] == rarest)
+            .collect::<Vec<_>>();
+
+        Some(rarest[rand::thread_rng().gen_range(0..rarest.len())] as u32)
+    }
+
+    /// Get block `i` of piece `index`.
+    fn block(&self, index: u32, i: usize) -> Block {
+        let begin = i as u32 * BLOCK_SIZE;
+        let length = (self.piece_size(index) - begin as u64).min(BLOCK_SIZE as u64) as u32;
+
+        Block {
+            index,
+            begin,
+            length,
+        }
+    }
+
+    /// Get state of `block`, if it belongs to a piece in progress.
+    fn state_mut(&mut self, block: Block) -> Option<&mut BlockState> {
+        let i = (block.begin / BLOCK_SIZE) as usize;
+        let expected = block.begin % BLOCK_SIZE == 0
+            && block.begin as u64 + (block.length as u64) <= self.piece_size(block.index)
+            && block == self.block(block.index, i);
+
+        let piece = self.partial.get_mut(&block.index)?;
+        expected.then(|| piece.blocks.get_mut(i)).flatten()
+    }
+
+    fn mark_requested(&mut self, block: Block) {
+        if let Some(state) = self.state_mut(block) {
+            *state = match *state {
+                BlockState::Requested(n) => BlockState::Requested(n + 1),
+                _ => BlockState::Requested(1),
+            };
+        }
+    }
+
+    /// Give back a requested `block` that won't arrive, such as when a peer chokes us or disconnects.
+    pub fn cancel(&mut self, block: Block) {
+        if let Some(state) = self.state_mut(block) {
+            *state = match *state {
+                BlockState::Requested(n) if n > 1 => BlockState::Requested(n - 1),
+                BlockState::Requested(_) => BlockState::Missing,
+                other => other,
+            };
+        }
+    }
+
+    /// Reserve `block` received from a peer while it's written, unless it isn't expected or
+    /// was received already.
+    pub fn reserve(&mut self, block: Block) -> bool {
+        let Some(state) = self.state_mut(block) else {
+            return false;
+        };
+        if !matches!(state, BlockState::Missing | BlockState::Requested(_)) {
+            return false;
+        }
+        *state = BlockState::Writing;
+
+        true
+    }
+
+    /// Give back reserved `block` that wasn't written, so it's downloaded again.
+    pub fn unreserve(&mut self, block: Block) {
+        if let Some(state) = self.state_mut(block) {
+            if *state == BlockState::Writing {
+                *state = BlockState::Missing;
+            }
+        }
+    }
+
+    /// Mark reserved `block` as written, having come from `sender`. Gets the sender of each
+    /// block of the piece once all of them are.
+    pub fn receive(&mut self, block: Block, sender: IpAddr) -> Option<Vec<IpAddr>> {
+        let state = self.state_mut(block)?;
+        if *state != BlockState::Writing {
+            return None;
+        }
+        *state = BlockState::Received;
+
+        let piece = self.partial.get_mut(&block.index)?;
+        piece.senders[(block.begin / BLOCK_SIZE) as usize] = Some(sender);
+        if !piece
+            .blocks
+            .iter()
+            .all(|state| *state == BlockState::Received)
+        {
+            return None;
+        }
+
+        let piece = self.partial.remove(&block.index)?;
+        Some(piece.senders.into_iter().flatten().collect())
+    }
+
+    /// Mark piece `index` as verified.
+    pub fn piece_verified(&mut self, index: u32) {
+        self.have.set(index as usize);
+        self.deadlines.remove(&index);
+    }
+
+    /// Mark piece `index` as failed, so it is downloaded again.
+    pub fn piece_failed(&mut self, index: u32) {
+        self.partial.remove(&index);
+    }
+}
+
+#[test]
+fn test_picker() {
+    let info = TorrentInfo {
+        files: vec![File {
+            length: BLOCK_SIZE as u64 * 3 + 1,
+            path: Vec::new(),
+            md5sum: None,
+        }],
+        name: b"test".to_vec(),
+        piece_length: BLOCK_SIZE as usize * 2,
+        pieces: vec![0; 40],
+        private: None,
+        is_single_file: true,
+    };
+    let mut picker = Picker::new(&info, Bitfield::new(2));
+    let peer = Bitfield::full(2);
+    picker.add_availability(0);
+
+    // Piece 1 is rarer, so it's picked first.
+    let first = picker.pick(&peer, &[], true).unwrap();
+    assert_eq!((first.index, first.begin, first.length), (1, 0, BLOCK_SIZE));
+    let second = picker.pick(&peer, &[first], true).unwrap();
+    assert_eq!((second.index, second.length), (1, 1));
+
+    // Piece 0 is started once piece 1 is fully requested.
+    let third = picker.pick(&peer, &[first, second], true).unwrap();
+    assert_eq!(third.index, 0);
+    let fourth = picker.pick(&peer, &[first, second, third], true).unwrap();
+
+    // End game hands out outstanding blocks again, except to the peer that has them.
+    let repeat = picker.pick(&peer, &[first, second, third], true).unwrap();
+    assert_eq!(repeat, fourth);
+    assert_eq!(
+        picker.pick(&peer, &[first, second, third, fourth], true),
+        None
+    );
+
+    let (a, b) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+    assert!(picker.reserve(first) && !picker.reserve(first));
+    assert_eq!(picker.receive(first, a), None);
+    assert_eq!(picker.receive(first, b), None);
+    let short = Block { length: 1, ..third };
+    assert!(!picker.reserve(short));
+    assert!(picker.reserve(second));
+    picker.unreserve(second);
+    assert_eq!(picker.receive(second, b), None);
+    assert!(picker.reserve(second));
+    assert_eq!(picker.receive(second, b), Some(vec![a, b]));
+    picker.piece_verified(1);
+    assert!(picker.have().get(1));
+
+    picker.cancel(third);
+    assert_eq!(picker.pick(&peer, &[fourth], true), Some(third));
+}
+
+#[test]
+fn test_picker_priorities() {
+    let info = TorrentInfo {
+        files: vec![File {
+            length: BLOCK_SIZE as u64 * 3,
+            path: Vec::new(),
+            md5sum: None,
+        }],
+        name: b"test".to_vec(),
+        piece_length: BLOCK_SIZE as usize,
+        pieces: vec![0; 60],
+        private: None,
+        is_single_file: true,
+    };
+    let mut picker = Picker::new(&info, Bitfield::new(3));
+    let peer = Bitfield::full(3);
+    picker.add_availability(2);
+    picker.set_priorities(vec![
+        FilePriority::Skip,
+        FilePriority::High,
+        FilePriority::Low,
+    ]);
+
+    // The high priority piece goes first, even though piece 2 is rarer.
+    let first = picker.pick(&peer, &[], true).unwrap();
+    assert_eq!(first.index, 1);
+    let second = picker.pick(&peer, &[first], true).unwrap();
+    assert_eq!(second.index, 2);
+    // Piece 0 is skipped, so only end game is left.
+    assert_eq!(picker.pick(&peer, &[first, second], true), None);
+
+    let sender = IpAddr::from([10, 0, 0, 1]);
+    assert!(picker.reserve(first));
+    assert_eq!(picker.receive(first, sender), Some(vec![sender]));
+    picker.piece_verified(1);
+    assert!(!picker.is_complete());
+    assert!(picker.reserve(second));
+    assert_eq!(picker.receive(second, sender), Some(vec![sender]));
+    picker.piece_verified(2);
+    assert!(picker.is_complete());
+    assert!(!picker.is_interesting(&peer));
+}
+
+#[test]
+fn test_picker_deadlines() {
+    let info = TorrentInfo {
+        files: vec![File {
+            length: BLOCK_SIZE as u64 * 4,
+            path: Vec::new(),
+            md5sum: None,
+        }],
+        name: b"test".to_vec(),
+        piece_length: BLOCK_SIZE as usize,
+        pieces: vec![0; 80],
+        private: None,
+        is_single_file: true,
+    };
+    let mut picker = Picker::new(&info, Bitfield::new(4));
+    let peer = Bitfield::full(4);
+    picker.set_sequential(true);
+
+    let now = Instant::now();
+    let later = now + std::time::Duration::from_secs(60);
+    picker.set_deadlines([(3, later), (2, later + std::time::Duration::from_secs(1))]);
+
+    // Slow peers don't get pieces with deadlines that haven't passed, and go in order.
+    let slow = picker.pick(&peer, &[], false).unwrap();
+    assert_eq!(slow.index, 0);
+    // Fast peers get the earliest deadline first.
+    assert_eq!(picker.pick(&peer, &[], true).unwrap().index, 3);
+    assert_eq!(picker.pick(&peer, &[], true).unwrap().index, 2);
+
+    // Passed deadlines go to any peer, and come before anything else.
+    picker.set_deadlines([(1, now)]);
+    assert_eq!(picker.pick(&peer, &[slow], false).unwrap().index, 1);
+}
@@ -1,18 +1,33 @@
 mod request;
 mod response;
+mod scrape;
 
 use crate::error::Error;
 use crate::prelude::*;
-use request::TrackerRequest;
-use response::TrackerResponse;
+
+pub use request::TrackerRequest;
+pub use response::TrackerResponse;
+pub use scrape::{ScrapeRequest, ScrapeResponse};
 
 /// A torrent tracker.
 #[derive(Debug)]
 pub struct Tracker {}
 
 impl Tracker {
-    /// Create a [`TrackerRequest`] for a `torrent` and its `agent`.
-    pub fn create_request(torrent: &Torrent, agent: &Agent) -> Result<TrackerRequest, Error> {
-        TrackerRequest::with(torrent, agent)
+    /// Create a [`TrackerRequest`] for a `torrent` whose agent has `peer_id` and listens at `port`.
+    pub fn create_request(
+        torrent: &Torrent,
+        peer_id: [u8; 20],
+        port: u16,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    ) -> TrackerRequest {
+        TrackerRequest::with(torrent, peer_id, port, uploaded, downloaded, left)
+    }
+
+    /// Create a [`ScrapeRequest`] for a `torrent`.
+    pub fn create_scrape(torrent: &Torrent) -> Result<ScrapeRequest, Error> {
+        ScrapeRequest::with(torrent)
     }
 }
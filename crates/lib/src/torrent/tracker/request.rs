@@ -1,7 +1,7 @@
 use super::TrackerResponse;
-use crate::prelude::*;
 use crate::error::Error;
-use rand::distributions::{Alphanumeric, DistString};
+use crate::prelude::*;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 /// Tracker GET request.
 #[derive(Debug)]
@@ -10,56 +10,109 @@ pub struct TrackerRequest {
     pub announce: String,
     /// Torrent info hash.
     pub info_hash: Vec<u8>,
-    /// Random peer id.
-    pub peer_id: String,
+    /// Peer ID of the agent.
+    pub peer_id: [u8; 20],
     /// Optional peer ip.
     pub ip: Option<String>,
+    /// Optional IPv4 address of the agent, for a tracker reached over IPv6 (BEP 7).
+    pub ipv4: Option<Ipv4Addr>,
+    /// Optional IPv6 address of the agent, for a tracker reached over IPv4 (BEP 7).
+    pub ipv6: Option<Ipv6Addr>,
     /// Port peer is listening at.
     pub port: u16,
     /// Total amount uploaded.
-    pub uploaded: usize,
+    pub uploaded: u64,
     /// Total amount downloaded.
-    pub downloaded: usize,
+    pub downloaded: u64,
     /// Total amount left.
-    pub left: usize,
+    pub left: u64,
     /// Optional status.
     pub event: Option<String>,
 }
 
 impl TrackerRequest {
-    /// Create a [`TrackerRequest`] from a [`Torrent`] and its [`Agent`].
-    pub fn with(torrent: &Torrent, agent: &Agent) -> Result<Self, Error> {
-        let file = agent.get_file(&torrent.get_hash())?;
-
-        Ok(Self {
+    /// Create a [`TrackerRequest`] for a [`Torrent`], with its transfer totals.
+    pub fn with(
+        torrent: &Torrent,
+        peer_id: [u8; 20],
+        port: u16,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
+    ) -> Self {
+        Self {
             announce: torrent.announce.clone(),
             info_hash: torrent.get_hash().to_vec(),
-            peer_id: Alphanumeric.sample_string(&mut rand::thread_rng(), 20),
+            peer_id,
             ip: None,
-            port: agent.get_port(),
-            uploaded: file.get_uploaded(),
-            downloaded: file.get_downloaded(),
-            left: file.get_left(),
+            ipv4: None,
+            ipv6: None,
+            port,
+            uploaded,
+            downloaded,
+            left,
             event: None,
-        })
+        }
     }
 
     /// Send [`TrackerRequest`] and wait for [`TrackerResponse`].
     pub async fn send(&self) -> Result<TrackerResponse, Error> {
-        let final_url = format!(
-            "{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}",
+        self.send_with(&reqwest::Client::new()).await
+    }
+
+    /// Send [`TrackerRequest`] through `client` and wait for [`TrackerResponse`].
+    pub async fn send_with(&self, client: &reqwest::Client) -> Result<TrackerResponse, Error> {
+        let bytes = client.get(self.url()).send().await?.bytes().await?.to_vec();
+
+        TrackerResponse::from_bytes(&bytes)
+    }
+
+    /// Get URL of the request.
+    fn url(&self) -> String {
+        let mut url = format!(
+            "{}?info_hash={}&peer_id={}&port={}&uploaded={}&downloaded={}&left={}&compact=1",
             self.announce,
             urlencoding::encode_binary(&self.info_hash),
-            urlencoding::encode(&self.peer_id),
+            urlencoding::encode_binary(&self.peer_id),
             urlencoding::encode(&self.port.to_string()),
             urlencoding::encode(&self.uploaded.to_string()),
             urlencoding::encode(&self.downloaded.to_string()),
             urlencoding::encode(&self.left.to_string()),
-            //urlencoding::encode(self.event.as_ref().unwrap_or(&"".to_string())),
         );
-        println!("{:?}", final_url);
-        let bytes = reqwest::get(final_url).await?.bytes().await?.to_vec();
+        if let Some(event) = &self.event {
+            url.push_str(&format!("&event={}", urlencoding::encode(event)));
+        }
+        if let Some(ipv4) = self.ipv4 {
+            url.push_str(&format!("&ipv4={ipv4}"));
+        }
+        if let Some(ipv6) = self.ipv6 {
+            url.push_str(&format!("&ipv6={}", urlencoding::encode(&ipv6.to_string())));
+        }
 
-        TrackerResponse::from_bytes(&bytes)
+        url
     }
 }
+
+#[test]
+fn test_tracker_request_url() {
+    let mut request = TrackerRequest {
+        announce: "http://tracker.example/announce".to_string(),
+        info_hash: vec![0xab; 20],
+        peer_id: *b"-RP0000-abcdefghijkl",
+        ip: None,
+        ipv4: None,
+        ipv6: None,
+        port: 6881,
+        uploaded: 0,
+        downloaded: 0,
+        left: 100,
+        event: None,
+    };
+    let url = request.url();
+    assert!(!url.contains("ipv4=") && !url.contains("ipv6="));
+
+    request.ipv4 = Some(Ipv4Addr::new(203, 0, 113, 7));
+    request.ipv6 = Some("2001:db8::1".parse().unwrap());
+    let url = request.url();
+    assert!(url.ends_with("&left=100&compact=1&ipv4=203.0.113.7&ipv6=2001%3Adb8%3A%3A1"));
+}
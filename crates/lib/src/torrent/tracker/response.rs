@@ -1,32 +1,48 @@
-use crate::error::Error;
+use crate::error::{Error, TrackerError};
 use crate::prelude::*;
 
 /// Tracker response.
 #[derive(Debug)]
 pub struct TrackerResponse {
     /// Number of seconds to wait between regular rerequests.
-    interval: usize,
+    pub interval: usize,
     /// List of peers.
-    peers: Vec<Peer>,
+    pub peers: Vec<Peer>,
 }
 
 impl TrackerResponse {
     /// Create [`TrackerResponse`] from bytes.
     pub fn from_bytes(contents: &[u8]) -> Result<Self, Error> {
-        let dict = decode(contents)?.try_as::<Dictionary>()?;
+        let dict = decode_with(contents, DecodeOptions::network())?.try_as::<Dictionary>()?;
 
-        if let Ok(failure_reason) = dict.try_get_as::<ByteString>("failure reason") {
-            return Err(Error::Tracker(String::from_utf8(failure_reason.0)?));
+        if let Some(reason) = dict.get("failure reason").and_then(Value::as_bytes) {
+            return Err(Error::Tracker(TrackerError::Failure {
+                reason: String::from_utf8_lossy(reason).into_owned(),
+                retry_in: dict
+                    .get("retry in")
+                    .and_then(Value::as_int)
+                    .and_then(|minutes| minutes.try_into().ok()),
+            }));
         }
 
-        let interval = dict.try_get_as::<Integer>("interval")?.0 as usize;
-        let peers = dict
-            .try_get("peers")?
-            .clone()
-            .as_list_of::<Dictionary>()?
-            .iter()
-            .map(Peer::from_dictionary)
-            .collect::<Result<Vec<Peer>, Error>>()?;
+        let interval = dict.try_get_as::<Integer>("interval")?.try_to()?;
+        let peers6 = dict.get("peers6").and_then(Value::as_bytes);
+        // Trackers reached over IPv6 may only return `peers6` (BEP 7).
+        let mut peers = match (dict.get("peers"), peers6) {
+            (None, Some(_)) => Vec::new(),
+            _ => match dict.try_get("peers")? {
+                Value::ByteString(compact) => Peer::from_compact(&compact.0)?,
+                other => other
+                    .clone()
+                    .as_list_of::<Dictionary>()?
+                    .iter()
+                    .map(Peer::from_dictionary)
+                    .collect::<Result<Vec<Peer>, Error>>()?,
+            },
+        };
+        if let Some(compact) = peers6 {
+            peers.extend(Peer::from_compact6(compact)?);
+        }
 
         Ok(Self { interval, peers })
     }
@@ -36,5 +52,49 @@ impl TrackerResponse {
 fn test_tracker_response_from_bytes() {
     let bytes = b"d8:completei64e10:incompletei1e8:intervali1800e5:peersld2:ip37:2606:6080:1001:12:257a:8b87:f80d:75797:peer id20:-TR4030-0vjbp0s2z68f4:porti61406eed2:ip14:185.125.190.597:peer id20:T03I--00Y-FEdyCcD9xB4:porti6930eeee";
     TrackerResponse::from_bytes(bytes).unwrap();
+}
+
+#[test]
+fn test_tracker_response_compact() {
+    let bytes = b"d8:intervali900e5:peers12:\x7f\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50e";
+    let response = TrackerResponse::from_bytes(bytes).unwrap();
+    let addrs = response
+        .peers
+        .iter()
+        .map(|peer| peer.addr().unwrap().to_string())
+        .collect::<Vec<_>>();
+
+    assert_eq!(addrs, ["127.0.0.1:6881", "10.0.0.2:80"]);
+}
+
+#[test]
+fn test_tracker_response_peers6() {
+    let mut bytes = b"d8:intervali900e5:peers6:\x7f\x00\x00\x01\x1a\xe16:peers618:".to_vec();
+    bytes.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+    bytes.extend_from_slice(b"\x1a\xe1e");
+    let response = TrackerResponse::from_bytes(&bytes).unwrap();
+    let addrs = response
+        .peers
+        .iter()
+        .map(|peer| peer.addr().unwrap().to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(addrs, ["127.0.0.1:6881", "[2001:db8::1]:6881"]);
+
+    let mut bytes = b"d8:intervali900e6:peers618:".to_vec();
+    bytes.extend_from_slice(&[0; 16]);
+    bytes.extend_from_slice(b"\x00\x50e");
+    let response = TrackerResponse::from_bytes(&bytes).unwrap();
+    assert_eq!(response.peers[0].addr().unwrap().to_string(), "[::]:80");
+    assert!(TrackerResponse::from_bytes(b"d8:intervali900e6:peers63:abce").is_err());
+}
+
+#[test]
+fn test_tracker_response_failure() {
+    let bytes = b"d14:failure reason12:unregistered8:retry ini30ee";
+    let error = TrackerResponse::from_bytes(bytes).unwrap_err();
 
+    assert!(matches!(
+        error,
+        Error::Tracker(TrackerError::Failure { reason, retry_in: Some(30) }) if reason == "unregistered"
+    ));
 }
@@ -0,0 +1,125 @@
+use crate::error::{Error, TrackerError};
+use crate::prelude::*;
+
+/// Tracker scrape request, asking for statistics of a torrent's swarm.
+#[derive(Debug)]
+pub struct ScrapeRequest {
+    /// Scrape URL, derived from the announce URL.
+    pub scrape: String,
+    /// Torrent info hash.
+    pub info_hash: Vec<u8>,
+}
+
+/// Statistics of a torrent's swarm, as reported by its tracker.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScrapeResponse {
+    /// Number of peers with the entire file (seeders).
+    pub complete: u64,
+    /// Number of times the download was completed.
+    pub downloaded: u64,
+    /// Number of peers still downloading (leechers).
+    pub incomplete: u64,
+}
+
+impl ScrapeRequest {
+    /// Create a [`ScrapeRequest`] for a [`Torrent`].
+    ///
+    /// By convention, the scrape URL is the announce URL with its last path segment's
+    /// `announce` replaced by `scrape`. Trackers whose URL doesn't follow it can't be scraped.
+    pub fn with(torrent: &Torrent) -> Result<Self, Error> {
+        let announce = &torrent.announce;
+        let scrape = announce
+            .rfind('/')
+            .filter(|slash| announce[slash + 1..].starts_with("announce"))
+            .map(|slash| {
+                format!(
+                    "{}scrape{}",
+                    &announce[..slash + 1],
+                    &announce[slash + 1 + "announce".len()..]
+                )
+            })
+            .ok_or_else(|| TrackerError::ScrapeUnsupported(announce.clone()))?;
+
+        Ok(Self {
+            scrape,
+            info_hash: torrent.get_hash().to_vec(),
+        })
+    }
+
+    /// Send [`ScrapeRequest`] and wait for [`ScrapeResponse`].
+    pub async fn send(&self) -> Result<ScrapeResponse, Error> {
+        let separator = if self.scrape.contains('?') { '&' } else { '?' };
+        let final_url = format!(
+            "{}{separator}info_hash={}",
+            self.scrape,
+            urlencoding::encode_binary(&self.info_hash),
+        );
+        let bytes = reqwest::get(final_url).await?.bytes().await?.to_vec();
+
+        ScrapeResponse::from_bytes(&bytes, &self.info_hash)
+    }
+}
+
+impl ScrapeResponse {
+    /// Create [`ScrapeResponse`] for torrent `info_hash` from bytes.
+    pub fn from_bytes(contents: &[u8], info_hash: &[u8]) -> Result<Self, Error> {
+        let dict = decode_with(contents, DecodeOptions::network())?.try_as::<Dictionary>()?;
+
+        if let Some(reason) = dict.get("failure reason").and_then(Value::as_bytes) {
+            return Err(Error::Tracker(TrackerError::Failure {
+                reason: String::from_utf8_lossy(reason).into_owned(),
+                retry_in: None,
+            }));
+        }
+
+        let files = dict.try_get_as::<Dictionary>("files")?;
+        let stats = files
+            .0
+            .get(info_hash)
+            .and_then(Value::as_dictionary)
+            .ok_or(TrackerError::NotScraped)?;
+
+        Ok(Self {
+            complete: stats.try_get_as::<Integer>("complete")?.try_to()?,
+            downloaded: stats.try_get_as::<Integer>("downloaded")?.try_to()?,
+            incomplete: stats.try_get_as::<Integer>("incomplete")?.try_to()?,
+        })
+    }
+}
+
+#[test]
+fn test_scrape_url() {
+    let path = std::env::current_dir()
+        .unwrap()
+        .join("./tests/torrents/ubuntu-23.04-desktop-amd64.iso.torrent");
+    let mut torrent = Torrent::from_bytes(&std::fs::read(path).unwrap()).unwrap();
+
+    let request = ScrapeRequest::with(&torrent).unwrap();
+    assert_eq!(request.scrape, "https://torrent.ubuntu.com/scrape");
+
+    torrent.announce = "http://example.com/announce.php?passkey=x".to_string();
+    let request = ScrapeRequest::with(&torrent).unwrap();
+    assert_eq!(request.scrape, "http://example.com/scrape.php?passkey=x");
+
+    torrent.announce = "http://example.com/a".to_string();
+    assert!(ScrapeRequest::with(&torrent).is_err());
+}
+
+#[test]
+fn test_scrape_response_from_bytes() {
+    let hash = [7; 20];
+    let mut bytes = b"d5:filesd20:".to_vec();
+    bytes.extend_from_slice(&hash);
+    bytes.extend_from_slice(b"d8:completei5e10:downloadedi50e10:incompletei10eeee");
+
+    let response = ScrapeResponse::from_bytes(&bytes, &hash).unwrap();
+    assert_eq!(
+        response,
+        ScrapeResponse {
+            complete: 5,
+            downloaded: 50,
+            incomplete: 10
+        }
+    );
+    assert!(ScrapeResponse::from_bytes(&bytes, &[0; 20]).is_err());
+}
@@ -0,0 +1,118 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Get `addr` with an IPv4-mapped IPv6 address, as dual-stack sockets report IPv4 peers,
+/// turned back into an IPv4 address.
+pub(crate) fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(_) => addr,
+    }
+}
+
+/// Get the address of this host that traffic to addresses of the same family as `remote`
+/// leaves from, as the routing table says. Nothing is sent.
+pub(crate) async fn source_ip(remote: IpAddr) -> Option<IpAddr> {
+    let any = match remote {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = tokio::net::UdpSocket::bind((any, 0)).await.ok()?;
+    socket.connect((remote, 9)).await.ok()?;
+
+    Some(socket.local_addr().ok()?.ip())
+}
+
+/// Encode `bytes` as lowercase hexadecimal.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Decode hexadecimal `text`, returning `None` if it isn't valid.
+pub(crate) fn from_hex(text: &str) -> Option<Vec<u8>> {
+    // `from_str_radix` alone would take signs, like in `+f`.
+    if text.len() % 2 != 0 || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Encode `bytes` as unpadded uppercase base32 (RFC 4648).
+pub(crate) fn to_base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    let mut out = String::new();
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+
+    out
+}
+
+/// Decode unpadded base32 `text` (RFC 4648) in either case, returning `None` if it isn't valid.
+pub(crate) fn from_base32(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for byte in text.bytes() {
+        let value = match byte.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u16;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(out)
+}
+
+#[test]
+fn test_hex() {
+    assert_eq!(to_hex(&[0x00, 0xab, 0x7f]), "00ab7f");
+    assert_eq!(from_hex("00ab7F").unwrap(), vec![0x00, 0xab, 0x7f]);
+    assert!(from_hex("abc").is_none());
+    assert!(from_hex("zz").is_none());
+    assert!(from_hex("+f").is_none());
+    assert!(from_hex("-0").is_none());
+}
+
+#[test]
+fn test_base32() {
+    assert_eq!(to_base32(b""), "");
+    assert_eq!(to_base32(b"f"), "MY");
+    assert_eq!(to_base32(b"foobar"), "MZXW6YTBOI");
+    assert_eq!(to_base32(&[0xff; 20]).len(), 32);
+    assert_eq!(from_base32("MZXW6YTBOI").unwrap(), b"foobar");
+    assert_eq!(from_base32("mzxw6ytboi").unwrap(), b"foobar");
+    assert_eq!(from_base32(&to_base32(&[0xab; 20])).unwrap(), [0xab; 20]);
+    assert!(from_base32("MZ1").is_none());
+}
+
+#[test]
+fn test_canonical() {
+    let mapped = "[::ffff:10.0.0.2]:6881".parse().unwrap();
+    assert_eq!(canonical(mapped).to_string(), "10.0.0.2:6881");
+    let v6 = "[2001:db8::1]:6881".parse().unwrap();
+    assert_eq!(canonical(v6), v6);
+}
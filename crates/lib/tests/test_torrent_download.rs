@@ -7,7 +7,10 @@ async fn test_torrent_tracker() {
     let torrent_path = this_dir.join("./torrents/ubuntu-23.04-desktop-amd64.iso.torrent");
     let out_path = this_dir.join("./torrents");
 
-    let mut agent = Agent::new().unwrap();
-    agent.add_torrents(vec![torrent_path]).await.unwrap();
-    agent.download(&out_path).await.unwrap();
+    let agent = Agent::new().await.unwrap();
+    let hashes = agent
+        .add_torrents(vec![torrent_path], &out_path)
+        .await
+        .unwrap();
+    agent.wait(&hashes[0]).await.unwrap();
 }
@@ -7,4 +7,12 @@ fn test_torrent_parse() {
         .join("./tests/torrents/ubuntu-23.04-desktop-amd64.iso.torrent");
     let contents = std::fs::read(path).unwrap();
     let torrent = Torrent::from_bytes(&contents).unwrap();
+
+    assert!(torrent.info.is_single_file);
+    assert_eq!(
+        torrent.comment.as_deref(),
+        Some("Ubuntu CD releases.ubuntu.com")
+    );
+    assert_eq!(torrent.creation_date, Some(1681992794));
+    assert_eq!(torrent.announce_list.unwrap().len(), 2);
 }
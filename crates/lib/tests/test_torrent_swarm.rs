@@ -0,0 +1,1069 @@
+use rand::RngCore;
+use rip_lib::prelude::*;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+/// Create a temporary directory unique to this test run.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rip_{name}_{}", rand::random::<u64>()));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Create a multi-file torrent of random data, returning it and the contents of its files.
+fn make_torrent(piece_length: usize, lengths: &[usize]) -> (Torrent, Vec<Vec<u8>>) {
+    let contents = lengths
+        .iter()
+        .map(|length| {
+            let mut data = vec![0; *length];
+            rand::thread_rng().fill_bytes(&mut data);
+            data
+        })
+        .collect::<Vec<_>>();
+
+    let joined = contents.concat();
+    let pieces = joined
+        .chunks(piece_length)
+        .flat_map(|piece| sha1_smol::Sha1::from(piece).digest().bytes())
+        .collect::<Vec<_>>();
+    let files = contents
+        .iter()
+        .enumerate()
+        .map(|(i, data)| {
+            bdict! {
+                "length" => data.len() as i64,
+                "path" => blist![format!("file{i}.bin")],
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let metainfo = bdict! {
+        "announce" => "http://127.0.0.1:1/announce",
+        "info" => bdict! {
+            "files" => files,
+            "name" => "swarm",
+            "piece length" => piece_length as i64,
+            "pieces" => pieces,
+        },
+    };
+
+    (Torrent::from_bytes(&encode(&metainfo)).unwrap(), contents)
+}
+
+/// Read the next message other than a keep-alive from `stream`.
+async fn read_message(stream: &mut tokio::net::TcpStream) -> wire::Message {
+    loop {
+        match wire::Message::read(stream, 64 * 1024).await.unwrap() {
+            wire::Message::KeepAlive => {}
+            message => return message,
+        }
+    }
+}
+
+/// Seed `torrent` with the files in `contents` from a new temporary directory, getting the
+/// seeding agent, the info hash and the directory.
+async fn seed(torrent: &Torrent, contents: &[Vec<u8>], name: &str) -> (Agent, Vec<u8>, PathBuf) {
+    seed_with(Agent::with_port(0).await.unwrap(), torrent, contents, name).await
+}
+
+/// Like [`seed`], but seed with `agent`.
+async fn seed_with(
+    agent: Agent,
+    torrent: &Torrent,
+    contents: &[Vec<u8>],
+    name: &str,
+) -> (Agent, Vec<u8>, PathBuf) {
+    let dir = temp_dir(name);
+    for (i, data) in contents.iter().enumerate() {
+        let path = dir.join("swarm").join(format!("file{i}.bin"));
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+
+    let hash = agent.add_torrent(torrent.clone(), &dir).await.unwrap();
+    agent.wait(&hash).await.unwrap();
+
+    (agent, hash, dir)
+}
+
+/// Accept the next announce to `tracker`, getting its connection and request line.
+async fn read_announce(tracker: &tokio::net::TcpListener) -> (tokio::net::TcpStream, String) {
+    let (mut stream, _) = tracker.accept().await.unwrap();
+    let mut request = Vec::new();
+    while !request.ends_with(b"\r\n\r\n") {
+        request.push(stream.read_u8().await.unwrap());
+    }
+    let request = String::from_utf8(request).unwrap();
+    (stream, request.lines().next().unwrap().to_string())
+}
+
+/// Answer an announce read with [`read_announce`] with no peers.
+async fn answer_announce(mut stream: tokio::net::TcpStream) {
+    let body = encode(&bdict! { "interval" => 1800, "peers" => "" });
+    let head = format!(
+        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        body.len()
+    );
+    stream.write_all(head.as_bytes()).await.unwrap();
+    stream.write_all(&body).await.unwrap();
+}
+
+/// Answer the next announce with no peers, getting its request line.
+async fn announced(tracker: &tokio::net::TcpListener) -> String {
+    let (stream, request) = read_announce(tracker).await;
+    answer_announce(stream).await;
+    request
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_torrent_swarm() {
+    let (torrent, contents) = make_torrent(32 * 1024, &[100_000, 0, 70_001]);
+    let (seeder, hash, seed_dir) = seed(&torrent, &contents, "seed").await;
+
+    let leech_dir = temp_dir("leech");
+    let leecher = Agent::with_port(0).await.unwrap();
+    let mut events = leecher.subscribe();
+    leecher.add_torrent(torrent, &leech_dir).await.unwrap();
+    let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+    leecher.add_peer(&hash, seeder_addr).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(30), leecher.wait(&hash))
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+    for (i, data) in contents.iter().enumerate() {
+        let path = leech_dir.join("swarm").join(format!("file{i}.bin"));
+        assert_eq!(&std::fs::read(path).unwrap(), data);
+    }
+    let status = leecher.status(&hash).await.unwrap();
+    assert_eq!(status.state, TorrentState::Seeding);
+    assert_eq!(status.verified, 170_001);
+
+    let stats = leecher.stats().await.unwrap();
+    let files = &stats.torrents[0].files;
+    assert_eq!(stats.torrents[0].progress(), 1.0);
+    assert_eq!(
+        files.iter().map(|file| file.verified).collect::<Vec<_>>(),
+        [100_000, 0, 70_001]
+    );
+
+    let mut verified = 0;
+    let mut completed_files = Vec::new();
+    let mut finished = false;
+    while let Ok(event) = events.try_recv() {
+        match event {
+            AgentEvent::PieceVerified { .. } => verified += 1,
+            AgentEvent::FileCompleted { index, .. } => completed_files.push(index),
+            AgentEvent::TorrentFinished { .. } => finished = true,
+            _ => {}
+        }
+    }
+    completed_files.sort();
+    assert_eq!(verified, 6);
+    assert_eq!(completed_files, [0, 2]);
+    assert!(finished);
+
+    leecher.pause(&hash).await.unwrap();
+    assert_eq!(
+        leecher.status(&hash).await.unwrap().state,
+        TorrentState::Paused
+    );
+    leecher.remove(&hash, true).await.unwrap();
+    assert!(!leech_dir.join("swarm").exists());
+    assert!(leecher.status(&hash).await.is_err());
+
+    seeder.shutdown().await.unwrap();
+    std::fs::remove_dir_all(seed_dir).unwrap();
+    std::fs::remove_dir_all(leech_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_rate_limit() {
+    let (torrent, contents) = make_torrent(32 * 1024, &[150_000]);
+    let agent = Agent::with_config(AgentConfig {
+        listen_ports: 0..=0,
+        exempt_local_peers: false,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    let (seeder, hash, seed_dir) = seed_with(agent, &torrent, &contents, "limit_seed").await;
+    seeder
+        .set_rate_limits(RateLimits {
+            download: None,
+            upload: Some(100_000),
+        })
+        .await
+        .unwrap();
+    let stats = seeder.stats().await.unwrap();
+    assert_eq!(stats.rate_limits.upload, Some(100_000));
+
+    let leech_dir = temp_dir("limit_leech");
+    let leecher = Agent::with_port(0).await.unwrap();
+    leecher.add_torrent(torrent, &leech_dir).await.unwrap();
+    let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+    let start = std::time::Instant::now();
+    leecher.add_peer(&hash, seeder_addr).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(30), leecher.wait(&hash))
+        .await
+        .expect("download timed out")
+        .unwrap();
+    // 150 KB at 100 KB/s, less the first block.
+    assert!(start.elapsed() >= Duration::from_millis(1200));
+
+    seeder.shutdown().await.unwrap();
+    leecher.shutdown().await.unwrap();
+    std::fs::remove_dir_all(seed_dir).unwrap();
+    std::fs::remove_dir_all(leech_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_file_priorities() {
+    let (torrent, contents) = make_torrent(32 * 1024, &[100_000, 0, 70_001]);
+    let (seeder, hash, seed_dir) = seed(&torrent, &contents, "select_seed").await;
+
+    // File 2 is skipped, its part of the piece it shares with file 0 goes to the partfile.
+    let leech_dir = temp_dir("select_leech");
+    let leecher = Agent::with_port(0).await.unwrap();
+    let (normal, skip) = (FilePriority::Normal, FilePriority::Skip);
+    assert!(leecher
+        .add_torrent_with_priorities(torrent.clone(), &leech_dir, vec![normal])
+        .await
+        .is_err());
+    leecher
+        .add_torrent_with_priorities(torrent, &leech_dir, vec![normal, normal, skip])
+        .await
+        .unwrap();
+    let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+    leecher.add_peer(&hash, seeder_addr).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(30), leecher.wait(&hash))
+        .await
+        .expect("download timed out")
+        .unwrap();
+    let swarm = leech_dir.join("swarm");
+    assert_eq!(std::fs::read(swarm.join("file0.bin")).unwrap(), contents[0]);
+    assert!(swarm.join("file1.bin").exists());
+    assert!(!swarm.join("file2.bin").exists());
+    assert!(leech_dir.join(".swarm.parts").exists());
+    let stats = leecher.stats().await.unwrap();
+    assert_eq!(stats.torrents[0].progress(), 1.0);
+    assert_eq!(stats.torrents[0].files[2].priority, skip);
+    assert_eq!(stats.torrents[0].status.verified, 4 * 32 * 1024);
+
+    // Selecting file 2 later downloads the rest, and moves its part out of the partfile.
+    leecher
+        .set_file_priorities(&hash, vec![normal, normal, normal])
+        .await
+        .unwrap();
+    assert_eq!(
+        leecher.status(&hash).await.unwrap().state,
+        TorrentState::Downloading
+    );
+    leecher.add_peer(&hash, seeder_addr).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(30), leecher.wait(&hash))
+        .await
+        .expect("download timed out")
+        .unwrap();
+    assert_eq!(std::fs::read(swarm.join("file2.bin")).unwrap(), contents[2]);
+    assert!(!leech_dir.join(".swarm.parts").exists());
+
+    seeder.shutdown().await.unwrap();
+    leecher.shutdown().await.unwrap();
+    std::fs::remove_dir_all(seed_dir).unwrap();
+    std::fs::remove_dir_all(leech_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_streaming() {
+    let (torrent, contents) = make_torrent(32 * 1024, &[100_000, 0, 70_001]);
+    let (seeder, hash, seed_dir) = seed(&torrent, &contents, "stream_seed").await;
+
+    // Reads wait for their pieces, which are requested ahead of the rest.
+    let leech_dir = temp_dir("stream_leech");
+    let leecher = Agent::with_port(0).await.unwrap();
+    leecher.add_torrent(torrent, &leech_dir).await.unwrap();
+    leecher.set_sequential(&hash, true).await.unwrap();
+    assert!(leecher.open_file(&hash, 3).await.is_err());
+    assert!(leecher.set_playhead(&hash, 3, 0).await.is_err());
+    let mut file = leecher.open_file(&hash, 2).await.unwrap();
+    assert_eq!(file.get_length(), 70_001);
+    let reader = tokio::spawn(async move {
+        file.seek(std::io::SeekFrom::Start(50_000)).await.unwrap();
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).await.unwrap();
+        file.rewind().await.unwrap();
+        let mut head = vec![0; 1000];
+        file.read_exact(&mut head).await.unwrap();
+        assert!(file.seek(std::io::SeekFrom::Current(-2000)).await.is_err());
+        (head, tail)
+    });
+    let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+    leecher.add_peer(&hash, seeder_addr).await.unwrap();
+
+    let (head, tail) = tokio::time::timeout(Duration::from_secs(30), reader)
+        .await
+        .expect("read timed out")
+        .unwrap();
+    assert_eq!(tail, contents[2][50_000..]);
+    assert_eq!(head, contents[2][..1000]);
+    tokio::time::timeout(Duration::from_secs(30), leecher.wait(&hash))
+        .await
+        .expect("download timed out")
+        .unwrap();
+    leecher.clear_playhead(&hash).await.unwrap();
+
+    seeder.shutdown().await.unwrap();
+    leecher.shutdown().await.unwrap();
+    std::fs::remove_dir_all(seed_dir).unwrap();
+    std::fs::remove_dir_all(leech_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_encryption() {
+    let (torrent, contents) = make_torrent(32 * 1024, &[100_000, 0, 70_001]);
+
+    // Policies of seeder and leecher, and whether the connection ends up encrypted.
+    let cases = [
+        (EncryptionPolicy::Forced, EncryptionPolicy::Forced, true),
+        (EncryptionPolicy::Enabled, EncryptionPolicy::Forced, true),
+        (EncryptionPolicy::Forced, EncryptionPolicy::Enabled, true),
+        (EncryptionPolicy::Disabled, EncryptionPolicy::Enabled, false),
+        (EncryptionPolicy::Enabled, EncryptionPolicy::Disabled, false),
+    ];
+    for (seeder_policy, leecher_policy, encrypted) in cases {
+        let agent = |encryption| {
+            Agent::with_config(AgentConfig {
+                listen_ports: 0..=0,
+                encryption,
+                ..Default::default()
+            })
+        };
+        let seeder = agent(seeder_policy).await.unwrap();
+        let (seeder, hash, seed_dir) = seed_with(seeder, &torrent, &contents, "mse_seed").await;
+
+        let leech_dir = temp_dir("mse_leech");
+        let leecher = agent(leecher_policy).await.unwrap();
+        let mut events = leecher.subscribe();
+        leecher
+            .add_torrent(torrent.clone(), &leech_dir)
+            .await
+            .unwrap();
+        let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+        leecher.add_peer(&hash, seeder_addr).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(30), leecher.wait(&hash))
+            .await
+            .expect("download timed out")
+            .unwrap();
+        for (i, data) in contents.iter().enumerate() {
+            let path = leech_dir.join("swarm").join(format!("file{i}.bin"));
+            assert_eq!(&std::fs::read(path).unwrap(), data);
+        }
+        let mut connected = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            if let AgentEvent::PeerConnected { encrypted, .. } = event {
+                connected.push(encrypted);
+            }
+        }
+        assert_eq!(
+            connected,
+            [encrypted],
+            "{seeder_policy:?} {leecher_policy:?}"
+        );
+
+        seeder.shutdown().await.unwrap();
+        leecher.shutdown().await.unwrap();
+        std::fs::remove_dir_all(seed_dir).unwrap();
+        std::fs::remove_dir_all(leech_dir).unwrap();
+    }
+
+    // A forced connection can't fall back to a plain one.
+    let (seeder, hash, seed_dir) = seed(&torrent, &contents, "mse_seed").await;
+    let leech_dir = temp_dir("mse_leech");
+    let leecher = Agent::with_config(AgentConfig {
+        listen_ports: 0..=0,
+        encryption: EncryptionPolicy::Forced,
+        ..Default::default()
+    })
+    .await
+    .unwrap();
+    leecher.add_torrent(torrent, &leech_dir).await.unwrap();
+    let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+    leecher.add_peer(&hash, seeder_addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(leecher.status(&hash).await.unwrap().peers, 0);
+    assert_eq!(seeder.status(&hash).await.unwrap().peers, 0);
+
+    seeder.shutdown().await.unwrap();
+    leecher.shutdown().await.unwrap();
+    std::fs::remove_dir_all(seed_dir).unwrap();
+    std::fs::remove_dir_all(leech_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_utp() {
+    let (torrent, contents) = make_torrent(32 * 1024, &[300_000, 40_000]);
+
+    // Both sides accept TCP and uTP on the same port, the leecher picks uTP, encrypted.
+    let agent = |preferred_transport| {
+        Agent::with_config(AgentConfig {
+            listen_ports: 0..=0,
+            encryption: EncryptionPolicy::Enabled,
+            preferred_transport,
+            ..Default::default()
+        })
+    };
+    let seeder = agent(Transport::Tcp).await.unwrap();
+    let (seeder, hash, seed_dir) = seed_with(seeder, &torrent, &contents, "utp_seed").await;
+
+    let leech_dir = temp_dir("utp_leech");
+    let leecher = agent(Transport::Utp).await.unwrap();
+    let mut events = leecher.subscribe();
+    leecher.add_torrent(torrent, &leech_dir).await.unwrap();
+    let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+    leecher.add_peer(&hash, seeder_addr).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(30), leecher.wait(&hash))
+        .await
+        .expect("download timed out")
+        .unwrap();
+    for (i, data) in contents.iter().enumerate() {
+        let path = leech_dir.join("swarm").join(format!("file{i}.bin"));
+        assert_eq!(&std::fs::read(path).unwrap(), data);
+    }
+    let mut connected = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        if let AgentEvent::PeerConnected {
+            transport,
+            encrypted,
+            ..
+        } = event
+        {
+            connected.push((transport, encrypted));
+        }
+    }
+    assert_eq!(connected, [(Transport::Utp, true)]);
+
+    seeder.shutdown().await.unwrap();
+    leecher.shutdown().await.unwrap();
+    std::fs::remove_dir_all(seed_dir).unwrap();
+    std::fs::remove_dir_all(leech_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_ipv6() {
+    // Hosts without IPv6 can't run this.
+    if std::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)).is_err() {
+        return;
+    }
+
+    let (torrent, contents) = make_torrent(32 * 1024, &[200_000]);
+    let (seeder, hash, seed_dir) = seed(&torrent, &contents, "ipv6_seed").await;
+    let mut seeder_events = seeder.subscribe();
+
+    // The seeder listens on IPv6 and IPv4, one leecher connects over each, by TCP and uTP.
+    let mut dirs = vec![seed_dir];
+    for (name, ip, preferred_transport) in [
+        (
+            "ipv6_tcp",
+            IpAddr::from(Ipv6Addr::LOCALHOST),
+            Transport::Tcp,
+        ),
+        (
+            "ipv6_utp",
+            IpAddr::from(Ipv6Addr::LOCALHOST),
+            Transport::Utp,
+        ),
+        (
+            "ipv4_utp",
+            IpAddr::from(Ipv4Addr::LOCALHOST),
+            Transport::Utp,
+        ),
+    ] {
+        let leech_dir = temp_dir(name);
+        let leecher = Agent::with_config(AgentConfig {
+            listen_ports: 0..=0,
+            preferred_transport,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+        let mut events = leecher.subscribe();
+        leecher
+            .add_torrent(torrent.clone(), &leech_dir)
+            .await
+            .unwrap();
+        let seeder_addr = SocketAddr::new(ip, seeder.get_port());
+        leecher.add_peer(&hash, seeder_addr).await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(30), leecher.wait(&hash))
+            .await
+            .expect("download timed out")
+            .unwrap();
+        let path = leech_dir.join("swarm").join("file0.bin");
+        assert_eq!(std::fs::read(path).unwrap(), contents[0]);
+        let connected = std::iter::from_fn(|| events.try_recv().ok())
+            .find_map(|event| match event {
+                AgentEvent::PeerConnected {
+                    addr, transport, ..
+                } => Some((addr, transport)),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(connected, (seeder_addr, preferred_transport));
+        // IPv4 peers are seen at their IPv4 address, not an IPv4-mapped one.
+        let seen = std::iter::from_fn(|| seeder_events.try_recv().ok())
+            .find_map(|event| match event {
+                AgentEvent::PeerConnected { addr, .. } => Some(addr.ip()),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(seen, ip);
+
+        leecher.shutdown().await.unwrap();
+        dirs.push(leech_dir);
+    }
+
+    seeder.shutdown().await.unwrap();
+    for dir in dirs {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_fast_extension() {
+    use rip_lib::prelude::wire::{allowed_fast_set, Block, Handshake, Message};
+
+    let (torrent, contents) = make_torrent(16 * 1024, &[500_000]);
+    let (seeder, hash, seed_dir) = seed(&torrent, &contents, "fast_seed").await;
+
+    // A peer that never says it's interested, so it stays choked.
+    let info_hash: [u8; 20] = hash.clone().try_into().unwrap();
+    let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+    let mut stream = tokio::net::TcpStream::connect(seeder_addr).await.unwrap();
+    Handshake::new(info_hash, [7; 20])
+        .with_fast_extension()
+        .write(&mut stream)
+        .await
+        .unwrap();
+    assert!(Handshake::read(&mut stream)
+        .await
+        .unwrap()
+        .has_fast_extension());
+
+    assert_eq!(read_message(&mut stream).await, Message::HaveAll);
+    let allowed = allowed_fast_set(Ipv4Addr::LOCALHOST.into(), &info_hash, 31, 10);
+    for index in &allowed {
+        assert_eq!(
+            read_message(&mut stream).await,
+            Message::AllowedFast(*index)
+        );
+    }
+
+    // Pieces allowed fast are served while choked, others are rejected. The last piece is
+    // shorter than a block, so it isn't requested.
+    let block = |index: u32| Block {
+        index,
+        begin: 0,
+        length: 16 * 1024,
+    };
+    let served = allowed.iter().copied().find(|index| *index < 30).unwrap();
+    let refused = (0..30).find(|index| !allowed.contains(index)).unwrap();
+    for index in [served, refused] {
+        Message::Request(block(index))
+            .write(&mut stream)
+            .await
+            .unwrap();
+    }
+    let message = read_message(&mut stream).await;
+    let start = served as usize * 16 * 1024;
+    assert_eq!(
+        message,
+        Message::Piece {
+            index: served,
+            begin: 0,
+            data: contents[0][start..start + 16 * 1024].to_vec(),
+        }
+    );
+    let message = read_message(&mut stream).await;
+    assert_eq!(message, Message::RejectRequest(block(refused)));
+
+    seeder.shutdown().await.unwrap();
+    std::fs::remove_dir_all(seed_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_extension() {
+    use rip_lib::prelude::wire::{Handshake, Message};
+    use std::collections::BTreeMap;
+
+    // Greets peers that support it, then echoes their messages.
+    struct Echo;
+    impl Extension for Echo {
+        fn name(&self) -> &str {
+            "echo"
+        }
+
+        fn handshake(&self, _info_hash: &[u8; 20]) -> BTreeMap<String, Value> {
+            BTreeMap::from([("echo_version".to_string(), 1.into())])
+        }
+
+        fn on_peer_connected(&self, peer: &ExtensionPeer) {
+            peer.send(b"hello".to_vec());
+        }
+
+        fn on_message(&self, peer: &ExtensionPeer, payload: &[u8]) -> Result<(), Error> {
+            peer.send(payload.to_vec());
+            Ok(())
+        }
+    }
+
+    let (torrent, contents) = make_torrent(16 * 1024, &[40_000]);
+    let seeder = Agent::with_port(0).await.unwrap();
+    seeder.add_extension(Echo).unwrap();
+    assert!(seeder.add_extension(Echo).is_err());
+    let (seeder, hash, seed_dir) = seed_with(seeder, &torrent, &contents, "extension_seed").await;
+
+    let info_hash: [u8; 20] = hash.clone().try_into().unwrap();
+    let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+    let mut stream = tokio::net::TcpStream::connect(seeder_addr).await.unwrap();
+    Handshake::new(info_hash, [7; 20])
+        .with_extension_protocol()
+        .write(&mut stream)
+        .await
+        .unwrap();
+    assert!(Handshake::read(&mut stream)
+        .await
+        .unwrap()
+        .has_extension_protocol());
+
+    assert!(matches!(
+        read_message(&mut stream).await,
+        Message::Bitfield(_)
+    ));
+    let Message::Extended { id: 0, payload } = read_message(&mut stream).await else {
+        panic!("expected extension handshake");
+    };
+    let handshake = ExtendedHandshake::from_bytes(&payload).unwrap();
+    // Metadata exchange is built in, and comes before added extensions.
+    assert_eq!(
+        handshake.extensions,
+        BTreeMap::from([("ut_metadata".to_string(), 1), ("echo".to_string(), 2)])
+    );
+    assert_eq!(handshake.your_ip, Some(Ipv4Addr::LOCALHOST.into()));
+    assert_eq!(handshake.port, Some(seeder.get_port()));
+    assert_eq!(handshake.max_requests, Some(250));
+    assert_eq!(handshake.extra["echo_version"], 1.into());
+
+    // Messages of the extension go out under the ID we gave it.
+    let handshake = ExtendedHandshake {
+        extensions: BTreeMap::from([("echo".to_string(), 5)]),
+        client: None,
+        max_requests: None,
+        your_ip: None,
+        port: None,
+        extra: BTreeMap::new(),
+    };
+    let payload = handshake.to_bytes();
+    let message = Message::Extended { id: 0, payload };
+    message.write(&mut stream).await.unwrap();
+    let greeting = Message::Extended {
+        id: 5,
+        payload: b"hello".to_vec(),
+    };
+    assert_eq!(read_message(&mut stream).await, greeting);
+
+    let message = Message::Extended {
+        id: 2,
+        payload: b"ping".to_vec(),
+    };
+    message.write(&mut stream).await.unwrap();
+    let echo = Message::Extended {
+        id: 5,
+        payload: b"ping".to_vec(),
+    };
+    assert_eq!(read_message(&mut stream).await, echo);
+
+    seeder.shutdown().await.unwrap();
+    std::fs::remove_dir_all(seed_dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_torrent_swarm_magnet() {
+    // Enough files for metadata of more than one piece of 16 KiB.
+    let (torrent, contents) = make_torrent(16 * 1024, &[100; 500]);
+    assert!(torrent.get_metadata().len() > 16 * 1024);
+    let (seeder, hash, seed_dir) = seed(&torrent, &contents, "magnet_seed").await;
+
+    let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+    let uri = format!("{}&x.pe={seeder_addr}", torrent.magnet_uri());
+    let magnet = Magnet::parse(&uri).unwrap();
+    let leecher = Agent::with_port(0).await.unwrap();
+    let timeout = Duration::from_secs(30);
+    let fetched = tokio::time::timeout(timeout, leecher.fetch_metadata(&magnet))
+        .await
+        .expect("fetching metadata timed out")
+        .unwrap();
+    assert_eq!(fetched.get_hash(), hash);
+    assert_eq!(fetched.get_metadata(), torrent.get_metadata());
+    assert_eq!(fetched.announce, torrent.announce);
+
+    let leech_dir = temp_dir("magnet_leech");
+    leecher.add_torrent(fetched, &leech_dir).await.unwrap();
+    leecher.add_peer(&hash, seeder_addr).await.unwrap();
+    tokio::time::timeout(timeout, leecher.wait(&hash))
+        .await
+        .expect("download timed out")
+        .unwrap();
+    for (i, data) in contents.iter().enumerate() {
+        let path = leech_dir.join("swarm").join(format!("file{i}.bin"));
+        assert_eq!(&std::fs::read(path).unwrap(), data);
+    }
+
+    // Peers that don't have the torrent can't send its metadata.
+    let unknown = Magnet {
+        info_hash: [1; 20],
+        name: None,
+        trackers: Vec::new(),
+        peers: vec![seeder_addr],
+    };
+    let error = leecher.fetch_metadata(&unknown).await.unwrap_err();
+    assert!(matches!(error, Error::Agent(AgentError::MetadataNotFound)));
+
+    leecher.shutdown().await.unwrap();
+    seeder.shutdown().await.unwrap();
+    std::fs::remove_dir_all(seed_dir).unwrap();
+    std::fs::remove_dir_all(leech_dir).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_torrent_swarm_pex() {
+    /// Wait until an agent with `events` connected to a peer at `addr`.
+    async fn connected(
+        events: &mut tokio::sync::broadcast::Receiver<AgentEvent>,
+        addr: SocketAddr,
+    ) {
+        while let Ok(event) = events.recv().await {
+            if matches!(event, AgentEvent::PeerConnected { addr: peer, .. } if peer == addr) {
+                return;
+            }
+        }
+        panic!("no connection to {addr}");
+    }
+
+    // The seeder is reached over IPv6 where the host has it, so an IPv6 peer is exchanged.
+    let ip = match std::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)) {
+        Ok(_) => IpAddr::from(Ipv6Addr::LOCALHOST),
+        Err(_) => IpAddr::from(Ipv4Addr::LOCALHOST),
+    };
+    let config = || AgentConfig {
+        listen_ports: 0..=0,
+        pex: true,
+        ..Default::default()
+    };
+    let (torrent, contents) = make_torrent(32 * 1024, &[100_000]);
+    let seeder = Agent::with_config(config()).await.unwrap();
+    let (seeder, hash, seed_dir) = seed_with(seeder, &torrent, &contents, "pex_seed").await;
+    let seeder_addr = SocketAddr::new(ip, seeder.get_port());
+    let timeout = Duration::from_secs(30);
+
+    // The first leecher knows the seeder, the second one only knows the first one.
+    let first_dir = temp_dir("pex_first");
+    let first = Agent::with_config(config()).await.unwrap();
+    let mut first_events = first.subscribe();
+    first
+        .add_torrent(torrent.clone(), &first_dir)
+        .await
+        .unwrap();
+    first.add_peer(&hash, seeder_addr).await.unwrap();
+    let seeder_connected = connected(&mut first_events, seeder_addr);
+    tokio::time::timeout(timeout, seeder_connected)
+        .await
+        .unwrap();
+
+    let second_dir = temp_dir("pex_second");
+    let second = Agent::with_config(config()).await.unwrap();
+    let mut second_events = second.subscribe();
+    second.add_torrent(torrent, &second_dir).await.unwrap();
+    let first_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, first.get_port()));
+    second.add_peer(&hash, first_addr).await.unwrap();
+
+    // The first leecher tells the second one about the seeder, which it connects to.
+    let seeder_connected = connected(&mut second_events, seeder_addr);
+    tokio::time::timeout(timeout, seeder_connected)
+        .await
+        .unwrap();
+
+    for agent in [seeder, first, second] {
+        agent.shutdown().await.unwrap();
+    }
+    for dir in [seed_dir, first_dir, second_dir] {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_torrent_swarm_dht() {
+    /// Ask the DHT node at `node` for peers of the torrent with `hash` over `socket`,
+    /// getting whether it knows any.
+    async fn has_peers(socket: &tokio::net::UdpSocket, node: SocketAddr, hash: &[u8]) -> bool {
+        let query = bdict! {
+            "a" => bdict! { "id" => vec![7u8; 20], "info_hash" => hash.to_vec() },
+            "q" => "get_peers",
+            "t" => "aa",
+            "y" => "q",
+        };
+        socket.send_to(&encode(&query), node).await.unwrap();
+        let mut buf = vec![0; 2048];
+        let receiving = socket.recv_from(&mut buf);
+        let Ok(Ok((len, _))) = tokio::time::timeout(Duration::from_secs(1), receiving).await else {
+            return false;
+        };
+        let response = decode(&buf[..len]).unwrap().try_as::<Dictionary>().unwrap();
+        let response = response.get("r").and_then(Value::as_dictionary).unwrap();
+        response.has("values")
+    }
+
+    // The DHT is reached over IPv6 where the host has it (BEP 32).
+    let ip = match std::net::UdpSocket::bind((Ipv6Addr::LOCALHOST, 0)) {
+        Ok(_) => IpAddr::from(Ipv6Addr::LOCALHOST),
+        Err(_) => IpAddr::from(Ipv4Addr::LOCALHOST),
+    };
+    let config = || AgentConfig {
+        listen_ports: 0..=0,
+        dht: true,
+        dht_routers: Vec::new(),
+        ..Default::default()
+    };
+    let without_dht = Agent::with_port(0).await.unwrap();
+    let error = without_dht.add_dht_node(SocketAddr::new(ip, 1)).await;
+    assert!(matches!(error, Err(Error::Agent(AgentError::DhtDisabled))));
+
+    // A node without torrents that the others join the DHT through.
+    let bootstrap = Agent::with_config(config()).await.unwrap();
+    let node = SocketAddr::new(ip, bootstrap.get_port());
+    let (torrent, contents) = make_torrent(32 * 1024, &[100_000]);
+    let seeder = Agent::with_config(config()).await.unwrap();
+    seeder.add_dht_node(node).await.unwrap();
+    let (seeder, hash, seed_dir) = seed_with(seeder, &torrent, &contents, "dht_seed").await;
+
+    // The seeder announces itself to the node it knows.
+    let socket = tokio::net::UdpSocket::bind((ip, 0)).await.unwrap();
+    let announced = async {
+        while !has_peers(&socket, node, &hash).await {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(30), announced)
+        .await
+        .expect("seeder didn't announce itself");
+
+    // The leecher is told of no peer, it finds the seeder through the DHT.
+    let leech_dir = temp_dir("dht_leech");
+    let leecher = Agent::with_config(config()).await.unwrap();
+    leecher.add_dht_node(node).await.unwrap();
+    leecher.add_torrent(torrent, &leech_dir).await.unwrap();
+    tokio::time::timeout(Duration::from_secs(30), leecher.wait(&hash))
+        .await
+        .expect("download timed out")
+        .unwrap();
+    let path = leech_dir.join("swarm").join("file0.bin");
+    assert_eq!(std::fs::read(path).unwrap(), contents[0]);
+
+    for agent in [without_dht, bootstrap, seeder, leecher] {
+        agent.shutdown().await.unwrap();
+    }
+    for dir in [seed_dir, leech_dir] {
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_ban() {
+    use rip_lib::prelude::wire::{Handshake, Message};
+
+    let (torrent, _) = make_torrent(16 * 1024, &[100_000]);
+    let info_hash: [u8; 20] = torrent.get_hash().try_into().unwrap();
+    let leech_dir = temp_dir("ban_leech");
+    let leecher = Agent::with_port(0).await.unwrap();
+    let mut events = leecher.subscribe();
+    let hash = leecher.add_torrent(torrent, &leech_dir).await.unwrap();
+
+    // Connecting to ourselves is noticed, and not tried again.
+    let own_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, leecher.get_port()));
+    leecher.add_peer(&hash, own_addr).await.unwrap();
+
+    // A peer that has every piece, but only sends zeros.
+    let listener = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    leecher
+        .add_peer(&hash, listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let (mut stream, _) = listener.accept().await.unwrap();
+    Handshake::read(&mut stream).await.unwrap();
+    Handshake::new(info_hash, [7; 20])
+        .write(&mut stream)
+        .await
+        .unwrap();
+    Message::Bitfield(vec![0xfe])
+        .write(&mut stream)
+        .await
+        .unwrap();
+    Message::Unchoke.write(&mut stream).await.unwrap();
+    let serve = async {
+        while let Ok(message) = Message::read(&mut stream, 64 * 1024).await {
+            if let Message::Request(block) = message {
+                let data = vec![0; block.length as usize];
+                let message = Message::Piece {
+                    index: block.index,
+                    begin: block.begin,
+                    data,
+                };
+                // Fails once the leecher hung up.
+                let _ = message.write(&mut stream).await;
+            }
+        }
+    };
+    // The leecher hangs up once it bans the peer.
+    tokio::time::timeout(Duration::from_secs(30), serve)
+        .await
+        .expect("peer wasn't banned");
+
+    let mut failed = 0;
+    let mut banned = Vec::new();
+    while let Ok(event) = events.try_recv() {
+        match event {
+            AgentEvent::PieceFailed { .. } => failed += 1,
+            AgentEvent::PeerBanned { ip, .. } => banned.push(ip),
+            AgentEvent::PeerConnected { addr, .. } => assert_ne!(addr, own_addr),
+            _ => {}
+        }
+    }
+    assert!(failed >= 2);
+    assert_eq!(banned, [IpAddr::from(Ipv4Addr::LOCALHOST)]);
+
+    // Banned peers aren't connected to again.
+    leecher
+        .add_peer(&hash, listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let accepted = tokio::time::timeout(Duration::from_secs(2), listener.accept()).await;
+    assert!(accepted.is_err());
+
+    leecher.shutdown().await.unwrap();
+    std::fs::remove_dir_all(leech_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_tracker_stopped() {
+    let (mut torrent, _) = make_torrent(32 * 1024, &[50_000]);
+    let tracker = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    torrent.announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+    let leech_dir = temp_dir("stopped");
+
+    let leecher = Agent::with_port(0).await.unwrap();
+    let hash = leecher
+        .add_torrent_paused(torrent, &leech_dir)
+        .await
+        .unwrap();
+    // Torrents added paused don't announce until they're resumed.
+    let early = Duration::from_millis(500);
+    assert!(tokio::time::timeout(early, tracker.accept()).await.is_err());
+    leecher.resume(&hash).await.unwrap();
+    let timeout = Duration::from_secs(30);
+    let started = tokio::time::timeout(timeout, announced(&tracker)).await;
+    assert!(started.unwrap().contains("event=started"));
+
+    // The tracker hears that we left, both on pause and on shutdown.
+    leecher.pause(&hash).await.unwrap();
+    let stopped = tokio::time::timeout(timeout, announced(&tracker)).await;
+    assert!(stopped.unwrap().contains("event=stopped"));
+    leecher.resume(&hash).await.unwrap();
+    let started = tokio::time::timeout(timeout, announced(&tracker)).await;
+    assert!(started.unwrap().contains("event=started"));
+
+    let (shutdown, stopped) = tokio::join!(leecher.shutdown(), announced(&tracker));
+    shutdown.unwrap();
+    assert!(stopped.contains("event=stopped"));
+    std::fs::remove_dir_all(leech_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_tracker_completed() {
+    let (mut torrent, contents) = make_torrent(32 * 1024, &[50_000]);
+    let tracker = tokio::net::TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+    let (seeder, hash, seed_dir) = seed(&torrent, &contents, "completed-seed").await;
+    torrent.announce = format!("http://{}/announce", tracker.local_addr().unwrap());
+    let leech_dir = temp_dir("completed");
+
+    let leecher = Agent::with_port(0).await.unwrap();
+    leecher.add_torrent(torrent, &leech_dir).await.unwrap();
+    let timeout = Duration::from_secs(30);
+    let (stream, started) = tokio::time::timeout(timeout, read_announce(&tracker))
+        .await
+        .unwrap();
+    assert!(started.contains("event=started"));
+
+    // The download finishes while the first announce is still waiting for its answer.
+    let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+    leecher.add_peer(&hash, seeder_addr).await.unwrap();
+    tokio::time::timeout(timeout, leecher.wait(&hash))
+        .await
+        .expect("download timed out")
+        .unwrap();
+    answer_announce(stream).await;
+    let completed = tokio::time::timeout(timeout, announced(&tracker)).await;
+    assert!(completed.unwrap().contains("event=completed"));
+
+    let (shutdown, stopped) = tokio::join!(leecher.shutdown(), announced(&tracker));
+    shutdown.unwrap();
+    assert!(stopped.contains("event=stopped"));
+    seeder.shutdown().await.unwrap();
+    std::fs::remove_dir_all(seed_dir).unwrap();
+    std::fs::remove_dir_all(leech_dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_torrent_swarm_memory() {
+    let (torrent, contents) = make_torrent(32 * 1024, &[100_000, 0, 50_000]);
+    let seed_storage = MemoryStorage::with_data(&torrent.info, contents.concat()).unwrap();
+    let leech_storage = Arc::new(MemoryStorage::new(&torrent.info).unwrap());
+
+    let seeder = Agent::with_port(0).await.unwrap();
+    let hash = seeder
+        .add_torrent_with_storage(torrent.clone(), Arc::new(seed_storage))
+        .await
+        .unwrap();
+    seeder.wait(&hash).await.unwrap();
+
+    let leecher = Agent::with_port(0).await.unwrap();
+    let storage = Arc::clone(&leech_storage) as Arc<dyn Storage>;
+    leecher
+        .add_torrent_with_storage(torrent, storage)
+        .await
+        .unwrap();
+    let seeder_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.get_port()));
+    leecher.add_peer(&hash, seeder_addr).await.unwrap();
+
+    tokio::time::timeout(Duration::from_secs(30), leecher.wait(&hash))
+        .await
+        .expect("download timed out")
+        .unwrap();
+    assert_eq!(leech_storage.get_data(), contents.concat());
+    assert!(!leech_storage.root().exists());
+
+    leecher.shutdown().await.unwrap();
+    seeder.shutdown().await.unwrap();
+}